@@ -0,0 +1,132 @@
+//! Run with `cargo run -F codegen && cargo bench`.
+
+use std::collections::HashMap;
+use std::future::{Future, ready};
+use std::hint::black_box;
+use std::sync::RwLock;
+
+use bytes::Bytes;
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+
+use perfume::identity::{
+    ConnectionBridge, Population, RemoteStore, Storage, StorageState, check_blob,
+};
+
+include!(concat!(env!("TMPDIR"), "/perfume.rs"));
+
+const BRAZILIAN: Population = Population {
+    domain: "br",
+    secret: b"0123456789abcdef0123456789abcdef",
+    ingredients: &PERFUME_INGREDIENTS,
+};
+
+/// Records in each benchmarked blob, about a fifth of the Brazil population size.
+const BLOB_RECORDS: usize = 10_000;
+
+#[derive(Default, Clone)]
+struct MemoryBridge(std::sync::Arc<RwLock<HashMap<String, Bytes>>>);
+
+impl ConnectionBridge for MemoryBridge {
+    fn get(&self, key: &str) -> std::io::Result<Option<Bytes>> {
+        Ok(self.0.read().unwrap().get(key).cloned())
+    }
+    fn put(&self, key: &str, body: Bytes) -> std::io::Result<()> {
+        self.0.write().unwrap().insert(key.to_string(), body);
+        Ok(())
+    }
+    fn get_async(&self, key: &str) -> impl Future<Output = std::io::Result<Option<Bytes>>> + Send {
+        ready(self.get(key))
+    }
+    fn put_async(
+        &self,
+        key: &str,
+        body: Bytes,
+    ) -> impl Future<Output = std::io::Result<()>> + Send {
+        ready(self.put(key, body))
+    }
+}
+
+/// A store with `BLOB_RECORDS` identities sharing the storage key of the returned identifier.
+fn populated_store() -> (RemoteStore<MemoryBridge>, Storage) {
+    let mut store = RemoteStore::new(MemoryBridge::default());
+    let known = BRAZILIAN.storage_object("known@example.br");
+    let mut digests = vec![known.digest.as_str().to_string()];
+    let mut i = 0;
+    while digests.len() < BLOB_RECORDS {
+        let storage = BRAZILIAN.storage_object(&format!("{i}@example.br"));
+        i += 1;
+        // blake3 output is uniform, so any digest is realistic
+        digests.push(storage.digest.as_str().to_string());
+    }
+    let mut records = digests
+        .iter()
+        .enumerate()
+        .map(|(offset, digest)| (digest.as_str(), offset))
+        .collect::<Vec<_>>();
+    records.sort();
+    let blob = records
+        .iter()
+        .map(|(digest, offset)| format!("{digest} {offset:>5}\n"))
+        .collect::<String>();
+    store.bridge.put(known.key.as_str(), blob.into()).unwrap();
+    store.digest_offset("br", &known).unwrap();
+    (store, known)
+}
+
+fn digest(c: &mut Criterion) {
+    c.bench_function("storage_object", |b| {
+        b.iter(|| BRAZILIAN.storage_object(black_box("someone@example.br")))
+    });
+}
+
+fn blob_parsing(c: &mut Criterion) {
+    let (store, known) = populated_store();
+    let blob = store.bridge.get(known.key.as_str()).unwrap().unwrap();
+    c.bench_function("check_blob", |b| b.iter(|| check_blob(black_box(&blob))));
+}
+
+fn lookup(c: &mut Criterion) {
+    let (mut store, known) = populated_store();
+    c.bench_function("digest_offset/found", |b| {
+        b.iter(|| store.digest_offset("br", black_box(&known)).unwrap())
+    });
+    c.bench_function("digest_offset/absent", |b| {
+        b.iter_batched(
+            || RemoteStore::new(MemoryBridge::default()),
+            |mut store| store.digest_offset("br", &known).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    c.bench_function("digest_offset/inserted", |b| {
+        let (store, known) = populated_store();
+        let mut new_digest = known.clone();
+        new_digest.digest = BRAZILIAN.storage_object("new@example.br").digest;
+        b.iter_batched(
+            || {
+                RemoteStore::new(MemoryBridge(std::sync::Arc::new(RwLock::new(
+                    store.bridge.0.read().unwrap().clone(),
+                ))))
+            },
+            |mut store| store.digest_offset("br", &new_digest).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn identity(c: &mut Criterion) {
+    let mut store = RemoteStore::new(MemoryBridge::default());
+    BRAZILIAN
+        .identity("someone@example.br", &mut store)
+        .unwrap();
+    c.bench_function("identity", |b| {
+        b.iter(|| {
+            BRAZILIAN
+                .identity(black_box("someone@example.br"), &mut store)
+                .unwrap()
+        })
+    });
+    c.bench_function("sample_names", |b| b.iter(|| BRAZILIAN.sample_names(1)));
+}
+
+criterion_group!(benches, digest, blob_parsing, lookup, identity);
+criterion_main!(benches);
@@ -23,9 +23,7 @@ pub fn test_server(addr: &str) -> std::thread::JoinHandle<()> {
                         Ok((req, body)) => {
                             if let Some(response_str) = response_body(req, body, &mut resources) {
                                 stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
-                                for line in response_str.lines() {
-                                    stream.write_all(line.as_bytes()).unwrap();
-                                }
+                                stream.write_all(response_str.as_bytes()).unwrap();
                             } else {
                                 stream.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n").unwrap();
                             }
@@ -82,6 +80,7 @@ fn response_body<'rs>(
 ) -> Option<&'rs str> {
     match (req.method, req.path, body) {
         (Some("GET"), Some(path), _) => resources.get(path).map(|r| r.as_str()),
+        (Some("HEAD"), Some(path), _) => resources.get(path).map(|_| ""),
         (Some("PUT"), Some(path), Some(body)) => {
             let body_string = String::from_utf8_lossy(&body[..]).to_string();
             resources.insert(path.to_string(), body_string);
@@ -1,10 +1,6 @@
-use std::io::Error;
-use std::result::Result;
-
-use bytes::Bytes;
 use const_env::env_item;
 
-use perfume::identity::{ConnectionBridge, Population, RemoteStore};
+use perfume::identity::{ConnectionBridge, KeyTemplate, Population, RemoteStore, UreqBridge};
 
 mod common;
 use common::test_server;
@@ -17,19 +13,17 @@ const PERFUME_SECRET: &[u8] = b"3D5aPzC0jwT25eAWlEa4FcW8d9FNz00g";
 
 const BHUTANESE: Population = Population {
     domain: "bt",
-    secret: PERFUME_SECRET,            // 32 bytes for keyed hasher
+    secret: PERFUME_SECRET,            // the key of the keyed hasher
     ingredients: &PERFUME_INGREDIENTS, // see build.rs example below
 };
 
 fn main() {
     let _server_handle = test_server("127.0.0.1:9090");
 
-    let mut store = RemoteStore {
-        bridge: ExampleBridge {
-            url: "http://localhost:9090".try_into().unwrap(),
-            domain: BHUTANESE.domain.to_string(),
-        },
-    };
+    // blobs are stored at http://localhost:9090/bt/<storage key>
+    let template = KeyTemplate::new("{domain}/{key}", BHUTANESE.domain).unwrap();
+    let bridge = UreqBridge::new("http://localhost:9090").unwrap();
+    let mut store = RemoteStore::new(bridge).with_key_template(template);
 
     let user1 = BHUTANESE.identity("flying@wom.bt", &mut store).unwrap();
     let user2 = BHUTANESE.identity("fast@serpent.bt", &mut store).unwrap();
@@ -45,74 +39,19 @@ fn main() {
     );
 
     // storage is based on the 64 character hash output of the identifier "flying@wom.bt"
-    let stored_blob = store
-        .bridge
-        .get(user1.storage.key.as_str()) // storage key is the first 3 characters of the hash
-        .unwrap()
-        .unwrap();
+    // storage key is the first 3 characters of the hash, placed in the key template
+    let stored_key = store.bridge_key(user1.storage.key.as_str());
+    assert_eq!(stored_key, format!("bt/{}", user1.storage.key));
+    assert!(store.bridge.exists(&stored_key).unwrap());
+    let stored_blob = store.bridge.get(&stored_key).unwrap().unwrap();
     assert_eq!(
         String::from_utf8_lossy(stored_blob.as_ref()),
         // first line of the blob is the last 61 characters of the hash,
         // followed by an offset into a list of random names
         String::from_utf8_lossy(
-            [user1.storage.digest.as_str().as_bytes(), b"     0"]
+            [user1.storage.digest.as_str().as_bytes(), b"     0\n"]
                 .concat()
                 .as_ref()
         )
     );
 }
-
-struct ExampleBridge {
-    url: http::Uri,
-    domain: String,
-}
-
-impl ConnectionBridge for ExampleBridge {
-    fn get(&self, key: &str) -> Result<Option<Bytes>, Error> {
-        let resource_url = format!("{}{}/{}", self.url, self.domain, key);
-        let response = ureq::get(&resource_url)
-            .config()
-            .http_status_as_error(false)
-            .build()
-            .call()
-            .map_err(|e| Error::other(format!("IO failure on request to {resource_url}: {e}")))?;
-        match response.status() {
-            http::StatusCode::OK => {
-                let body = response.into_body().read_to_vec().map_err(|e| {
-                    Error::other(format!(
-                        "error parsing response body on request to {resource_url}: {e}"
-                    ))
-                })?;
-                Ok(Some(Bytes::from(body)))
-            }
-            http::StatusCode::NOT_FOUND => Ok(None),
-            unexpected => Err(Error::other(format!(
-                "unexpected HTTP response on request to {resource_url}: {unexpected}"
-            ))),
-        }
-    }
-
-    fn put(&self, key: &str, body: Bytes) -> Result<(), Error> {
-        let resource_url = format!("{}{}/{}", self.url, self.domain, key);
-        let response = ureq::put(&resource_url)
-            .config()
-            .http_status_as_error(false)
-            .build()
-            .send(&body[..])
-            .map_err(|e| Error::other(format!("IO failure on request to {resource_url}: {e}")))?;
-        match response.status() {
-            http::StatusCode::OK => Ok(()),
-            unexpected => Err(Error::other(format!(
-                "unexpected HTTP response on request to {resource_url}: {unexpected}"
-            ))),
-        }
-    }
-
-    async fn get_async(&self, _key: &str) -> Result<Option<Bytes>, Error> {
-        unimplemented!()
-    }
-
-    async fn put_async(&self, _key: &str, _body: Bytes) -> Result<(), Error> {
-        unimplemented!()
-    }
-}
@@ -0,0 +1,68 @@
+//! Storage blobs are fetched from a remote store, and must never cause a panic or an
+//! unbounded allocation when they are parsed, checked, converted or searched.
+
+#![no_main]
+
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use perfume::identity::{
+    BlobFormat, ConnectionBridge, RemoteStore, Storage, StorageState, check_blob,
+};
+use perfume::testing::MockBridge;
+
+const KEY: &str = "abc";
+// searched in every blob, along with the digest of its first line
+const DIGESTS: [&str; 3] = [
+    "0000000000000000000000000000000000000000000000000000000000000",
+    "8000000000000000000000000000000000000000000000000000000000000",
+    "fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+];
+
+fuzz_target!(|input: (u8, &[u8])| {
+    let (options, blob) = input;
+
+    let _ = check_blob(blob);
+    for format in [BlobFormat::Text, BlobFormat::JsonLines] {
+        if let Ok(text) = format.decode(blob) {
+            let _ = format.encode(&text);
+        }
+    }
+
+    let bridge = MockBridge::default();
+    bridge.put(KEY, Bytes::copy_from_slice(blob)).unwrap();
+    let mut store = RemoteStore::new(bridge);
+    if options & 1 != 0 {
+        store = store.with_streaming();
+    }
+    if options & 2 != 0 {
+        store = store.with_offset_index(4);
+    }
+    if options & 4 != 0 {
+        store = store.with_blob_cache(4);
+    }
+    if options & 8 != 0 {
+        store = store.with_quarantine();
+    }
+    if options & 16 != 0 {
+        store = store.with_blob_format(BlobFormat::JsonLines);
+    }
+    if options & 32 != 0 {
+        store = store.with_bloom_filter(100, 0.01);
+    }
+    if options & 64 != 0 {
+        store = store.with_digest_length(16);
+    }
+
+    let first_digest = blob
+        .split(|&b| b == b' ')
+        .next()
+        .and_then(|digest| std::str::from_utf8(digest).ok());
+    for digest in DIGESTS.into_iter().chain(first_digest) {
+        let Ok(storage) = format!("{KEY}{digest}").parse::<Storage>() else {
+            continue;
+        };
+        // twice, so that cached blobs and indexes are searched too
+        let _ = store.digest_offset("fuzz", &storage);
+        let _ = store.digest_offset("fuzz", &storage);
+    }
+});
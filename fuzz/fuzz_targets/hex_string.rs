@@ -0,0 +1,25 @@
+//! Hex strings and storage objects are parsed from untrusted bytes, such as stored records and
+//! database columns, and must be rejected rather than cause a panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use perfume::hex_string::HexString;
+use perfume::identity::Storage;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(storage) = Storage::try_from(data) {
+        let text = storage.to_string();
+        assert_eq!(text, String::from_utf8_lossy(data).to_ascii_lowercase());
+        assert_eq!(Storage::try_from(text.as_bytes()).unwrap(), storage);
+    }
+
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    if let Ok(key) = text.parse::<HexString<3>>() {
+        assert_eq!(key.as_str(), text.to_ascii_lowercase());
+        let value: u16 = HexString::<4>::from(format!("{key}0").as_bytes()).into();
+        assert_eq!(format!("{value:04x}"), format!("{key}0"));
+    }
+});
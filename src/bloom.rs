@@ -0,0 +1,70 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// A probabilistic set, which can determine that an item is *certainly not* a member.
+#[derive(Debug, Clone)]
+pub(crate) struct Bloom {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl Bloom {
+    /// Sized so that `expected_items` members produce the given rate of false positives.
+    pub fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let expected_items = expected_items.max(1) as f64;
+        let bit_count = (-expected_items * false_positive_rate.ln() / (ln2 * ln2)).ceil();
+        let hashes = (bit_count / expected_items * ln2).round().max(1.0) as u32;
+        Self {
+            bits: vec![0; (bit_count as usize).div_ceil(64).max(1)],
+            hashes,
+        }
+    }
+
+    /// The bytes held by the filter.
+    pub fn size(&self) -> usize {
+        std::mem::size_of_val(self.bits.as_slice())
+    }
+
+    pub fn insert(&mut self, item: &[u8]) {
+        for bit in self.bit_indexes(item) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.bit_indexes(item)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    // double hashing, see Kirsch and Mitzenmacher (2006)
+    fn bit_indexes(&self, item: &[u8]) -> impl Iterator<Item = usize> + use<> {
+        let bit_count = self.bits.len() as u64 * 64;
+        let mut hasher = DefaultHasher::new();
+        item.hash(&mut hasher);
+        let h1 = hasher.finish();
+        1u8.hash(&mut hasher);
+        let h2 = hasher.finish() | 1;
+        (0..self.hashes as u64)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bit_count) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_false_negatives() {
+        let mut bloom = Bloom::new(1000, 0.01);
+        let items: Vec<String> = (0..1000).map(|i| format!("item {i}")).collect();
+        for item in &items {
+            bloom.insert(item.as_bytes());
+        }
+        assert!(items.iter().all(|i| bloom.contains(i.as_bytes())));
+
+        let false_positives = (0..1000)
+            .filter(|i| bloom.contains(format!("other {i}").as_bytes()))
+            .count();
+        assert!(false_positives < 50, "{false_positives} false positives");
+    }
+}
@@ -0,0 +1,85 @@
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use perfume::Error;
+use perfume::identity::{Population, StorageState};
+
+use super::output::Output;
+
+/// Resolve `identities` random identifiers using `concurrency` threads, each with its own store.
+/// Concurrent writers may overwrite each other's assignments, so a dedicated domain should be used.
+pub fn bench<S, F>(
+    population: &Population,
+    new_store: F,
+    identities: usize,
+    concurrency: usize,
+    out: &mut Output,
+) -> Result<(), Error>
+where
+    S: StorageState,
+    F: Fn() -> Result<S, Error> + Sync,
+{
+    let concurrency = concurrency.max(1);
+    let start = Instant::now();
+    let results: Vec<Result<Vec<Duration>, Error>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..concurrency)
+            .map(|thread| {
+                let count =
+                    identities / concurrency + usize::from(thread < identities % concurrency);
+                let new_store = &new_store;
+                scope.spawn(move || -> Result<Vec<Duration>, Error> {
+                    let mut store = new_store()?;
+                    let mut latencies = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        let identifier = format!("{:032x}", rand::random::<u128>());
+                        let started = Instant::now();
+                        population.identity(&identifier, &mut store)?;
+                        latencies.push(started.elapsed());
+                    }
+                    Ok(latencies)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("benchmark thread should not panic"))
+            .collect()
+    });
+    let elapsed = start.elapsed();
+
+    let mut latencies = vec![];
+    for result in results {
+        latencies.extend(result?);
+    }
+    latencies.sort();
+    let p50 = percentile(&latencies, 50);
+    let p99 = percentile(&latencies, 99);
+    let throughput = latencies.len() as f64 / elapsed.as_secs_f64();
+
+    out.emit(
+        format_args!(
+            "resolved {} identities with {concurrency} threads in {elapsed:?}\n{}\n{}\n{}",
+            latencies.len(),
+            format_args!("p50:        {p50:?}"),
+            format_args!("p99:        {p99:?}"),
+            format_args!("throughput: {throughput:.1}/s"),
+        ),
+        json!({
+            "identities": latencies.len(),
+            "concurrency": concurrency,
+            "p50_us": p50.as_micros(),
+            "p99_us": p99.as_micros(),
+            "throughput": throughput,
+            "elapsed_ms": elapsed.as_millis(),
+        }),
+    )?;
+    Ok(())
+}
+
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    sorted[(sorted.len() * percent / 100).min(sorted.len() - 1)]
+}
@@ -0,0 +1,267 @@
+use std::io::Error;
+use std::ops::Range;
+
+use bytes::Bytes;
+
+use perfume::identity::{ConnectionBridge, Validated};
+
+/// The keys of the blob protocol, relative to the url of the server,
+/// see [`perfume::identity::KeyTemplate`].
+pub const KEY_TEMPLATE: &str = "{domain}/{key}";
+
+/// Stores blobs on an HTTP server using GET and PUT requests.
+/// Implements the protocol described by openapi/perfume.json, see examples/remote_store_ureq.rs
+/// Keys are paths made from [`KEY_TEMPLATE`] by the store.
+pub struct HttpBridge {
+    url: String,
+}
+
+impl HttpBridge {
+    pub fn new(url: &str) -> Result<Self, Error> {
+        let _: http::Uri = url
+            .try_into()
+            .map_err(|e| Error::other(format!("invalid store url {url}: {e}")))?;
+        Ok(Self {
+            url: url.trim_end_matches('/').to_string(),
+        })
+    }
+
+    fn resource_url(&self, key: &str) -> String {
+        format!("{}/{}", self.url, key)
+    }
+
+    // stores `body`, only if the blob matches the header `condition` when there is one
+    fn send_put(
+        &self,
+        key: &str,
+        body: &[u8],
+        condition: Option<(&str, &str)>,
+    ) -> Result<(), Error> {
+        let resource_url = self.resource_url(key);
+        let mut request = ureq::put(&resource_url);
+        if let Some((name, value)) = condition {
+            request = request.header(name, value);
+        }
+        let response = request
+            .config()
+            .http_status_as_error(false)
+            .build()
+            .send(body)
+            .map_err(|e| Error::other(format!("IO failure on request to {resource_url}: {e}")))?;
+        match response.status() {
+            http::StatusCode::OK => Ok(()),
+            http::StatusCode::PRECONDITION_FAILED => Err(Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("blob at {resource_url} was modified by another writer"),
+            )),
+            unexpected => Err(Error::other(format!(
+                "unexpected HTTP response on request to {resource_url}: {unexpected}"
+            ))),
+        }
+    }
+}
+
+impl ConnectionBridge for HttpBridge {
+    fn get(&self, key: &str) -> Result<Option<Bytes>, Error> {
+        let resource_url = self.resource_url(key);
+        let response = ureq::get(&resource_url)
+            .config()
+            .http_status_as_error(false)
+            .build()
+            .call()
+            .map_err(|e| Error::other(format!("IO failure on request to {resource_url}: {e}")))?;
+        match response.status() {
+            http::StatusCode::OK => {
+                let body = response.into_body().read_to_vec().map_err(|e| {
+                    Error::other(format!(
+                        "error parsing response body on request to {resource_url}: {e}"
+                    ))
+                })?;
+                Ok(Some(Bytes::from(body)))
+            }
+            http::StatusCode::NOT_FOUND => Ok(None),
+            unexpected => Err(Error::other(format!(
+                "unexpected HTTP response on request to {resource_url}: {unexpected}"
+            ))),
+        }
+    }
+
+    fn get_validated(&self, key: &str, validator: Option<&str>) -> Result<Validated, Error> {
+        let resource_url = self.resource_url(key);
+        let mut request = ureq::get(&resource_url);
+        if let Some(etag) = validator {
+            request = request.header("If-None-Match", etag);
+        }
+        let response = request
+            .config()
+            .http_status_as_error(false)
+            .build()
+            .call()
+            .map_err(|e| Error::other(format!("IO failure on request to {resource_url}: {e}")))?;
+        let etag = response
+            .headers()
+            .get(http::header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        match response.status() {
+            http::StatusCode::NOT_MODIFIED => Ok(Validated::NotModified),
+            http::StatusCode::OK => {
+                let body = response.into_body().read_to_vec().map_err(|e| {
+                    Error::other(format!(
+                        "error parsing response body on request to {resource_url}: {e}"
+                    ))
+                })?;
+                Ok(Validated::Modified {
+                    body: Some(Bytes::from(body)),
+                    validator: etag,
+                })
+            }
+            http::StatusCode::NOT_FOUND => Ok(Validated::Modified {
+                body: None,
+                validator: None,
+            }),
+            unexpected => Err(Error::other(format!(
+                "unexpected HTTP response on request to {resource_url}: {unexpected}"
+            ))),
+        }
+    }
+
+    fn get_range(&self, key: &str, range: Range<u64>) -> Result<Option<(Bytes, u64)>, Error> {
+        let resource_url = self.resource_url(key);
+        let last = range.end.max(range.start + 1) - 1;
+        let response = ureq::get(&resource_url)
+            .header("Range", format!("bytes={}-{last}", range.start))
+            .config()
+            .http_status_as_error(false)
+            .build()
+            .call()
+            .map_err(|e| Error::other(format!("IO failure on request to {resource_url}: {e}")))?;
+        // "bytes <first>-<last>/<length>" or "bytes */<length>"
+        let length = response
+            .headers()
+            .get(http::header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok()?.rsplit_once('/')?.1.parse::<u64>().ok());
+        let status = response.status();
+        let body = match status {
+            http::StatusCode::OK | http::StatusCode::PARTIAL_CONTENT => {
+                let body = response.into_body().read_to_vec().map_err(|e| {
+                    Error::other(format!(
+                        "error parsing response body on request to {resource_url}: {e}"
+                    ))
+                })?;
+                Bytes::from(body)
+            }
+            _ => Bytes::new(),
+        };
+        let wanted = (range.end - range.start.min(range.end)) as usize;
+        match (status, length) {
+            (http::StatusCode::PARTIAL_CONTENT, Some(length)) => {
+                Ok(Some((body.slice(..wanted.min(body.len())), length)))
+            }
+            (http::StatusCode::RANGE_NOT_SATISFIABLE, Some(length)) => {
+                Ok(Some((Bytes::new(), length)))
+            }
+            // the server sent the whole blob
+            (http::StatusCode::OK, _) => {
+                let start = (range.start as usize).min(body.len());
+                let end = (range.end as usize).clamp(start, body.len());
+                Ok(Some((body.slice(start..end), body.len() as u64)))
+            }
+            (http::StatusCode::NOT_FOUND, _) => Ok(None),
+            (unexpected, _) => Err(Error::other(format!(
+                "unexpected HTTP response on request to {resource_url}: {unexpected}"
+            ))),
+        }
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> Result<(), Error> {
+        self.send_put(key, &body, None)
+    }
+
+    fn put_if_match(&self, key: &str, body: Bytes, validator: Option<&str>) -> Result<(), Error> {
+        let condition = match validator {
+            Some(etag) => ("If-Match", etag),
+            None => ("If-None-Match", "*"),
+        };
+        self.send_put(key, &body, Some(condition))
+    }
+
+    fn exists(&self, key: &str) -> Result<bool, Error> {
+        let resource_url = self.resource_url(key);
+        let response = ureq::head(&resource_url)
+            .config()
+            .http_status_as_error(false)
+            .build()
+            .call()
+            .map_err(|e| Error::other(format!("IO failure on request to {resource_url}: {e}")))?;
+        match response.status() {
+            http::StatusCode::OK => Ok(true),
+            http::StatusCode::NOT_FOUND => Ok(false),
+            unexpected => Err(Error::other(format!(
+                "unexpected HTTP response on request to {resource_url}: {unexpected}"
+            ))),
+        }
+    }
+
+    // the command line interface is not async, these are never called
+    async fn get_async(&self, key: &str) -> Result<Option<Bytes>, Error> {
+        self.get(key)
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> Result<(), Error> {
+        self.put(key, body)
+    }
+
+    async fn get_range_async(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> Result<Option<(Bytes, u64)>, Error> {
+        self.get_range(key, range)
+    }
+
+    async fn put_if_match_async(
+        &self,
+        key: &str,
+        body: Bytes,
+        validator: Option<&str>,
+    ) -> Result<(), Error> {
+        self.put_if_match(key, body, validator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use perfume::identity::KeyTemplate;
+    use serde_json::Value;
+
+    use super::*;
+
+    // the response statuses which HttpBridge handles for each operation
+    const OPERATIONS: [(&str, &str, &[&str]); 3] = [
+        ("get", "getBlob", &["200", "206", "304", "404", "416"]),
+        ("head", "headBlob", &["200", "404"]),
+        ("put", "putBlob", &["200", "412"]),
+    ];
+
+    #[test]
+    fn test_openapi() {
+        let spec: Value = serde_json::from_str(include_str!("../../openapi/perfume.json")).unwrap();
+        assert_eq!(spec["info"]["version"], env!("CARGO_PKG_VERSION"));
+
+        let template = format!("/{KEY_TEMPLATE}");
+        let bridge = HttpBridge::new("http://localhost:8080/").unwrap();
+        let key = KeyTemplate::new(KEY_TEMPLATE, "br").unwrap().apply("abc");
+        assert_eq!(bridge.resource_url(&key), "http://localhost:8080/br/abc");
+
+        let path = spec["paths"][&template].as_object().unwrap();
+        let methods = path.keys().filter(|k| *k != "parameters");
+        assert_eq!(methods.count(), OPERATIONS.len());
+        for (method, operation_id, statuses) in OPERATIONS {
+            let operation = &path[method];
+            assert_eq!(operation["operationId"], operation_id);
+            let documented = operation["responses"].as_object().unwrap().keys();
+            assert_eq!(documented.collect::<Vec<_>>(), statuses, "{operation_id}");
+        }
+    }
+}
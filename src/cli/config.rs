@@ -0,0 +1,95 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use perfume::Error;
+
+/// The contents of a `perfume.toml` file, as written by the `init` command.
+/// Secrets are never read from this file, only the name of the environment variable holding one.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub domain: Option<String>,
+    pub secret_env: Option<String>,
+    pub store: StoreConfig,
+    pub ingredients: IngredientsConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreConfig {
+    pub kind: Option<String>,
+    pub url: Option<String>,
+    /// Digest characters held by new blobs, see `RemoteStore::with_digest_length`.
+    pub digest_length: Option<usize>,
+    /// The format of stored blobs, one of text-v1, json-lines or binary.
+    pub format: Option<String>,
+}
+
+/// Word lists used by `validate-words`. Relative paths are relative to the working directory.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IngredientsConfig {
+    pub size: Option<String>,
+    pub prefixes: Option<PathBuf>,
+    pub colors: Option<PathBuf>,
+    pub animals: Option<PathBuf>,
+    /// Identifies the compiled ingredients which names were first assigned with,
+    /// see `perfume::diagnostics::ingredients_fingerprint`. Checked by `doctor`.
+    pub fingerprint: Option<String>,
+}
+
+impl Config {
+    /// Read the configuration at `path`. A missing file is only an error if it is `required`.
+    pub fn load(path: &Path, required: bool) -> Result<Self, Error> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => {
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        toml::from_str(&contents).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid configuration {}: {e}", path.display()),
+            )
+            .into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_init_config() {
+        let config: Config = toml::from_str(
+            r#"
+            domain = "br"
+            secret_env = "BR_SECRET"
+
+            [store]
+            kind = "http"
+            url = "http://localhost:9090"
+
+            [ingredients]
+            size = "bhutan"
+            prefixes = "data/gerunds.txt"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.domain.as_deref(), Some("br"));
+        assert_eq!(config.secret_env.as_deref(), Some("BR_SECRET"));
+        assert_eq!(config.store.url.as_deref(), Some("http://localhost:9090"));
+        assert_eq!(config.ingredients.size.as_deref(), Some("bhutan"));
+        assert_eq!(config.ingredients.colors, None);
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        assert!(toml::from_str::<Config>("secret = \"hunter2\"").is_err());
+    }
+}
@@ -0,0 +1,49 @@
+use serde_json::json;
+
+use perfume::Error;
+use perfume::diagnostics::{Status, report};
+use perfume::identity::{ConnectionBridge, Population, RemoteStore};
+
+use super::output::Output;
+
+/// Write the diagnostics report of a domain, failing if any check failed.
+pub fn doctor<B>(
+    population: &Population,
+    store: &RemoteStore<B>,
+    manifest: Option<&str>,
+    out: &mut Output,
+) -> Result<(), Error>
+where
+    B: ConnectionBridge + Send,
+{
+    let report = report(population, store, manifest);
+    let checks: Vec<_> = report
+        .checks
+        .iter()
+        .map(|check| {
+            json!({
+                "name": check.name,
+                "status": format!("{:?}", check.status).to_lowercase(),
+                "detail": check.detail,
+            })
+        })
+        .collect();
+    out.emit(
+        &report,
+        json!({
+            "domain": population.domain,
+            "fingerprint": report.fingerprint,
+            "checks": checks,
+        }),
+    )?;
+
+    let failed = report
+        .checks
+        .iter()
+        .filter(|check| check.status == Status::Fail)
+        .count();
+    if failed > 0 {
+        return Err(std::io::Error::other(format!("{failed} checks failed")).into());
+    }
+    Ok(())
+}
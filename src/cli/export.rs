@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::Instant;
+
+use bytes::Bytes;
+use serde_json::json;
+
+use perfume::Error;
+use perfume::hex_string::HexString;
+use perfume::identity::{ConnectionBridge, RemoteStore, Snapshot};
+
+use super::output::Output;
+
+/// Archive entry holding "<checksum>  <key>" lines, in the format of `b3sum`.
+const CHECKSUMS: &str = "BLAKE3SUMS";
+
+/// Write each blob as an archive entry named "<domain>/<key>", compressed with zstd,
+/// followed by the checksum of each blob. Up to `parallelism` blobs are fetched at a time.
+pub fn export<B>(
+    store: &RemoteStore<B>,
+    domain: &str,
+    output: &Path,
+    parallelism: usize,
+    out: &mut Output,
+) -> Result<(), Error>
+where
+    B: ConnectionBridge + Sync,
+{
+    let start = Instant::now();
+    let snapshot = store.export_parallel(parallelism)?;
+
+    let encoder = zstd::Encoder::new(File::create(output)?, 0)?;
+    let mut archive = tar::Builder::new(encoder);
+    let mut append = |name: String, bytes: &[u8]| {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(bytes.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive.append_data(&mut header, name, bytes)
+    };
+    let mut checksums = String::new();
+    for ((key, bytes), checksum) in snapshot.blobs.iter().zip(snapshot.checksums()) {
+        append(format!("{domain}/{key}"), bytes)?;
+        checksums.push_str(&format!("{checksum}  {key}\n"));
+    }
+    append(format!("{domain}/{CHECKSUMS}"), checksums.as_bytes())?;
+    archive.into_inner()?.finish()?;
+
+    out.emit(
+        format_args!(
+            "exported {} blobs from domain {domain} to {}",
+            snapshot.blobs.len(),
+            output.display()
+        ),
+        json!({
+            "domain": domain,
+            "path": output.display().to_string(),
+            "blobs": snapshot.blobs.len(),
+            "elapsed_ms": start.elapsed().as_millis(),
+        }),
+    )?;
+    Ok(())
+}
+
+/// Read an archive created by [`export`]. Entries are restored by key, ignoring their domain.
+/// Blobs are checked against their checksums, when the archive has them, before any are stored.
+/// Up to `parallelism` blobs are stored at a time.
+pub fn import<B>(
+    store: &RemoteStore<B>,
+    input: &Path,
+    parallelism: usize,
+    out: &mut Output,
+) -> Result<(), Error>
+where
+    B: ConnectionBridge + Sync,
+{
+    let start = Instant::now();
+    let decoder = zstd::Decoder::new(File::open(input)?)?;
+    let mut archive = tar::Archive::new(decoder);
+
+    let mut snapshot = Snapshot::default();
+    let mut expected_checksums: Option<HashMap<String, String>> = None;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if path.file_name().is_some_and(|n| n == CHECKSUMS) {
+            let mut checksums = String::new();
+            entry.read_to_string(&mut checksums)?;
+            let checksums = checksums
+                .lines()
+                .filter_map(|line| line.split_once("  "))
+                .map(|(checksum, key)| (key.to_string(), checksum.to_string()));
+            expected_checksums = Some(checksums.collect());
+            continue;
+        }
+        let key = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .filter(|n| n.len() == perfume::STORAGE_KEY_LENGTH)
+            .filter(|n| n.chars().all(|c| c.is_ascii_hexdigit()))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected archive entry {path:?}"),
+                )
+            })?;
+        let key = HexString::from(key.as_bytes());
+        let mut bytes = vec![];
+        entry.read_to_end(&mut bytes)?;
+        snapshot.blobs.push((key, Bytes::from(bytes)));
+    }
+    if let Some(expected) = &expected_checksums {
+        for ((key, _bytes), checksum) in snapshot.blobs.iter().zip(snapshot.checksums()) {
+            if expected.get(key.as_str()) != Some(&checksum) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("checksum of blob {key} does not match the archive"),
+                )
+                .into());
+            }
+        }
+    }
+    store.import_parallel(&snapshot, parallelism)?;
+
+    out.emit(
+        format_args!(
+            "imported {} blobs from {}",
+            snapshot.blobs.len(),
+            input.display()
+        ),
+        json!({
+            "path": input.display().to_string(),
+            "blobs": snapshot.blobs.len(),
+            "checksums_verified": expected_checksums.is_some(),
+            "elapsed_ms": start.elapsed().as_millis(),
+        }),
+    )?;
+    Ok(())
+}
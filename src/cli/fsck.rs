@@ -0,0 +1,67 @@
+use std::time::Instant;
+
+use serde_json::json;
+
+use perfume::Error;
+use perfume::identity::{BlobIssue, ConnectionBridge, RemoteStore};
+
+use super::output::Output;
+
+/// Write the problems found in each blob, and the lines which a repair drops from it,
+/// failing if there were any problems which were not repaired.
+pub fn fsck<B>(store: &RemoteStore<B>, repair: bool, out: &mut Output) -> Result<(), Error>
+where
+    B: ConnectionBridge + Send,
+{
+    let start = Instant::now();
+    let results = store.fsck(repair)?;
+    let elapsed_ms = start.elapsed().as_millis();
+
+    for (key, check) in &results {
+        let issues: Vec<String> = check.issues.iter().map(|i| i.to_string()).collect();
+        let dropped: Vec<String> = check
+            .dropped
+            .iter()
+            .map(|(line, text)| format!("dropped line {line}: {text}"))
+            .collect();
+        let lines = [&issues[..], &dropped[..]].concat();
+        out.emit(
+            format_args!("{key}:\n  {}", lines.join("\n  ")),
+            json!({
+                "key": key.as_str(),
+                "issues": issues,
+                "dropped": check
+                    .dropped
+                    .iter()
+                    .map(|(line, text)| json!({"line": line, "text": text}))
+                    .collect::<Vec<_>>(),
+                "repaired": repair && check.changed,
+                "elapsed_ms": elapsed_ms,
+            }),
+        )?;
+    }
+
+    // offset problems can not be repaired without renaming identities, nor blobs of a later
+    // format without misreading them
+    let unrepaired = results
+        .iter()
+        .flat_map(|(_key, check)| check.issues.iter())
+        .filter(|issue| {
+            !repair
+                || matches!(
+                    issue,
+                    BlobIssue::OffsetGap(_)
+                        | BlobIssue::DuplicateOffset(_)
+                        | BlobIssue::UnsupportedFormat(_)
+                )
+        })
+        .count();
+    if repair && out.is_text() {
+        let rewritten = results.iter().filter(|(_key, check)| check.changed).count();
+        eprintln!("repaired {rewritten} blobs");
+    }
+    if unrepaired > 0 {
+        return Err(std::io::Error::other(format!("{unrepaired} problems remain")).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+
+use rand::TryRngCore;
+use rand::rngs::OsRng;
+use serde_json::json;
+
+use perfume::Error;
+
+use super::output::Output;
+
+// the number of random bytes of generated secrets
+const SECRET_LENGTH: usize = 32;
+
+/// Generated secrets are this prefix followed by their bytes in hex, which are decoded when
+/// they are loaded. Other secrets are used as they are, so their populations keep their names.
+pub const HEX_SECRET_PREFIX: &str = "hex:";
+
+/// Write a starter configuration file to `path`, and a freshly generated secret to `out`.
+pub fn init(
+    path: &Path,
+    domain: &str,
+    url: &str,
+    fingerprint: &str,
+    force: bool,
+    out: &mut Output,
+) -> Result<(), Error> {
+    let secret = generate_secret()?;
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .create_new(!force)
+        .open(path)?;
+    write!(
+        file,
+        r#"# perfume configuration. This file does not contain secrets and can be committed.
+
+# shared by all identities of a population
+domain = "{domain}"
+# the population secret is read from this environment variable
+secret_env = "PERFUME_SECRET"
+
+[store]
+kind = "http"
+url = "{url}"
+
+[ingredients]
+size = "brazil"
+prefixes = "data/gerunds.txt"
+colors = "data/colors.txt"
+animals = "data/animals.txt"
+# identifies the compiled word lists, which must not change once names have been assigned
+fingerprint = "{fingerprint}"
+"#
+    )?;
+
+    out.emit(
+        format_args!(
+            "wrote {}\n\n{}\n{}\n\n  export PERFUME_SECRET={secret}\n\n{}",
+            path.display(),
+            "Store this secret in your secret manager, and provide it to perfume as shown below.",
+            "It can not be changed once names have been assigned.",
+            "Never commit it, or copy secrets from examples."
+        ),
+        json!({
+            "config": path.display().to_string(),
+            "secret": secret,
+        }),
+    )?;
+    Ok(())
+}
+
+// random bytes from the operating system, see `HEX_SECRET_PREFIX`
+fn generate_secret() -> io::Result<String> {
+    let mut bytes = [0; SECRET_LENGTH];
+    OsRng.try_fill_bytes(&mut bytes).map_err(io::Error::other)?;
+    let mut buf = [0; 2 * SECRET_LENGTH];
+    let hex = base16ct::lower::encode_str(&bytes, &mut buf).expect("buffer should fit the hex");
+    Ok(format!("{HEX_SECRET_PREFIX}{hex}"))
+}
+
+/// The bytes of `secret`, decoded from hex if it was generated by `init`.
+pub fn decode_secret(secret: String) -> Result<Vec<u8>, base16ct::Error> {
+    let Some(hex) = secret.strip_prefix(HEX_SECRET_PREFIX) else {
+        return Ok(secret.into_bytes());
+    };
+    let mut bytes = vec![0; hex.len() / 2];
+    let length = base16ct::mixed::decode(hex, &mut bytes)?.len();
+    bytes.truncate(length);
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_secret() {
+        let secret = generate_secret().unwrap();
+        assert_eq!(decode_secret(secret.clone()).unwrap().len(), SECRET_LENGTH);
+        assert_ne!(generate_secret().unwrap(), secret);
+
+        // other secrets are used as they are
+        let chosen = "0123456789abcdef0123456789abcdef".to_string();
+        assert_eq!(decode_secret(chosen.clone()).unwrap(), chosen.as_bytes());
+        assert!(decode_secret(format!("{HEX_SECRET_PREFIX}xyz")).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+
+use bytes::Bytes;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use perfume::Error;
+use perfume::identity::{
+    BlobIssue, ConnectionBridge, RemoteStore, check_blob, narrow_blob, storage_keys,
+};
+
+use super::output::Output;
+
+/// Storage blob formats which can be migrated between.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum BlobFormat {
+    /// Sorted "<digest> <offset>" lines, as read by `RemoteStore`.
+    TextV1,
+    /// One {"digest": "...", "offset": n} object per line.
+    JsonLines,
+    /// Packed 32-byte digests and 4-byte offsets.
+    Binary,
+}
+
+impl BlobFormat {
+    pub fn library(&self) -> perfume::identity::BlobFormat {
+        match self {
+            Self::TextV1 => perfume::identity::BlobFormat::Text,
+            Self::JsonLines => perfume::identity::BlobFormat::JsonLines,
+            Self::Binary => perfume::identity::BlobFormat::Binary,
+        }
+    }
+
+    /// Rewrite `blob` from format `from`, sorting its records. A blob with records which
+    /// would be dropped is an error, since `fsck --repair` should decide which are kept.
+    fn convert(&self, from: BlobFormat, blob: &[u8], digest_length: usize) -> Result<Bytes, Error> {
+        let text = from.library().decode(blob)?;
+        let check = check_blob(&text);
+        let dropped: Vec<String> = check
+            .issues
+            .iter()
+            .filter(|issue| {
+                matches!(
+                    issue,
+                    BlobIssue::MalformedLine(_)
+                        | BlobIssue::DuplicateDigest(_)
+                        | BlobIssue::UnsupportedFormat(_)
+                )
+            })
+            .map(|issue| issue.to_string())
+            .collect();
+        if !dropped.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}, run fsck --repair first", dropped.join(", ")),
+            )
+            .into());
+        }
+        let text = narrow_blob(&check.canonical, digest_length)?;
+        Ok(self.library().encode(&text)?)
+    }
+}
+
+/// The progress of a migration, which is only resumed by the same migration.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Checkpoint {
+    domain: String,
+    from: String,
+    to: String,
+    digest_length: usize,
+    /// The last key which was migrated.
+    key: String,
+}
+
+impl Checkpoint {
+    /// True if this is the progress of the same migration as `other`, whatever its key.
+    fn resumes(&self, other: &Checkpoint) -> bool {
+        (&self.domain, &self.from, &self.to, self.digest_length)
+            == (&other.domain, &other.from, &other.to, other.digest_length)
+    }
+}
+
+/// Rewrite every blob of `domain` from format `from` into format `to`,
+/// with digests of at least `digest_length`.
+/// The last migrated key is recorded in `checkpoint`, along with the domain, formats and digest
+/// length, so that an interrupted migration can resume, and no other migration resumes it.
+pub fn migrate<B>(
+    store: &RemoteStore<B>,
+    domain: &str,
+    from: BlobFormat,
+    to: BlobFormat,
+    digest_length: usize,
+    checkpoint: &Path,
+    out: &mut Output,
+) -> Result<(), Error>
+where
+    B: ConnectionBridge + Send,
+{
+    let start = Instant::now();
+    let mut progress = Checkpoint {
+        domain: domain.to_string(),
+        from: format!("{from:?}"),
+        to: format!("{to:?}"),
+        digest_length,
+        key: String::new(),
+    };
+    let resume_after = match fs::read_to_string(checkpoint) {
+        Ok(saved) => {
+            let saved: Checkpoint = serde_json::from_str(&saved).map_err(|e| {
+                let message = format!("checkpoint {} can not be read: {e}", checkpoint.display());
+                std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+            })?;
+            if !saved.resumes(&progress) {
+                let message = format!(
+                    "checkpoint {} is of a migration of domain {} from {} to {} with digest length {}, remove it to start over",
+                    checkpoint.display(),
+                    saved.domain,
+                    saved.from,
+                    saved.to,
+                    saved.digest_length
+                );
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, message).into());
+            }
+            Some(saved.key)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(key) = &resume_after {
+        eprintln!("resuming after key {key}");
+    }
+
+    let keys: Vec<_> = storage_keys()
+        .filter(|k| {
+            resume_after
+                .as_deref()
+                .is_none_or(|after| k.as_str() > after)
+        })
+        .collect();
+    let mut migrated = 0;
+    for (i, key) in keys.iter().enumerate() {
+        let resource = store.bridge_key(key.as_str());
+        if let Some(blob) = store.bridge.get(&resource)? {
+            let converted = to.convert(from, &blob, digest_length).map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, format!("blob {key}: {e}"))
+            })?;
+            if converted != blob {
+                store.bridge.put(&resource, converted)?;
+                migrated += 1;
+            }
+        }
+        progress.key = key.as_str().to_string();
+        let saved = serde_json::to_string(&progress).map_err(std::io::Error::from)?;
+        fs::write(checkpoint, saved)?;
+        if (i + 1) % 256 == 0 {
+            eprintln!("checked {} of {} keys", i + 1, keys.len());
+        }
+    }
+    fs::remove_file(checkpoint)?;
+
+    out.emit(
+        format_args!(
+            "migrated {migrated} blobs to {to:?} in {:?}",
+            start.elapsed()
+        ),
+        json!({
+            "format": format!("{to:?}"),
+            "checked": keys.len(),
+            "migrated": migrated,
+            "elapsed_ms": start.elapsed().as_millis(),
+        }),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert() {
+        let line = |digit: &str, offset: usize| format!("{} {offset:>5}\n", digit.repeat(61));
+        let unsorted = [line("b", 1), line("a", 0)].concat();
+        let converted = BlobFormat::TextV1.convert(BlobFormat::TextV1, unsorted.as_bytes(), 61);
+        assert_eq!(converted.unwrap(), [line("a", 0), line("b", 1)].concat());
+
+        // records which would be dropped are not
+        let duplicate = [line("a", 0), line("a", 1)].concat();
+        let e = BlobFormat::Binary
+            .convert(BlobFormat::TextV1, duplicate.as_bytes(), 61)
+            .unwrap_err();
+        assert!(e.to_string().contains("duplicate digest"), "{e}");
+        let malformed = [line("a", 0), "not a record\n".to_string()].concat();
+        let e = BlobFormat::JsonLines
+            .convert(BlobFormat::TextV1, malformed.as_bytes(), 61)
+            .unwrap_err();
+        assert!(e.to_string().contains("malformed line 1"), "{e}");
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let checkpoint = |domain: &str, to: BlobFormat, key: &str| Checkpoint {
+            domain: domain.to_string(),
+            from: format!("{:?}", BlobFormat::TextV1),
+            to: format!("{to:?}"),
+            digest_length: 61,
+            key: key.to_string(),
+        };
+        let saved = checkpoint("br", BlobFormat::Binary, "abc");
+        let json = serde_json::to_string(&saved).unwrap();
+        assert_eq!(serde_json::from_str::<Checkpoint>(&json).unwrap(), saved);
+        assert!(saved.resumes(&checkpoint("br", BlobFormat::Binary, "")));
+        assert!(!saved.resumes(&checkpoint("be", BlobFormat::Binary, "")));
+        assert!(!saved.resumes(&checkpoint("br", BlobFormat::JsonLines, "")));
+    }
+}
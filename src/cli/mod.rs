@@ -0,0 +1,449 @@
+//! Command line interface for operating on persisted identities.
+//! Requires the `cli` feature, and compiled data from `cargo run -F codegen`.
+
+mod bench;
+mod bridge;
+mod config;
+mod doctor;
+mod export;
+mod fsck;
+mod init;
+mod migrate;
+mod name;
+mod output;
+mod preview;
+mod stats;
+mod validate;
+
+use std::io;
+use std::path::PathBuf;
+
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+
+use perfume::codegen::PopulationSize;
+use perfume::diagnostics::ingredients_fingerprint;
+use perfume::identity::{KeyTemplate, MIN_SECRET_LENGTH, Population, RemoteStore};
+use perfume::{Error, MIN_STORAGE_DIGEST_LENGTH, STORAGE_DIGEST_LENGTH};
+
+use bridge::HttpBridge;
+use config::Config;
+use output::{ColorChoice, Format, Output};
+
+include!(concat!(env!("TMPDIR"), "/perfume.rs"));
+
+const BLOB_CACHE_CAPACITY: usize = 16;
+
+#[derive(Parser)]
+#[command(name = "perfume", version, about)]
+struct Cli {
+    /// Configuration file, see the `init` command. [default: perfume.toml]
+    #[arg(long, global = true, env = "PERFUME_CONFIG")]
+    config: Option<PathBuf>,
+    /// The population domain, overriding the configuration file.
+    #[arg(long, global = true, env = "PERFUME_DOMAIN")]
+    domain: Option<String>,
+    /// Base URL of the blob store, overriding the configuration file.
+    /// Blobs are located at <url>/<domain>/<key>. [default: http://localhost:9090]
+    #[arg(long, global = true, env = "PERFUME_URL")]
+    url: Option<String>,
+    /// Environment variable containing the population secret, overriding the configuration file.
+    /// [default: PERFUME_SECRET]
+    #[arg(long, global = true, env = "PERFUME_SECRET_ENV")]
+    secret_env: Option<String>,
+    /// How results are written to standard output.
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+    output: Format,
+    /// When to print the color of each name in that color.
+    #[arg(long, global = true, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Measure identity resolution latency and throughput against the store.
+    /// Existing names in the domain may be overwritten, so the domain is "bench" unless
+    /// `--domain` is given, rather than the one of the configuration file or `PERFUME_DOMAIN`.
+    Bench {
+        /// How many random identifiers to resolve.
+        #[arg(long, default_value_t = 1000)]
+        identities: usize,
+        /// How many identifiers to resolve at the same time.
+        #[arg(long, default_value_t = 1)]
+        concurrency: usize,
+    },
+    /// Check the ingredients, secret and store of a domain for problems which change names.
+    /// The ingredients are compared with the fingerprint in the configuration file.
+    Doctor,
+    /// Write every storage blob of a domain to a compressed archive.
+    Export {
+        /// Path of the archive to create, for example snapshot.tar.zst
+        // not named `output`, which is the global option for the format of results
+        #[arg(short = 'o', long)]
+        archive: PathBuf,
+        /// How many blobs to fetch at the same time.
+        #[arg(long, default_value_t = 8)]
+        parallelism: usize,
+    },
+    /// Restore storage blobs of a domain from an archive created by `export`.
+    Import {
+        /// Path of the archive to read.
+        #[arg(short, long)]
+        input: PathBuf,
+        /// How many blobs to store at the same time.
+        #[arg(long, default_value_t = 8)]
+        parallelism: usize,
+    },
+    /// Check the storage blobs of a domain for malformed lines, duplicates and offset gaps.
+    Fsck {
+        /// Rewrite blobs with problems into canonical form.
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Generate a secret and write a starter configuration file to the `--config` path.
+    Init {
+        /// Replace an existing configuration file.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Rewrite every storage blob of a domain into another format.
+    Migrate {
+        /// The format to rewrite blobs into.
+        #[arg(long, value_enum)]
+        to: migrate::BlobFormat,
+        /// The format which blobs are currently in.
+        #[arg(long, value_enum, default_value_t = migrate::BlobFormat::TextV1)]
+        from: migrate::BlobFormat,
+        /// Records progress, so that an interrupted migration resumes where it stopped.
+        #[arg(long, default_value = ".perfume-migrate")]
+        checkpoint: PathBuf,
+        /// Truncate digests to this many characters, or as many more as needed to keep them
+        /// distinct. Digests are never lengthened. [default: 61]
+        #[arg(long)]
+        digest_length: Option<usize>,
+    },
+    /// Print the name of each identifier as "<identifier>\t<name>".
+    Name {
+        /// Read identifiers from standard input, one per line.
+        #[arg(long)]
+        stdin: bool,
+        /// Identifiers to name, in addition to any read from standard input.
+        identifiers: Vec<String>,
+    },
+    /// Print a random sample of names which could be generated, without using storage.
+    Preview {
+        /// How many names to print.
+        #[arg(long, default_value_t = 20)]
+        count: usize,
+    },
+    /// Report blob sizes, assigned identities and saturation of a domain.
+    Stats {
+        /// How many of the largest blobs to list.
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+    /// Check word lists for problems, without generating anything.
+    /// Word lists which are not specified are taken from the configuration file.
+    ValidateWords {
+        /// Directory containing the word lists.
+        dir: Option<PathBuf>,
+        /// Word list for the first component of each name, relative to `dir`. [default: gerunds.txt]
+        #[arg(long)]
+        prefixes: Option<PathBuf>,
+        /// Word list for the second component of each name, relative to `dir`. [default: colors.txt]
+        #[arg(long)]
+        colors: Option<PathBuf>,
+        /// Word list for the third component of each name, relative to `dir`. [default: animals.txt]
+        #[arg(long)]
+        animals: Option<PathBuf>,
+        /// One of bhutan, belgium or brazil. [default: brazil]
+        #[arg(long)]
+        size: Option<PopulationSize>,
+        /// Words which must not be used, one per line.
+        #[arg(long)]
+        blocklist: Option<PathBuf>,
+        /// The maximum number of characters in a word.
+        #[arg(long, default_value_t = 15)]
+        max_length: usize,
+        /// Shorthand for `--output json`.
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+pub fn run() -> Result<(), Error> {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let settings = Settings::new(&cli, &matches)?;
+    let format = match cli.command {
+        Command::ValidateWords { json: true, .. } => Format::Json,
+        _ => cli.output,
+    };
+    let mut out = Output::new(format, cli.color);
+    let result = match cli.command {
+        Command::Bench {
+            identities,
+            concurrency,
+        } => {
+            // a benchmark only assigns names in a configured domain when it is asked to
+            let domain = match settings.domain_on_command_line {
+                true => settings.domain(None)?,
+                false => "bench".to_string(),
+            };
+            let secret = settings.secret()?;
+            let population = population(&domain, &secret)?;
+            let new_store = || settings.remote_store(&domain);
+            bench::bench(&population, new_store, identities, concurrency, &mut out)
+        }
+        Command::Doctor => {
+            let domain = settings.domain(None)?;
+            // a missing or short secret is reported rather than an error
+            let secret = std::env::var(settings.secret_env()).unwrap_or_default();
+            let secret = secret.into_bytes();
+            let population = population(&domain, &secret)?;
+            let store = settings.remote_store(&domain)?;
+            let manifest = settings.config.ingredients.fingerprint.as_deref();
+            doctor::doctor(&population, &store, manifest, &mut out)
+        }
+        Command::Export {
+            archive,
+            parallelism,
+        } => {
+            let domain = settings.domain(None)?;
+            let store = settings.remote_store(&domain)?;
+            export::export(&store, &domain, &archive, parallelism, &mut out)
+        }
+        Command::Import { input, parallelism } => {
+            let store = settings.remote_store(&settings.domain(None)?)?;
+            export::import(&store, &input, parallelism, &mut out)
+        }
+        Command::Fsck { repair } => {
+            let store = settings.remote_store(&settings.domain(None)?)?;
+            fsck::fsck(&store, repair, &mut out)
+        }
+        Command::Init { force } => {
+            let domain = settings.domain(Some("default"))?;
+            let fingerprint = ingredients_fingerprint(&PERFUME_INGREDIENTS);
+            let url = settings.url();
+            init::init(&settings.path, &domain, &url, &fingerprint, force, &mut out)
+        }
+        Command::Migrate {
+            to,
+            from,
+            checkpoint,
+            digest_length,
+        } => {
+            let domain = settings.domain(None)?;
+            let store = settings.remote_store(&domain)?;
+            let digest_length = digest_length.unwrap_or(STORAGE_DIGEST_LENGTH);
+            migrate::migrate(
+                &store,
+                &domain,
+                from,
+                to,
+                digest_length,
+                &checkpoint,
+                &mut out,
+            )
+        }
+        Command::Name { stdin, identifiers } => {
+            let domain = settings.domain(None)?;
+            let secret = settings.secret()?;
+            let population = population(&domain, &secret)?;
+            let mut store = settings.remote_store(&domain)?;
+            name::name(&population, &mut store, identifiers, stdin, &mut out)
+        }
+        Command::Preview { count } => {
+            let domain = settings.domain(Some("preview"))?;
+            // the secret only determines which names are assigned to which identifiers
+            let secret = settings
+                .secret()
+                .unwrap_or_else(|_| rand::random::<[u8; 32]>().to_vec());
+            let population = population(&domain, &secret)?;
+            preview::preview(&population, count, &mut out)
+        }
+        Command::Stats { top } => {
+            let store = settings.remote_store(&settings.domain(None)?)?;
+            stats::stats(&store, PERFUME_INGREDIENTS.0, top, &mut out)
+        }
+        Command::ValidateWords {
+            dir,
+            prefixes,
+            colors,
+            animals,
+            size,
+            blocklist,
+            max_length,
+            json: _,
+        } => {
+            let ingredients = &settings.config.ingredients;
+            let size = match (size, &ingredients.size) {
+                (Some(size), _) => size,
+                (None, Some(size)) => size.parse()?,
+                (None, None) => PopulationSize::Brazil,
+            };
+            // paths from the command line are relative to `dir`
+            let dir = dir.unwrap_or_default();
+            let word_list = |flag: Option<PathBuf>, configured: &Option<PathBuf>, default| match (
+                flag, configured,
+            ) {
+                (Some(path), _) => dir.join(path),
+                (None, Some(path)) => path.clone(),
+                (None, None) => dir.join(default),
+            };
+            validate::validate_words(
+                size,
+                [
+                    word_list(prefixes, &ingredients.prefixes, "gerunds.txt"),
+                    word_list(colors, &ingredients.colors, "colors.txt"),
+                    word_list(animals, &ingredients.animals, "animals.txt"),
+                ],
+                blocklist,
+                max_length,
+                &mut out,
+            )
+        }
+    };
+    out.finish()?;
+    result
+}
+
+/// Options resolved in order of precedence:
+/// command line, `PERFUME_*` environment variables, configuration file, then defaults.
+struct Settings {
+    path: PathBuf,
+    config: Config,
+    domain: Option<String>,
+    // whether `domain` was given with `--domain`, rather than `PERFUME_DOMAIN`
+    domain_on_command_line: bool,
+    url: Option<String>,
+    secret_env: Option<String>,
+}
+
+impl Settings {
+    fn new(cli: &Cli, matches: &ArgMatches) -> Result<Self, Error> {
+        let path = cli.config.clone().unwrap_or("perfume.toml".into());
+        // a configuration file which was asked for must exist, except when creating it
+        let required = cli.config.is_some() && !matches!(cli.command, Command::Init { .. });
+        let config = Config::load(&path, required)?;
+        if let Some(kind) = config.store.kind.as_deref().filter(|&k| k != "http") {
+            return Err(usage_error(&format!("unsupported store kind {kind:?}")));
+        }
+        let digest_lengths = MIN_STORAGE_DIGEST_LENGTH..=STORAGE_DIGEST_LENGTH;
+        if let Some(length) = config
+            .store
+            .digest_length
+            .filter(|l| !digest_lengths.contains(l))
+        {
+            return Err(usage_error(&format!(
+                "store digest_length {length} is not between {MIN_STORAGE_DIGEST_LENGTH} and {STORAGE_DIGEST_LENGTH}"
+            )));
+        }
+        Ok(Self {
+            path,
+            config,
+            domain: cli.domain.clone(),
+            domain_on_command_line: matches.value_source("domain")
+                == Some(ValueSource::CommandLine),
+            url: cli.url.clone(),
+            secret_env: cli.secret_env.clone(),
+        })
+    }
+
+    fn domain(&self, default: Option<&str>) -> Result<String, Error> {
+        self.domain
+            .as_deref()
+            .or(self.config.domain.as_deref())
+            .or(default)
+            .map(str::to_string)
+            .ok_or_else(|| usage_error("a domain is required, use --domain or perfume.toml"))
+    }
+
+    fn url(&self) -> String {
+        self.url
+            .as_deref()
+            .or(self.config.store.url.as_deref())
+            .unwrap_or("http://localhost:9090")
+            .to_string()
+    }
+
+    /// The environment variable which holds the population secret.
+    fn secret_env(&self) -> &str {
+        self.secret_env
+            .as_deref()
+            .or(self.config.secret_env.as_deref())
+            .unwrap_or("PERFUME_SECRET")
+    }
+
+    /// The population secret, read from the environment variable named by `secret_env`.
+    fn secret(&self) -> Result<Vec<u8>, Error> {
+        let name = self.secret_env();
+        let secret =
+            std::env::var(name).map_err(|_| usage_error(&format!("{name} must be set")))?;
+        let secret = init::decode_secret(secret).map_err(|_| {
+            let prefix = init::HEX_SECRET_PREFIX;
+            usage_error(&format!("{name} must be hex after {prefix:?}"))
+        })?;
+        if secret.len() < MIN_SECRET_LENGTH {
+            let message = format!("{name} must be at least {MIN_SECRET_LENGTH} bytes");
+            return Err(usage_error(&message));
+        }
+        Ok(secret)
+    }
+
+    fn remote_store(&self, domain: &str) -> Result<RemoteStore<HttpBridge>, Error> {
+        let bridge = HttpBridge::new(&self.url())?;
+        // blobs are revalidated using ETags, see HttpBridge::get_validated
+        let mut store = RemoteStore::new(bridge)
+            .with_key_template(KeyTemplate::new(bridge::KEY_TEMPLATE, domain)?)
+            .with_blob_cache(BLOB_CACHE_CAPACITY);
+        if let Some(format) = &self.config.store.format {
+            let format = migrate::BlobFormat::from_str(format, true)
+                .map_err(|_| usage_error(&format!("unknown store format {format:?}")))?;
+            store = store.with_blob_format(format.library());
+        }
+        Ok(match self.config.store.digest_length {
+            Some(length) => store.with_digest_length(length),
+            None => store,
+        })
+    }
+}
+
+fn usage_error(message: &str) -> Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message).into()
+}
+
+// checks that the compiled ingredients can name every offset, see `Population::new`
+fn population<'dom>(domain: &'dom str, secret: &'dom [u8]) -> Result<Population<'dom>, Error> {
+    Population::new(domain, secret, &PERFUME_INGREDIENTS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_domain_on_command_line() {
+        let source = |args: &[&str]| {
+            let matches = Cli::command().try_get_matches_from(args).unwrap();
+            matches.value_source("domain")
+        };
+        let command_line = Some(ValueSource::CommandLine);
+        assert_eq!(
+            source(&["perfume", "--domain", "br", "bench"]),
+            command_line
+        );
+        assert_eq!(
+            source(&["perfume", "bench", "--domain", "br"]),
+            command_line
+        );
+        assert_ne!(source(&["perfume", "bench"]), command_line);
+    }
+}
@@ -0,0 +1,75 @@
+use std::io::{self, BufRead};
+use std::time::Instant;
+
+use serde_json::json;
+
+use perfume::Error;
+use perfume::identity::{Population, StorageState};
+
+use super::output::Output;
+
+// identifiers are resolved in batches, so that output appears while input is still being read,
+// and each blob is fetched and written once for all the identifiers of a batch which it holds
+const BATCH_SIZE: usize = 1000;
+
+/// Write "<identifier>\t<name>" for each identifier, followed by each line of standard input.
+pub fn name(
+    population: &Population,
+    store: &mut impl StorageState,
+    identifiers: Vec<String>,
+    stdin: bool,
+    out: &mut Output,
+) -> Result<(), Error> {
+    write_names(population, store, &identifiers, out)?;
+
+    if stdin {
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        for line in io::stdin().lock().lines() {
+            let line = line?;
+            let identifier = line.trim_end_matches('\r');
+            if identifier.is_empty() {
+                continue;
+            }
+            batch.push(identifier.to_string());
+            if batch.len() == BATCH_SIZE {
+                write_names(population, store, &batch, out)?;
+                batch.clear();
+            }
+        }
+        write_names(population, store, &batch, out)?;
+    }
+
+    Ok(())
+}
+
+fn write_names(
+    population: &Population,
+    store: &mut impl StorageState,
+    identifiers: &[String],
+    out: &mut Output,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    // resolved together, see `StorageState::digest_offsets`
+    let identities = population.identities(identifiers, store)?;
+    // the mean time taken to resolve each identifier of the batch
+    let elapsed_us = start.elapsed().as_micros() / identifiers.len().max(1) as u128;
+
+    for (identifier, identity) in identifiers.iter().zip(identities) {
+        let name = match out.is_colored() {
+            true => identity.render_ansi(),
+            false => identity.friendly_name.clone(),
+        };
+        out.emit(
+            format_args!("{identifier}\t{name}"),
+            json!({
+                "identifier": identifier,
+                "name": identity.friendly_name,
+                "domain": identity.domain,
+                "key": identity.storage.key.as_str(),
+                "offset": identity.offset,
+                "elapsed_us": elapsed_us,
+            }),
+        )?;
+    }
+    Ok(())
+}
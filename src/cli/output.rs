@@ -0,0 +1,85 @@
+use std::fmt::Display;
+use std::io::{self, BufWriter, IsTerminal, Stdout, Write};
+
+use clap::ValueEnum;
+use serde_json::Value;
+
+/// How command results are written to standard output.
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum Format {
+    /// Human readable text.
+    #[default]
+    Text,
+    /// A single JSON array of records, written when the command completes.
+    Json,
+    /// One JSON record per line, written as soon as it is available.
+    Ndjson,
+}
+
+/// When names in text results are colored, see [`perfume::identity::Identity::render_ansi`].
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum ColorChoice {
+    /// When standard output is a terminal, and `NO_COLOR` is not set.
+    #[default]
+    Auto,
+    /// Always, such as when piping to `less -R`.
+    Always,
+    /// Never.
+    Never,
+}
+
+/// Writes each result of a command in the chosen [`Format`].
+pub struct Output {
+    format: Format,
+    color: bool,
+    writer: BufWriter<Stdout>,
+    records: Vec<Value>,
+}
+
+impl Output {
+    pub fn new(format: Format, color: ColorChoice) -> Self {
+        let stdout = io::stdout();
+        let color = match color {
+            ColorChoice::Auto => {
+                stdout.is_terminal() && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+            }
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        };
+        Self {
+            format,
+            color: color && matches!(format, Format::Text),
+            writer: BufWriter::new(stdout),
+            records: vec![],
+        }
+    }
+
+    pub fn is_text(&self) -> bool {
+        matches!(self.format, Format::Text)
+    }
+
+    /// True if names in text results should be colored.
+    pub fn is_colored(&self) -> bool {
+        self.color
+    }
+
+    /// Write `text` in text format, otherwise `record`.
+    pub fn emit(&mut self, text: impl Display, record: Value) -> io::Result<()> {
+        match self.format {
+            Format::Text => writeln!(self.writer, "{text}"),
+            Format::Json => {
+                self.records.push(record);
+                Ok(())
+            }
+            Format::Ndjson => writeln!(self.writer, "{record}"),
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Format::Json = self.format {
+            let records = std::mem::take(&mut self.records);
+            writeln!(self.writer, "{}", Value::Array(records))?;
+        }
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,14 @@
+use serde_json::json;
+
+use perfume::Error;
+use perfume::identity::Population;
+
+use super::output::Output;
+
+/// Write `count` sample names.
+pub fn preview(population: &Population, count: usize, out: &mut Output) -> Result<(), Error> {
+    for name in population.sample_names(count) {
+        out.emit(&name, json!({ "name": name }))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use std::fmt::Write;
+use std::time::Instant;
+
+use serde_json::json;
+
+use perfume::Error;
+use perfume::identity::{ConnectionBridge, RemoteStore, storage_keys};
+
+use super::output::Output;
+
+/// Summarize how close a domain is to exhausting the names available to each blob.
+pub fn stats<B>(
+    store: &RemoteStore<B>,
+    population_size: usize,
+    top: usize,
+    out: &mut Output,
+) -> Result<(), Error>
+where
+    B: ConnectionBridge + Send,
+{
+    let start = Instant::now();
+    let snapshot = store.export()?;
+    let counts = snapshot.identity_counts(store.blob_format())?;
+
+    // names are assigned per blob, so a single full blob is a problem even when the total is low
+    let key_count = storage_keys().count();
+    let per_key_capacity = population_size / key_count;
+    let total_identities: usize = counts.iter().sum();
+    let total_bytes: usize = snapshot.blobs.iter().map(|(_k, b)| b.len()).sum();
+
+    let mut largest: Vec<_> = snapshot.blobs.iter().zip(counts.iter()).collect();
+    largest.sort_by_key(|&(_blob, count)| std::cmp::Reverse(*count));
+    largest.truncate(top);
+
+    let mut text = String::new();
+    writeln!(
+        text,
+        "blobs:             {} of {key_count}",
+        snapshot.blobs.len()
+    )
+    .unwrap();
+    writeln!(text, "blob bytes:        {total_bytes}").unwrap();
+    writeln!(text, "identities:        {total_identities}").unwrap();
+    writeln!(
+        text,
+        "saturation:        {:.4}% of {population_size}",
+        percentage(total_identities, population_size)
+    )
+    .unwrap();
+    writeln!(text, "capacity per blob: {per_key_capacity}").unwrap();
+    write!(text, "largest blobs:").unwrap();
+    for ((key, bytes), count) in &largest {
+        write!(
+            text,
+            "\n  {key}  {count:>6} identities  {:>9} bytes  {:>8.4}% saturated",
+            bytes.len(),
+            percentage(**count, per_key_capacity)
+        )
+        .unwrap();
+    }
+
+    let largest: Vec<_> = largest
+        .iter()
+        .map(|((key, bytes), count)| {
+            json!({
+                "key": key.as_str(),
+                "identities": count,
+                "bytes": bytes.len(),
+                "saturation": percentage(**count, per_key_capacity),
+            })
+        })
+        .collect();
+    out.emit(
+        text,
+        json!({
+            "blobs": snapshot.blobs.len(),
+            "keys": key_count,
+            "bytes": total_bytes,
+            "identities": total_identities,
+            "population_size": population_size,
+            "saturation": percentage(total_identities, population_size),
+            "capacity_per_blob": per_key_capacity,
+            "largest": largest,
+            "elapsed_ms": start.elapsed().as_millis(),
+        }),
+    )?;
+
+    Ok(())
+}
+
+fn percentage(count: usize, capacity: usize) -> f64 {
+    count as f64 * 100.0 / capacity as f64
+}
@@ -0,0 +1,66 @@
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use serde_json::json;
+
+use perfume::Error;
+use perfume::codegen::{self, PopulationSize, WordListReport};
+
+use super::output::Output;
+
+/// Write a report for each word list, failing if any problems were found.
+pub fn validate_words(
+    size: PopulationSize,
+    [prefixes, colors, animals]: [PathBuf; 3],
+    blocklist: Option<PathBuf>,
+    max_length: usize,
+    out: &mut Output,
+) -> Result<(), Error> {
+    let reports = codegen::validate_words(size, prefixes, colors, animals, blocklist, max_length)?;
+
+    for report in &reports {
+        out.emit(to_text(report), to_json(report))?;
+    }
+
+    if reports.iter().all(WordListReport::is_ok) {
+        Ok(())
+    } else {
+        Err(Error::Codegen("word lists failed validation".to_string()))
+    }
+}
+
+fn to_text(report: &WordListReport) -> String {
+    let status = if report.is_ok() { "ok" } else { "FAILED" };
+    let mut text = String::new();
+    write!(text, "{} [{status}]", report.path.display()).unwrap();
+    write!(
+        text,
+        "\n  words: {} ({} needed)",
+        report.word_count, report.required_count
+    )
+    .unwrap();
+    for (label, words) in [
+        ("duplicates", &report.duplicates),
+        ("invalid characters", &report.invalid),
+        ("too long", &report.too_long),
+        ("blocked", &report.blocked),
+    ] {
+        if !words.is_empty() {
+            write!(text, "\n  {label} ({}): {}", words.len(), words.join(" ")).unwrap();
+        }
+    }
+    text
+}
+
+fn to_json(report: &WordListReport) -> serde_json::Value {
+    json!({
+        "path": report.path.display().to_string(),
+        "ok": report.is_ok(),
+        "word_count": report.word_count,
+        "required_count": report.required_count,
+        "duplicates": report.duplicates,
+        "invalid": report.invalid,
+        "too_long": report.too_long,
+        "blocked": report.blocked,
+    })
+}
@@ -1,10 +1,12 @@
 //! Compile data to use for creating a [`crate::identity::Population`].
 
 use std::cmp::max;
+use std::collections::{BTreeSet, HashSet};
 use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::ops::RangeInclusive;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use crate::random::randomized;
 use crate::{Error, STORAGE_KEY_LENGTH, read_lines};
@@ -21,6 +23,178 @@ pub enum PopulationSize {
     Brazil = 203_080_756,
 }
 
+impl FromStr for PopulationSize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "bhutan" => Ok(Self::Bhutan),
+            "belgium" => Ok(Self::Belgium),
+            "brazil" => Ok(Self::Brazil),
+            other => Err(Error::Codegen(format!(
+                "unknown population size {other:?}, expected bhutan, belgium or brazil"
+            ))),
+        }
+    }
+}
+
+/// Problems found in a single word list by [`validate_words`].
+#[derive(Debug, Clone)]
+pub struct WordListReport {
+    /// The word list which was checked.
+    pub path: PathBuf,
+    /// The number of lines in the word list.
+    pub word_count: u32,
+    /// The number of words which are needed for the chosen [`PopulationSize`].
+    pub required_count: u32,
+    /// Words which appear more than once.
+    pub duplicates: Vec<String>,
+    /// Words containing characters other than lowercase ascii letters.
+    /// These would produce names which can not be split into their components.
+    pub invalid: Vec<String>,
+    /// Words which are longer than the maximum length.
+    pub too_long: Vec<String>,
+    /// Words which appear in the blocklist.
+    pub blocked: Vec<String>,
+}
+
+impl WordListReport {
+    /// True if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.word_count >= self.required_count
+            && self.duplicates.is_empty()
+            && self.invalid.is_empty()
+            && self.too_long.is_empty()
+            && self.blocked.is_empty()
+    }
+}
+
+/// Check the word lists which would be passed to [`ingredients`], without generating anything.
+/// Each word is checked for duplicates, invalid characters, a length above `max_length`,
+/// and membership in the optional `blocklist` file (one word per line, such as profanity).
+///
+/// Returns one report for each of `prefixes`, `colors` and `animals`.
+pub fn validate_words<P: AsRef<Path>>(
+    size: PopulationSize,
+    prefixes: P,
+    colors: P,
+    animals: P,
+    blocklist: Option<P>,
+    max_length: usize,
+) -> Result<[WordListReport; 3], Error> {
+    let blocked_words: HashSet<String> = match blocklist {
+        Some(path) => read_lines(path)?
+            .map_while(Result::ok)
+            .map(|w| w.trim().to_lowercase())
+            .collect(),
+        None => HashSet::default(),
+    };
+
+    let required_prefixes = 16u32.pow(STORAGE_KEY_LENGTH as u32);
+    let color_count = count_lines(colors.as_ref())?;
+    let required_color_animals = size as u32 / required_prefixes;
+    let required_animals = required_color_animals.div_ceil(max(1, color_count));
+
+    let check = |path: &Path, required_count: u32| -> Result<WordListReport, Error> {
+        let words = read_lines(path)?.map_while(Result::ok).collect::<Vec<_>>();
+        let mut seen = HashSet::new();
+        let mut duplicates = BTreeSet::new();
+        for word in &words {
+            if !seen.insert(word) {
+                duplicates.insert(word.clone());
+            }
+        }
+        Ok(WordListReport {
+            path: path.to_path_buf(),
+            word_count: words.len() as u32,
+            required_count,
+            duplicates: duplicates.into_iter().collect(),
+            invalid: words
+                .iter()
+                .filter(|w| w.is_empty() || !w.chars().all(|c| c.is_ascii_lowercase()))
+                .cloned()
+                .collect(),
+            too_long: words
+                .iter()
+                .filter(|w| w.chars().count() > max_length)
+                .cloned()
+                .collect(),
+            blocked: words
+                .iter()
+                .filter(|w| blocked_words.contains(*w))
+                .cloned()
+                .collect(),
+        })
+    };
+
+    Ok([
+        check(prefixes.as_ref(), required_prefixes)?,
+        check(colors.as_ref(), 1)?,
+        check(animals.as_ref(), required_animals)?,
+    ])
+}
+
+/// Reported by [`ingredients_with_progress`] as generation proceeds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Progress {
+    /// A word list was read.
+    FileRead {
+        /// The word list.
+        path: PathBuf,
+        /// The number of lines in the word list.
+        words: u32,
+    },
+    /// Some of the words were written to the output.
+    WordsProcessed {
+        /// Words written so far.
+        count: usize,
+        /// Words which will be written in total.
+        total: usize,
+    },
+    /// The output was completed, with this many bytes.
+    BytesWritten(u64),
+    /// A problem which does not prevent generation.
+    Warning(Warning),
+}
+
+/// A problem found by [`ingredients_with_progress`] which does not prevent generation.
+/// Build scripts can show these with `cargo:warning=`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Warning {
+    /// More words were provided than can be used, and those at the end of the list were ignored.
+    UnusedWords {
+        /// The word list.
+        path: PathBuf,
+        /// The number of words which were ignored.
+        count: u32,
+    },
+    /// Words which appear more than once, and so may produce the same name twice.
+    DuplicateWords {
+        /// The word list.
+        path: PathBuf,
+        /// Each repeated word, once.
+        words: Vec<String>,
+    },
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnusedWords { path, count } => {
+                write!(f, "{path:?}: the last {count} words are not used")
+            }
+            Self::DuplicateWords { path, words } => {
+                write!(f, "{path:?}: duplicate words {}", words.join(", "))
+            }
+        }
+    }
+}
+
+// words processed between progress reports
+const PROGRESS_INTERVAL: usize = 10_000;
+
 /// Compile words from `prefixes`, `colors` and `animals` files into `output` file.
 /// The resulting static item will be named using `static_name`.
 ///
@@ -38,14 +212,110 @@ where
     P1: AsRef<Path>,
     P2: AsRef<Path>,
 {
-    let prefixes_path: &Path = prefixes.as_ref();
-    let colors_path: &Path = colors.as_ref();
-    let animals_path: &Path = animals.as_ref();
+    ingredients_with_progress(static_name, size, prefixes, colors, animals, output, |_| {})
+}
+
+/// Like [`ingredients`], calling `progress` as files are read and words are written,
+/// and with any [`Warning`] about the word lists.
+pub fn ingredients_with_progress<P1, P2, F>(
+    static_name: &str,
+    size: PopulationSize,
+    prefixes: P1,
+    colors: P1,
+    animals: P1,
+    output: P2,
+    mut progress: F,
+) -> Result<(), Error>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+    F: FnMut(Progress),
+{
     let output_path: &Path = output.as_ref();
+    let [prefix_words, color_words, animal_words] = read_ingredients(
+        size,
+        prefixes.as_ref(),
+        colors.as_ref(),
+        animals.as_ref(),
+        &mut progress,
+    )?;
+    let required_prefixes = 16u32.pow(STORAGE_KEY_LENGTH as u32);
+
+    let mut output_writer = BufWriter::new(File::create(output_path).unwrap());
+    writeln!(output_writer, "#[allow(dead_code)]")?;
+    writeln!(output_writer, "pub static {}:", static_name.to_uppercase())?;
+    // there are unit tests which depend on this generated code
+    // which can not reference types which are also required by codegen (avoiding separate codegen crate)
+    writeln!(
+        output_writer,
+        "(usize, phf::Map<&str, &str>, &[&str], &[&str]) = ("
+    )?;
+    writeln!(output_writer, "{},", size as usize)?;
+    let mut processed = WordsProcessed {
+        count: 0,
+        total: required_prefixes as usize + color_words.len() + animal_words.len(),
+        progress: &mut progress,
+    };
+    write_prefixes(&prefix_words, &mut output_writer)?;
+    processed.add(required_prefixes as usize);
+    write_words(&color_words, &mut output_writer, &mut processed)?;
+    write_words(&animal_words, &mut output_writer, &mut processed)?;
+    writeln!(output_writer, ");")?;
+
+    let file = output_writer.into_inner().map_err(|e| e.into_error())?;
+    progress(Progress::BytesWritten(file.metadata()?.len()));
+    Ok(())
+}
+
+/// Like [`ingredients`], but writes the canonical text form described by
+/// [`crate::diagnostics::canonical_ingredients`] instead of Rust code. The output only changes
+/// when a name would change, so it can be committed as a golden file and compared in CI with
+/// the canonical form of the compiled ingredients, see [`crate::diagnostics::diff_ingredients`].
+pub fn canonical_ingredients<P1, P2>(
+    size: PopulationSize,
+    prefixes: P1,
+    colors: P1,
+    animals: P1,
+    output: P2,
+) -> Result<(), Error>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let [prefix_words, color_words, animal_words] = read_ingredients(
+        size,
+        prefixes.as_ref(),
+        colors.as_ref(),
+        animals.as_ref(),
+        &mut |_| {},
+    )?;
+    let canonical = crate::diagnostics::canonical_form(
+        size as usize,
+        prefix_entries(&prefix_words)
+            .iter()
+            .map(|(key, prefix)| (key.as_str(), prefix.as_str())),
+        &color_words,
+        &animal_words,
+    );
+    std::fs::write(output, canonical)?;
+    Ok(())
+}
+
+// reads and checks the word lists for a population of `size`
+fn read_ingredients(
+    size: PopulationSize,
+    prefixes_path: &Path,
+    colors_path: &Path,
+    animals_path: &Path,
+    progress: &mut impl FnMut(Progress),
+) -> Result<[Vec<String>; 3], Error> {
+    let prefix_words = read_words(prefixes_path, &mut *progress)?;
+    let color_words = read_words(colors_path, &mut *progress)?;
+    let animal_words = read_words(animals_path, &mut *progress)?;
 
     // each prefix will be mapped to a different storage key (see storage.rs)
     let required_prefixes = 16u32.pow(STORAGE_KEY_LENGTH as u32);
-    let prefix_count = count_lines(prefixes_path)?;
+    let prefix_count = prefix_words.len() as u32;
     if prefix_count < required_prefixes {
         return Err(Error::Codegen(format!(
             "insufficient seed words. {}. {}",
@@ -60,8 +330,8 @@ where
     // within each storage blob,
     // each storage digest will be mapped to a different (color, animal)
     let required_color_animals = size as u32 / required_prefixes;
-    let color_count = count_lines(colors_path)?;
-    let animal_count = count_lines(animals_path)?;
+    let color_count = color_words.len() as u32;
+    let animal_count = animal_words.len() as u32;
     if required_color_animals > color_count * animal_count {
         return Err(Error::Codegen(format!(
             "insufficient seed words. {}. {}",
@@ -76,26 +346,73 @@ where
             )
         )));
     }
+    if prefix_count > required_prefixes {
+        progress(Progress::Warning(Warning::UnusedWords {
+            path: prefixes_path.to_path_buf(),
+            count: prefix_count - required_prefixes,
+        }));
+    }
+    Ok([prefix_words, color_words, animal_words])
+}
 
-    let mut output_writer = BufWriter::new(File::create(output_path).unwrap());
-    writeln!(output_writer, "#[allow(dead_code)]")?;
-    writeln!(output_writer, "pub static {}:", static_name.to_uppercase())?;
-    // there are unit tests which depend on this generated code
-    // which can not reference types which are also required by codegen (avoiding separate codegen crate)
-    writeln!(
-        output_writer,
-        "(usize, phf::Map<&str, &str>, &[&str], &[&str]) = ("
-    )?;
-    writeln!(output_writer, "{},", size as usize)?;
-    write_prefixes(prefixes_path, &mut output_writer)?;
-    write_words(colors_path, &mut output_writer)?;
-    write_words(animals_path, &mut output_writer)?;
-    writeln!(output_writer, ");")?;
+// reads the lines of `path`, reporting them along with any duplicates
+fn read_words(path: &Path, progress: &mut impl FnMut(Progress)) -> Result<Vec<String>, Error> {
+    let words = read_lines(path)?.map_while(Result::ok).collect::<Vec<_>>();
+    progress(Progress::FileRead {
+        path: path.to_path_buf(),
+        words: words.len() as u32,
+    });
+
+    let mut seen = HashSet::new();
+    let mut duplicates = BTreeSet::new();
+    for word in &words {
+        if !seen.insert(word) {
+            duplicates.insert(word.clone());
+        }
+    }
+    if !duplicates.is_empty() {
+        progress(Progress::Warning(Warning::DuplicateWords {
+            path: path.to_path_buf(),
+            words: duplicates.into_iter().collect(),
+        }));
+    }
+    Ok(words)
+}
+
+// reports Progress::WordsProcessed every PROGRESS_INTERVAL words, and when all are processed
+struct WordsProcessed<'a, F> {
+    count: usize,
+    total: usize,
+    progress: &'a mut F,
+}
+
+impl<F: FnMut(Progress)> WordsProcessed<'_, F> {
+    fn add(&mut self, count: usize) {
+        let interval = self.count / PROGRESS_INTERVAL;
+        self.count += count;
+        if self.count / PROGRESS_INTERVAL > interval || self.count == self.total {
+            (self.progress)(Progress::WordsProcessed {
+                count: self.count,
+                total: self.total,
+            });
+        }
+    }
+}
+
+fn write_prefixes(input: &[String], output: &mut BufWriter<File>) -> Result<(), Error> {
+    let entries = prefix_entries(input);
+    let mut map = &mut phf_codegen::Map::<&'static str>::new();
+    for (k, v) in &entries {
+        map = map.entry(k, format!("\"{v}\""));
+    }
+
+    writeln!(output, "{},", map.build())?;
 
     Ok(())
 }
 
-fn write_prefixes(input: &Path, output: &mut BufWriter<File>) -> Result<(), Error> {
+// the prefix word of each storage key, in order of key
+fn prefix_entries(input: &[String]) -> Vec<(String, String)> {
     // generate a list of all possible storage keys
     let hex_digits = "0123456789abcdef".chars().collect::<Vec<_>>();
     let mut hex_keys = vec![];
@@ -108,29 +425,29 @@ fn write_prefixes(input: &Path, output: &mut BufWriter<File>) -> Result<(), Erro
     // randomly select a word to associate with each key
     // rng_seed is hardcoded here to prevent accidental misuse
     let rng_seed = 656437432927126634;
-    let prefix_words = read_lines(input)?
-        .map_while(Result::ok)
+    let prefix_words = input
+        .iter()
         .take(hex_keys.len())
-        .collect::<Vec<String>>();
-    let prefix_words = prefix_words.iter().map(|w| &w[..]).collect::<Vec<&str>>();
+        .map(|w| &w[..])
+        .collect::<Vec<&str>>();
     let prefix_words = randomized(prefix_words.as_slice(), rng_seed);
     assert_eq!(hex_keys.len(), prefix_words.len());
 
-    let mut map = &mut phf_codegen::Map::<&'static str>::new();
-    for (k, v) in hex_keys.iter().zip(prefix_words.iter()) {
-        map = map.entry(k, format!("\"{v}\""));
-    }
-
-    writeln!(output, "{},", map.build())?;
-
-    Ok(())
+    hex_keys
+        .into_iter()
+        .zip(prefix_words.into_iter().map(str::to_string))
+        .collect()
 }
 
-fn write_words(input: &Path, output: &mut BufWriter<File>) -> Result<(), Error> {
-    let input = read_lines(input)?.map_while(Result::ok);
+fn write_words<F: FnMut(Progress)>(
+    input: &[String],
+    output: &mut BufWriter<File>,
+    processed: &mut WordsProcessed<'_, F>,
+) -> Result<(), Error> {
     writeln!(output, "&[")?;
     for word in input {
         writeln!(output, "  \"{word}\",")?;
+        processed.add(1);
     }
     writeln!(output, "],")?;
     Ok(())
@@ -192,6 +509,111 @@ fn count_lines(file: &Path) -> Result<u32, std::io::Error> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_validate_words() -> Result<(), Error> {
+        let tmp_dir = std::env::var("TMPDIR").unwrap_or("/tmp".to_string());
+        let animals = Path::new(&tmp_dir).join("perfume_test_animals.txt");
+        std::fs::write(&animals, "ant\nBee\nant\nrhinocerosbeetle\ncat\n")?;
+        let blocklist = Path::new(&tmp_dir).join("perfume_test_blocklist.txt");
+        std::fs::write(&blocklist, "cat\n")?;
+
+        let [prefixes, colors, animals] = validate_words(
+            PopulationSize::Bhutan,
+            Path::new("data/gerunds.txt"),
+            Path::new("data/colors.txt"),
+            animals.as_path(),
+            Some(blocklist.as_path()),
+            15,
+        )?;
+        assert!(prefixes.is_ok());
+        assert!(colors.is_ok());
+        assert!(!animals.is_ok());
+        assert_eq!(animals.word_count, 5);
+        assert_eq!(animals.duplicates, vec!["ant"]);
+        assert_eq!(animals.invalid, vec!["Bee"]);
+        assert_eq!(animals.too_long, vec!["rhinocerosbeetle"]);
+        assert_eq!(animals.blocked, vec!["cat"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ingredients_progress() -> Result<(), Error> {
+        let tmp_dir = std::env::var("TMPDIR").unwrap_or("/tmp".to_string());
+        let colors = Path::new(&tmp_dir).join("perfume_test_colors.txt");
+        std::fs::write(&colors, "red\nblue\nred\n")?;
+        let output = Path::new(&tmp_dir).join("perfume_test_progress.rs");
+
+        let mut events = vec![];
+        ingredients_with_progress(
+            "TEST_INGREDIENTS",
+            PopulationSize::Bhutan,
+            Path::new("data/gerunds.txt"),
+            colors.as_path(),
+            Path::new("data/animals.txt"),
+            output.as_path(),
+            |event| events.push(event),
+        )?;
+
+        let files_read = events
+            .iter()
+            .filter(|e| matches!(e, Progress::FileRead { .. }))
+            .count();
+        assert_eq!(files_read, 3);
+        let warnings = events
+            .iter()
+            .filter_map(|e| match e {
+                Progress::Warning(warning) => Some(warning.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            warnings,
+            [
+                Warning::DuplicateWords {
+                    path: colors.clone(),
+                    words: vec!["red".to_string()],
+                },
+                Warning::UnusedWords {
+                    path: PathBuf::from("data/gerunds.txt"),
+                    count: count_lines(Path::new("data/gerunds.txt"))? - 4096,
+                },
+            ]
+        );
+        let total = 4096 + 3 + count_lines(Path::new("data/animals.txt"))? as usize;
+        assert!(events.contains(&Progress::WordsProcessed {
+            count: total,
+            total
+        }));
+        let bytes = std::fs::metadata(&output)?.len();
+        assert_eq!(events.last(), Some(&Progress::BytesWritten(bytes)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_canonical_ingredients() -> Result<(), Error> {
+        let tmp_dir = std::env::var("TMPDIR").unwrap_or("/tmp".to_string());
+        let output = Path::new(&tmp_dir).join("perfume_test_canonical.txt");
+        canonical_ingredients(
+            PopulationSize::Brazil,
+            "data/gerunds.txt",
+            "data/colors.txt",
+            "data/animals.txt",
+            &output,
+        )?;
+        // the same ingredients as the generated code, see main.rs
+        let compiled =
+            crate::diagnostics::canonical_ingredients(&crate::identity::tests::PERFUME_INGREDIENTS);
+        assert_eq!(std::fs::read_to_string(&output)?, compiled);
+        Ok(())
+    }
+
+    #[test]
+    fn test_population_size_from_str() {
+        assert!(matches!("Belgium".parse(), Ok(PopulationSize::Belgium)));
+        assert!("atlantis".parse::<PopulationSize>().is_err());
+    }
+
     #[test]
     fn test_find_combinations_base() {
         let mut result = vec![];
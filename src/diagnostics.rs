@@ -0,0 +1,434 @@
+//! A report of the configuration and health of a population and its store, for diagnosing
+//! names which change unexpectedly. See [`report`].
+
+use std::collections::BTreeMap;
+
+use async_generic::async_generic;
+
+use crate::Error;
+use crate::identity::{
+    BlobFormat, ConcurrentStore, ConnectionBridge, Ingredients, MIN_SECRET_LENGTH, PING_KEY,
+    Population, RemoteStore, check_blob,
+};
+
+// resolved by the round trip check, never stored
+const SAMPLE_IDENTIFIER: &str = "perfume-diagnostics@example.com";
+
+/// The outcome of a [`Check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    /// Nothing is wrong.
+    Pass,
+    /// Could not be checked, or may be a problem.
+    Warn,
+    /// Names will not be resolved, or will not match those already assigned.
+    Fail,
+}
+
+/// One finding of a [`Report`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    /// What was checked, such as "secret".
+    pub name: &'static str,
+    /// The outcome.
+    pub status: Status,
+    /// Explains the outcome.
+    pub detail: String,
+}
+
+/// The result of [`report`].
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// See [`ingredients_fingerprint`].
+    pub fingerprint: String,
+    /// Findings, in the order they were checked.
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// The worst status of any check.
+    pub fn status(&self) -> Status {
+        self.checks
+            .iter()
+            .map(|check| check.status)
+            .max()
+            .unwrap_or(Status::Pass)
+    }
+
+    fn check(&mut self, name: &'static str, status: Status, detail: String) {
+        self.checks.push(Check {
+            name,
+            status,
+            detail,
+        });
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ingredients fingerprint: {}", self.fingerprint)?;
+        for check in &self.checks {
+            let status = match check.status {
+                Status::Pass => "ok",
+                Status::Warn => "warn",
+                Status::Fail => "FAIL",
+            };
+            write!(f, "\n{status:>4}  {:<12}  {}", check.name, check.detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// Identifies the words and population size of `ingredients`. Names are only stable while
+/// this is unchanged, so it should be recorded when a population is first used.
+pub fn ingredients_fingerprint(ingredients: &Ingredients) -> String {
+    let (population_size, prefixes, colors, animals) = ingredients;
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&(*population_size as u64).to_le_bytes());
+    let mut prefixes = prefixes.entries().collect::<Vec<_>>();
+    prefixes.sort_unstable();
+    for (key, prefix) in prefixes {
+        hasher.update(format!("{key}={prefix}\n").as_bytes());
+    }
+    for words in [colors, animals] {
+        hasher.update(b"\n");
+        for word in words.iter() {
+            hasher.update(format!("{word}\n").as_bytes());
+        }
+    }
+    hasher.finalize().to_hex().to_string()
+}
+
+/// Write `ingredients` in a canonical text form, which only changes when a name would change:
+/// the population size, then the prefix of each storage key in order of key, then the colors
+/// and animals in order. Compare this with a committed golden file using [`diff_ingredients`],
+/// which can be written by `codegen::canonical_ingredients` with the `codegen` feature.
+pub fn canonical_ingredients(ingredients: &Ingredients) -> String {
+    let (population_size, prefixes, colors, animals) = ingredients;
+    canonical_form(
+        *population_size,
+        prefixes.entries().map(|(key, prefix)| (*key, *prefix)),
+        colors,
+        animals,
+    )
+}
+
+pub(crate) fn canonical_form<'a>(
+    population_size: usize,
+    prefixes: impl IntoIterator<Item = (&'a str, &'a str)>,
+    colors: &[impl AsRef<str>],
+    animals: &[impl AsRef<str>],
+) -> String {
+    let mut prefixes = prefixes.into_iter().collect::<Vec<_>>();
+    prefixes.sort_unstable();
+    let mut canonical =
+        String::from("# perfume ingredients, see diagnostics::canonical_ingredients\n");
+    canonical.push_str(&format!("size {population_size}\n"));
+    for (key, prefix) in prefixes {
+        canonical.push_str(&format!("prefix {key} {prefix}\n"));
+    }
+    push_words(&mut canonical, "color", colors);
+    push_words(&mut canonical, "animal", animals);
+    canonical
+}
+
+fn push_words(canonical: &mut String, section: &str, words: &[impl AsRef<str>]) {
+    for (index, word) in words.iter().enumerate() {
+        canonical.push_str(&format!("{section} {index} {}\n", word.as_ref()));
+    }
+}
+
+/// A difference found by [`diff_ingredients`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IngredientChange {
+    /// What changed: "size", "prefix", "color" or "animal".
+    pub kind: &'static str,
+    /// The storage key of a prefix, or the position of a color or animal.
+    pub key: String,
+    /// The value before, if there was one.
+    pub old: Option<String>,
+    /// The value after, if there is one.
+    pub new: Option<String>,
+}
+
+impl std::fmt::Display for IngredientChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let old = self.old.as_deref().unwrap_or("(none)");
+        let new = self.new.as_deref().unwrap_or("(none)");
+        match self.kind {
+            "size" => write!(f, "size: {old} -> {new}"),
+            kind => write!(f, "{kind} {}: {old} -> {new}", self.key),
+        }
+    }
+}
+
+/// The differences between two outputs of [`canonical_ingredients`], in the order of the
+/// canonical form. Blank lines, comments, spacing and the order of lines are ignored.
+/// Any difference changes the names of some identities, and a changed size changes most.
+pub fn diff_ingredients(old: &str, new: &str) -> Result<Vec<IngredientChange>, Error> {
+    let old = parse_canonical(old)?;
+    let mut new = parse_canonical(new)?;
+    let mut changes = vec![];
+    for (key, old_value) in old {
+        let new_value = new.remove(&key);
+        if new_value.as_ref() != Some(&old_value) {
+            changes.push((key, Some(old_value), new_value));
+        }
+    }
+    changes.extend(new.into_iter().map(|(key, value)| (key, None, Some(value))));
+    changes.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    Ok(changes
+        .into_iter()
+        .map(|((section, index, key), old, new)| IngredientChange {
+            kind: SECTIONS[section],
+            key: if section == 1 { key } else { index.to_string() },
+            old,
+            new,
+        })
+        .collect())
+}
+
+const SECTIONS: [&str; 4] = ["size", "prefix", "color", "animal"];
+
+// (section, position, storage key) -> value
+fn parse_canonical(canonical: &str) -> Result<BTreeMap<(usize, usize, String), String>, Error> {
+    let mut values = BTreeMap::new();
+    for (number, line) in canonical.lines().enumerate() {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let entry = match fields[..] {
+            [] => continue,
+            [comment, ..] if comment.starts_with('#') => continue,
+            ["size", size] => Some(((0, 0, String::new()), size)),
+            ["prefix", key, prefix] => Some(((1, 0, key.to_string()), prefix)),
+            [section @ ("color" | "animal"), index, word] => index.parse().ok().map(|index| {
+                let section = if section == "color" { 2 } else { 3 };
+                ((section, index, String::new()), word)
+            }),
+            _ => None,
+        };
+        let Some((key, value)) = entry else {
+            let message = format!("line {number} of canonical ingredients is malformed");
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message).into());
+        };
+        values.insert(key, value.to_string());
+    }
+    Ok(values)
+}
+
+/// Check `population` and `store` for the usual causes of missing or changed names:
+/// ingredients which differ from the `manifest` fingerprint recorded when the population was
+/// first used (see [`ingredients_fingerprint`]), a short secret, an unreachable bridge,
+/// a stored blob which is not in the format of the store, and names which do not resolve the
+/// same way twice. Nothing is written to the store.
+#[async_generic]
+#[allow(unused_assignments)]
+pub fn report<B>(
+    population: &Population<'_>,
+    store: &RemoteStore<B>,
+    manifest: Option<&str>,
+) -> Report
+where
+    B: ConnectionBridge + Send,
+{
+    let mut report = Report {
+        fingerprint: ingredients_fingerprint(population.ingredients),
+        checks: vec![],
+    };
+
+    let (status, detail) = match manifest {
+        Some(expected) if expected == report.fingerprint => {
+            (Status::Pass, "matches the manifest".to_string())
+        }
+        Some(expected) => (
+            Status::Fail,
+            format!("the manifest expects {expected}, names will differ from those assigned"),
+        ),
+        None => (Status::Warn, "no manifest to compare with".to_string()),
+    };
+    report.check("ingredients", status, detail);
+
+    let secret_length = population.secret.len();
+    let secret_ok = secret_length >= MIN_SECRET_LENGTH;
+    report.check(
+        "secret",
+        if secret_ok {
+            Status::Pass
+        } else {
+            Status::Fail
+        },
+        format!("{secret_length} bytes, at least {MIN_SECRET_LENGTH} are required"),
+    );
+
+    let resource = store.bridge_key(PING_KEY);
+    let mut fetched = Ok(None);
+    if _async {
+        fetched = store.bridge.get_async(&resource).await;
+    } else {
+        fetched = store.bridge.get(&resource);
+    }
+    match fetched {
+        Err(e) => {
+            report.check("bridge", Status::Fail, e.to_string());
+            report.check("blob format", Status::Warn, "bridge unreachable".into());
+        }
+        Ok(None) => {
+            report.check("bridge", Status::Pass, "reachable".into());
+            let detail = format!("no blob at {PING_KEY} to check");
+            report.check("blob format", Status::Warn, detail);
+        }
+        Ok(Some(blob)) => {
+            report.check("bridge", Status::Pass, "reachable".into());
+            let (status, detail) = check_format(store.blob_format(), &blob);
+            report.check("blob format", status, detail);
+        }
+    }
+
+    // names from a rejected secret would not be kept, so there is nothing to round trip
+    if secret_ok {
+        let (status, detail) = check_round_trip(population);
+        report.check("round trip", status, detail);
+    }
+    report
+}
+
+fn check_format(format: BlobFormat, blob: &[u8]) -> (Status, String) {
+    let text = match format.decode(blob) {
+        Ok(text) => text,
+        Err(e) => {
+            return (
+                Status::Fail,
+                format!("blob {PING_KEY} is not {format:?}: {e}"),
+            );
+        }
+    };
+    if let Some(issue) = check_blob(&text).issues.first() {
+        return (Status::Fail, format!("blob {PING_KEY}: {issue}"));
+    }
+    match format
+        .encode(&text)
+        .and_then(|encoded| format.decode(&encoded))
+    {
+        Ok(decoded) if decoded == text => {
+            let records = text.iter().filter(|&&b| b == b'\n').count();
+            (
+                Status::Pass,
+                format!("{format:?}, {records} records at {PING_KEY}"),
+            )
+        }
+        _ => (
+            Status::Fail,
+            format!("blob {PING_KEY} changes when it is rewritten as {format:?}"),
+        ),
+    }
+}
+
+fn check_round_trip(population: &Population<'_>) -> (Status, String) {
+    let store = ConcurrentStore::new();
+    let resolve = || population.identity(SAMPLE_IDENTIFIER, &mut &store);
+    match (resolve(), resolve()) {
+        (Ok(first), Ok(second)) if first == second => {
+            (Status::Pass, format!("resolved {}", first.friendly_name))
+        }
+        (Ok(first), Ok(second)) => (
+            Status::Fail,
+            format!(
+                "resolved {} then {}",
+                first.friendly_name, second.friendly_name
+            ),
+        ),
+        (Err(e), _) | (_, Err(e)) => (Status::Fail, e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::identity::tests::*;
+
+    #[test]
+    fn test_report() {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let fingerprint = ingredients_fingerprint(&PERFUME_INGREDIENTS);
+        let store = RemoteStore::new(MockBridge::default());
+
+        let report = report(&brazilian, &store, Some(&fingerprint));
+        assert_eq!(report.fingerprint, fingerprint);
+        let statuses = report
+            .checks
+            .iter()
+            .map(|check| (check.name, check.status))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            [
+                ("ingredients", Status::Pass),
+                ("secret", Status::Pass),
+                ("bridge", Status::Pass),
+                ("blob format", Status::Warn),
+                ("round trip", Status::Pass),
+            ]
+        );
+        assert!(store.bridge.is_empty());
+
+        store
+            .bridge
+            .put(PING_KEY, Bytes::from_static(b"corrupt\n"))
+            .unwrap();
+        let short = Population {
+            secret: b"short",
+            ..brazilian
+        };
+        let report = super::report(&short, &store, Some("0"));
+        assert_eq!(report.status(), Status::Fail);
+        let failed = report
+            .checks
+            .iter()
+            .filter(|check| check.status == Status::Fail)
+            .map(|check| check.name)
+            .collect::<Vec<_>>();
+        assert_eq!(failed, ["ingredients", "secret", "blob format"]);
+        assert!(!report.to_string().contains("short"));
+    }
+
+    #[test]
+    fn test_diff_ingredients() -> Result<(), Error> {
+        let canonical = canonical_ingredients(&PERFUME_INGREDIENTS);
+        assert!(canonical.contains("\nsize 203080756\nprefix 000 "));
+        assert_eq!(diff_ingredients(&canonical, &canonical)?, []);
+
+        // reformatted and reordered
+        let reformatted = canonical
+            .lines()
+            .rev()
+            .map(|line| format!("  {}\n\n", line.replace(' ', "\t")))
+            .collect::<String>();
+        assert_eq!(diff_ingredients(&canonical, &reformatted)?, []);
+
+        let (_size, _prefixes, colors, _animals) = &PERFUME_INGREDIENTS;
+        let changed = canonical
+            .replace("prefix 000 ", "prefix 000 x")
+            .replace(&format!("color 1 {}\n", colors[1]), "")
+            + "animal 5000 newt\n";
+        let changes = diff_ingredients(&canonical, &changed)?;
+        let summary = changes
+            .iter()
+            .map(|c| (c.kind, c.key.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [("prefix", "000"), ("color", "1"), ("animal", "5000")]
+        );
+        assert_eq!(
+            changes[1].to_string(),
+            format!("color 1: {} -> (none)", colors[1])
+        );
+        assert!(diff_ingredients(&canonical, "size").is_err());
+        Ok(())
+    }
+}
@@ -6,6 +6,11 @@ cfg_if::cfg_if! {
 
         /// `N` hex characters from '[0-9a-f]'.
         #[derive(Clone, PartialEq, Eq, Hash)]
+        #[cfg_attr(
+            feature = "diesel",
+            derive(diesel::expression::AsExpression, diesel::deserialize::FromSqlRow),
+            diesel(sql_type = diesel::sql_types::Text)
+        )]
         pub struct HexString<const N: usize>([Char; N]);
         impl<const N: usize> HexString<N> {
             /// View as a UTF-8 `str`.
@@ -46,6 +51,11 @@ cfg_if::cfg_if! {
     } else {
         /// `N` hex characters from '[0-9a-f]'.
         #[derive(Clone, PartialEq, Eq, Hash)]
+        #[cfg_attr(
+            feature = "diesel",
+            derive(diesel::expression::AsExpression, diesel::deserialize::FromSqlRow),
+            diesel(sql_type = diesel::sql_types::Text)
+        )]
         pub struct HexString<const N: usize>(String);
         impl<const N: usize> HexString<N> {
             /// View as a UTF-8 `str`.
@@ -55,10 +65,10 @@ cfg_if::cfg_if! {
         }
         impl<const N: usize> From<&[u8]> for HexString<N> {
             fn from(value: &[u8]) -> Self {
-                let mut string = String::from_utf8(value.to_vec()).expect("should be valid utf-8");
-                assert!(string.chars().all(|c| c.is_ascii_hexdigit()));
-                assert_eq!(string.len(), N, "string length should be {N}");
-                string.make_ascii_lowercase();
+                // checked before copying, so that only one allocation is needed
+                assert!(value.iter().all(u8::is_ascii_hexdigit));
+                assert_eq!(value.len(), N, "string length should be {N}");
+                let string = String::from_utf8(value.to_ascii_lowercase()).expect("should be ascii");
                 Self(string)
             }
         }
@@ -82,6 +92,22 @@ impl<const N: usize> std::fmt::Display for HexString<N> {
     }
 }
 
+impl<const N: usize> std::str::FromStr for HexString<N> {
+    type Err = crate::Error;
+
+    /// Parse `N` hex characters, of either case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != N || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("expected {N} hex characters, found {s:?}"),
+            )
+            .into());
+        }
+        Ok(Self::from(s.as_bytes()))
+    }
+}
+
 impl From<HexString<4>> for u16 {
     /// Produces an array index in ["0000" .. "ffff"].
     /// u16 indicates the range of possible values: [0 .. 65535]
@@ -114,6 +140,14 @@ mod tests {
         assert_eq!(s.as_str(), "ab1");
     }
 
+    #[test]
+    fn test_from_str() {
+        let s: HexString<3> = "AB1".parse().unwrap();
+        assert_eq!(s.to_string(), "ab1");
+        assert!("ab".parse::<HexString<3>>().is_err());
+        assert!("abg".parse::<HexString<3>>().is_err());
+    }
+
     #[test]
     fn test_to_u16() {
         let cases = [
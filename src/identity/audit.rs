@@ -0,0 +1,139 @@
+//! Audit records of changes to stored offsets.
+
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::hex_string::HexString;
+use crate::{MIN_STORAGE_DIGEST_LENGTH, STORAGE_KEY_LENGTH};
+
+/// A change to the offsets held by a store, see [`AuditRecord::action`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum AuditAction {
+    /// A new digest was assigned an offset.
+    Assigned,
+    /// The record of a digest was removed, or replaced by a tombstone,
+    /// see [`super::RemoteStore::delete`].
+    Deleted,
+}
+
+/// Describes one change to the offsets held by a store, see [`AuditSink`].
+/// Digests are abbreviated, so that records can be kept apart from the store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// When the change was made, according to the system clock.
+    pub timestamp: SystemTime,
+    /// The domain of the population which made the change.
+    pub domain: String,
+    /// The storage key of the changed blob.
+    pub key: HexString<STORAGE_KEY_LENGTH>,
+    /// The first [`MIN_STORAGE_DIGEST_LENGTH`] characters of the digest.
+    pub digest_prefix: String,
+    /// The offset which was assigned or deleted.
+    pub offset: usize,
+    /// What happened.
+    pub action: AuditAction,
+    /// Who made the change, as configured for the store.
+    pub actor: String,
+}
+
+/// Receives an [`AuditRecord`] for each change to a store, such as for shipping an append-only
+/// audit log. Records are sent after the change was stored, by the thread which made it.
+/// Implemented for closures.
+pub trait AuditSink: Send + Sync {
+    /// Handle a change. Errors should be handled by the sink, because the change was made.
+    fn record(&self, record: AuditRecord);
+}
+
+impl<F> AuditSink for F
+where
+    F: Fn(AuditRecord) + Send + Sync,
+{
+    fn record(&self, record: AuditRecord) {
+        self(record)
+    }
+}
+
+/// An [`AuditSink`] along with the actor of the store which sends to it.
+#[derive(Clone)]
+pub(crate) struct Audit {
+    sink: Arc<dyn AuditSink>,
+    actor: String,
+}
+
+impl Audit {
+    pub(crate) fn new(sink: Arc<dyn AuditSink>, actor: String) -> Self {
+        Self { sink, actor }
+    }
+
+    pub(crate) fn record(
+        &self,
+        action: AuditAction,
+        domain: &str,
+        key: &str,
+        digest: &str,
+        offset: usize,
+    ) {
+        self.sink.record(AuditRecord {
+            timestamp: SystemTime::now(),
+            domain: domain.to_string(),
+            key: HexString::from(key.as_bytes()),
+            digest_prefix: digest[..MIN_STORAGE_DIGEST_LENGTH].to_string(),
+            offset,
+            action,
+            actor: self.actor.clone(),
+        });
+    }
+}
+
+impl std::fmt::Debug for Audit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Audit")
+            .field("actor", &self.actor)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::Error;
+    use crate::identity::{Population, RemoteStore, tests::*};
+
+    #[test]
+    fn test_audit_sink() -> Result<(), Error> {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let records = Arc::new(Mutex::new(vec![]));
+        let sink = {
+            let records = records.clone();
+            move |record| records.lock().unwrap().push(record)
+        };
+        let mut store = RemoteStore::new(MockBridge::default()).with_audit_sink(sink, "importer");
+        let identities = brazilian.identities(["a@b.br", "c@d.br", "a@b.br"], &mut store)?;
+
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 2);
+        for (record, identity) in records.iter().zip(&identities) {
+            assert_eq!(record.action, AuditAction::Assigned);
+            assert_eq!(record.domain, "br");
+            assert_eq!(record.actor, "importer");
+            assert_eq!(record.key, identity.storage.key);
+            assert_eq!(record.offset, identity.offset);
+            assert!(
+                identity
+                    .storage
+                    .digest
+                    .as_str()
+                    .starts_with(&record.digest_prefix)
+            );
+            assert_eq!(record.digest_prefix.len(), MIN_STORAGE_DIGEST_LENGTH);
+        }
+        Ok(())
+    }
+}
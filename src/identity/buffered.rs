@@ -0,0 +1,210 @@
+//! A [`StorageState`] which writes assigned offsets in batches.
+
+use std::time::{Duration, Instant};
+
+use async_generic::async_generic;
+
+use crate::Error;
+
+use super::storage::{ConnectionBridge, RemoteStore, Storage, StorageState};
+
+/// Assigns offsets against copies of blobs held in memory, and writes the changed blobs
+/// together when [`BufferedStore::flush`] is called, or when one of its thresholds is reached:
+/// 1,000 changed blobs, 64 MiB of changed blobs, or a minute since the oldest unwritten
+/// assignment, by default. Thresholds are checked after each assignment, so a store which is
+/// idle keeps its changes until it is flushed. Batch pipelines can then trade how soon
+/// assignments are durable for fewer writes, each of which carries many assignments.
+///
+/// Changed blobs are also written when the store is dropped, ignoring errors, so call `flush`
+/// at the end of a batch to handle them.
+///
+/// **This is only correct while this store is the only writer to its domain**, as for
+/// [`RemoteStore::with_write_behind`], which it relies on. Returned offsets are not persisted
+/// until they are written, and concurrent assignments will be overwritten.
+#[derive(Debug)]
+pub struct BufferedStore<B: ConnectionBridge> {
+    store: RemoteStore<B>,
+    max_blobs: usize,
+    max_bytes: usize,
+    max_delay: Duration,
+}
+
+impl<B: ConnectionBridge + Send> BufferedStore<B> {
+    /// Buffer the blobs changed by `store`, replacing its write-behind window.
+    pub fn new(store: RemoteStore<B>) -> Self {
+        Self {
+            store: store.with_write_behind(Duration::MAX),
+            max_blobs: 1000,
+            max_bytes: 64 << 20,
+            max_delay: Duration::from_secs(60),
+        }
+    }
+
+    /// Flush once `max_blobs` blobs have changed.
+    pub fn with_max_blobs(mut self, max_blobs: usize) -> Self {
+        self.max_blobs = max_blobs;
+        self
+    }
+
+    /// Flush once the changed blobs hold `max_bytes` bytes.
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Flush once an assignment has not been written for `max_delay`.
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// The store which assigns offsets.
+    pub fn store(&self) -> &RemoteStore<B> {
+        &self.store
+    }
+
+    /// The store which assigns offsets, with the blobs which are not written yet.
+    pub fn into_inner(self) -> RemoteStore<B> {
+        self.store
+    }
+
+    /// The number of changed blobs which are not written yet.
+    pub fn pending_blobs(&self) -> usize {
+        self.store.pending_blobs().count()
+    }
+
+    /// The size of the changed blobs which are not written yet.
+    pub fn pending_bytes(&self) -> usize {
+        self.store.pending_blobs().map(|(_, blob)| blob.len()).sum()
+    }
+
+    /// Write every changed blob. Blobs which could not be written remain pending.
+    #[async_generic]
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if _async {
+            self.store.flush_async().await
+        } else {
+            self.store.flush()
+        }
+    }
+
+    // true if a threshold is reached
+    fn due(&self) -> bool {
+        let (mut blobs, mut bytes, mut oldest) = (0, 0, None::<Instant>);
+        for (since, blob) in self.store.pending_blobs() {
+            blobs += 1;
+            bytes += blob.len();
+            oldest = Some(oldest.map_or(since, |oldest| oldest.min(since)));
+        }
+        blobs >= self.max_blobs
+            || bytes >= self.max_bytes
+            || oldest.is_some_and(|oldest| oldest.elapsed() >= self.max_delay)
+    }
+
+    #[async_generic]
+    fn flush_if_due(&mut self) -> Result<(), Error> {
+        if !self.due() {
+            return Ok(());
+        }
+        event!(
+            DEBUG,
+            blobs = self.pending_blobs(),
+            "flushing buffered blobs"
+        );
+        if _async {
+            self.store.flush_async().await
+        } else {
+            self.store.flush()
+        }
+    }
+}
+
+impl<B> StorageState for BufferedStore<B>
+where
+    B: ConnectionBridge + Send,
+{
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn digest_offset(&mut self, domain: &str, storage: &Storage) -> Result<usize, Error> {
+        let mut offset = 0;
+        if _async {
+            offset = self.store.digest_offset_async(domain, storage).await?;
+            self.flush_if_due_async().await?;
+        } else {
+            offset = self.store.digest_offset(domain, storage)?;
+            self.flush_if_due()?;
+        }
+        Ok(offset)
+    }
+
+    /// Resolves the digests of each storage key together, see
+    /// [`RemoteStore::digest_offsets`](StorageState::digest_offsets).
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn digest_offsets(&mut self, domain: &str, storages: &[Storage]) -> Result<Vec<usize>, Error> {
+        let mut offsets = vec![];
+        if _async {
+            offsets = self.store.digest_offsets_async(domain, storages).await?;
+            self.flush_if_due_async().await?;
+        } else {
+            offsets = self.store.digest_offsets(domain, storages)?;
+            self.flush_if_due()?;
+        }
+        Ok(offsets)
+    }
+
+    /// Checks the store, see [`RemoteStore::health_check`](StorageState::health_check).
+    #[async_generic]
+    fn health_check(&mut self) -> Result<(), Error> {
+        if _async {
+            self.store.health_check_async().await
+        } else {
+            self.store.health_check()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::{Population, tests::*};
+
+    #[test]
+    fn test_buffered_store() -> Result<(), Error> {
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let identifiers = (0..10).map(|i| format!("{i}@b.br")).collect::<Vec<_>>();
+
+        // changed blobs are written once there are enough of them
+        let mut store = BufferedStore::new(RemoteStore::new(MockBridge::default()))
+            .with_max_blobs(4)
+            .with_max_bytes(usize::MAX);
+        let identities = population.identities(&identifiers[..3], &mut store)?;
+        assert_eq!(store.pending_blobs(), 3);
+        assert!(store.pending_bytes() > 0);
+        assert!(store.store().bridge.is_empty());
+        population.identity(&identifiers[3], &mut store)?;
+        assert_eq!(store.pending_blobs(), 0);
+        assert_eq!(store.store().bridge.len(), 4);
+
+        // or by flush
+        population.identities(&identifiers[4..], &mut store)?;
+        store.flush()?;
+        assert_eq!(store.pending_blobs(), 0);
+        let mut reopened = RemoteStore::new(std::mem::take(&mut store.store.bridge));
+        let found = population.identities(&identifiers[..3], &mut reopened)?;
+        assert_eq!(found, identities);
+
+        // or once an assignment waited long enough
+        let mut store = BufferedStore::new(RemoteStore::new(MockBridge::default()))
+            .with_max_delay(Duration::ZERO);
+        let storage = population.storage_object(&identifiers[0]);
+        assert_eq!(store.digest_offsets("br", &[storage])?, vec![0]);
+        assert_eq!(store.pending_blobs(), 0);
+        assert_eq!(store.store().bridge.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+//! A [`StorageState`] which remembers the offsets resolved by another.
+
+use std::time::{Duration, Instant};
+
+use async_generic::async_generic;
+
+use crate::Error;
+use crate::lru::{Lru, MemoryBudget};
+
+use super::storage::{ConnectionBridge, RemoteStore, Storage, StorageState};
+
+/// Remembers up to `capacity` of the offsets resolved by another store, so that the handful of
+/// identities which an interactive application looks up repeatedly only reach the store once,
+/// rather than over the network every time. The least recently used offsets are evicted first.
+///
+/// An assigned offset only stops being valid when its digest is deleted, or discarded from
+/// a corrupt blob, see [`RemoteStore::with_quarantine`]. Offsets are forgotten when they are
+/// deleted through [`CachedStore::delete`], and when [`CachedStore::store_mut`] lends out the
+/// store, which could change them. Changes by other writers are not seen until an offset expires,
+/// so set a time to live with [`CachedStore::with_ttl`] if digests are deleted elsewhere.
+/// Offsets are remembered by the hash of their identifier, so identifiers are never retained.
+#[derive(Debug)]
+pub struct CachedStore<S> {
+    store: S,
+    // (domain, storage object) -> (time resolved, offset)
+    cache: Lru<(String, Storage), (Instant, usize)>,
+    ttl: Option<Duration>,
+}
+
+impl<S: StorageState> CachedStore<S> {
+    /// Remember up to `capacity` offsets resolved by `store`, for as long as they are used.
+    pub fn new(store: S, capacity: usize) -> Self {
+        Self {
+            store,
+            cache: Lru::new(capacity),
+            ttl: None,
+        }
+    }
+
+    /// Resolve offsets with the store again once they were remembered for `ttl`.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Limit the memory held by remembered offsets, together with any other caches which share
+    /// `budget`. Forgets any offsets which are already remembered.
+    pub fn with_memory_budget(mut self, budget: &MemoryBudget) -> Self {
+        self.cache.set_budget(budget, |(domain, storage), _| {
+            domain.len() + storage.key.as_str().len() + storage.digest.as_str().len()
+        });
+        self
+    }
+
+    /// The store which resolves offsets.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// The store which resolves offsets, forgetting every remembered offset, since the store
+    /// could change them.
+    pub fn store_mut(&mut self) -> &mut S {
+        self.cache.clear();
+        &mut self.store
+    }
+
+    /// The store which resolves offsets.
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+
+    /// The number of remembered offsets, including expired ones.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// True if no offsets are remembered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forget the offset of `storage` in `domain`.
+    pub fn invalidate(&mut self, domain: &str, storage: &Storage) {
+        self.cache.remove(&(domain.to_string(), storage.clone()));
+    }
+
+    /// Forget every remembered offset.
+    pub fn clear(&mut self) {
+        self.cache.clear();
+    }
+
+    // the remembered offset of `key`, unless it expired
+    fn remembered(&mut self, key: &(String, Storage)) -> Option<usize> {
+        let remembered = match self.cache.get(key) {
+            Some((resolved, _)) if self.ttl.is_some_and(|ttl| resolved.elapsed() >= ttl) => {
+                self.cache.remove(key);
+                None
+            }
+            Some((_, offset)) => Some(offset),
+            None => None,
+        };
+        counter!(
+            "perfume_cache_requests_total",
+            1,
+            "cache" => "store",
+            "outcome" => if remembered.is_some() { "hit" } else { "miss" }
+        );
+        remembered
+    }
+}
+
+impl<B: ConnectionBridge + Send> CachedStore<RemoteStore<B>> {
+    /// Delete the digest of `storage`, and forget its offset, see [`RemoteStore::delete`].
+    #[async_generic]
+    #[allow(unused_assignments)]
+    pub fn delete(&mut self, domain: &str, storage: &Storage) -> Result<bool, Error> {
+        self.invalidate(domain, storage);
+        let mut deleted = false;
+        if _async {
+            deleted = self.store.delete_async(domain, storage).await?;
+        } else {
+            deleted = self.store.delete(domain, storage)?;
+        }
+        Ok(deleted)
+    }
+}
+
+impl<S> StorageState for CachedStore<S>
+where
+    S: StorageState + Send,
+{
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn digest_offset(&mut self, domain: &str, storage: &Storage) -> Result<usize, Error> {
+        let key = (domain.to_string(), storage.clone());
+        if let Some(offset) = self.remembered(&key) {
+            return Ok(offset);
+        }
+        let mut offset = 0;
+        if _async {
+            offset = self.store.digest_offset_async(domain, storage).await?;
+        } else {
+            offset = self.store.digest_offset(domain, storage)?;
+        }
+        self.cache.insert(key, (Instant::now(), offset));
+        Ok(offset)
+    }
+
+    /// Checks the store, see [`StorageState::health_check`].
+    #[async_generic]
+    fn health_check(&mut self) -> Result<(), Error> {
+        if _async {
+            self.store.health_check_async().await
+        } else {
+            self.store.health_check()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_generic::async_generic;
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::identity::storage::BridgeResult;
+    use crate::identity::{Population, tests::*};
+
+    #[derive(Default)]
+    struct CountingBridge {
+        inner: MockBridge,
+        gets: AtomicUsize,
+    }
+
+    impl ConnectionBridge for CountingBridge {
+        #[async_generic]
+        fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+            self.gets.fetch_add(1, Ordering::Relaxed);
+            self.inner.get(key)
+        }
+
+        #[async_generic]
+        fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+            self.inner.put(key, body)
+        }
+    }
+
+    #[test]
+    fn test_cached_store() -> Result<(), Error> {
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let mut store = CachedStore::new(RemoteStore::new(CountingBridge::default()), 1);
+        let gets = |store: &CachedStore<RemoteStore<CountingBridge>>| {
+            store.store().bridge.gets.load(Ordering::Relaxed)
+        };
+
+        let identity = population.identity("a@b.br", &mut store)?;
+        let fetched = gets(&store);
+        assert_eq!(population.identity("a@b.br", &mut store)?, identity);
+        assert_eq!(gets(&store), fetched);
+
+        // the least recently used offset is evicted
+        population.identity("c@d.br", &mut store)?;
+        assert_eq!(store.len(), 1);
+        let fetched = gets(&store);
+        assert_eq!(population.identity("a@b.br", &mut store)?, identity);
+        assert!(gets(&store) > fetched);
+
+        // the offset of a deleted digest is forgotten
+        assert!(store.delete("br", &identity.storage)?);
+        assert!(population.identity("a@b.br", &mut store).is_err());
+
+        // and offsets expire
+        let mut store = CachedStore::new(store.into_inner(), 10).with_ttl(Duration::ZERO);
+        population.identity("c@d.br", &mut store)?;
+        let fetched = gets(&store);
+        population.identity("c@d.br", &mut store)?;
+        assert!(gets(&store) > fetched);
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+//! CBOR interchange of identities and storage records. Requires the `cbor` feature.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::hex_string::HexString;
+use crate::{Error, STORAGE_KEY_LENGTH};
+
+use super::Identity;
+use super::fsck::check_blob;
+use super::snapshot::Snapshot;
+use super::storage::{RecordFlag, parse_record, split_version, text_record};
+
+/// An [`Identity`] as written by [`write_identities`], which owns its fields.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdentityRecord {
+    /// See [`Identity::domain`].
+    pub domain: String,
+    /// See [`Identity::friendly_name`].
+    pub name: String,
+    /// The storage key of the identity, as hex.
+    pub key: String,
+    /// The storage digest of the identity, as hex.
+    pub digest: String,
+    /// See [`Identity::offset`].
+    pub offset: usize,
+}
+
+impl From<&Identity<'_>> for IdentityRecord {
+    fn from(identity: &Identity<'_>) -> Self {
+        Self {
+            domain: identity.domain.to_string(),
+            name: identity.friendly_name.clone(),
+            key: identity.storage.key.as_str().to_string(),
+            digest: identity.storage.digest.as_str().to_string(),
+            offset: identity.offset,
+        }
+    }
+}
+
+/// Write each of `identities` to `writer` as a CBOR map, one after another (RFC 8742).
+pub fn write_identities<'a, 'dom: 'a>(
+    identities: impl IntoIterator<Item = &'a Identity<'dom>>,
+    mut writer: impl Write,
+) -> Result<(), Error> {
+    for identity in identities {
+        ciborium::into_writer(&IdentityRecord::from(identity), &mut writer)
+            .map_err(|e| invalid_data(e.to_string()))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read every identity written by [`write_identities`] from `reader`.
+pub fn read_identities(reader: impl Read) -> Result<Vec<IdentityRecord>, Error> {
+    let mut reader = io::BufReader::new(reader);
+    let mut records = vec![];
+    while !io::BufRead::fill_buf(&mut reader)?.is_empty() {
+        let record = ciborium::from_reader(&mut reader).map_err(|e| invalid_data(e.to_string()))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+// a map of storage key to the records of its blob
+type CborSnapshot = BTreeMap<String, Vec<CborRecord>>;
+
+// a [digest, offset] pair, or a [digest, offset, flag] triple for tombstones
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum CborRecord {
+    Live(String, usize),
+    Flagged(String, usize, String),
+}
+
+impl Snapshot {
+    /// Encode the records of every blob as a CBOR map of storage key to `[digest, offset]` pairs.
+    /// Tombstones are `[digest, offset, flag]`, naming their [`RecordFlag`].
+    /// Blobs are expected in the text format, see [`super::BlobFormat::decode`].
+    pub fn to_cbor(&self) -> Result<Vec<u8>, Error> {
+        let mut snapshot = CborSnapshot::new();
+        for (key, bytes) in &self.blobs {
+            // versions are not interchanged, see super::RemoteStore::with_blob_versions
+            let (_version, version_length) = split_version(bytes)?;
+            let text = std::str::from_utf8(&bytes[version_length..])
+                .map_err(|e| invalid_data(e.to_string()))?;
+            let records = text
+                .lines()
+                .map(|line| {
+                    let (digest, flag, offset) = parse_record(line)
+                        .ok_or_else(|| invalid_data(format!("malformed record in blob {key}")))?;
+                    let digest = digest.to_string();
+                    Ok(match flag {
+                        RecordFlag::Live => CborRecord::Live(digest, offset),
+                        flag => CborRecord::Flagged(digest, offset, flag.as_str().to_string()),
+                    })
+                })
+                .collect::<io::Result<_>>()?;
+            snapshot.insert(key.as_str().to_string(), records);
+        }
+        let mut cbor = vec![];
+        ciborium::into_writer(&snapshot, &mut cbor).map_err(|e| invalid_data(e.to_string()))?;
+        Ok(cbor)
+    }
+
+    /// Decode a snapshot written by [`Snapshot::to_cbor`], as blobs in the text format.
+    /// Blobs with any [`super::BlobIssue`] are rejected, so that they are never imported.
+    pub fn from_cbor(cbor: &[u8]) -> Result<Self, Error> {
+        let snapshot: CborSnapshot =
+            ciborium::from_reader(cbor).map_err(|e| invalid_data(e.to_string()))?;
+        let mut blobs = Vec::with_capacity(snapshot.len());
+        for (key, records) in snapshot {
+            if key.len() != STORAGE_KEY_LENGTH
+                || !key
+                    .bytes()
+                    .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+            {
+                return Err(invalid_data(format!("invalid storage key {key}")).into());
+            }
+            let mut records = records
+                .into_iter()
+                .map(|record| match record {
+                    CborRecord::Live(digest, offset) => Ok((digest, RecordFlag::Live, offset)),
+                    CborRecord::Flagged(digest, offset, flag) => {
+                        Ok((digest, flag.parse()?, offset))
+                    }
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            records.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+            let text = records
+                .iter()
+                .map(|(digest, flag, offset)| text_record(digest, *flag, *offset))
+                .collect::<String>();
+            if let Some(issue) = check_blob(text.as_bytes()).issues.first() {
+                return Err(invalid_data(format!("blob {key}: {issue}")).into());
+            }
+            blobs.push((HexString::from(key.as_bytes()), Bytes::from(text)));
+        }
+        Ok(Self { blobs })
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::{Population, RemoteStore, tests::*};
+
+    #[test]
+    fn test_cbor() -> Result<(), Error> {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let mut store = RemoteStore::new(MockBridge::default());
+        let identities = brazilian.identities(["a@b.br", "c@d.br", "e@f.br"], &mut store)?;
+
+        let mut cbor = vec![];
+        write_identities(&identities, &mut cbor)?;
+        let records = read_identities(cbor.as_slice())?;
+        assert_eq!(records.len(), identities.len());
+        for (record, identity) in records.iter().zip(&identities) {
+            assert_eq!(record, &IdentityRecord::from(identity));
+        }
+
+        let snapshot = store.export()?;
+        let cbor = snapshot.to_cbor()?;
+        assert_eq!(Snapshot::from_cbor(&cbor)?.blobs, snapshot.blobs);
+
+        let mut duplicated = CborSnapshot::new();
+        let records = vec![
+            CborRecord::Live("0".repeat(61), 0),
+            CborRecord::Live("1".repeat(61), 0),
+        ];
+        duplicated.insert("abc".into(), records);
+        let mut cbor = vec![];
+        ciborium::into_writer(&duplicated, &mut cbor).unwrap();
+        assert!(Snapshot::from_cbor(&cbor).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,378 @@
+//! A [`ConnectionBridge`] which stops calling another after repeated failures.
+
+use std::io;
+use std::ops::{ControlFlow, Range};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use super::storage::{BridgeResult, ConnectionBridge, Validated};
+
+/// The state of a [`CircuitBreakerBridge`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Operations are passed to the bridge.
+    Closed,
+    /// Operations fail without calling the bridge, until the cooldown ends.
+    Open,
+    /// The cooldown ended, and the next operation is passed to the bridge to find out whether
+    /// it recovered.
+    HalfOpen,
+}
+
+/// Fails fast while another bridge is down, so that naming requests are not held up by
+/// a backend which does not answer.
+///
+/// After 5 consecutive failures by default, the circuit opens, and every operation fails
+/// with [`io::ErrorKind::ResourceBusy`] for a cooldown of 30 seconds by default, without
+/// calling the bridge. The first operation after the cooldown is passed to the bridge, while
+/// the others still fail: the circuit closes if it succeeds, and opens for another cooldown
+/// if it fails. Conflicts, invalid requests and unsupported operations are answers of the
+/// backend, so they are not counted as failures, see [`CircuitBreakerBridge::with_failure_if`].
+///
+/// Operations fail fast with a retryable error, so a [`super::RetryBridge`] wrapping this
+/// bridge waits for the cooldown between its attempts, while one wrapped by it retries each
+/// operation before it counts as a failure.
+#[derive(Debug)]
+pub struct CircuitBreakerBridge<B> {
+    bridge: B,
+    threshold: u32,
+    cooldown: Duration,
+    failure_if: fn(&io::Error) -> bool,
+    breaker: Mutex<Breaker>,
+}
+
+#[derive(Debug, Default)]
+struct Breaker {
+    // consecutive failures
+    failures: u32,
+    // when the circuit last opened, unless it is closed
+    opened: Option<Instant>,
+    // when the operation which probes a half-open circuit started. A probe which does not
+    // finish within the cooldown, such as a dropped future, is replaced by another one.
+    probing: Option<Instant>,
+}
+
+// all errors but conflicts, invalid requests and unsupported operations, which stores probe
+// for, such as `append` and `get_range`
+fn is_failure(e: &io::Error) -> bool {
+    !matches!(
+        e.kind(),
+        io::ErrorKind::AlreadyExists | io::ErrorKind::InvalidInput | io::ErrorKind::Unsupported
+    )
+}
+
+impl<B: ConnectionBridge> CircuitBreakerBridge<B> {
+    /// Open the circuit after 5 consecutive failures of `bridge`, for 30 seconds.
+    pub fn new(bridge: B) -> Self {
+        Self {
+            bridge,
+            threshold: 5,
+            cooldown: Duration::from_secs(30),
+            failure_if: is_failure,
+            breaker: Mutex::default(),
+        }
+    }
+
+    /// Open the circuit after `threshold` consecutive failures, at least one.
+    pub fn with_threshold(mut self, threshold: u32) -> Self {
+        self.threshold = threshold.max(1);
+        self
+    }
+
+    /// Fail fast for `cooldown` after the circuit opens.
+    pub fn with_cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// Count the errors for which `failure_if` is true, instead of all errors but
+    /// [`io::ErrorKind::AlreadyExists`], [`io::ErrorKind::InvalidInput`] and
+    /// [`io::ErrorKind::Unsupported`].
+    pub fn with_failure_if(mut self, failure_if: fn(&io::Error) -> bool) -> Self {
+        self.failure_if = failure_if;
+        self
+    }
+
+    /// The bridge which is guarded.
+    pub fn inner(&self) -> &B {
+        &self.bridge
+    }
+
+    /// The current state of the circuit, for monitoring.
+    pub fn state(&self) -> CircuitState {
+        match self.breaker.lock().unwrap().opened {
+            None => CircuitState::Closed,
+            Some(opened) if opened.elapsed() < self.cooldown => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// The number of consecutive failures of the bridge.
+    pub fn consecutive_failures(&self) -> u32 {
+        self.breaker.lock().unwrap().failures
+    }
+
+    // fails unless the bridge should be called
+    fn admit(&self) -> BridgeResult<()> {
+        let mut breaker = self.breaker.lock().unwrap();
+        let Some(opened) = breaker.opened else {
+            return Ok(());
+        };
+        let probing = breaker
+            .probing
+            .is_some_and(|started| started.elapsed() < self.cooldown);
+        if opened.elapsed() < self.cooldown || probing {
+            let message = format!(
+                "circuit is open after {} consecutive bridge failures",
+                breaker.failures
+            );
+            return Err(io::Error::new(io::ErrorKind::ResourceBusy, message));
+        }
+        breaker.probing = Some(Instant::now());
+        Ok(())
+    }
+
+    fn record<T>(&self, result: &BridgeResult<T>) {
+        let mut breaker = self.breaker.lock().unwrap();
+        if !result.as_ref().is_err_and(self.failure_if) {
+            if breaker.opened.is_some() {
+                event!(INFO, "bridge circuit closed");
+            }
+            *breaker = Breaker::default();
+            return;
+        }
+        breaker.failures = breaker.failures.saturating_add(1);
+        if breaker.opened.is_some() || breaker.failures >= self.threshold {
+            if breaker.opened.is_none() {
+                counter!("perfume_circuit_trips_total", 1);
+                event!(WARN, failures = breaker.failures, error = ?result.as_ref().err(), "bridge circuit opened");
+            }
+            breaker.opened = Some(Instant::now());
+            breaker.probing = None;
+        }
+    }
+
+    fn call<T>(&self, operation: impl FnOnce() -> BridgeResult<T>) -> BridgeResult<T> {
+        self.admit()?;
+        let result = operation();
+        self.record(&result);
+        result
+    }
+
+    async fn call_async<T>(
+        &self,
+        operation: impl Future<Output = BridgeResult<T>>,
+    ) -> BridgeResult<T> {
+        self.admit()?;
+        let result = operation.await;
+        self.record(&result);
+        result
+    }
+}
+
+impl<B: ConnectionBridge + Sync> ConnectionBridge for CircuitBreakerBridge<B> {
+    fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        self.call(|| self.bridge.get(key))
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.call(|| self.bridge.put(key, body))
+    }
+
+    async fn get_async(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        self.call_async(self.bridge.get_async(key)).await
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.call_async(self.bridge.put_async(key, body)).await
+    }
+
+    fn put_if_match(&self, key: &str, body: Bytes, validator: Option<&str>) -> BridgeResult<()> {
+        self.call(|| self.bridge.put_if_match(key, body, validator))
+    }
+
+    async fn put_if_match_async(
+        &self,
+        key: &str,
+        body: Bytes,
+        validator: Option<&str>,
+    ) -> BridgeResult<()> {
+        self.call_async(self.bridge.put_if_match_async(key, body, validator))
+            .await
+    }
+
+    fn append(&self, key: &str, bytes: Bytes) -> BridgeResult<()> {
+        self.call(|| self.bridge.append(key, bytes))
+    }
+
+    async fn append_async(&self, key: &str, bytes: Bytes) -> BridgeResult<()> {
+        self.call_async(self.bridge.append_async(key, bytes)).await
+    }
+
+    fn exists(&self, key: &str) -> BridgeResult<bool> {
+        self.call(|| self.bridge.exists(key))
+    }
+
+    async fn exists_async(&self, key: &str) -> BridgeResult<bool> {
+        self.call_async(self.bridge.exists_async(key)).await
+    }
+
+    fn ping(&self) -> BridgeResult<()> {
+        self.call(|| self.bridge.ping())
+    }
+
+    async fn ping_async(&self) -> BridgeResult<()> {
+        self.call_async(self.bridge.ping_async()).await
+    }
+
+    fn get_validated(&self, key: &str, validator: Option<&str>) -> BridgeResult<Validated> {
+        self.call(|| self.bridge.get_validated(key, validator))
+    }
+
+    async fn get_validated_async(
+        &self,
+        key: &str,
+        validator: Option<&str>,
+    ) -> BridgeResult<Validated> {
+        self.call_async(self.bridge.get_validated_async(key, validator))
+            .await
+    }
+
+    fn get_chunks(
+        &self,
+        key: &str,
+        visit: &mut dyn FnMut(&[u8]) -> ControlFlow<()>,
+    ) -> BridgeResult<bool> {
+        self.call(|| self.bridge.get_chunks(key, visit))
+    }
+
+    async fn get_chunks_async(
+        &self,
+        key: &str,
+        visit: &mut (dyn FnMut(&[u8]) -> ControlFlow<()> + Send),
+    ) -> BridgeResult<bool> {
+        self.call_async(self.bridge.get_chunks_async(key, visit))
+            .await
+    }
+
+    fn get_range(&self, key: &str, range: Range<u64>) -> BridgeResult<Option<(Bytes, u64)>> {
+        self.call(|| self.bridge.get_range(key, range))
+    }
+
+    async fn get_range_async(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> BridgeResult<Option<(Bytes, u64)>> {
+        self.call_async(self.bridge.get_range_async(key, range))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+    use async_generic::async_generic;
+
+    use super::*;
+    use crate::Error;
+    use crate::identity::{Population, RemoteStore, tests::*};
+    use crate::testing::bridge_conformance;
+
+    // fails every operation while it is down
+    #[derive(Default)]
+    struct DownBridge {
+        inner: MockBridge,
+        down: AtomicBool,
+        calls: AtomicU32,
+    }
+
+    impl DownBridge {
+        fn check(&self) -> BridgeResult<()> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            match self.down.load(Ordering::Relaxed) {
+                true => Err(io::ErrorKind::ConnectionRefused.into()),
+                false => Ok(()),
+            }
+        }
+    }
+
+    impl ConnectionBridge for DownBridge {
+        #[async_generic]
+        fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+            self.check()?;
+            self.inner.get(key)
+        }
+
+        #[async_generic]
+        fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+            self.check()?;
+            self.inner.put(key, body)
+        }
+    }
+
+    #[test]
+    fn test_circuit_breaker_bridge() -> Result<(), Error> {
+        bridge_conformance(|| CircuitBreakerBridge::new(MockBridge::default()));
+
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let bridge = CircuitBreakerBridge::new(DownBridge::default())
+            .with_threshold(2)
+            .with_cooldown(Duration::from_millis(50));
+        let mut store = RemoteStore::new(bridge);
+        let identity = population.identity("a@b.br", &mut store)?;
+        let bridge = &store.bridge;
+        let calls = || bridge.inner().calls.load(Ordering::Relaxed);
+
+        // opens after consecutive failures, and fails fast
+        bridge.inner().down.store(true, Ordering::Relaxed);
+        assert!(bridge.get("abc").is_err());
+        assert_eq!(bridge.state(), CircuitState::Closed);
+        assert!(bridge.get("abc").is_err());
+        assert_eq!(bridge.state(), CircuitState::Open);
+        let before = calls();
+        let error = bridge.get("abc").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ResourceBusy);
+        assert_eq!(calls(), before);
+
+        // a failed probe opens the circuit again
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(bridge.state(), CircuitState::HalfOpen);
+        assert!(bridge.get("abc").is_err());
+        assert_eq!(calls(), before + 1);
+        assert_eq!(bridge.state(), CircuitState::Open);
+
+        // and a successful one closes it
+        bridge.inner().down.store(false, Ordering::Relaxed);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(population.identity("a@b.br", &mut store)?, identity);
+        assert_eq!(store.bridge.state(), CircuitState::Closed);
+        assert_eq!(store.bridge.consecutive_failures(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsupported_operations() -> Result<(), Error> {
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        // the store probes for ranged reads, which `MockBridge` does not support
+        let bridge = CircuitBreakerBridge::new(MockBridge::default()).with_threshold(1);
+        let mut store = RemoteStore::new(bridge).with_ranged_reads();
+        let identity = population.identity("a@b.br", &mut store)?;
+        assert_eq!(population.identity("a@b.br", &mut store)?, identity);
+        population.identity("c@d.br", &mut store)?;
+        assert_eq!(store.bridge.state(), CircuitState::Closed);
+        assert_eq!(store.bridge.consecutive_failures(), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,434 @@
+//! A [`StorageState`] which resolves concurrent digests of the same blob together.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Poll, Waker};
+
+use crate::Error;
+
+use super::storage::{ConnectionBridge, RemoteStore, Storage, StorageState};
+
+/// Shares a [`RemoteStore`] between threads or tasks, and coalesces the digests which they
+/// resolve at the same time, so that the digests of each storage key are found or inserted
+/// with a single fetch and write of its blob, rather than one for each digest. Bursts of
+/// lookups of identifiers sharing a storage key then cost about as much as one lookup.
+///
+/// One caller at a time resolves every digest which is waiting, while the others wait for
+/// their offsets, and the next waiting caller takes over once it is done. If resolving the
+/// digests of a key fails, they are resolved again one by one, so that each caller receives
+/// its own error. `StorageState` is implemented for `&CoalescingStore`, so a single store can
+/// be shared.
+pub struct CoalescingStore<B: ConnectionBridge> {
+    // taken by the caller which is resolving digests
+    store: Mutex<Option<RemoteStore<B>>>,
+    queue: Mutex<Queue>,
+}
+
+#[derive(Default)]
+struct Queue {
+    // digests waiting to be resolved
+    waiting: Vec<Waiting>,
+    // a caller is resolving digests
+    resolving: bool,
+}
+
+// a digest, and the slot where its caller receives its offset
+struct Waiting {
+    domain: String,
+    storage: Storage,
+    slot: Arc<Slot>,
+}
+
+#[derive(Default)]
+struct Slot {
+    state: Mutex<SlotState>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+enum SlotState {
+    #[default]
+    Waiting,
+    // waiting in an async context
+    Polled(Waker),
+    // the caller should resolve the waiting digests
+    Resolve,
+    Done(Result<usize, Error>),
+}
+
+impl Slot {
+    fn set(&self, state: SlotState) {
+        let previous = std::mem::replace(&mut *self.state.lock().unwrap(), state);
+        if let SlotState::Polled(waker) = previous {
+            waker.wake();
+        }
+        self.ready.notify_one();
+    }
+
+    // takes the next state which is not waiting
+    fn wait(&self) -> SlotState {
+        let mut state = self.state.lock().unwrap();
+        while matches!(*state, SlotState::Waiting) {
+            state = self.ready.wait(state).unwrap();
+        }
+        std::mem::take(&mut *state)
+    }
+
+    fn wait_async(&self) -> impl Future<Output = SlotState> + Send + '_ {
+        std::future::poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            match std::mem::take(&mut *state) {
+                SlotState::Waiting | SlotState::Polled(_) => {
+                    *state = SlotState::Polled(cx.waker().clone());
+                    Poll::Pending
+                }
+                state => Poll::Ready(state),
+            }
+        })
+    }
+}
+
+// a queued caller, which passes on resolving the queue if it is dropped before doing so,
+// such as when an async call is cancelled
+struct Caller<'s, B: ConnectionBridge> {
+    owner: &'s CoalescingStore<B>,
+    slot: Arc<Slot>,
+}
+
+impl<B: ConnectionBridge> Drop for Caller<'_, B> {
+    fn drop(&mut self) {
+        let resolve = matches!(*self.slot.state.lock().unwrap(), SlotState::Resolve);
+        if resolve {
+            let mut queue = self.owner.queue.lock().unwrap();
+            queue
+                .waiting
+                .retain(|waiting| !Arc::ptr_eq(&waiting.slot, &self.slot));
+            drop(queue);
+            self.owner.hand_over(None);
+        }
+    }
+}
+
+// the digests being resolved by a caller, which are queued again if it stops before they are
+type Group = (String, Vec<(Storage, Arc<Slot>)>);
+
+struct Resolving<'s, B: ConnectionBridge> {
+    owner: &'s CoalescingStore<B>,
+    store: Option<RemoteStore<B>>,
+    // the digests of each domain and storage key
+    groups: VecDeque<Group>,
+}
+
+impl<B: ConnectionBridge> Drop for Resolving<'_, B> {
+    fn drop(&mut self) {
+        let unresolved = self.groups.drain(..).flat_map(|(domain, waiting)| {
+            waiting.into_iter().map(move |(storage, slot)| Waiting {
+                domain: domain.clone(),
+                storage,
+                slot,
+            })
+        });
+        let mut queue = self.owner.queue.lock().unwrap();
+        let queued = std::mem::take(&mut queue.waiting);
+        queue.waiting = unresolved.chain(queued).collect();
+        drop(queue);
+        self.owner.hand_over(self.store.take());
+    }
+}
+
+impl<B: ConnectionBridge> CoalescingStore<B> {
+    /// Share `store`.
+    pub fn new(store: RemoteStore<B>) -> Self {
+        Self {
+            store: Mutex::new(Some(store)),
+            queue: Mutex::default(),
+        }
+    }
+
+    /// The shared store.
+    pub fn into_inner(self) -> RemoteStore<B> {
+        let store = self.store.into_inner().unwrap();
+        store.expect("store should be returned by the caller which resolved digests")
+    }
+
+    // queues `storage`, and lets its caller resolve the queue if nobody else is
+    fn enqueue(&self, domain: &str, storage: &Storage) -> Caller<'_, B> {
+        let slot = Arc::new(Slot::default());
+        let mut queue = self.queue.lock().unwrap();
+        queue.waiting.push(Waiting {
+            domain: domain.to_string(),
+            storage: storage.clone(),
+            slot: slot.clone(),
+        });
+        if !std::mem::replace(&mut queue.resolving, true) {
+            slot.set(SlotState::Resolve);
+        }
+        Caller { owner: self, slot }
+    }
+
+    // takes the store and the waiting digests, grouped by domain and storage key
+    fn start_resolving(&self) -> Resolving<'_, B> {
+        let store = self.store.lock().unwrap().take();
+        let waiting = std::mem::take(&mut self.queue.lock().unwrap().waiting);
+        let mut groups: VecDeque<Group> = VecDeque::new();
+        let mut group_of: HashMap<(String, String), usize> = HashMap::new();
+        for Waiting {
+            domain,
+            storage,
+            slot,
+        } in waiting
+        {
+            let index = *group_of
+                .entry((domain.clone(), storage.key.to_string()))
+                .or_insert_with(|| {
+                    groups.push_back((domain, vec![]));
+                    groups.len() - 1
+                });
+            groups[index].1.push((storage, slot));
+        }
+        Resolving {
+            owner: self,
+            store: Some(
+                store.expect("store should be returned by the caller which resolved digests"),
+            ),
+            groups,
+        }
+    }
+
+    // returns the store, and lets the next waiting caller resolve the digests queued since
+    fn hand_over(&self, store: Option<RemoteStore<B>>) {
+        if let Some(store) = store {
+            *self.store.lock().unwrap() = Some(store);
+        }
+        let mut queue = self.queue.lock().unwrap();
+        // callers which were dropped are skipped
+        queue
+            .waiting
+            .retain(|waiting| Arc::strong_count(&waiting.slot) > 1);
+        match queue.waiting.first() {
+            Some(waiting) => waiting.slot.set(SlotState::Resolve),
+            None => queue.resolving = false,
+        }
+    }
+}
+
+impl<B> CoalescingStore<B>
+where
+    B: ConnectionBridge + Send,
+{
+    fn resolve(&self) {
+        let mut resolving = self.start_resolving();
+        while let Some((domain, waiting)) = resolving.groups.front() {
+            let store = resolving.store.as_mut().unwrap();
+            let storages = waiting
+                .iter()
+                .map(|(storage, _)| storage.clone())
+                .collect::<Vec<_>>();
+            match store.assign_offsets(domain, &storages) {
+                Ok(offsets) => {
+                    for ((_, slot), offset) in waiting.iter().zip(offsets) {
+                        slot.set(SlotState::Done(Ok(offset)));
+                    }
+                }
+                Err(_) => {
+                    for (storage, slot) in waiting {
+                        slot.set(SlotState::Done(store.digest_offset(domain, storage)));
+                    }
+                }
+            }
+            resolving.groups.pop_front();
+        }
+    }
+
+    async fn resolve_async(&self) {
+        let mut resolving = self.start_resolving();
+        while let Some((domain, waiting)) = resolving.groups.front() {
+            let store = resolving.store.as_mut().unwrap();
+            let storages = waiting
+                .iter()
+                .map(|(storage, _)| storage.clone())
+                .collect::<Vec<_>>();
+            match store.assign_offsets_async(domain, &storages).await {
+                Ok(offsets) => {
+                    for ((_, slot), offset) in waiting.iter().zip(offsets) {
+                        slot.set(SlotState::Done(Ok(offset)));
+                    }
+                }
+                Err(_) => {
+                    for (storage, slot) in waiting {
+                        let result = store.digest_offset_async(domain, storage).await;
+                        slot.set(SlotState::Done(result));
+                    }
+                }
+            }
+            resolving.groups.pop_front();
+        }
+    }
+
+    fn offset(&self, domain: &str, storage: &Storage) -> Result<usize, Error> {
+        let caller = self.enqueue(domain, storage);
+        loop {
+            match caller.slot.wait() {
+                SlotState::Done(result) => return result,
+                _ => self.resolve(),
+            }
+        }
+    }
+
+    async fn offset_async(&self, domain: &str, storage: &Storage) -> Result<usize, Error> {
+        let caller = self.enqueue(domain, storage);
+        loop {
+            match caller.slot.wait_async().await {
+                SlotState::Done(result) => return result,
+                _ => self.resolve_async().await,
+            }
+        }
+    }
+}
+
+impl<B: ConnectionBridge> std::fmt::Debug for CoalescingStore<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let queue = self.queue.lock().unwrap();
+        f.debug_struct("CoalescingStore")
+            .field("waiting", &queue.waiting.len())
+            .field("resolving", &queue.resolving)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B> StorageState for &CoalescingStore<B>
+where
+    B: ConnectionBridge + Send,
+{
+    fn digest_offset(&mut self, domain: &str, storage: &Storage) -> Result<usize, Error> {
+        self.offset(domain, storage)
+    }
+
+    fn digest_offset_async(
+        &mut self,
+        domain: &str,
+        storage: &Storage,
+    ) -> impl Future<Output = Result<usize, Error>> + Send {
+        self.offset_async(domain, storage)
+    }
+}
+
+impl<B> StorageState for CoalescingStore<B>
+where
+    B: ConnectionBridge + Send,
+{
+    fn digest_offset(&mut self, domain: &str, storage: &Storage) -> Result<usize, Error> {
+        self.offset(domain, storage)
+    }
+
+    fn digest_offset_async(
+        &mut self,
+        domain: &str,
+        storage: &Storage,
+    ) -> impl Future<Output = Result<usize, Error>> + Send {
+        self.offset_async(domain, storage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_generic::async_generic;
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::identity::{Population, tests::*};
+
+    // counts writes, which are slow enough for digests to be queued meanwhile
+    #[derive(Default)]
+    struct SlowBridge {
+        inner: MockBridge,
+        puts: AtomicUsize,
+    }
+
+    impl ConnectionBridge for SlowBridge {
+        #[async_generic]
+        fn get(&self, key: &str) -> std::io::Result<Option<Bytes>> {
+            self.inner.get(key)
+        }
+
+        #[async_generic]
+        fn put(&self, key: &str, body: Bytes) -> std::io::Result<()> {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            self.puts.fetch_add(1, Ordering::Relaxed);
+            self.inner.put(key, body)
+        }
+    }
+
+    #[test]
+    fn test_coalescing_store() -> Result<(), Error> {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let store = CoalescingStore::new(RemoteStore::new(SlowBridge::default()));
+        let storage = brazilian.storage_object("a@b.br");
+
+        // digests of the same key are given distinct offsets, with fewer writes
+        let mut offsets = std::thread::scope(|scope| {
+            let workers = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut store = &store;
+                        (0..10)
+                            .map(|_| {
+                                let mut next = storage.clone();
+                                next.digest = random_hex_string();
+                                store.digest_offset("br", &next).unwrap()
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .flat_map(|w| w.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        offsets.sort();
+        assert_eq!(offsets, (0..80).collect::<Vec<_>>());
+        let first = brazilian.identity("a@b.br", &mut &store)?;
+        assert_eq!(first.offset, 80);
+        assert_eq!(brazilian.identity("a@b.br", &mut &store)?, first);
+
+        let mut remote = store.into_inner();
+        assert!(remote.bridge.puts.load(Ordering::Relaxed) <= 40);
+        assert_eq!(brazilian.identity("a@b.br", &mut remote)?, first);
+
+        // and by tasks
+        let store = Arc::new(CoalescingStore::new(remote));
+        let runtime = tokio::runtime::Runtime::new()?;
+        let mut offsets = runtime.block_on(async {
+            let tasks = (0..8)
+                .map(|_| {
+                    let (store, storage) = (store.clone(), storage.clone());
+                    tokio::spawn(async move {
+                        let mut offsets = vec![];
+                        for _ in 0..10 {
+                            let mut next = storage.clone();
+                            next.digest = random_hex_string();
+                            offsets.push((&*store).digest_offset_async("br", &next).await?);
+                        }
+                        Ok::<_, Error>(offsets)
+                    })
+                })
+                .collect::<Vec<_>>();
+            let mut offsets = vec![];
+            for task in tasks {
+                offsets.extend(task.await.unwrap()?);
+            }
+            Ok::<_, Error>(offsets)
+        })?;
+        offsets.sort();
+        assert_eq!(offsets, (81..161).collect::<Vec<_>>());
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+//! A [`ConnectionBridge`] which compresses the blobs of another.
+
+use std::io::Read;
+
+use bytes::Bytes;
+
+use super::storage::{BridgeResult, ConnectionBridge, Validated};
+
+/// The compression of the blobs written by a [`CompressedBridge`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Zstandard frames, at the default level. Requires the `zstd` feature.
+    #[cfg(feature = "zstd")]
+    Zstd,
+    /// Gzip members, at the default level. Requires the `gzip` feature.
+    #[cfg(feature = "gzip")]
+    Gzip,
+}
+
+impl Compression {
+    /// The bytes which begin every body in this compression.
+    pub const fn magic(self) -> &'static [u8] {
+        match self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd => &[0x28, 0xb5, 0x2f, 0xfd],
+            #[cfg(feature = "gzip")]
+            Self::Gzip => &[0x1f, 0x8b, 0x08],
+        }
+    }
+
+    fn compress(self, body: &[u8]) -> BridgeResult<Bytes> {
+        let compressed = match self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::encode_all(body, 0)?,
+            #[cfg(feature = "gzip")]
+            Self::Gzip => {
+                use std::io::Write;
+
+                let compressed = Vec::with_capacity(body.len() / 2);
+                let mut encoder =
+                    flate2::write::GzEncoder::new(compressed, flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()?
+            }
+        };
+        Ok(compressed.into())
+    }
+
+    fn decompress(self, body: &[u8]) -> BridgeResult<Bytes> {
+        let mut decompressed = Vec::with_capacity(body.len() * 4);
+        match self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::Decoder::new(body)?.read_to_end(&mut decompressed)?,
+            #[cfg(feature = "gzip")]
+            Self::Gzip => flate2::read::GzDecoder::new(body).read_to_end(&mut decompressed)?,
+        };
+        Ok(decompressed.into())
+    }
+}
+
+const COMPRESSIONS: &[Compression] = &[
+    #[cfg(feature = "zstd")]
+    Compression::Zstd,
+    #[cfg(feature = "gzip")]
+    Compression::Gzip,
+];
+
+/// Compresses the blobs written to another bridge, and decompresses them when they are read,
+/// so that a population stored as text blobs, whose hex digests compress to about half their
+/// size, costs less to store and to transfer.
+///
+/// Blobs are recognized by the magic bytes which begin them, so an existing bridge can be
+/// wrapped in place: blobs which were written uncompressed, or in another supported
+/// compression, are read as they are stored until they are next written. A blob which begins
+/// like a compressed one but cannot be decompressed, which a [`super::BlobFormat::Binary`]
+/// blob very rarely could, is also read as it is stored.
+///
+/// Blobs cannot be appended to in place, or read in ranges, so
+/// [`super::RemoteStore::with_ranged_reads`] reads whole blobs through this bridge.
+#[derive(Debug)]
+pub struct CompressedBridge<B> {
+    bridge: B,
+    compression: Compression,
+}
+
+impl<B: ConnectionBridge> CompressedBridge<B> {
+    /// Store the blobs of `bridge` in `compression`.
+    pub fn new(bridge: B, compression: Compression) -> Self {
+        Self {
+            bridge,
+            compression,
+        }
+    }
+
+    /// The bridge which holds compressed blobs.
+    pub fn inner(&self) -> &B {
+        &self.bridge
+    }
+
+    // the key is only logged
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn compressed(&self, key: &str, body: &[u8]) -> BridgeResult<Bytes> {
+        let compressed = self.compression.compress(body)?;
+        event!(
+            TRACE,
+            key,
+            bytes = body.len(),
+            compressed = compressed.len(),
+            "compressed blob"
+        );
+        Ok(compressed)
+    }
+
+    fn decompressed(body: Option<Bytes>) -> Option<Bytes> {
+        let body = body?;
+        let compression = COMPRESSIONS.iter().find(|c| body.starts_with(c.magic()));
+        match compression.map(|c| c.decompress(&body)) {
+            Some(Ok(decompressed)) => Some(decompressed),
+            _ => Some(body),
+        }
+    }
+
+    fn decompressed_validated(validated: Validated) -> Validated {
+        match validated {
+            Validated::Modified { body, validator } => Validated::Modified {
+                body: Self::decompressed(body),
+                validator,
+            },
+            not_modified => not_modified,
+        }
+    }
+}
+
+impl<B: ConnectionBridge + Sync> ConnectionBridge for CompressedBridge<B> {
+    fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        self.bridge.get(key).map(Self::decompressed)
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.bridge.put(key, self.compressed(key, &body)?)
+    }
+
+    async fn get_async(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        self.bridge.get_async(key).await.map(Self::decompressed)
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        let compressed = self.compressed(key, &body)?;
+        self.bridge.put_async(key, compressed).await
+    }
+
+    fn put_if_match(&self, key: &str, body: Bytes, validator: Option<&str>) -> BridgeResult<()> {
+        let compressed = self.compressed(key, &body)?;
+        self.bridge.put_if_match(key, compressed, validator)
+    }
+
+    async fn put_if_match_async(
+        &self,
+        key: &str,
+        body: Bytes,
+        validator: Option<&str>,
+    ) -> BridgeResult<()> {
+        let compressed = self.compressed(key, &body)?;
+        self.bridge
+            .put_if_match_async(key, compressed, validator)
+            .await
+    }
+
+    fn exists(&self, key: &str) -> BridgeResult<bool> {
+        self.bridge.exists(key)
+    }
+
+    async fn exists_async(&self, key: &str) -> BridgeResult<bool> {
+        self.bridge.exists_async(key).await
+    }
+
+    fn ping(&self) -> BridgeResult<()> {
+        self.bridge.ping()
+    }
+
+    async fn ping_async(&self) -> BridgeResult<()> {
+        self.bridge.ping_async().await
+    }
+
+    fn get_validated(&self, key: &str, validator: Option<&str>) -> BridgeResult<Validated> {
+        let validated = self.bridge.get_validated(key, validator)?;
+        Ok(Self::decompressed_validated(validated))
+    }
+
+    async fn get_validated_async(
+        &self,
+        key: &str,
+        validator: Option<&str>,
+    ) -> BridgeResult<Validated> {
+        let validated = self.bridge.get_validated_async(key, validator).await?;
+        Ok(Self::decompressed_validated(validated))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use crate::hex_string::HexString;
+    use crate::identity::tests::*;
+    use crate::identity::{RemoteStore, Storage, StorageState};
+    use crate::testing::bridge_conformance;
+
+    #[test]
+    fn test_compressed_bridge() -> Result<(), Error> {
+        for &compression in COMPRESSIONS {
+            bridge_conformance(|| CompressedBridge::new(MockBridge::default(), compression));
+        }
+        let compression = COMPRESSIONS[0];
+        let storages = (0..100)
+            .map(|_| Storage {
+                key: HexString::from(&b"abc"[..]),
+                digest: random_hex_string(),
+            })
+            .collect::<Vec<_>>();
+
+        // blobs written before compression are read as they are
+        let mut store = RemoteStore::new(MockBridge::default());
+        let offsets = store.digest_offsets("", &storages[..50])?;
+        let mut store = RemoteStore::new(CompressedBridge::new(
+            std::mem::take(&mut store.bridge),
+            compression,
+        ));
+        assert_eq!(store.digest_offsets("", &storages[..50])?, offsets);
+
+        // and compressed when they are next written
+        store.digest_offsets("", &storages[50..])?;
+        let stored = store.bridge.inner().get("abc")?.unwrap();
+        assert!(stored.starts_with(compression.magic()));
+        let decompressed = store.bridge.get("abc")?.unwrap();
+        assert!(stored.len() * 10 < decompressed.len() * 6);
+        assert_eq!(store.digest_offsets("", &storages[..50])?, offsets);
+        Ok(())
+    }
+}
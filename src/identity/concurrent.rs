@@ -0,0 +1,208 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::Bytes;
+use dashmap::DashMap;
+
+use crate::hex_string::HexString;
+use crate::{Error, STORAGE_DIGEST_LENGTH, STORAGE_KEY_LENGTH};
+
+use super::snapshot::Snapshot;
+use super::storage::{OFFSET_WIDTH, Storage, StorageState, storage_keys};
+
+/// Implements [`StorageState`] in process memory, for resolving many identities from many
+/// threads at once, such as when pseudonymizing logs. `StorageState` is implemented for
+/// `&ConcurrentStore`, so a single store can be shared.
+///
+/// Offsets are held in a [`DashMap`] for each domain, and allocated from an [`AtomicUsize`]
+/// for each of its storage keys, so threads only wait for each other while they first give
+/// an offset to digests in the same shard of the map.
+///
+/// Offsets are not persisted, but can be saved with [`ConcurrentStore::snapshot`].
+#[derive(Debug, Default)]
+pub struct ConcurrentStore {
+    domains: DashMap<String, Domain>,
+}
+
+#[derive(Debug)]
+struct Domain {
+    offsets: DashMap<Storage, usize>,
+    // the next offset of each storage key
+    next: Box<[AtomicUsize]>,
+}
+
+impl Default for Domain {
+    fn default() -> Self {
+        Self {
+            offsets: DashMap::new(),
+            next: storage_keys().map(|_| AtomicUsize::new(0)).collect(),
+        }
+    }
+}
+
+impl ConcurrentStore {
+    /// An empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of stored digests, of all domains.
+    pub fn len(&self) -> usize {
+        self.domains.iter().map(|domain| domain.offsets.len()).sum()
+    }
+
+    /// True if no digests are stored.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The stored offsets of `domain` as storage blobs, which can be imported into a
+    /// [`super::RemoteStore`] with [`super::RemoteStore::import`].
+    pub fn snapshot(&self, domain: &str) -> Snapshot {
+        type Digests = HashMap<HexString<STORAGE_DIGEST_LENGTH>, usize>;
+        let mut keys: BTreeMap<usize, (HexString<STORAGE_KEY_LENGTH>, Digests)> = BTreeMap::new();
+        if let Some(domain) = self.domains.get(domain) {
+            for entry in domain.offsets.iter() {
+                let (storage, &offset) = entry.pair();
+                keys.entry(key_index(&storage.key))
+                    .or_insert_with(|| (storage.key.clone(), HashMap::new()))
+                    .1
+                    .insert(storage.digest.clone(), offset);
+            }
+        }
+        let blobs = keys
+            .into_values()
+            .map(|(key, digests)| (key, blob(&digests)))
+            .collect();
+        Snapshot { blobs }
+    }
+
+    fn offset(&self, domain: &str, storage: &Storage) -> usize {
+        let domain = match self.domains.get(domain) {
+            Some(domain) => domain,
+            None => self
+                .domains
+                .entry(domain.to_string())
+                .or_default()
+                .downgrade(),
+        };
+        if let Some(offset) = domain.offsets.get(storage) {
+            return *offset;
+        }
+        // the entry is locked while the offset is allocated, so that each digest takes one
+        let next = &domain.next[key_index(&storage.key)];
+        *domain
+            .offsets
+            .entry(storage.clone())
+            .or_insert_with(|| next.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+// the records of `digests`, in the text format of a storage blob
+pub(super) fn blob(digests: &HashMap<HexString<STORAGE_DIGEST_LENGTH>, usize>) -> Bytes {
+    let mut records = digests
+        .iter()
+        .map(|(digest, offset)| (digest.as_str(), *offset))
+        .collect::<Vec<_>>();
+    records.sort_unstable();
+    let blob = records
+        .iter()
+        .map(|(digest, offset)| format!("{digest} {offset:>OFFSET_WIDTH$}\n"))
+        .collect::<String>();
+    Bytes::from(blob)
+}
+
+fn key_index(key: &HexString<STORAGE_KEY_LENGTH>) -> usize {
+    usize::from_str_radix(key.as_str(), 16).expect("storage key should be hex")
+}
+
+impl StorageState for &ConcurrentStore {
+    fn digest_offset(&mut self, domain: &str, storage: &Storage) -> Result<usize, Error> {
+        Ok(self.offset(domain, storage))
+    }
+
+    fn digest_offset_async(
+        &mut self,
+        domain: &str,
+        storage: &Storage,
+    ) -> impl Future<Output = Result<usize, Error>> + Send {
+        std::future::ready(self.digest_offset(domain, storage))
+    }
+}
+
+impl StorageState for ConcurrentStore {
+    fn digest_offset(&mut self, domain: &str, storage: &Storage) -> Result<usize, Error> {
+        Ok(self.offset(domain, storage))
+    }
+
+    fn digest_offset_async(
+        &mut self,
+        domain: &str,
+        storage: &Storage,
+    ) -> impl Future<Output = Result<usize, Error>> + Send {
+        std::future::ready(self.digest_offset(domain, storage))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::{Population, RemoteStore, tests::*};
+
+    #[test]
+    fn test_concurrent_store() -> Result<(), Error> {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let store = ConcurrentStore::new();
+        let storage = brazilian.storage_object("a@b.br");
+
+        // digests of the same key are given distinct offsets by all threads
+        let mut offsets = std::thread::scope(|scope| {
+            let workers = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut store = &store;
+                        (0..100)
+                            .map(|_| {
+                                let mut next = storage.clone();
+                                next.digest = random_hex_string();
+                                store.digest_offset("br", &next).unwrap()
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .flat_map(|w| w.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        offsets.sort();
+        assert_eq!(offsets, (0..800).collect::<Vec<_>>());
+
+        let first = brazilian.identity("a@b.br", &mut &store)?;
+        assert_eq!(first.offset, 800);
+        assert_eq!(brazilian.identity("a@b.br", &mut &store)?, first);
+
+        // offsets are kept by a snapshot
+        let mut remote = RemoteStore::new(MockBridge::default());
+        remote.import(&store.snapshot("br"))?;
+        assert_eq!(brazilian.identity("a@b.br", &mut remote)?, first);
+
+        // and domains are given offsets independently
+        let mut next = storage.clone();
+        next.digest = random_hex_string();
+        assert_eq!((&store).digest_offset("mx", &first.storage)?, 0);
+        assert_eq!((&store).digest_offset("mx", &next)?, 1);
+        assert_eq!((&store).digest_offset("br", &first.storage)?, 800);
+        assert_eq!(store.snapshot("mx").blobs.len(), 1);
+        assert!(store.snapshot("ar").blobs.is_empty());
+        assert_eq!(store.len(), 803);
+
+        Ok(())
+    }
+}
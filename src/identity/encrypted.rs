@@ -0,0 +1,208 @@
+//! A [`ConnectionBridge`] which encrypts the blobs of another.
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use bytes::Bytes;
+
+use super::storage::{BridgeResult, ConnectionBridge, Validated};
+
+// bytes of the random nonce which begins each encrypted blob
+const NONCE_LENGTH: usize = 12;
+
+/// Encrypts the blobs written to another bridge with AES-256-GCM, and decrypts them when they
+/// are read, so that the digests of identifiers are encrypted at rest, as some compliance
+/// regimes require even of pseudonymous data.
+///
+/// Each blob is stored as a random 96-bit nonce followed by its ciphertext and tag. The key of
+/// the blob is authenticated along with it, so a blob copied to another key cannot be read.
+/// A blob which cannot be decrypted, including one written before encryption, is an error of
+/// kind [`std::io::ErrorKind::InvalidData`]: migrate existing blobs by reading them through the
+/// unwrapped bridge and writing them through this one, such as with
+/// [`super::RemoteStore::export`] and [`super::RemoteStore::import`].
+///
+/// Blobs cannot be appended to in place, or read in ranges, so
+/// [`super::RemoteStore::with_ranged_reads`] reads whole blobs through this bridge.
+pub struct EncryptedBridge<B> {
+    bridge: B,
+    cipher: Aes256Gcm,
+}
+
+impl<B> std::fmt::Debug for EncryptedBridge<B>
+where
+    B: std::fmt::Debug,
+{
+    /// Never shows the key.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptedBridge")
+            .field("bridge", &self.bridge)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B: ConnectionBridge> EncryptedBridge<B> {
+    /// Encrypt the blobs of `bridge` with the 256-bit `key`.
+    pub fn new(bridge: B, key: &[u8; 32]) -> Self {
+        Self {
+            bridge,
+            cipher: Aes256Gcm::new(key.into()),
+        }
+    }
+
+    /// The bridge which holds encrypted blobs.
+    pub fn inner(&self) -> &B {
+        &self.bridge
+    }
+
+    fn encrypted(&self, key: &str, body: &[u8]) -> BridgeResult<Bytes> {
+        let nonce: [u8; NONCE_LENGTH] = rand::random();
+        let payload = Payload {
+            msg: body,
+            aad: key.as_bytes(),
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| std::io::Error::other(format!("failed to encrypt blob {key}")))?;
+        Ok([&nonce[..], &ciphertext].concat().into())
+    }
+
+    fn decrypted(&self, key: &str, body: Option<Bytes>) -> BridgeResult<Option<Bytes>> {
+        let Some(body) = body else {
+            return Ok(None);
+        };
+        let plaintext = body
+            .split_at_checked(NONCE_LENGTH)
+            .and_then(|(nonce, msg)| {
+                let payload = Payload {
+                    msg,
+                    aad: key.as_bytes(),
+                };
+                self.cipher.decrypt(Nonce::from_slice(nonce), payload).ok()
+            });
+        match plaintext {
+            Some(plaintext) => Ok(Some(plaintext.into())),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("blob {key} can not be decrypted with this key"),
+            )),
+        }
+    }
+
+    fn decrypted_validated(&self, key: &str, validated: Validated) -> BridgeResult<Validated> {
+        Ok(match validated {
+            Validated::Modified { body, validator } => Validated::Modified {
+                body: self.decrypted(key, body)?,
+                validator,
+            },
+            not_modified => not_modified,
+        })
+    }
+}
+
+impl<B: ConnectionBridge + Sync> ConnectionBridge for EncryptedBridge<B> {
+    fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        let body = self.bridge.get(key)?;
+        self.decrypted(key, body)
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.bridge.put(key, self.encrypted(key, &body)?)
+    }
+
+    async fn get_async(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        let body = self.bridge.get_async(key).await?;
+        self.decrypted(key, body)
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        let encrypted = self.encrypted(key, &body)?;
+        self.bridge.put_async(key, encrypted).await
+    }
+
+    fn put_if_match(&self, key: &str, body: Bytes, validator: Option<&str>) -> BridgeResult<()> {
+        let encrypted = self.encrypted(key, &body)?;
+        self.bridge.put_if_match(key, encrypted, validator)
+    }
+
+    async fn put_if_match_async(
+        &self,
+        key: &str,
+        body: Bytes,
+        validator: Option<&str>,
+    ) -> BridgeResult<()> {
+        let encrypted = self.encrypted(key, &body)?;
+        self.bridge
+            .put_if_match_async(key, encrypted, validator)
+            .await
+    }
+
+    fn exists(&self, key: &str) -> BridgeResult<bool> {
+        self.bridge.exists(key)
+    }
+
+    async fn exists_async(&self, key: &str) -> BridgeResult<bool> {
+        self.bridge.exists_async(key).await
+    }
+
+    fn ping(&self) -> BridgeResult<()> {
+        self.bridge.ping()
+    }
+
+    async fn ping_async(&self) -> BridgeResult<()> {
+        self.bridge.ping_async().await
+    }
+
+    fn get_validated(&self, key: &str, validator: Option<&str>) -> BridgeResult<Validated> {
+        let validated = self.bridge.get_validated(key, validator)?;
+        self.decrypted_validated(key, validated)
+    }
+
+    async fn get_validated_async(
+        &self,
+        key: &str,
+        validator: Option<&str>,
+    ) -> BridgeResult<Validated> {
+        let validated = self.bridge.get_validated_async(key, validator).await?;
+        self.decrypted_validated(key, validated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use crate::identity::{Population, RemoteStore, tests::*};
+    use crate::testing::bridge_conformance;
+
+    #[test]
+    fn test_encrypted_bridge() -> Result<(), Error> {
+        let key = [7; 32];
+        bridge_conformance(|| EncryptedBridge::new(MockBridge::default(), &key));
+
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let mut store = RemoteStore::new(EncryptedBridge::new(MockBridge::default(), &key));
+        let identity = population.identity("a@b.br", &mut store)?;
+        assert_eq!(population.identity("a@b.br", &mut store)?, identity);
+
+        // digests are not stored in the clear
+        let blob_key = identity.storage.key.as_str();
+        let stored = store.bridge.inner().get(blob_key)?.unwrap();
+        let digest = identity.storage.digest.as_str().as_bytes();
+        assert!(!stored.windows(digest.len()).any(|w| w == digest));
+        assert!(!format!("{:?}", store.bridge).contains("cipher"));
+
+        // and can not be read with another key, or at another key
+        let other = EncryptedBridge::new(std::mem::take(&mut store.bridge.bridge), &[8; 32]);
+        let e = other.get(blob_key).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        let bridge = EncryptedBridge::new(other.bridge, &key);
+        bridge.inner().put("fff", stored)?;
+        assert!(bridge.get("fff").is_err());
+        assert!(bridge.get(blob_key)?.is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+//! A [`ConnectionBridge`] which keeps blobs as keys of an etcd cluster.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bytes::Bytes;
+use serde_json::{Value, json};
+
+use super::storage::{BridgeResult, ConnectionBridge, Validated};
+
+/// Stores each blob as the value of a key of an [etcd](https://etcd.io) cluster, through the
+/// JSON gateway of its v3 API, such as `http://localhost:2379`.
+///
+/// Each blob is replaced in a transaction which only succeeds if the key has not been modified
+/// since the bridge last read it, by comparing its mod revision, so that a
+/// [`super::RemoteStore`] never overwrites offsets assigned by another writer of the cluster.
+/// A put which loses this race fails with [`io::ErrorKind::AlreadyExists`], which is a
+/// [`crate::ErrorKind::Conflict`] that can be retried. Keys which the bridge has not read are
+/// written without comparison. Mod revisions are also the validators of
+/// [`ConnectionBridge::get_validated`]. The async methods block.
+#[derive(Debug)]
+pub struct EtcdBridge {
+    agent: ureq::Agent,
+    url: String,
+    // key -> mod revision of the last read or write of the bridge, 0 if the key was absent
+    revisions: Mutex<HashMap<String, i64>>,
+}
+
+impl EtcdBridge {
+    /// Keep blobs in the cluster of the gateway at `url`.
+    pub fn new(url: &str) -> Self {
+        Self {
+            agent: ureq::Agent::new_with_defaults(),
+            url: url.trim_end_matches('/').to_string(),
+            revisions: Mutex::default(),
+        }
+    }
+
+    /// Send requests with `agent`, such as one configured with the client certificate or
+    /// timeouts required by the cluster.
+    pub fn with_agent(mut self, agent: ureq::Agent) -> Self {
+        self.agent = agent;
+        self
+    }
+
+    // posts `request` to the endpoint of `method`, such as "kv/range"
+    fn call(&self, method: &str, request: Value) -> BridgeResult<Value> {
+        let url = format!("{}/v3/{method}", self.url);
+        self.agent
+            .post(&url)
+            .send_json(request)
+            .and_then(|response| response.into_body().read_json())
+            .map_err(|e| io::Error::other(format!("etcd request to {url} failed: {e}")))
+    }
+
+    // the value and mod revision of `key`, which is 0 if it is absent
+    fn range(&self, key: &str) -> BridgeResult<(Option<Bytes>, i64)> {
+        let response = self.call("kv/range", json!({ "key": STANDARD.encode(key) }))?;
+        let Some(kv) = response["kvs"].get(0) else {
+            self.revisions.lock().unwrap().insert(key.to_string(), 0);
+            return Ok((None, 0));
+        };
+        let revision = int64(&kv["mod_revision"])?;
+        let value = kv["value"].as_str().unwrap_or_default();
+        let value = STANDARD
+            .decode(value)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.revisions
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), revision);
+        Ok((Some(Bytes::from(value)), revision))
+    }
+}
+
+// int64 fields are strings in the JSON mapping of protocol buffers
+fn int64(value: &Value) -> BridgeResult<i64> {
+    let invalid = || {
+        let message = format!("etcd returned {value} instead of a revision");
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    };
+    value
+        .as_str()
+        .ok_or_else(invalid)?
+        .parse()
+        .map_err(|_| invalid())
+}
+
+impl ConnectionBridge for EtcdBridge {
+    fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        self.range(key).map(|(body, _revision)| body)
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        let encoded_key = STANDARD.encode(key);
+        let put = json!({ "request_put": { "key": encoded_key, "value": STANDARD.encode(&body) } });
+        let revision = self.revisions.lock().unwrap().get(key).copied();
+        let compare = revision.map_or(vec![], |revision| {
+            vec![json!({
+                "key": encoded_key,
+                "target": "MOD",
+                "result": "EQUAL",
+                "mod_revision": revision.to_string(),
+            })]
+        });
+        let response = self.call("kv/txn", json!({ "compare": compare, "success": [put] }))?;
+        // fields with default values, such as false, are omitted
+        if response["succeeded"] != Value::Bool(true) {
+            self.revisions.lock().unwrap().remove(key);
+            let message = format!("etcd key {key} was modified by another writer");
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, message));
+        }
+        let revision = int64(&response["header"]["revision"])?;
+        self.revisions
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), revision);
+        Ok(())
+    }
+
+    async fn get_async(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        self.get(key)
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.put(key, body)
+    }
+
+    fn get_validated(&self, key: &str, validator: Option<&str>) -> BridgeResult<Validated> {
+        let (body, revision) = self.range(key)?;
+        let revision = revision.to_string();
+        if body.is_some() && validator == Some(revision.as_str()) {
+            return Ok(Validated::NotModified);
+        }
+        Ok(Validated::Modified {
+            validator: body.as_ref().map(|_| revision),
+            body,
+        })
+    }
+
+    async fn get_validated_async(
+        &self,
+        key: &str,
+        validator: Option<&str>,
+    ) -> BridgeResult<Validated> {
+        self.get_validated(key, validator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::tests::*;
+
+    // the range and txn requests of the gateway, for single keys
+    fn etcd_server() -> String {
+        let mut revision = 1;
+        let mut kvs = HashMap::<String, (String, i64)>::new();
+        http_server(0, move |request| {
+            let request: Value = serde_json::from_slice(&request.body).unwrap();
+            let header = json!({ "revision": revision.to_string() });
+            let response = match request.get("compare") {
+                None => match kvs.get(request["key"].as_str().unwrap()) {
+                    Some((value, modified)) => json!({
+                        "header": header,
+                        "kvs": [{
+                            "key": request["key"],
+                            "value": value,
+                            "mod_revision": modified.to_string(),
+                        }],
+                    }),
+                    None => json!({ "header": header }),
+                },
+                Some(compare) => {
+                    let succeeded = compare.as_array().unwrap().iter().all(|compare| {
+                        let key = compare["key"].as_str().unwrap();
+                        let modified = kvs.get(key).map_or(0, |(_value, modified)| *modified);
+                        compare["mod_revision"].as_str() == Some(&modified.to_string())
+                    });
+                    if succeeded {
+                        revision += 1;
+                        for put in request["success"].as_array().unwrap() {
+                            let put = &put["request_put"];
+                            let value = put["value"].as_str().unwrap().to_string();
+                            kvs.insert(put["key"].as_str().unwrap().to_string(), (value, revision));
+                        }
+                        json!({ "header": { "revision": revision.to_string() }, "succeeded": true })
+                    } else {
+                        json!({ "header": header })
+                    }
+                }
+            };
+            ("200 OK", vec![], response.to_string().into_bytes())
+        })
+    }
+
+    #[test]
+    fn test_etcd_bridge() -> Result<(), crate::Error> {
+        let url = etcd_server();
+        crate::testing::bridge_conformance(|| EtcdBridge::new(&etcd_server()));
+
+        let first = EtcdBridge::new(&url);
+        let second = EtcdBridge::new(&url);
+        assert_eq!(first.get("br/abc")?, None);
+        assert_eq!(second.get("br/abc")?, None);
+        first.put("br/abc", Bytes::from("first\n"))?;
+
+        // the second writer has not seen the first blob
+        let e = second.put("br/abc", Bytes::from("second\n")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert!(crate::Error::from(e).is_retryable());
+        assert_eq!(second.get("br/abc")?.as_deref(), Some(&b"first\n"[..]));
+        second.put("br/abc", Bytes::from("second\n"))?;
+
+        let Validated::Modified { validator, .. } = first.get_validated("br/abc", None)? else {
+            panic!("the blob should be modified");
+        };
+        assert!(matches!(
+            first.get_validated("br/abc", validator.as_deref())?,
+            Validated::NotModified
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+//! A [`ConnectionBridge`] which pairs a fast bridge with a durable one.
+
+use bytes::Bytes;
+
+use super::storage::{BridgeResult, ConnectionBridge, Validated};
+
+// validators are marked with the bridge which issued them, since the validators of two bridges
+// could be equal for different blobs
+const PRIMARY: &str = "primary:";
+const SECONDARY: &str = "secondary:";
+
+/// Reads blobs from a primary bridge, such as a `SledBridge` on local disk, and
+/// from a durable secondary bridge, such as an `S3Bridge`, when the primary has no blob or
+/// fails. Blobs found in the secondary are copied to the primary.
+///
+/// Blobs are written to the secondary and then to the primary, so that an assignment is only
+/// acknowledged once it is durable. A failed write to the primary is returned as an error,
+/// and leaves its earlier blob in place until the next write. Writes are never replicated in
+/// the background, since an assignment lost before reaching the secondary could give its
+/// offset to another identifier.
+///
+/// The primary is read first, so every writer of a domain should share it: a primary which
+/// misses the writes of another process returns outdated blobs, and the next write through it
+/// discards the other assignments. Validators are those of the bridge which returned the blob,
+/// and conditional writes are checked by the secondary, unless their validator was issued by
+/// the primary, in which case the write is unconditional.
+#[derive(Debug)]
+pub struct FallbackBridge<A, B> {
+    primary: A,
+    secondary: B,
+}
+
+impl<A: ConnectionBridge, B: ConnectionBridge> FallbackBridge<A, B> {
+    /// Read from `primary` before `secondary`, and write to both.
+    pub fn new(primary: A, secondary: B) -> Self {
+        Self { primary, secondary }
+    }
+
+    /// The bridge which is read first.
+    pub fn primary(&self) -> &A {
+        &self.primary
+    }
+
+    /// The durable bridge.
+    pub fn secondary(&self) -> &B {
+        &self.secondary
+    }
+}
+
+// the validator of `bridge` within `validator`, or `None` if it was issued by the other bridge
+fn unmarked<'v>(validator: Option<&'v str>, bridge: &str) -> Option<&'v str> {
+    validator.and_then(|validator| validator.strip_prefix(bridge))
+}
+
+fn marked(validated: Validated, bridge: &str) -> Validated {
+    match validated {
+        Validated::Modified { body, validator } => Validated::Modified {
+            body,
+            validator: validator.map(|validator| format!("{bridge}{validator}")),
+        },
+        Validated::NotModified => Validated::NotModified,
+    }
+}
+
+impl<A, B> ConnectionBridge for FallbackBridge<A, B>
+where
+    A: ConnectionBridge + Sync,
+    B: ConnectionBridge + Sync,
+{
+    fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        if let Ok(Some(body)) = self.primary.get(key) {
+            return Ok(Some(body));
+        }
+        let body = self.secondary.get(key)?;
+        if let Some(body) = &body {
+            let _ = self.primary.put(key, body.clone());
+        }
+        Ok(body)
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.secondary.put(key, body.clone())?;
+        self.primary.put(key, body)
+    }
+
+    async fn get_async(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        if let Ok(Some(body)) = self.primary.get_async(key).await {
+            return Ok(Some(body));
+        }
+        let body = self.secondary.get_async(key).await?;
+        if let Some(body) = &body {
+            let _ = self.primary.put_async(key, body.clone()).await;
+        }
+        Ok(body)
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.secondary.put_async(key, body.clone()).await?;
+        self.primary.put_async(key, body).await
+    }
+
+    fn put_if_match(&self, key: &str, body: Bytes, validator: Option<&str>) -> BridgeResult<()> {
+        match validator {
+            Some(validator) if !validator.starts_with(SECONDARY) => {
+                self.secondary.put(key, body.clone())?
+            }
+            _ => self
+                .secondary
+                .put_if_match(key, body.clone(), unmarked(validator, SECONDARY))?,
+        }
+        self.primary.put(key, body)
+    }
+
+    async fn put_if_match_async(
+        &self,
+        key: &str,
+        body: Bytes,
+        validator: Option<&str>,
+    ) -> BridgeResult<()> {
+        match validator {
+            Some(validator) if !validator.starts_with(SECONDARY) => {
+                self.secondary.put_async(key, body.clone()).await?
+            }
+            _ => {
+                self.secondary
+                    .put_if_match_async(key, body.clone(), unmarked(validator, SECONDARY))
+                    .await?
+            }
+        }
+        self.primary.put_async(key, body).await
+    }
+
+    fn exists(&self, key: &str) -> BridgeResult<bool> {
+        if let Ok(true) = self.primary.exists(key) {
+            return Ok(true);
+        }
+        self.secondary.exists(key)
+    }
+
+    async fn exists_async(&self, key: &str) -> BridgeResult<bool> {
+        if let Ok(true) = self.primary.exists_async(key).await {
+            return Ok(true);
+        }
+        self.secondary.exists_async(key).await
+    }
+
+    // every write reaches both bridges
+    fn ping(&self) -> BridgeResult<()> {
+        self.secondary.ping()?;
+        self.primary.ping()
+    }
+
+    async fn ping_async(&self) -> BridgeResult<()> {
+        self.secondary.ping_async().await?;
+        self.primary.ping_async().await
+    }
+
+    fn get_validated(&self, key: &str, validator: Option<&str>) -> BridgeResult<Validated> {
+        match self
+            .primary
+            .get_validated(key, unmarked(validator, PRIMARY))
+        {
+            Ok(Validated::Modified { body: None, .. }) | Err(_) => {}
+            Ok(validated) => return Ok(marked(validated, PRIMARY)),
+        }
+        let validated = self
+            .secondary
+            .get_validated(key, unmarked(validator, SECONDARY))?;
+        if let Validated::Modified {
+            body: Some(body), ..
+        } = &validated
+        {
+            let _ = self.primary.put(key, body.clone());
+        }
+        Ok(marked(validated, SECONDARY))
+    }
+
+    async fn get_validated_async(
+        &self,
+        key: &str,
+        validator: Option<&str>,
+    ) -> BridgeResult<Validated> {
+        let primary = self
+            .primary
+            .get_validated_async(key, unmarked(validator, PRIMARY))
+            .await;
+        match primary {
+            Ok(Validated::Modified { body: None, .. }) | Err(_) => {}
+            Ok(validated) => return Ok(marked(validated, PRIMARY)),
+        }
+        let validated = self
+            .secondary
+            .get_validated_async(key, unmarked(validator, SECONDARY))
+            .await?;
+        if let Validated::Modified {
+            body: Some(body), ..
+        } = &validated
+        {
+            let _ = self.primary.put_async(key, body.clone()).await;
+        }
+        Ok(marked(validated, SECONDARY))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use crate::identity::{Population, RemoteStore, tests::*};
+    use crate::testing::bridge_conformance;
+
+    #[test]
+    fn test_fallback_bridge() -> Result<(), Error> {
+        bridge_conformance(|| FallbackBridge::new(MockBridge::default(), MockBridge::default()));
+
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let bridge = FallbackBridge::new(MockBridge::default(), MockBridge::default());
+        let mut store = RemoteStore::new(bridge);
+        let identity = population.identity("a@b.br", &mut store)?;
+        let key = identity.storage.key.as_str();
+        let blob = store.bridge.primary().get(key)?;
+        assert!(blob.is_some());
+        assert_eq!(store.bridge.secondary().get(key)?, blob);
+
+        // a new primary is filled from the secondary
+        let secondary = std::mem::take(&mut store.bridge.secondary);
+        let bridge = FallbackBridge::new(MockBridge::default(), secondary);
+        assert!(bridge.exists(key)?);
+        assert!(bridge.primary().is_empty());
+        assert!(matches!(
+            bridge.get_validated(key, Some("secondary:abc"))?,
+            Validated::Modified { body: Some(_), .. }
+        ));
+        assert_eq!(bridge.primary().get(key)?, blob);
+        let mut store = RemoteStore::new(bridge);
+        assert_eq!(population.identity("a@b.br", &mut store)?, identity);
+        Ok(())
+    }
+}
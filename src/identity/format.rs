@@ -0,0 +1,359 @@
+use bytes::Bytes;
+
+use crate::{MIN_STORAGE_DIGEST_LENGTH, STORAGE_DIGEST_LENGTH};
+
+use super::storage::{MAX_OFFSET, RecordFlag, malformed, parse_record, split_version, text_record};
+
+/// The encoding of storage blobs, see [`super::RemoteStore::with_blob_format`].
+/// Blobs are searched as sorted "<digest> <offset>" text records, and other formats are
+/// converted to and from that form when they are fetched and stored.
+/// Formats are added by features and releases, so matches need a wildcard arm.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BlobFormat {
+    /// Fixed length "<digest> <offset>\n" records, sorted by digest. Searched in place.
+    #[default]
+    Text,
+    /// One `{"digest": "...", "offset": n}` object per line, sorted by digest. Tombstones have
+    /// a `"flag"` field naming their [`RecordFlag`], such as `"flag": "tombstone"`.
+    /// Whitespace and the order of fields do not matter when reading.
+    JsonLines,
+    /// A `StorageBlob` message of proto/perfume.proto. Requires the `prost` feature.
+    #[cfg(feature = "prost")]
+    Protobuf,
+    /// A byte holding the number of hex characters of each digest, followed by
+    /// [`BINARY_RECORD_LENGTH`] byte records sorted by digest: the digest packed into 32 bytes,
+    /// padded with zero bits, and a little-endian `u32` whose top two bits hold the [`RecordFlag`]
+    /// and whose remaining bits hold the offset. About half the size of [`BlobFormat::Text`],
+    /// and record `i` starts at byte `1 + i * BINARY_RECORD_LENGTH`, so it can be binary
+    /// searched by slicing. Empty blobs have no records and no header.
+    Binary,
+}
+
+/// Bytes of each record of a [`BlobFormat::Binary`] blob, 36.
+pub const BINARY_RECORD_LENGTH: usize = BINARY_DIGEST_LENGTH + 4;
+// bytes of a packed digest, which holds up to 64 hex characters
+const BINARY_DIGEST_LENGTH: usize = 32;
+// the bits of a binary record's u32 which hold its flag
+const BINARY_FLAG_SHIFT: u32 = 30;
+
+impl BlobFormat {
+    /// Convert a blob in this format into [`BlobFormat::Text`].
+    pub fn decode(&self, blob: &[u8]) -> std::io::Result<Bytes> {
+        match self {
+            Self::Text => Ok(Bytes::copy_from_slice(blob)),
+            Self::JsonLines => {
+                let text = std::str::from_utf8(blob).map_err(invalid_data)?;
+                let mut records = text
+                    .lines()
+                    .enumerate()
+                    .filter(|(_number, line)| !line.trim().is_empty())
+                    .map(|(number, line)| {
+                        parse_json_record(line).ok_or_else(|| {
+                            malformed(number, format!("malformed json record on line {number}"))
+                        })
+                    })
+                    .collect::<std::io::Result<Vec<_>>>()?;
+                records.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+                Ok(records
+                    .iter()
+                    .map(|(digest, offset, flag)| text_record(digest, *flag, *offset))
+                    .collect::<String>()
+                    .into())
+            }
+            #[cfg(feature = "prost")]
+            Self::Protobuf => super::proto::decode(blob),
+            Self::Binary => decode_binary(blob),
+        }
+    }
+
+    /// Convert a blob in [`BlobFormat::Text`] into this format. Only text blobs keep their
+    /// version line, see [`super::RemoteStore::with_blob_versions`].
+    pub fn encode(&self, text: &[u8]) -> std::io::Result<Bytes> {
+        let records = || -> std::io::Result<Vec<(&str, RecordFlag, usize)>> {
+            let (_version, version_length) = split_version(text)?;
+            let text = std::str::from_utf8(&text[version_length..]).map_err(invalid_data)?;
+            text.lines()
+                .map(|line| parse_record(line).ok_or_else(|| invalid_data("malformed text record")))
+                .collect()
+        };
+        match self {
+            Self::Text => Ok(Bytes::copy_from_slice(text)),
+            Self::JsonLines => {
+                let mut json = String::with_capacity(text.len() * 2);
+                for (digest, flag, offset) in records()? {
+                    let flag = match flag {
+                        RecordFlag::Live => String::new(),
+                        flag => format!(", \"flag\": \"{}\"", flag.as_str()),
+                    };
+                    json.push_str(&format!(
+                        "{{\"digest\": \"{digest}\", \"offset\": {offset}{flag}}}\n"
+                    ));
+                }
+                Ok(json.into())
+            }
+            #[cfg(feature = "prost")]
+            Self::Protobuf => Ok(super::proto::encode(
+                records()?
+                    .into_iter()
+                    .map(|(d, f, o)| (d.to_string(), f, o)),
+            )),
+            Self::Binary => encode_binary(&records()?),
+        }
+    }
+
+    /// Convert `blob` from format `from` into this format.
+    pub fn convert(&self, from: BlobFormat, blob: &[u8]) -> std::io::Result<Bytes> {
+        if from == *self {
+            return Ok(Bytes::copy_from_slice(blob));
+        }
+        self.encode(&from.decode(blob)?)
+    }
+}
+
+/// Reads `{"digest": "<hex>", "offset": <n>}` with an optional `"flag": "<flag>"`, which is
+/// all that JSON lines blobs hold.
+fn parse_json_record(line: &str) -> Option<(String, usize, RecordFlag)> {
+    let fields = line.trim().strip_prefix('{')?.strip_suffix('}')?;
+    let (mut digest, mut offset, mut flag) = (None, None, RecordFlag::Live);
+    for field in fields.split(',') {
+        let (name, value) = field.split_once(':')?;
+        match name.trim().trim_matches('"') {
+            "digest" => {
+                let value = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+                if !value.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return None;
+                }
+                digest = Some(value.to_ascii_lowercase());
+            }
+            "offset" => offset = Some(value.trim().parse().ok()?),
+            "flag" => {
+                let value = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+                flag = value.parse().ok()?;
+            }
+            _ => return None,
+        }
+    }
+    let (digest, offset) = (digest?, offset?);
+    is_valid_record(&digest, offset).then_some((digest, offset, flag))
+}
+
+/// Reads a [`BlobFormat::Binary`] blob as text.
+fn decode_binary(blob: &[u8]) -> std::io::Result<Bytes> {
+    let Some((&digest_length, packed)) = blob.split_first() else {
+        return Ok(Bytes::new());
+    };
+    let digest_length = digest_length as usize;
+    if digest_length > 2 * BINARY_DIGEST_LENGTH
+        || !packed.len().is_multiple_of(BINARY_RECORD_LENGTH)
+    {
+        return Err(invalid_data("malformed binary blob header"));
+    }
+    let mut records = packed
+        .chunks_exact(BINARY_RECORD_LENGTH)
+        .enumerate()
+        .map(|(number, record)| {
+            let (digest, offset) = record.split_at(BINARY_DIGEST_LENGTH);
+            let hex = digest
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<String>();
+            let value = u32::from_le_bytes(offset.try_into().expect("4 bytes"));
+            let flag = [
+                RecordFlag::Live,
+                RecordFlag::Tombstone,
+                RecordFlag::Compacted,
+            ]
+            .get((value >> BINARY_FLAG_SHIFT) as usize)
+            .copied();
+            let offset = (value & ((1 << BINARY_FLAG_SHIFT) - 1)) as usize;
+            let (digest, padding) = hex.split_at(digest_length);
+            match flag {
+                Some(flag) if padding.bytes().all(|b| b == b'0') => {
+                    is_valid_record(digest, offset).then(|| (digest.to_string(), offset, flag))
+                }
+                _ => None,
+            }
+            .ok_or_else(|| malformed(number, format!("malformed binary record {number}")))
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+    records.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    Ok(records
+        .iter()
+        .map(|(digest, offset, flag)| text_record(digest, *flag, *offset))
+        .collect::<String>()
+        .into())
+}
+
+/// Writes text records as a [`BlobFormat::Binary`] blob.
+fn encode_binary(records: &[(&str, RecordFlag, usize)]) -> std::io::Result<Bytes> {
+    let Some((digest, _, _)) = records.first() else {
+        return Ok(Bytes::new());
+    };
+    let mut binary = Vec::with_capacity(1 + records.len() * BINARY_RECORD_LENGTH);
+    binary.push(digest.len() as u8);
+    for (digest, flag, offset) in records {
+        if digest.len() != binary[0] as usize || !is_valid_record(digest, *offset) {
+            return Err(invalid_data("malformed text record"));
+        }
+        let mut packed = [0; BINARY_DIGEST_LENGTH];
+        for (i, nibble) in digest.bytes().enumerate() {
+            let nibble = (nibble as char).to_digit(16).expect("hex digit") as u8;
+            packed[i / 2] |= nibble << if i % 2 == 0 { 4 } else { 0 };
+        }
+        let flag = match flag {
+            RecordFlag::Live => 0,
+            RecordFlag::Tombstone => 1,
+            RecordFlag::Compacted => 2,
+        };
+        binary.extend_from_slice(&packed);
+        binary.extend_from_slice(&((flag << BINARY_FLAG_SHIFT) | *offset as u32).to_le_bytes());
+    }
+    Ok(binary.into())
+}
+
+// whether a record decoded from another format can be written as a text record
+pub(super) fn is_valid_record(digest: &str, offset: usize) -> bool {
+    (MIN_STORAGE_DIGEST_LENGTH..=STORAGE_DIGEST_LENGTH).contains(&digest.len())
+        && digest
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        && offset <= MAX_OFFSET
+}
+
+fn invalid_data<E>(error: E) -> std::io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::{ConnectionBridge, Population, RemoteStore, tests::*};
+    use crate::{Error, STORAGE_DIGEST_LENGTH};
+
+    #[test]
+    fn test_json_lines() -> Result<(), Error> {
+        let text = format!(
+            "{} {:>5}\n{} {:>5}\n",
+            "0".repeat(STORAGE_DIGEST_LENGTH),
+            1,
+            "a".repeat(STORAGE_DIGEST_LENGTH),
+            0
+        );
+        let json = BlobFormat::JsonLines.encode(text.as_bytes())?;
+        assert!(json.starts_with(b"{\"digest\": \"000"));
+        assert_eq!(BlobFormat::JsonLines.decode(&json)?, text);
+
+        // fields may be reordered, and records unsorted
+        let reordered = format!(
+            "{{ \"offset\": 0, \"digest\": \"{}\" }}\n{{\"digest\":\"{}\",\"offset\":1}}\n",
+            "A".repeat(STORAGE_DIGEST_LENGTH),
+            "0".repeat(STORAGE_DIGEST_LENGTH),
+        );
+        assert_eq!(BlobFormat::JsonLines.decode(reordered.as_bytes())?, text);
+        assert!(BlobFormat::JsonLines.decode(b"{\"digest\": 1}").is_err());
+        let huge_offset = format!("{{\"digest\": \"{}\", \"offset\": 100000}}", "a".repeat(61));
+        assert!(
+            BlobFormat::JsonLines
+                .decode(huge_offset.as_bytes())
+                .is_err()
+        );
+        let short_digest = b"{\"digest\": \"a\", \"offset\": 0}";
+        assert!(BlobFormat::JsonLines.decode(short_digest).is_err());
+
+        let tombstone = text.replacen(' ', "-", 1);
+        let json = BlobFormat::JsonLines.encode(tombstone.as_bytes())?;
+        let first_line = format!(
+            "{{\"digest\": \"{}\", \"offset\": 1, \"flag\": \"tombstone\"}}\n",
+            "0".repeat(STORAGE_DIGEST_LENGTH)
+        );
+        assert!(json.starts_with(first_line.as_bytes()));
+        assert_eq!(BlobFormat::JsonLines.decode(&json)?, tombstone);
+
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let mut store =
+            RemoteStore::new(MockBridge::default()).with_blob_format(BlobFormat::JsonLines);
+        let first = brazilian.identity("a@b.br", &mut store)?;
+        let second = brazilian.identity("c@d.br", &mut store)?;
+        assert_eq!(brazilian.identity("a@b.br", &mut store)?, first);
+        assert_eq!(brazilian.identity("c@d.br", &mut store)?, second);
+        let stored = store.bridge.get(first.storage.key.as_str())?.unwrap();
+        assert!(stored.starts_with(b"{\"digest\": "));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_binary() -> Result<(), Error> {
+        let text = format!(
+            "{} {:>5}\n{}-{:>5}\n",
+            "0".repeat(STORAGE_DIGEST_LENGTH),
+            1,
+            "a".repeat(STORAGE_DIGEST_LENGTH),
+            99999
+        );
+        let binary = BlobFormat::Binary.encode(text.as_bytes())?;
+        assert_eq!(binary.len(), 1 + 2 * BINARY_RECORD_LENGTH);
+        assert_eq!(binary[0] as usize, STORAGE_DIGEST_LENGTH);
+        // offsets are little-endian
+        assert_eq!(binary[1 + 32..1 + BINARY_RECORD_LENGTH], [1, 0, 0, 0]);
+        assert_eq!(BlobFormat::Binary.decode(&binary)?, text);
+        assert_eq!(
+            BlobFormat::JsonLines.convert(BlobFormat::Binary, &binary)?,
+            BlobFormat::JsonLines.encode(text.as_bytes())?
+        );
+        assert!(
+            BlobFormat::Binary
+                .decode(&binary[..binary.len() - 1])
+                .is_err()
+        );
+        // the padding of odd length digests is zero
+        let mut padded = binary.to_vec();
+        padded[1 + BINARY_RECORD_LENGTH + 31] |= 1;
+        assert!(BlobFormat::Binary.decode(&padded).is_err());
+        assert!(BlobFormat::Binary.encode(b"")?.is_empty());
+
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let mut store =
+            RemoteStore::new(MockBridge::default()).with_blob_format(BlobFormat::Binary);
+        let first = brazilian.identity("a@b.br", &mut store)?;
+        assert_eq!(brazilian.identity("a@b.br", &mut store)?, first);
+        let stored = store.bridge.get(first.storage.key.as_str())?.unwrap();
+        assert_eq!(stored.len(), 1 + BINARY_RECORD_LENGTH);
+        Ok(())
+    }
+
+    #[cfg(feature = "prost")]
+    #[test]
+    fn test_protobuf() -> Result<(), Error> {
+        let text = format!(
+            "{} {:>5}\n{} {:>5}\n",
+            "0".repeat(STORAGE_DIGEST_LENGTH),
+            1,
+            "a".repeat(STORAGE_DIGEST_LENGTH),
+            0
+        );
+        let encoded = BlobFormat::Protobuf.encode(text.as_bytes())?;
+        assert!(encoded.len() < text.len());
+        assert_eq!(BlobFormat::Protobuf.decode(&encoded)?, text);
+        let tombstone = text.replacen(' ', "-", 1);
+        let tombstoned = BlobFormat::Protobuf.encode(tombstone.as_bytes())?;
+        assert_eq!(BlobFormat::Protobuf.decode(&tombstoned)?, tombstone);
+        assert_eq!(
+            BlobFormat::JsonLines.convert(BlobFormat::Protobuf, &encoded)?,
+            BlobFormat::JsonLines.encode(text.as_bytes())?
+        );
+        Ok(())
+    }
+}
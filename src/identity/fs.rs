@@ -0,0 +1,206 @@
+//! A [`ConnectionBridge`] which keeps blobs as files of a local directory.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::{ControlFlow, Range};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::Bytes;
+
+use super::storage::{BridgeResult, ConnectionBridge};
+
+// the size of the chunks passed to `get_chunks`
+const CHUNK_SIZE: usize = 64 << 10;
+
+// distinguishes the temporary files of concurrent writes within a process
+static TEMPORARY_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Stores each blob as a file under a root directory, at the path given by its key, so that
+/// keys such as `br/abc` made by a [`crate::identity::KeyTemplate`] of `{domain}/{key}` keep
+/// the blobs of each domain in a subdirectory. Directories are created as they are needed.
+///
+/// Blobs are written to a temporary file beside their own, which is synced and then renamed
+/// over it, so that readers and other processes only ever see whole blobs. Keys are checked
+/// to be relative paths within the root. The async methods block on the filesystem.
+#[derive(Debug, Clone)]
+pub struct FsBridge {
+    root: PathBuf,
+}
+
+impl FsBridge {
+    /// Keep blobs under `root`, which is created with the first blob.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// The directory holding the blobs.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The path of the file of `key`.
+    pub fn path(&self, key: &str) -> BridgeResult<PathBuf> {
+        let valid = key.split('/').all(|part| {
+            !part.is_empty() && part != "." && part != ".." && !part.contains(['\\', '\0'])
+        });
+        if !valid {
+            let message = format!("key {key:?} is not a relative path of segments");
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                message,
+            ));
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+// `None` if the file does not exist
+fn open(path: &Path) -> BridgeResult<Option<std::fs::File>> {
+    match std::fs::File::open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl ConnectionBridge for FsBridge {
+    fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        let Some(mut file) = open(&self.path(key)?)? else {
+            return Ok(None);
+        };
+        let mut body = Vec::with_capacity(file.metadata().map_or(0, |m| m.len() as usize));
+        file.read_to_end(&mut body)?;
+        Ok(Some(Bytes::from(body)))
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        let path = self.path(key)?;
+        let dir = path.parent().expect("keys should be within the root");
+        std::fs::create_dir_all(dir)?;
+
+        let number = TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed);
+        let mut temporary = path
+            .file_name()
+            .expect("keys should be checked")
+            .to_os_string();
+        temporary.push(format!(".{}-{number}.tmp", std::process::id()));
+        let temporary = dir.join(temporary);
+        let written = std::fs::File::create(&temporary).and_then(|mut file| {
+            file.write_all(&body)?;
+            file.sync_all()
+        });
+        match written.and_then(|()| std::fs::rename(&temporary, &path)) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                let _ = std::fs::remove_file(&temporary);
+                Err(e)
+            }
+        }
+    }
+
+    async fn get_async(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        self.get(key)
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.put(key, body)
+    }
+
+    fn exists(&self, key: &str) -> BridgeResult<bool> {
+        match std::fs::metadata(self.path(key)?) {
+            Ok(metadata) => Ok(metadata.is_file()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn exists_async(&self, key: &str) -> BridgeResult<bool> {
+        self.exists(key)
+    }
+
+    fn get_chunks(
+        &self,
+        key: &str,
+        visit: &mut dyn FnMut(&[u8]) -> ControlFlow<()>,
+    ) -> BridgeResult<bool> {
+        let Some(mut file) = open(&self.path(key)?)? else {
+            return Ok(false);
+        };
+        let mut chunk = vec![0; CHUNK_SIZE];
+        loop {
+            let read = file.read(&mut chunk)?;
+            if read == 0 || visit(&chunk[..read]).is_break() {
+                return Ok(true);
+            }
+        }
+    }
+
+    async fn get_chunks_async(
+        &self,
+        key: &str,
+        visit: &mut (dyn FnMut(&[u8]) -> ControlFlow<()> + Send),
+    ) -> BridgeResult<bool> {
+        self.get_chunks(key, visit)
+    }
+
+    fn get_range(&self, key: &str, range: Range<u64>) -> BridgeResult<Option<(Bytes, u64)>> {
+        let Some(mut file) = open(&self.path(key)?)? else {
+            return Ok(None);
+        };
+        // a file which is replaced keeps its length while it is open
+        let length = file.metadata()?.len();
+        file.seek(SeekFrom::Start(range.start.min(length)))?;
+        let mut bytes = vec![];
+        file.take(range.end.saturating_sub(range.start))
+            .read_to_end(&mut bytes)?;
+        Ok(Some((Bytes::from(bytes), length)))
+    }
+
+    async fn get_range_async(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> BridgeResult<Option<(Bytes, u64)>> {
+        self.get_range(key, range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use crate::identity::{KeyTemplate, Population, RemoteStore, tests::*};
+    use crate::testing::bridge_conformance;
+
+    #[test]
+    fn test_fs_bridge() -> Result<(), Error> {
+        let tmp_dir = std::env::var("TMPDIR").unwrap_or("/tmp".to_string());
+        let root = Path::new(&tmp_dir).join(format!("perfume_test_fs_{}", std::process::id()));
+        let bridges = AtomicU64::new(0);
+        bridge_conformance(|| {
+            let bridge = bridges.fetch_add(1, Ordering::Relaxed);
+            FsBridge::new(root.join(bridge.to_string()))
+        });
+
+        let bridge = FsBridge::new(root.join("store"));
+        for key in ["", "/abc", "br/../abc", "br//abc", "br\\abc"] {
+            assert!(bridge.put(key, Bytes::new()).is_err(), "{key}");
+        }
+        let template = KeyTemplate::new("{domain}/{key}", "br")?;
+        let mut store = RemoteStore::new(bridge.clone()).with_key_template(template);
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let identity = population.identity("a@b.br", &mut store)?;
+        let key = identity.storage.key.as_str();
+        assert!(bridge.root().join("br").join(key).is_file());
+        let template = KeyTemplate::new("{domain}/{key}", "br")?;
+        let mut reopened = RemoteStore::new(bridge).with_key_template(template);
+        assert_eq!(population.identity("a@b.br", &mut reopened)?, identity);
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,462 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use async_generic::async_generic;
+use bytes::Bytes;
+
+use crate::hex_string::HexString;
+use crate::{
+    Error, MIN_STORAGE_DIGEST_LENGTH, Operation, STORAGE_DIGEST_LENGTH, STORAGE_KEY_LENGTH,
+};
+
+use super::snapshot::Snapshot;
+use super::storage::{
+    ConnectionBridge, FORMAT_PREFIX, MAX_OFFSET, RecordFlag, Records, RemoteStore, VERSION_PREFIX,
+    compact_blob, format_line, parse_record, split_format, text_record, version_line,
+};
+
+/// A problem found in a storage blob by [`check_blob`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlobIssue {
+    /// A line which is not formatted as "<digest> <offset>" or a tombstone, see [`RecordFlag`],
+    /// or whose digest length differs from the first line. Contains the line number.
+    MalformedLine(usize),
+    /// A digest which appears on more than one line.
+    DuplicateDigest(String),
+    /// An offset which is assigned to more than one digest, so that they share a name.
+    DuplicateOffset(usize),
+    /// An offset which is not assigned, although a larger one is. Offsets held by tombstones
+    /// are assigned, as are those below a [`RecordFlag::Compacted`] tombstone.
+    OffsetGap(usize),
+    /// Digests are not in ascending order, which prevents them from being found.
+    Unsorted,
+    /// The blob is of a later format than [`super::FORMAT_VERSION`], which is not checked, and
+    /// is never repaired. Contains the format of the blob.
+    UnsupportedFormat(u32),
+}
+
+impl std::fmt::Display for BlobIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MalformedLine(line) => write!(f, "malformed line {line}"),
+            Self::DuplicateDigest(digest) => write!(f, "duplicate digest {digest}"),
+            Self::DuplicateOffset(offset) => write!(f, "duplicate offset {offset}"),
+            Self::OffsetGap(offset) => write!(f, "unassigned offset {offset}"),
+            Self::Unsorted => write!(f, "digests are not sorted"),
+            Self::UnsupportedFormat(format) => write!(f, "unsupported format {format}"),
+        }
+    }
+}
+
+/// The result of checking a single storage blob.
+#[derive(Debug, Clone)]
+pub struct BlobCheck {
+    /// Problems found, in the order they were encountered.
+    pub issues: Vec<BlobIssue>,
+    /// The blob rewritten with sorted digests, without malformed lines or duplicate digests.
+    /// Where a digest is duplicated, the smallest offset is kept.
+    /// Offsets are never renumbered, because that would change the names of existing identities.
+    /// A valid format line and version line are kept, see [`RemoteStore::with_format_header`]
+    /// and [`RemoteStore::with_blob_versions`].
+    pub canonical: Bytes,
+    /// True if [`BlobCheck::canonical`] differs from the checked blob, so that repairing the
+    /// blob rewrites it.
+    pub changed: bool,
+    /// The lines which [`BlobCheck::canonical`] drops, with their line numbers: malformed lines,
+    /// and the records of duplicated digests whose offsets were not kept.
+    pub dropped: Vec<(usize, String)>,
+    /// Records of deleted digests, which are kept so that their offsets are not reassigned.
+    /// See [`RemoteStore::delete`].
+    pub tombstones: usize,
+}
+
+/// A corrupt blob which was moved aside and rebuilt, see [`RemoteStore::with_quarantine`].
+#[derive(Debug, Clone)]
+pub struct RecoveryReport {
+    /// The storage key of the blob.
+    pub key: HexString<STORAGE_KEY_LENGTH>,
+    /// The key which the corrupt blob was copied to, "<key>.corrupt-<seconds since the epoch>".
+    pub quarantined_as: String,
+    /// Problems found in the corrupt blob.
+    pub issues: Vec<BlobIssue>,
+    /// Records kept in the rebuilt blob.
+    pub salvaged: usize,
+    /// Lines of the corrupt blob which were not kept.
+    pub discarded: usize,
+}
+
+/// Check that `blob` has the format expected by [`RemoteStore`].
+/// A blob of a later format is not checked, and its canonical form is the blob itself.
+pub fn check_blob(blob: &[u8]) -> BlobCheck {
+    let mut issues = vec![];
+    // digest -> flag, offset and line number of the record which is kept
+    let mut records: BTreeMap<&str, (RecordFlag, usize, usize)> = BTreeMap::new();
+    let mut dropped = vec![];
+    let mut offsets: BTreeMap<usize, usize> = BTreeMap::new();
+    let mut last_digest: Option<&str> = None;
+    let mut sorted = true;
+
+    let text = String::from_utf8_lossy(blob);
+    let lines: Vec<&str> = text.lines().collect();
+    let mut digest_length: Option<usize> = None;
+    // tombstones which were dropped held the unassigned offsets below this one
+    let mut compacted_below = 0;
+    let mut formatted = false;
+    let mut version = None;
+    for (number, line) in lines.iter().enumerate() {
+        // the format line comes first, see RemoteStore::with_format_header
+        if number == 0 && line.starts_with(FORMAT_PREFIX) {
+            match split_format(format!("{line}\n").as_bytes()) {
+                Ok(_) => formatted = true,
+                Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+                    let format = line[FORMAT_PREFIX.len()..].parse().unwrap_or_default();
+                    return BlobCheck {
+                        issues: vec![BlobIssue::UnsupportedFormat(format)],
+                        canonical: Bytes::copy_from_slice(blob),
+                        changed: false,
+                        dropped: vec![],
+                        tombstones: 0,
+                    };
+                }
+                Err(_) => {
+                    issues.push(BlobIssue::MalformedLine(number));
+                    dropped.push((number, line.to_string()));
+                }
+            }
+            continue;
+        }
+        // and the version line precedes the records, see RemoteStore::with_blob_versions
+        let first_record = usize::from(formatted);
+        let version_line = line.strip_prefix(VERSION_PREFIX);
+        if let Some(number_of_changes) = version_line.filter(|_| number == first_record) {
+            match number_of_changes.parse::<u64>() {
+                Ok(parsed) => version = Some(parsed),
+                Err(_) => {
+                    issues.push(BlobIssue::MalformedLine(number));
+                    dropped.push((number, line.to_string()));
+                }
+            }
+            continue;
+        }
+        let Some((digest, flag, offset)) = parse_line(line) else {
+            issues.push(BlobIssue::MalformedLine(number));
+            dropped.push((number, line.to_string()));
+            continue;
+        };
+        // every record of a blob has the same length, see RemoteStore::with_digest_length
+        if *digest_length.get_or_insert(digest.len()) != digest.len() {
+            issues.push(BlobIssue::MalformedLine(number));
+            dropped.push((number, line.to_string()));
+            continue;
+        }
+        if last_digest.is_some_and(|last| last > digest) {
+            sorted = false;
+        }
+        last_digest = Some(digest);
+        match records.get(digest) {
+            Some(&(_, existing, kept)) => {
+                issues.push(BlobIssue::DuplicateDigest(digest.to_string()));
+                if offset < existing {
+                    records.insert(digest, (flag, offset, number));
+                    dropped.push((kept, lines[kept].to_string()));
+                } else {
+                    dropped.push((number, line.to_string()));
+                }
+            }
+            None => {
+                records.insert(digest, (flag, offset, number));
+            }
+        }
+        if flag == RecordFlag::Compacted {
+            compacted_below = compacted_below.max(offset);
+        }
+        *offsets.entry(offset).or_default() += 1;
+    }
+
+    if !sorted {
+        issues.push(BlobIssue::Unsorted);
+    }
+    for (&offset, &count) in &offsets {
+        if count > 1 {
+            issues.push(BlobIssue::DuplicateOffset(offset));
+        }
+    }
+    if let Some(&max_offset) = offsets.keys().last() {
+        let assigned: BTreeSet<usize> = offsets.keys().cloned().collect();
+        for offset in (compacted_below..max_offset).filter(|o| !assigned.contains(o)) {
+            issues.push(BlobIssue::OffsetGap(offset));
+        }
+    }
+
+    let mut canonical = String::with_capacity(blob.len());
+    if formatted {
+        canonical.push_str(&format_line());
+    }
+    if let Some(version) = version {
+        canonical.push_str(&version_line(version));
+    }
+    let mut tombstones = 0;
+    for (digest, (flag, offset, _number)) in records {
+        canonical.push_str(&text_record(digest, flag, offset));
+        tombstones += usize::from(flag.is_deleted());
+    }
+
+    dropped.sort_unstable();
+    BlobCheck {
+        issues,
+        changed: canonical.as_bytes() != blob,
+        canonical: Bytes::from(canonical),
+        dropped,
+        tombstones,
+    }
+}
+
+fn parse_line(line: &str) -> Option<(&str, RecordFlag, usize)> {
+    let (digest, flag, offset) = parse_record(line)?;
+    if !(MIN_STORAGE_DIGEST_LENGTH..=STORAGE_DIGEST_LENGTH).contains(&digest.len()) {
+        return None;
+    }
+    // larger offsets can not be stored in a record, and would make the gaps before them huge
+    (offset <= MAX_OFFSET).then_some((digest, flag, offset))
+}
+
+impl<B> RemoteStore<B>
+where
+    B: ConnectionBridge + Send,
+{
+    /// Check every storage blob, returning the keys of blobs which have problems.
+    /// If `repair` is true, those blobs are replaced by their [`BlobCheck::canonical`] form
+    /// where it differs from them, see [`BlobCheck::changed`]. Blobs of a later format are never
+    /// changed, see [`BlobIssue::UnsupportedFormat`].
+    /// Blobs are checked as text and repaired in the format of
+    /// [`RemoteStore::with_blob_format`], so a blob which cannot be decoded is an error.
+    #[async_generic]
+    #[allow(unused_assignments)]
+    pub fn fsck(
+        &self,
+        repair: bool,
+    ) -> Result<Vec<(HexString<STORAGE_KEY_LENGTH>, BlobCheck)>, Error> {
+        let mut snapshot = Snapshot::default();
+        if _async {
+            snapshot = self.export_async().await?;
+        } else {
+            snapshot = self.export()?;
+        }
+
+        let mut results = vec![];
+        for (key, bytes) in snapshot.blobs {
+            let text = self
+                .blob_format()
+                .decode(&bytes)
+                .map_err(|e| Error::storage(e, key.as_str(), Operation::Parse))?;
+            let check = check_blob(&text);
+            if check.issues.is_empty() {
+                continue;
+            }
+            if repair && check.changed {
+                let encoded = self
+                    .blob_format()
+                    .encode(&check.canonical)
+                    .map_err(|e| Error::storage(e, key.as_str(), Operation::Put))?;
+                let resource = self.bridge_key(key.as_str());
+                if _async {
+                    self.bridge.put_async(&resource, encoded).await?;
+                } else {
+                    self.bridge.put(&resource, encoded)?;
+                }
+            }
+            results.push((key, check));
+        }
+        Ok(results)
+    }
+
+    /// Drop the tombstones of every storage blob, except the one holding the largest offset of
+    /// each blob, see [`compact_blob`]. Returns the number of tombstones which were dropped.
+    /// Blobs are compacted as text and stored in the format of [`RemoteStore::with_blob_format`],
+    /// as for [`RemoteStore::fsck`].
+    /// A deleted digest whose tombstone was dropped is assigned a new offset if it is stored
+    /// again. Should not run while other stores assign offsets, since their writes could be lost.
+    #[async_generic]
+    #[allow(unused_assignments)]
+    pub fn compact(&self) -> Result<usize, Error> {
+        let mut snapshot = Snapshot::default();
+        if _async {
+            snapshot = self.export_async().await?;
+        } else {
+            snapshot = self.export()?;
+        }
+
+        let mut dropped = 0;
+        for (key, bytes) in snapshot.blobs {
+            let parse = |e| Error::storage(e, key.as_str(), Operation::Parse);
+            let text = self.blob_format().decode(&bytes).map_err(parse)?;
+            let compacted = compact_blob(&text).map_err(parse)?;
+            if compacted == text {
+                continue;
+            }
+            let records = |blob: &[u8]| Records::new(blob).map(|records| records.len());
+            dropped += records(&text).map_err(parse)? - records(&compacted).map_err(parse)?;
+            let encoded = self
+                .blob_format()
+                .encode(&compacted)
+                .map_err(|e| Error::storage(e, key.as_str(), Operation::Put))?;
+            let resource = self.bridge_key(key.as_str());
+            if _async {
+                self.bridge.put_async(&resource, encoded).await?;
+            } else {
+                self.bridge.put(&resource, encoded)?;
+            }
+        }
+        Ok(dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::tests::*;
+    use crate::identity::{BlobFormat, Storage, StorageState};
+
+    fn line(digit: char, offset: usize) -> String {
+        let digest: String = std::iter::repeat_n(digit, STORAGE_DIGEST_LENGTH).collect();
+        format!("{digest} {offset:>5}\n")
+    }
+
+    #[test]
+    fn test_check_blob_valid() {
+        let blob = [line('0', 1), line('a', 0)].concat();
+        let check = check_blob(blob.as_bytes());
+        assert_eq!(check.issues, vec![]);
+        assert_eq!(check.canonical, blob);
+        assert!(!check.changed);
+    }
+
+    #[test]
+    fn test_check_blob_repair() {
+        let blob = [
+            line('b', 3),
+            "not a record\n".to_string(),
+            line('a', 0),
+            line('b', 1),
+            line('c', 0),
+        ]
+        .concat();
+        let check = check_blob(blob.as_bytes());
+        assert_eq!(
+            check.issues,
+            vec![
+                BlobIssue::MalformedLine(1),
+                BlobIssue::DuplicateDigest("b".repeat(STORAGE_DIGEST_LENGTH)),
+                BlobIssue::Unsorted,
+                BlobIssue::DuplicateOffset(0),
+                BlobIssue::OffsetGap(2),
+            ]
+        );
+        assert_eq!(
+            check.canonical,
+            [line('a', 0), line('b', 1), line('c', 0)].concat()
+        );
+        assert!(check.changed);
+        // the later record of digest "b" keeps the smaller offset
+        assert_eq!(
+            check.dropped,
+            vec![
+                (0, line('b', 3).trim_end().to_string()),
+                (1, "not a record".to_string()),
+            ]
+        );
+
+        // an offset which does not fit in a record
+        let blob = format!("{}{} 6670751226229669993\n", line('a', 0), "b".repeat(61));
+        assert_eq!(
+            check_blob(blob.as_bytes()).issues,
+            vec![BlobIssue::MalformedLine(1)]
+        );
+    }
+
+    #[test]
+    fn test_check_blob_tombstones() {
+        let flagged = |digit: char, flag: RecordFlag, offset: usize| {
+            let digest: String = std::iter::repeat_n(digit, STORAGE_DIGEST_LENGTH).collect();
+            text_record(&digest, flag, offset)
+        };
+        let blob = [
+            line('0', 0),
+            flagged('1', RecordFlag::Tombstone, 2),
+            line('2', 1),
+        ]
+        .concat();
+        let check = check_blob(blob.as_bytes());
+        assert_eq!((check.issues, check.tombstones), (vec![], 1));
+        assert_eq!(check.canonical, blob);
+
+        // offsets below the compacted tombstone were held by dropped tombstones
+        let compacted = compact_blob(blob.as_bytes()).unwrap();
+        assert_eq!(compacted, blob.replace('-', "+"));
+        let blob = [
+            line('0', 0),
+            flagged('1', RecordFlag::Compacted, 4),
+            line('2', 5),
+        ]
+        .concat();
+        assert_eq!(check_blob(blob.as_bytes()).issues, vec![]);
+        let blob = [
+            line('0', 0),
+            flagged('1', RecordFlag::Compacted, 2),
+            line('2', 5),
+        ]
+        .concat();
+        assert_eq!(
+            check_blob(blob.as_bytes()).issues,
+            vec![BlobIssue::OffsetGap(3), BlobIssue::OffsetGap(4)]
+        );
+    }
+
+    #[test]
+    fn test_fsck_blob_format() -> Result<(), Error> {
+        let mut store =
+            RemoteStore::new(MockBridge::default()).with_blob_format(BlobFormat::JsonLines);
+        let storages = (0..10)
+            .map(|_| Storage {
+                key: HexString::from(&b"abc"[..]),
+                digest: random_hex_string(),
+            })
+            .collect::<Vec<_>>();
+        let offsets = store.digest_offsets("", &storages)?;
+
+        // a duplicate digest, as an interrupted write could leave
+        let digest = storages[0].digest.as_str();
+        let duplicate = format!("{{\"digest\": \"{digest}\", \"offset\": 10}}\n");
+        let stored = store.bridge.get("abc")?.unwrap();
+        let corrupt = Bytes::from([&stored[..], duplicate.as_bytes()].concat());
+        store.bridge.put("abc", corrupt.clone())?;
+        let results = store.fsck(false)?;
+        assert_eq!(
+            results[0].1.issues,
+            vec![BlobIssue::DuplicateDigest(digest.to_string())]
+        );
+        assert_eq!(store.bridge.get("abc")?.unwrap(), corrupt);
+
+        // is dropped from the blob, which is still in its format
+        assert_eq!(store.fsck(true)?.len(), 1);
+        assert_eq!(store.fsck(false)?.len(), 0);
+        assert_eq!(store.bridge.get("abc")?.unwrap(), stored);
+        assert_eq!(store.digest_offsets("", &storages)?, offsets);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_blob_digest_length() {
+        let narrow = |digit: char, offset: usize| {
+            let digest: String = std::iter::repeat_n(digit, MIN_STORAGE_DIGEST_LENGTH).collect();
+            format!("{digest} {offset:>5}\n")
+        };
+        let blob = [narrow('0', 1), narrow('a', 0)].concat();
+        assert_eq!(check_blob(blob.as_bytes()).issues, vec![]);
+
+        let blob = [narrow('0', 1), line('a', 0)].concat();
+        assert_eq!(
+            check_blob(blob.as_bytes()).issues,
+            vec![BlobIssue::MalformedLine(1), BlobIssue::OffsetGap(0)]
+        );
+    }
+}
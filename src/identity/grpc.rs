@@ -0,0 +1,360 @@
+//! A [`ConnectionBridge`] which keeps blobs in a service of proto/blob_store.proto.
+
+use std::future::Future;
+
+use bytes::Bytes;
+use http::uri::PathAndQuery;
+use tonic::client::Grpc;
+use tonic::transport::{Channel, Endpoint};
+use tonic_prost::ProstCodec;
+
+use super::storage::{BridgeResult, ConnectionBridge, Validated};
+
+const GET: &str = "/perfume.BlobStore/Get";
+const PUT: &str = "/perfume.BlobStore/Put";
+const LIST: &str = "/perfume.BlobStore/List";
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct GetRequest {
+    #[prost(string, tag = "1")]
+    key: String,
+    #[prost(string, optional, tag = "2")]
+    if_none_match: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct GetResponse {
+    #[prost(bytes = "bytes", optional, tag = "1")]
+    body: Option<Bytes>,
+    #[prost(string, optional, tag = "2")]
+    validator: Option<String>,
+    #[prost(bool, tag = "3")]
+    not_modified: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct PutRequest {
+    #[prost(string, tag = "1")]
+    key: String,
+    #[prost(bytes = "bytes", tag = "2")]
+    body: Bytes,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct PutResponse {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ListRequest {
+    #[prost(string, tag = "1")]
+    prefix: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ListResponse {
+    #[prost(string, repeated, tag = "1")]
+    keys: Vec<String>,
+}
+
+/// Stores blobs in a `perfume.BlobStore` service, as described by proto/blob_store.proto,
+/// so that organizations can keep the blobs of every domain behind a service of their own.
+///
+/// Blobs which are not found are absent, and failures are errors of the kind which matches
+/// their status code, so that `UNAVAILABLE` and `DEADLINE_EXCEEDED` are retryable and `ABORTED`
+/// is a conflict, see [`crate::Error::kind`]. Blobs are revalidated with the validators of the
+/// service, see [`ConnectionBridge::get_validated`]. TLS and timeouts are configured on the
+/// [`Endpoint`] given to [`GrpcBridge::from_endpoint`].
+///
+/// The connection is driven by a runtime of the bridge, with one worker thread, so that async
+/// calls can be made from any runtime and blocking calls from any thread.
+#[derive(Debug)]
+pub struct GrpcBridge {
+    client: Grpc<Channel>,
+    // only taken when the bridge is dropped
+    runtime: Option<tokio::runtime::Runtime>,
+}
+
+impl GrpcBridge {
+    /// Connect to the service at `url`, such as `http://blobs.example.com:50051`, once the
+    /// first request is made.
+    pub fn new(url: &str) -> BridgeResult<Self> {
+        let endpoint = Endpoint::from_shared(url.to_string()).map_err(|e| {
+            let message = format!("invalid service url {url}: {e}");
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+        })?;
+        Self::from_endpoint(endpoint)
+    }
+
+    /// Connect to the service at `endpoint` once the first request is made.
+    pub fn from_endpoint(endpoint: Endpoint) -> BridgeResult<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("perfume-grpc")
+            .enable_all()
+            .build()?;
+        let channel = {
+            let _entered = runtime.enter();
+            endpoint.connect_lazy()
+        };
+        Ok(Self {
+            client: Grpc::new(channel),
+            runtime: Some(runtime),
+        })
+    }
+
+    /// The keys of the stored blobs which begin with `prefix`, in lexicographic order.
+    pub fn list(&self, prefix: &str) -> BridgeResult<Vec<String>> {
+        self.block_on(self.list_async(prefix))
+    }
+
+    /// The async version of [`GrpcBridge::list`].
+    pub async fn list_async(&self, prefix: &str) -> BridgeResult<Vec<String>> {
+        let request = ListRequest {
+            prefix: prefix.to_string(),
+        };
+        let response: ListResponse = self.call(LIST, prefix, request).await?;
+        Ok(response.keys)
+    }
+
+    async fn call<Request, Response>(
+        &self,
+        method: &'static str,
+        key: &str,
+        request: Request,
+    ) -> BridgeResult<Response>
+    where
+        Request: prost::Message + Send + Sync + 'static,
+        Response: prost::Message + Default + Send + Sync + 'static,
+    {
+        let mut client = self.client.clone();
+        client.ready().await.map_err(|e| {
+            let message = format!("gRPC {method} of {key} failed to connect: {e}");
+            std::io::Error::new(std::io::ErrorKind::NotConnected, message)
+        })?;
+        let codec = ProstCodec::<Request, Response>::default();
+        let path = PathAndQuery::from_static(method);
+        match client
+            .unary(tonic::Request::new(request), path, codec)
+            .await
+        {
+            Ok(response) => Ok(response.into_inner()),
+            Err(status) => Err(status_error(method, key, &status)),
+        }
+    }
+
+    // runs `request` to completion on the runtime of the bridge
+    fn block_on<T>(&self, request: impl Future<Output = BridgeResult<T>> + Send) -> BridgeResult<T>
+    where
+        T: Send,
+    {
+        let runtime = self
+            .runtime
+            .as_ref()
+            .expect("the bridge should not be dropped");
+        std::thread::scope(|scope| {
+            scope
+                .spawn(|| runtime.block_on(request))
+                .join()
+                .expect("request should complete without panicking")
+        })
+    }
+}
+
+impl Drop for GrpcBridge {
+    // runtimes cannot be dropped within an async context, which a bridge may be
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+fn status_error(method: &str, key: &str, status: &tonic::Status) -> std::io::Error {
+    use std::io::ErrorKind;
+    use tonic::Code;
+
+    let kind = match status.code() {
+        Code::NotFound => ErrorKind::NotFound,
+        Code::AlreadyExists | Code::Aborted | Code::FailedPrecondition => ErrorKind::AlreadyExists,
+        Code::DeadlineExceeded => ErrorKind::TimedOut,
+        Code::Unavailable => ErrorKind::ConnectionRefused,
+        Code::InvalidArgument | Code::OutOfRange => ErrorKind::InvalidInput,
+        Code::PermissionDenied | Code::Unauthenticated => ErrorKind::PermissionDenied,
+        _ => ErrorKind::Other,
+    };
+    let message = format!(
+        "gRPC {method} of {key} failed with {:?}: {}",
+        status.code(),
+        status.message()
+    );
+    std::io::Error::new(kind, message)
+}
+
+impl ConnectionBridge for GrpcBridge {
+    fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        self.block_on(self.get_async(key))
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.block_on(self.put_async(key, body))
+    }
+
+    async fn get_async(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        match self.get_validated_async(key, None).await? {
+            Validated::Modified { body, .. } => Ok(body),
+            Validated::NotModified => unreachable!("blobs without a validator are modified"),
+        }
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        let request = PutRequest {
+            key: key.to_string(),
+            body,
+        };
+        let _: PutResponse = self.call(PUT, key, request).await?;
+        Ok(())
+    }
+
+    fn get_validated(&self, key: &str, validator: Option<&str>) -> BridgeResult<Validated> {
+        self.block_on(self.get_validated_async(key, validator))
+    }
+
+    async fn get_validated_async(
+        &self,
+        key: &str,
+        validator: Option<&str>,
+    ) -> BridgeResult<Validated> {
+        let request = GetRequest {
+            key: key.to_string(),
+            if_none_match: validator.map(str::to_string),
+        };
+        let response: GetResponse = self.call(GET, key, request).await?;
+        if response.not_modified && validator.is_some() {
+            return Ok(Validated::NotModified);
+        }
+        Ok(Validated::Modified {
+            body: response.body,
+            validator: response.validator,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+
+    use tonic::transport::Server;
+    use tonic::transport::server::TcpIncoming;
+    use tower::service_fn;
+
+    use super::*;
+    use crate::Error;
+    use crate::identity::{KeyTemplate, Population, RemoteStore, tests::*};
+
+    type Blobs = Arc<Mutex<BTreeMap<String, Bytes>>>;
+
+    fn get(blobs: &Blobs, request: GetRequest) -> GetResponse {
+        let body = blobs.lock().unwrap().get(&request.key).cloned();
+        let validator = body
+            .as_ref()
+            .map(|body| blake3::hash(body).to_hex().to_string());
+        if validator.is_some() && request.if_none_match == validator {
+            return GetResponse {
+                not_modified: true,
+                ..Default::default()
+            };
+        }
+        GetResponse {
+            body,
+            validator,
+            not_modified: false,
+        }
+    }
+
+    // serves the blob store from memory, returning its url
+    async fn blob_store() -> String {
+        let blobs = Blobs::default();
+        let service = service_fn(move |request: http::Request<_>| {
+            let blobs = blobs.clone();
+            async move {
+                let response = match request.uri().path() {
+                    GET => {
+                        let codec = ProstCodec::<GetResponse, GetRequest>::default();
+                        let get = service_fn(|request: tonic::Request<GetRequest>| {
+                            let response = get(&blobs, request.into_inner());
+                            async { Ok(tonic::Response::new(response)) }
+                        });
+                        tonic::server::Grpc::new(codec).unary(get, request).await
+                    }
+                    PUT => {
+                        let codec = ProstCodec::<PutResponse, PutRequest>::default();
+                        let put = service_fn(|request: tonic::Request<PutRequest>| {
+                            let PutRequest { key, body } = request.into_inner();
+                            blobs.lock().unwrap().insert(key, body);
+                            async { Ok(tonic::Response::new(PutResponse {})) }
+                        });
+                        tonic::server::Grpc::new(codec).unary(put, request).await
+                    }
+                    LIST => {
+                        let codec = ProstCodec::<ListResponse, ListRequest>::default();
+                        let list = service_fn(|request: tonic::Request<ListRequest>| {
+                            let prefix = request.into_inner().prefix;
+                            let blobs = blobs.lock().unwrap();
+                            let keys = blobs.keys().filter(|key| key.starts_with(&prefix));
+                            let keys = keys.cloned().collect();
+                            async { Ok(tonic::Response::new(ListResponse { keys })) }
+                        });
+                        tonic::server::Grpc::new(codec).unary(list, request).await
+                    }
+                    _ => tonic::Status::unimplemented("unknown method").into_http(),
+                };
+                Ok::<_, Infallible>(response)
+            }
+        });
+        let incoming = TcpIncoming::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let url = format!("http://{}", incoming.local_addr().unwrap());
+        tokio::spawn(Server::builder().serve_with_incoming(service, incoming));
+        url
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_grpc_bridge() -> Result<(), Error> {
+        let bridge = GrpcBridge::new(&blob_store().await)?;
+        assert!(GrpcBridge::new("not a url").is_err());
+
+        assert_eq!(bridge.get_async("br/abc").await?, None);
+        bridge.put_async("br/abc", Bytes::from("blob\n")).await?;
+        let Validated::Modified { body, validator } =
+            bridge.get_validated_async("br/abc", None).await?
+        else {
+            panic!("the blob should be modified");
+        };
+        assert_eq!(body.as_deref(), Some(&b"blob\n"[..]));
+        assert!(matches!(
+            bridge
+                .get_validated_async("br/abc", validator.as_deref())
+                .await?,
+            Validated::NotModified
+        ));
+        assert_eq!(bridge.list_async("br/").await?, vec!["br/abc"]);
+
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let template = KeyTemplate::new("{domain}/{key}", "br")?;
+        let mut store = RemoteStore::new(bridge).with_key_template(template);
+        let identity = population.identity_async("a@b.br", &mut store).await?;
+        // blocking calls can be made from within the runtime
+        assert_eq!(population.identity("a@b.br", &mut store)?, identity);
+        assert_eq!(store.bridge.list("uy/")?, Vec::<String>::new());
+
+        // failures are never mistaken for absent blobs
+        let unreachable = GrpcBridge::new("http://127.0.0.1:9")?;
+        let e = crate::Error::from(unreachable.get("br/abc").unwrap_err());
+        assert!(e.is_retryable(), "{e}");
+        Ok(())
+    }
+}
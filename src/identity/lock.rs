@@ -0,0 +1,312 @@
+//! Locks which keep writers of a blob from replacing each other's changes.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::fs::FsBridge;
+use super::rate_limit::sleep;
+use super::storage::BridgeResult;
+
+// how long to wait before trying again to take a lock which is held
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Excludes other writers from a blob while a [`super::RemoteStore`] reads, changes and writes
+/// it, see [`super::RemoteStore::with_lock_provider`]. Locks are named by the key which the
+/// store passes to its bridge, so every writer of a domain should use the same key template.
+///
+/// `acquire` waits until the lock is held, and fails with [`io::ErrorKind::TimedOut`] if it
+/// is not taken in time. A lock is only released by the provider which acquired it.
+/// The async methods call the blocking ones by default.
+pub trait LockProvider: Send + Sync {
+    /// Wait until the lock of `key` is held.
+    fn acquire(&self, key: &str) -> BridgeResult<()>;
+    /// Give up the lock of `key`.
+    fn release(&self, key: &str) -> BridgeResult<()>;
+    /// The async version of `acquire`.
+    fn acquire_async<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> Pin<Box<dyn Future<Output = BridgeResult<()>> + Send + 'a>> {
+        Box::pin(std::future::ready(self.acquire(key)))
+    }
+    /// The async version of `release`.
+    fn release_async<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> Pin<Box<dyn Future<Output = BridgeResult<()>> + Send + 'a>> {
+        Box::pin(std::future::ready(self.release(key)))
+    }
+}
+
+impl<L: LockProvider + ?Sized> LockProvider for Arc<L> {
+    fn acquire(&self, key: &str) -> BridgeResult<()> {
+        (**self).acquire(key)
+    }
+
+    fn release(&self, key: &str) -> BridgeResult<()> {
+        (**self).release(key)
+    }
+
+    fn acquire_async<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> Pin<Box<dyn Future<Output = BridgeResult<()>> + Send + 'a>> {
+        (**self).acquire_async(key)
+    }
+
+    fn release_async<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> Pin<Box<dyn Future<Output = BridgeResult<()>> + Send + 'a>> {
+        (**self).release_async(key)
+    }
+}
+
+/// A [`LockProvider`] held by a store.
+#[derive(Clone)]
+pub(crate) struct Locks(Arc<dyn LockProvider>);
+
+impl Locks {
+    pub(crate) fn new(provider: Arc<dyn LockProvider>) -> Self {
+        Self(provider)
+    }
+
+    pub(crate) fn provider(&self) -> &dyn LockProvider {
+        &*self.0
+    }
+}
+
+impl std::fmt::Debug for Locks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Locks").finish_non_exhaustive()
+    }
+}
+
+// calls `try_acquire` until it takes the lock, for at most `timeout`
+pub(crate) fn wait_for_lock(
+    key: &str,
+    timeout: Duration,
+    mut try_acquire: impl FnMut() -> BridgeResult<bool>,
+) -> BridgeResult<()> {
+    let deadline = Instant::now() + timeout;
+    while !try_acquire()? {
+        if Instant::now() >= deadline {
+            return Err(lock_timed_out(key));
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+pub(crate) async fn wait_for_lock_async(
+    key: &str,
+    timeout: Duration,
+    mut try_acquire: impl FnMut() -> BridgeResult<bool>,
+) -> BridgeResult<()> {
+    let deadline = Instant::now() + timeout;
+    while !try_acquire()? {
+        if Instant::now() >= deadline {
+            return Err(lock_timed_out(key));
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+    Ok(())
+}
+
+fn lock_timed_out(key: &str) -> io::Error {
+    let message = format!("lock {key:?} is held by another writer");
+    io::Error::new(io::ErrorKind::TimedOut, message)
+}
+
+// an error for a lock which is no longer held by its provider
+pub(crate) fn lock_lost(key: &str) -> io::Error {
+    io::Error::other(format!(
+        "lock {key:?} was taken over before it was released"
+    ))
+}
+
+pub(crate) fn lock_not_held(key: &str) -> io::Error {
+    let message = format!("lock {key:?} is not held");
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// identifies one acquisition of a lock, among every process sharing it
+pub(crate) fn lock_token() -> String {
+    static ACQUIRED: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    format!(
+        "{}-{nanos}-{}",
+        std::process::id(),
+        ACQUIRED.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Takes a lock by creating the file `{key}.lock` under a directory, which fails while
+/// another writer holds it, and releases it by removing the file. Suits writers on one host,
+/// or on hosts sharing a network filesystem which creates files atomically, such as NFSv3+.
+///
+/// A lock file older than the expiry, 30 seconds by default, is removed by the next writer,
+/// so that a lock is not held forever by a process which stopped without releasing it.
+/// The expiry should be much longer than an insert takes. Relies on the system clock, which is
+/// unavailable on `wasm32-unknown-unknown`.
+#[derive(Debug)]
+pub struct FileLock {
+    files: FsBridge,
+    expiry: Duration,
+    timeout: Duration,
+    // key -> token of the lock file
+    held: Mutex<HashMap<String, String>>,
+}
+
+impl FileLock {
+    /// Keep lock files under `root`, which is created with the first lock.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            files: FsBridge::new(root),
+            expiry: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+            held: Mutex::default(),
+        }
+    }
+
+    /// Remove lock files which were last changed longer than `expiry` ago.
+    pub fn with_expiry(mut self, expiry: Duration) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    /// Wait at most `timeout` for a lock, 10 seconds by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn lock_path(&self, key: &str) -> BridgeResult<PathBuf> {
+        self.files.path(&format!("{key}.lock"))
+    }
+
+    // true if the lock was taken
+    fn try_acquire(&self, key: &str) -> BridgeResult<bool> {
+        let path = self.lock_path(key)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let token = lock_token();
+        match std::fs::File::create_new(&path) {
+            Ok(mut file) => {
+                if let Err(e) = file.write_all(token.as_bytes()) {
+                    let _ = std::fs::remove_file(&path);
+                    return Err(e);
+                }
+                self.held.lock().unwrap().insert(key.to_string(), token);
+                Ok(true)
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let expired = std::fs::metadata(&path)
+                    .and_then(|metadata| metadata.modified())
+                    .is_ok_and(|modified| {
+                        modified
+                            .elapsed()
+                            .is_ok_and(|elapsed| elapsed > self.expiry)
+                    });
+                if expired {
+                    match std::fs::remove_file(&path) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                        _ => {}
+                    }
+                }
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl LockProvider for FileLock {
+    fn acquire(&self, key: &str) -> BridgeResult<()> {
+        wait_for_lock(key, self.timeout, || self.try_acquire(key))
+    }
+
+    /// Fails if the lock file was removed as expired, and perhaps taken by another writer.
+    fn release(&self, key: &str) -> BridgeResult<()> {
+        let token = self.held.lock().unwrap().remove(key);
+        let Some(token) = token else {
+            return Err(lock_not_held(key));
+        };
+        let path = self.lock_path(key)?;
+        match std::fs::read(&path) {
+            Ok(held) if held == token.as_bytes() => std::fs::remove_file(&path),
+            Ok(_) => Err(lock_lost(key)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(lock_lost(key)),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn acquire_async<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> Pin<Box<dyn Future<Output = BridgeResult<()>> + Send + 'a>> {
+        Box::pin(wait_for_lock_async(key, self.timeout, || {
+            self.try_acquire(key)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use crate::identity::{Population, RemoteStore, StorageState, tests::*};
+
+    #[test]
+    fn test_file_lock() -> Result<(), Error> {
+        let tmp_dir = std::env::var("TMPDIR").unwrap_or("/tmp".to_string());
+        let root = PathBuf::from(tmp_dir).join(format!("perfume_test_lock_{}", std::process::id()));
+        let locks = Arc::new(FileLock::new(&root).with_timeout(Duration::from_millis(50)));
+        locks.acquire("br/abc")?;
+        let error = locks.acquire("br/abc").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(root.join("br/abc.lock").exists());
+        locks.release("br/abc")?;
+        assert!(!root.join("br/abc.lock").exists());
+        assert!(locks.release("br/abc").is_err());
+
+        // an expired lock is taken over, and can no longer be released by its holder
+        let expiring = FileLock::new(&root).with_expiry(Duration::ZERO);
+        let other = FileLock::new(&root).with_expiry(Duration::ZERO);
+        expiring.acquire("br/abc")?;
+        std::thread::sleep(Duration::from_millis(10));
+        other.acquire("br/abc")?;
+        assert!(expiring.release("br/abc").is_err());
+        other.release("br/abc")?;
+
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let mut store = RemoteStore::new(MockBridge::default()).with_lock_provider(locks.clone());
+        let identity = population.identity("a@b.br", &mut store)?;
+        assert!(store.delete("br", &identity.storage)?);
+        let key = identity.storage.key.as_str();
+        assert!(!root.join(format!("{key}.lock")).exists());
+
+        // a store waits for the lock held by another writer
+        locks.acquire(key)?;
+        let error = store.digest_offset("br", &identity.storage).unwrap_err();
+        assert!(error.is_retryable());
+        locks.release(key)?;
+        assert!(population.identity("c@d.br", &mut store).is_ok());
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+use std::sync::Mutex;
+
+use async_generic::async_generic;
+
+use crate::Error;
+use crate::lru::{Lru, MemoryBudget};
+
+use super::Identity;
+use super::population::Population;
+use super::storage::{Storage, StorageState};
+
+/// A [`Population`] which remembers up to `capacity` of the identities it has resolved,
+/// so that an identifier which is seen repeatedly only reaches the [`StorageState`] once.
+/// Identities are remembered by their hash, so identifiers are never retained.
+/// See [`Population::memoized`].
+pub struct MemoizedPopulation<'dom> {
+    population: Population<'dom>,
+    // storage object -> (friendly name, offset)
+    cache: Mutex<Lru<Storage, (String, usize)>>,
+}
+
+impl std::fmt::Debug for MemoizedPopulation<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoizedPopulation")
+            .field("population", &self.population)
+            .field("remembered", &self.len())
+            .finish()
+    }
+}
+
+impl<'dom> Population<'dom> {
+    /// Remember up to `capacity` resolved identities, evicting the least recently used.
+    pub fn memoized(self, capacity: usize) -> MemoizedPopulation<'dom> {
+        MemoizedPopulation {
+            population: self,
+            cache: Mutex::new(Lru::new(capacity)),
+        }
+    }
+}
+
+impl<'dom> MemoizedPopulation<'dom> {
+    /// The same as [`Population::identity`], but without using `state` for remembered identities.
+    #[async_generic]
+    #[allow(unused_assignments)]
+    pub fn identity(
+        &self,
+        identifier: &str,
+        state: &mut impl StorageState,
+    ) -> Result<Identity<'_>, Error> {
+        let storage = self.population.storage_object(identifier);
+        let remembered = self.cache.lock().unwrap().get(&storage);
+        counter!(
+            "perfume_cache_requests_total",
+            1,
+            "cache" => "memoized",
+            "outcome" => if remembered.is_some() { "hit" } else { "miss" }
+        );
+        if let Some((friendly_name, offset)) = remembered {
+            return Ok(Identity {
+                domain: self.population.domain,
+                friendly_name,
+                storage,
+                offset,
+            });
+        }
+
+        let mut identity = None;
+        if _async {
+            identity = Some(self.population.identity_async(identifier, state).await?);
+        } else {
+            identity = Some(self.population.identity(identifier, state)?);
+        }
+        let identity = identity.unwrap();
+
+        self.cache.lock().unwrap().insert(
+            identity.storage.clone(),
+            (identity.friendly_name.clone(), identity.offset),
+        );
+        Ok(identity)
+    }
+
+    /// Limit the memory held by remembered identities, together with any other caches which
+    /// share `budget`. Forgets any identities which are already remembered.
+    pub fn with_memory_budget(self, budget: &MemoryBudget) -> Self {
+        self.cache
+            .lock()
+            .unwrap()
+            .set_budget(budget, |storage, (friendly_name, _offset)| {
+                storage.key.as_str().len() + storage.digest.as_str().len() + friendly_name.len()
+            });
+        self
+    }
+
+    /// The population which identities are resolved from.
+    pub fn population(&self) -> &Population<'dom> {
+        &self.population
+    }
+
+    /// The number of remembered identities.
+    pub fn len(&self) -> usize {
+        self.cache.lock().unwrap().len()
+    }
+
+    /// True if no identities are remembered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forget all remembered identities.
+    pub fn clear(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    /// Write the remembered identities to `path`, such as on shutdown, so that another process
+    /// can [`MemoizedPopulation::warm`] its cache with them. Only the storage objects and offsets
+    /// of identities are written, so the file holds neither identifiers nor names.
+    /// The file is replaced atomically. Returns the number of identities written.
+    #[cfg(feature = "bincode")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "bincode")))]
+    pub fn persist(&self, path: impl AsRef<std::path::Path>) -> Result<usize, Error> {
+        let cache = CacheFile {
+            version: CACHE_FILE_VERSION,
+            fingerprint: self.fingerprint(),
+            entries: self
+                .cache
+                .lock()
+                .unwrap()
+                .entries()
+                .into_iter()
+                .map(|(storage, (_friendly_name, offset))| {
+                    let (key, digest) = (storage.key.as_str(), storage.digest.as_str());
+                    (key.to_string(), digest.to_string(), offset)
+                })
+                .collect(),
+        };
+
+        let path = path.as_ref();
+        let partial = path.with_extension("partial");
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&partial)?);
+        bincode::serde::encode_into_std_write(&cache, &mut file, bincode::config::standard())
+            .map_err(std::io::Error::other)?;
+        std::io::Write::flush(&mut file)?;
+        std::fs::rename(&partial, path)?;
+        Ok(cache.entries.len())
+    }
+
+    /// Remember the identities written to `path` by [`MemoizedPopulation::persist`], such as on
+    /// startup, so that they don't reach the [`StorageState`] again. Names are derived again from
+    /// the population, up to its capacity, and the most recently used identities are kept.
+    /// A missing file, or one written by a population with another secret, warms nothing.
+    /// Returns the number of identities read.
+    #[cfg(feature = "bincode")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "bincode")))]
+    pub fn warm(&self, path: impl AsRef<std::path::Path>) -> Result<usize, Error> {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut file = std::io::BufReader::new(file);
+        let cache: CacheFile =
+            bincode::serde::decode_from_std_read(&mut file, bincode::config::standard())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        if cache.version != CACHE_FILE_VERSION || cache.fingerprint != self.fingerprint() {
+            return Ok(0);
+        }
+
+        let mut lru = self.cache.lock().unwrap();
+        let mut warmed = 0;
+        for (key, digest, offset) in cache.entries {
+            let storage = Storage {
+                key: key.parse()?,
+                digest: digest.parse()?,
+            };
+            if let Some(friendly_name) = self.population.friendly_name(&storage, offset) {
+                lru.insert(storage, (friendly_name, offset));
+                warmed += 1;
+            }
+        }
+        Ok(warmed)
+    }
+
+    // distinguishes the storage objects of populations with different secrets
+    #[cfg(feature = "bincode")]
+    fn fingerprint(&self) -> String {
+        let storage = self.population.storage_object(FINGERPRINT_IDENTIFIER);
+        format!("{}{}", storage.key.as_str(), storage.digest.as_str())
+    }
+}
+
+#[cfg(feature = "bincode")]
+const CACHE_FILE_VERSION: u32 = 1;
+
+#[cfg(feature = "bincode")]
+const FINGERPRINT_IDENTIFIER: &str = "perfume memoized population";
+
+/// The file written by [`MemoizedPopulation::persist`].
+#[cfg(feature = "bincode")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CacheFile {
+    version: u32,
+    fingerprint: String,
+    // (storage key, digest, offset), from the least to the most recently used
+    entries: Vec<(String, String, usize)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::{storage::RemoteStore, tests::*};
+
+    #[test]
+    fn test_memoized_identity() -> Result<(), Error> {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        }
+        .memoized(1);
+        let mut store = RemoteStore::new(MockBridge::default());
+
+        let first = brazilian.identity("a@b.br", &mut store)?;
+        // the remembered identity does not need a bridge
+        let mut unreachable = RemoteStore::new(MockBridge::default());
+        assert_eq!(brazilian.identity("a@b.br", &mut unreachable)?, first);
+        assert!(unreachable.bridge.is_empty());
+
+        // evicted by capacity
+        brazilian.identity("c@d.br", &mut store)?;
+        assert_eq!(brazilian.len(), 1);
+        brazilian.identity("a@b.br", &mut unreachable)?;
+        assert!(!unreachable.bridge.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_budget() -> Result<(), Error> {
+        let budget = MemoryBudget::new(100);
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        }
+        .memoized(10)
+        .with_memory_budget(&budget);
+        let mut store = RemoteStore::new(MockBridge::default());
+
+        // each identity takes about 90 bytes
+        brazilian.identity("a@b.br", &mut store)?;
+        brazilian.identity("c@d.br", &mut store)?;
+        assert_eq!(brazilian.len(), 1);
+        assert!(budget.used() > 0 && budget.used() <= budget.limit());
+        drop(brazilian);
+        assert_eq!(budget.used(), 0);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_persist_and_warm() -> Result<(), Error> {
+        let tmp_dir = std::env::var("TMPDIR").unwrap_or("/tmp".to_string());
+        let path = std::path::Path::new(&tmp_dir).join("perfume_test_memoized.bin");
+        let secret = b"0123456789abcdef0123456789abcdef";
+        let population = || Population::new("br", secret, &PERFUME_INGREDIENTS);
+        let brazilian = population()?.memoized(10);
+        let mut store = RemoteStore::new(MockBridge::default());
+        let first = brazilian.identity("a@b.br", &mut store)?;
+        let second = brazilian.identity("c@d.br", &mut store)?;
+        assert_eq!(brazilian.persist(&path)?, 2);
+
+        // a restarted process
+        let restarted = population()?.memoized(1);
+        assert_eq!(restarted.warm(&path)?, 2);
+        assert_eq!(restarted.len(), 1);
+        let mut unreachable = RemoteStore::new(MockBridge::default());
+        assert_eq!(restarted.identity("c@d.br", &mut unreachable)?, second);
+        assert_eq!(
+            restarted.identity("c@d.br", &mut unreachable)?.offset,
+            second.offset
+        );
+        assert!(unreachable.bridge.is_empty());
+        assert_eq!(restarted.identity("a@b.br", &mut store)?, first);
+
+        let rotated = Population::new(
+            "br",
+            b"fedcba9876543210fedcba9876543210",
+            &PERFUME_INGREDIENTS,
+        )?;
+        assert_eq!(rotated.memoized(10).warm(&path)?, 0);
+        std::fs::remove_file(&path)?;
+        assert_eq!(population()?.memoized(10).warm(&path)?, 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+//! A [`StorageState`] which keeps the offsets of every domain in memory.
+
+use std::collections::HashMap;
+use std::future::Future;
+
+use crate::hex_string::HexString;
+use crate::{Error, STORAGE_DIGEST_LENGTH, STORAGE_KEY_LENGTH};
+
+use super::concurrent::blob;
+use super::snapshot::Snapshot;
+use super::storage::{Storage, StorageState};
+
+type Digests = HashMap<HexString<STORAGE_DIGEST_LENGTH>, usize>;
+
+/// Implements [`StorageState`] in process memory, for the offsets of any number of domains.
+/// Useful for unit tests and short-lived programs whose names need not outlive them, and as a
+/// reference for implementing `StorageState`: the first digest of each storage key is given
+/// offset 0, and each new digest the number of digests stored before it.
+///
+/// Offsets are not persisted, but can be saved with [`InMemoryStore::snapshot`].
+/// See [`super::ConcurrentStore`] for a store which can be shared by many threads.
+#[derive(Debug, Default, Clone)]
+pub struct InMemoryStore {
+    // indexed by domain, then by storage key
+    domains: HashMap<String, HashMap<HexString<STORAGE_KEY_LENGTH>, Digests>>,
+}
+
+impl InMemoryStore {
+    /// An empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of stored digests, of every domain.
+    pub fn len(&self) -> usize {
+        self.domains
+            .values()
+            .flat_map(HashMap::values)
+            .map(HashMap::len)
+            .sum()
+    }
+
+    /// True if no digests are stored.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The offset of the digest of `storage`, if it is stored.
+    pub fn stored_offset(&self, domain: &str, storage: &Storage) -> Option<usize> {
+        let digests = self.domains.get(domain)?.get(&storage.key)?;
+        digests.get(&storage.digest).copied()
+    }
+
+    /// The stored offsets of `domain` as storage blobs, which can be imported into a
+    /// [`super::RemoteStore`] with [`super::RemoteStore::import`].
+    pub fn snapshot(&self, domain: &str) -> Snapshot {
+        let mut keys = self
+            .domains
+            .get(domain)
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        keys.sort_unstable_by_key(|(key, _digests)| key.as_str());
+        Snapshot {
+            blobs: keys
+                .into_iter()
+                .map(|(key, digests)| (key.clone(), blob(digests)))
+                .collect(),
+        }
+    }
+}
+
+impl StorageState for InMemoryStore {
+    fn digest_offset(&mut self, domain: &str, storage: &Storage) -> Result<usize, Error> {
+        let keys = self.domains.entry(domain.to_string()).or_default();
+        let digests = keys.entry(storage.key.clone()).or_default();
+        let next_offset = digests.len();
+        Ok(*digests.entry(storage.digest.clone()).or_insert(next_offset))
+    }
+
+    fn digest_offset_async(
+        &mut self,
+        domain: &str,
+        storage: &Storage,
+    ) -> impl Future<Output = Result<usize, Error>> + Send {
+        std::future::ready(self.digest_offset(domain, storage))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::{BlobFormat, Population, RemoteStore, tests::*};
+
+    #[test]
+    fn test_in_memory_store() -> Result<(), Error> {
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let mut store = InMemoryStore::new();
+        let storage = population.storage_object("a@b.br");
+        let mut next = storage.clone();
+        next.digest = random_hex_string();
+        assert_eq!(store.digest_offset("br", &next)?, 0);
+
+        let identity = population.identity("a@b.br", &mut store)?;
+        assert_eq!(identity.offset, 1);
+        assert_eq!(population.identity("a@b.br", &mut store)?, identity);
+        assert_eq!(store.stored_offset("br", &storage), Some(1));
+        // domains have their own offsets
+        assert_eq!(store.digest_offset("uy", &storage)?, 0);
+        assert_eq!(store.len(), 3);
+
+        // offsets are kept by a snapshot
+        let snapshot = store.snapshot("br");
+        assert_eq!(snapshot.identity_counts(BlobFormat::Text)?, vec![2]);
+        let mut remote = RemoteStore::new(MockBridge::default());
+        remote.import(&snapshot)?;
+        assert_eq!(population.identity("a@b.br", &mut remote)?, identity);
+        assert!(InMemoryStore::new().snapshot("br").blobs.is_empty());
+        Ok(())
+    }
+}
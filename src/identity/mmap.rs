@@ -0,0 +1,187 @@
+//! A [`StorageState`] which searches memory-mapped blobs in place.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use memmap2::Mmap;
+
+use crate::{Error, Operation};
+
+use super::fs::FsBridge;
+use super::storage::{
+    ConnectionBridge, PING_KEY, Storage, StorageState, deleted_digest, insert_record, search_blob,
+};
+
+/// Keeps the text blobs of each domain as files under a root directory, at `{domain}/{key}`,
+/// and maps them into memory, so that digests are found by a binary search over the records
+/// of the mapped file without reading or copying it. Lookups of the digests of a Brazil-sized
+/// population only cost a `stat` of their blob, which is mapped again once it is replaced.
+///
+/// New digests are written as an [`FsBridge`] writes blobs, to a temporary file which is renamed
+/// over the blob, so mapped files are never changed in place. The layout is that of an
+/// `FsBridge` with a [`crate::identity::KeyTemplate`] of `{domain}/{key}`, so the blobs can
+/// also be read by a [`crate::identity::RemoteStore`]. Only one store should assign the offsets
+/// of a domain, since concurrent writers replace each other's blobs.
+#[derive(Debug)]
+pub struct MmapStore {
+    bridge: FsBridge,
+    // bridge key -> mapped blob
+    maps: HashMap<String, Mapped>,
+}
+
+// a mapped file, and its metadata when it was mapped
+#[derive(Debug)]
+struct Mapped {
+    map: Mmap,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl MmapStore {
+    /// Keep blobs under `root`, which is created with the first blob.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            bridge: FsBridge::new(root),
+            maps: HashMap::new(),
+        }
+    }
+
+    /// The directory holding the blobs.
+    pub fn root(&self) -> &Path {
+        self.bridge.root()
+    }
+
+    /// The offset of the digest of `storage`, if it is stored.
+    pub fn stored_offset(
+        &mut self,
+        domain: &str,
+        storage: &Storage,
+    ) -> Result<Option<usize>, Error> {
+        Ok(self.search(domain, storage)?.ok())
+    }
+
+    // the offset of the digest of `storage`, or else the next offset of its blob
+    fn search(&mut self, domain: &str, storage: &Storage) -> Result<Result<usize, usize>, Error> {
+        let key = storage.key.as_str();
+        let context = |operation| move |e| Error::storage(e, key, operation).in_domain(domain);
+        let blob = self
+            .mapped(&format!("{domain}/{key}"))
+            .map_err(context(Operation::Get))?;
+        match search_blob(blob, storage.digest.as_str()).map_err(context(Operation::Parse))? {
+            Ok((_offset, flag)) if flag.is_deleted() => {
+                Err(context(Operation::Get)(deleted_digest()))
+            }
+            Ok((offset, _flag)) => Ok(Ok(offset)),
+            Err(next_offset) => Ok(Err(next_offset)),
+        }
+    }
+
+    // the blob of `resource`, which is mapped again if its file was replaced since it was mapped
+    fn mapped(&mut self, resource: &str) -> std::io::Result<&[u8]> {
+        let path = self.bridge.path(resource)?;
+        let metadata = match std::fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.maps.remove(resource);
+                return Ok(&[]);
+            }
+            Err(e) => return Err(e),
+        };
+        let modified = metadata.modified().ok();
+        let current = self
+            .maps
+            .get(resource)
+            .is_some_and(|mapped| mapped.len == metadata.len() && mapped.modified == modified);
+        if !current {
+            let file = std::fs::File::open(&path)?;
+            // SAFETY: blobs are replaced by renaming another file over them, never changed in
+            // place, so the mapped file keeps its contents while it is mapped
+            let map = unsafe { Mmap::map(&file)? };
+            let mapped = Mapped {
+                len: map.len() as u64,
+                map,
+                modified,
+            };
+            self.maps.insert(resource.to_string(), mapped);
+        }
+        Ok(&self.maps[resource].map[..])
+    }
+}
+
+impl StorageState for MmapStore {
+    fn digest_offset(&mut self, domain: &str, storage: &Storage) -> Result<usize, Error> {
+        let next_offset = match self.search(domain, storage)? {
+            Ok(offset) => return Ok(offset),
+            Err(next_offset) => next_offset,
+        };
+        let key = storage.key.as_str();
+        let resource = format!("{domain}/{key}");
+        let parse = |e| Error::storage(e, key, Operation::Parse).in_domain(domain);
+        let blob = self.mapped(&resource).map_err(parse)?;
+        let blob = insert_record(blob, storage.digest.as_str(), next_offset).map_err(parse)?;
+        self.bridge
+            .put(&resource, blob)
+            .map_err(|e| Error::storage(e, key, Operation::Put).in_domain(domain))?;
+        Ok(next_offset)
+    }
+
+    fn digest_offset_async(
+        &mut self,
+        domain: &str,
+        storage: &Storage,
+    ) -> impl Future<Output = Result<usize, Error>> + Send {
+        std::future::ready(self.digest_offset(domain, storage))
+    }
+
+    /// Checks that the root directory can be created.
+    fn health_check(&mut self) -> Result<(), Error> {
+        std::fs::create_dir_all(self.root())
+            .map_err(|e| Error::storage(e, PING_KEY, Operation::Get))
+    }
+
+    fn health_check_async(&mut self) -> impl Future<Output = Result<(), Error>> + Send {
+        std::future::ready(self.health_check())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::{KeyTemplate, Population, RemoteStore, tests::*};
+
+    #[test]
+    fn test_mmap_store() -> Result<(), Error> {
+        let tmp_dir = std::env::var("TMPDIR").unwrap_or("/tmp".to_string());
+        let root = Path::new(&tmp_dir).join(format!("perfume_test_mmap_{}", std::process::id()));
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let mut store = MmapStore::new(&root);
+        store.health_check()?;
+        let storage = population.storage_object("a@b.br");
+        let mut next = storage.clone();
+        next.digest = random_hex_string();
+        assert_eq!(store.digest_offset("br", &next)?, 0);
+        let identity = population.identity("a@b.br", &mut store)?;
+        assert_eq!(identity.offset, 1);
+        assert_eq!(population.identity("a@b.br", &mut store)?, identity);
+        assert_eq!(store.stored_offset("br", &next)?, Some(0));
+        assert_eq!(store.stored_offset("uy", &next)?, None);
+
+        // the blobs are those of a remote store
+        let template = KeyTemplate::new("{domain}/{key}", "br")?;
+        let mut remote = RemoteStore::new(FsBridge::new(&root)).with_key_template(template);
+        assert_eq!(population.identity("a@b.br", &mut remote)?, identity);
+        let another = population.identity("c@d.br", &mut remote)?;
+        // which the mapped store sees once they are replaced
+        assert_eq!(population.identity("c@d.br", &mut store)?, another);
+        assert_eq!(store.stored_offset("br", &next)?, Some(0));
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
@@ -1,13 +1,150 @@
 //! Persistent random name generator.
 
+mod audit;
+mod buffered;
+mod cached;
+#[cfg(feature = "cbor")]
+mod cbor;
+mod circuit;
+mod coalesce;
+#[cfg(any(feature = "zstd", feature = "gzip"))]
+mod compressed;
+mod concurrent;
+#[cfg(feature = "aes-gcm")]
+mod encrypted;
+#[cfg(feature = "etcd")]
+mod etcd;
+mod fallback;
+mod format;
+mod fs;
+mod fsck;
+#[cfg(feature = "tonic")]
+mod grpc;
+mod lock;
+mod memoize;
+mod memory;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod multi_domain;
+#[cfg(feature = "nats")]
+mod nats;
+mod paged;
+mod partition;
 mod population;
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(feature = "prost")]
+#[cfg_attr(docsrs, doc(cfg(feature = "prost")))]
+pub mod proto;
+mod rate_limit;
+mod read_only;
+mod redis;
+mod render;
+#[cfg(feature = "reqwest")]
+mod reqwest;
+mod retry;
+#[cfg(feature = "aws")]
+mod s3;
+mod secret;
+#[cfg(feature = "sled")]
+mod sled;
+mod snapshot;
+#[cfg(feature = "sqlite")]
+mod sqlite;
 mod storage;
+mod timeout;
+#[cfg(feature = "ureq")]
+mod ureq;
+#[cfg(feature = "webdav")]
+mod webdav;
 
+pub use crate::lru::MemoryBudget;
+pub use audit::{AuditAction, AuditRecord, AuditSink};
+pub use buffered::BufferedStore;
+pub use cached::CachedStore;
+#[cfg(feature = "cbor")]
+#[cfg_attr(docsrs, doc(cfg(feature = "cbor")))]
+pub use cbor::{IdentityRecord, read_identities, write_identities};
+pub use circuit::{CircuitBreakerBridge, CircuitState};
+pub use coalesce::CoalescingStore;
+#[cfg(any(feature = "zstd", feature = "gzip"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "zstd", feature = "gzip"))))]
+pub use compressed::{CompressedBridge, Compression};
+pub use concurrent::ConcurrentStore;
+#[cfg(feature = "aes-gcm")]
+#[cfg_attr(docsrs, doc(cfg(feature = "aes-gcm")))]
+pub use encrypted::EncryptedBridge;
+#[cfg(feature = "etcd")]
+#[cfg_attr(docsrs, doc(cfg(feature = "etcd")))]
+pub use etcd::EtcdBridge;
+pub use fallback::FallbackBridge;
+pub use format::{BINARY_RECORD_LENGTH, BlobFormat};
+pub use fs::FsBridge;
+pub use fsck::{BlobCheck, BlobIssue, RecoveryReport, check_blob};
+#[cfg(feature = "tonic")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tonic")))]
+pub use grpc::GrpcBridge;
+pub use lock::{FileLock, LockProvider};
+pub use memoize::MemoizedPopulation;
+pub use memory::InMemoryStore;
+#[cfg(feature = "mmap")]
+#[cfg_attr(docsrs, doc(cfg(feature = "mmap")))]
+pub use mmap::MmapStore;
+pub use multi_domain::{identities_in_domains, identities_in_domains_async};
+#[cfg(feature = "nats")]
+#[cfg_attr(docsrs, doc(cfg(feature = "nats")))]
+pub use nats::NatsKvBridge;
+pub use paged::PagedBridge;
+pub use partition::{PartitionedStore, TimeBuckets};
 pub use population::{Ingredients, Population};
-pub use storage::{ConnectionBridge, RemoteStore, Storage, StorageState};
+#[cfg(feature = "postgres")]
+#[cfg_attr(docsrs, doc(cfg(feature = "postgres")))]
+pub use postgres::PostgresStore;
+pub use rate_limit::RateLimitedBridge;
+pub use read_only::ReadOnlyStore;
+pub use redis::RedisLock;
+#[cfg(feature = "reqwest")]
+#[cfg_attr(docsrs, doc(cfg(feature = "reqwest")))]
+pub use reqwest::ReqwestBridge;
+pub use retry::{RetryBridge, is_transient};
+#[cfg(feature = "aws")]
+#[cfg_attr(docsrs, doc(cfg(feature = "aws")))]
+pub use s3::S3Bridge;
+#[cfg(feature = "aws-kms")]
+#[cfg_attr(docsrs, doc(cfg(feature = "aws-kms")))]
+pub use secret::AwsKmsSecret;
+#[cfg(feature = "gcp-kms")]
+#[cfg_attr(docsrs, doc(cfg(feature = "gcp-kms")))]
+pub use secret::GcpKmsSecret;
+pub use secret::{CachedSecret, EnvSecret, FileSecret, MIN_SECRET_LENGTH, SecretProvider};
+#[cfg(feature = "sled")]
+#[cfg_attr(docsrs, doc(cfg(feature = "sled")))]
+pub use sled::SledBridge;
+pub use snapshot::Snapshot;
+#[cfg(feature = "sqlite")]
+#[cfg_attr(docsrs, doc(cfg(feature = "sqlite")))]
+pub use sqlite::SqliteStore;
+#[cfg(feature = "server")]
+pub(crate) use storage::slice_range;
+pub use storage::{
+    CONFLICT_ATTEMPTS, ConnectionBridge, DIGEST_CHECK_LENGTH, FORMAT_VERSION, KeyTemplate,
+    OFFSET_WIDTH, PING_KEY, RECORD_LENGTH, RecordFlag, RemoteStore, Storage, StorageState,
+    Validated, compact_blob, merge_blobs, migrate_blob, narrow_blob, record_length, storage_keys,
+};
+pub(crate) use storage::{
+    MalformedLine, malformed, parse_record, preamble_lines, split_format, split_version,
+    text_record, version_line,
+};
+pub use timeout::TimeoutBridge;
+#[cfg(feature = "ureq")]
+#[cfg_attr(docsrs, doc(cfg(feature = "ureq")))]
+pub use ureq::UreqBridge;
+#[cfg(feature = "webdav")]
+#[cfg_attr(docsrs, doc(cfg(feature = "webdav")))]
+pub use webdav::WebDavBridge;
 
 /// A distinct value generated from a population.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Identity<'dom> {
     /// Shared by all members of a population.
     pub domain: &'dom str,
@@ -16,6 +153,9 @@ pub struct Identity<'dom> {
     /// Needed to ensure that an identifier always maps to the same name.
     /// See [`StorageState`].
     pub storage: storage::Storage,
+    /// Position of the friendly name within the names available to the storage key.
+    /// See [`StorageState::digest_offset`].
+    pub offset: usize,
 }
 
 impl<'dom> PartialEq for Identity<'dom> {
@@ -24,39 +164,43 @@ impl<'dom> PartialEq for Identity<'dom> {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
-    use std::sync::RwLock;
+/// The friendly name of an [`Identity`], which can be stored in databases
+/// with the `sqlx` and `diesel` features.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "diesel",
+    derive(diesel::expression::AsExpression, diesel::deserialize::FromSqlRow),
+    diesel(sql_type = diesel::sql_types::Text)
+)]
+pub struct FriendlyName(pub String);
 
-    use async_generic::async_generic;
-    use bytes::Bytes;
+impl From<&Identity<'_>> for FriendlyName {
+    fn from(identity: &Identity<'_>) -> Self {
+        Self(identity.friendly_name.clone())
+    }
+}
 
-    use super::*;
-    use crate::hex_string::HexString;
-    use crate::identity::storage::BridgeResult;
+impl std::fmt::Display for FriendlyName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
 
-    include!(concat!(env!("TMPDIR"), "/perfume.rs"));
+impl std::str::FromStr for FriendlyName {
+    type Err = std::convert::Infallible;
 
-    #[derive(Default)]
-    pub struct MockBridge {
-        resources: RwLock<HashMap<String, Bytes>>,
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_string()))
     }
+}
 
-    impl ConnectionBridge for MockBridge {
-        #[async_generic]
-        fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
-            let resources = self.resources.read().unwrap();
-            let bytes = resources.get(key).map(|b| b.to_owned());
-            Ok(bytes)
-        }
-        #[async_generic]
-        fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
-            let mut resources = self.resources.write().unwrap();
-            resources.entry(key.to_string()).insert_entry(body);
-            Ok(())
-        }
-    }
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::hex_string::HexString;
+    pub use crate::testing::MockBridge;
+
+    include!(concat!(env!("TMPDIR"), "/perfume.rs"));
 
     impl<'dom> Default for Identity<'dom> {
         fn default() -> Self {
@@ -67,6 +211,7 @@ mod tests {
                     key: HexString::<3>::default(),
                     digest: HexString::<61>::default(),
                 },
+                offset: 0,
             }
         }
     }
@@ -83,4 +228,130 @@ mod tests {
         buf.fill_with(random_hex_byte);
         HexString::from(&buf[..])
     }
+
+    /// A request received by [`http_server`].
+    #[cfg(any(feature = "reqwest", feature = "ureq"))]
+    pub struct HttpRequest {
+        pub method: String,
+        pub path: String,
+        // with lowercase names
+        pub headers: std::collections::HashMap<String, String>,
+        pub body: Vec<u8>,
+    }
+
+    /// Answers each request with the status, headers and body returned by `handler`, for
+    /// testing bridges which use HTTP. The first `dropped` connections are closed without a
+    /// response. Returns the base url.
+    #[cfg(any(feature = "reqwest", feature = "ureq"))]
+    pub fn http_server<H>(dropped: usize, mut handler: H) -> String
+    where
+        H: FnMut(HttpRequest) -> (&'static str, Vec<(&'static str, String)>, Vec<u8>)
+            + Send
+            + 'static,
+    {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for (connection, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut buf = vec![];
+                let mut chunk = [0; 4096];
+                let request = loop {
+                    let read = stream.read(&mut chunk).unwrap();
+                    buf.extend_from_slice(&chunk[..read]);
+                    let mut headers = [httparse::EMPTY_HEADER; 16];
+                    let mut request = httparse::Request::new(&mut headers);
+                    let httparse::Status::Complete(length) = request.parse(&buf).unwrap() else {
+                        continue;
+                    };
+                    let headers = request
+                        .headers
+                        .iter()
+                        .map(|h| {
+                            let value = String::from_utf8_lossy(h.value).to_string();
+                            (h.name.to_ascii_lowercase(), value)
+                        })
+                        .collect::<std::collections::HashMap<_, _>>();
+                    let content_length = headers
+                        .get("content-length")
+                        .map_or(0, |l| l.parse().unwrap());
+                    if buf.len() >= length + content_length {
+                        break HttpRequest {
+                            method: request.method.unwrap().to_string(),
+                            path: request.path.unwrap().to_string(),
+                            headers,
+                            body: buf[length..length + content_length].to_vec(),
+                        };
+                    }
+                };
+                if connection < dropped {
+                    continue;
+                }
+                let head_only = request.method == "HEAD";
+                let (status, headers, body) = handler(request);
+                let mut head = format!("HTTP/1.1 {status}\r\n");
+                for (name, value) in headers {
+                    head.push_str(&format!("{name}: {value}\r\n"));
+                }
+                head.push_str(&format!(
+                    "content-length: {}\r\nconnection: close\r\n\r\n",
+                    body.len()
+                ));
+                stream.write_all(head.as_bytes()).unwrap();
+                if !head_only {
+                    stream.write_all(&body).unwrap();
+                }
+            }
+        });
+        url
+    }
+
+    /// Serves the blob protocol of openapi/perfume.json from memory, see [`http_server`].
+    #[cfg(any(feature = "reqwest", feature = "ureq"))]
+    pub fn blob_server(dropped: usize) -> String {
+        let mut blobs = std::collections::HashMap::<String, Vec<u8>>::new();
+        http_server(dropped, move |request| {
+            let blob = blobs.get(&request.path);
+            let tag = blob.map(|blob| format!("\"{}\"", blake3::hash(blob).to_hex()));
+            let etag = request.headers.get("if-none-match");
+            let precondition = match request.headers.get("if-match") {
+                Some(if_match) => tag.as_ref() == Some(if_match),
+                None => etag.is_none_or(|etag| etag != "*") || blob.is_none(),
+            };
+            let headers = tag.iter().map(|tag| ("etag", tag.clone())).collect();
+            match (request.method.as_str(), blob) {
+                ("PUT", _) if !precondition => ("412 Precondition Failed", vec![], vec![]),
+                ("PUT", _) => {
+                    blobs.insert(request.path, request.body);
+                    ("200 OK", vec![], vec![])
+                }
+                ("GET", Some(_)) if etag.is_some() && etag == tag.as_ref() => {
+                    ("304 Not Modified", headers, vec![])
+                }
+                ("GET", Some(blob)) if request.headers.contains_key("range") => {
+                    let range = &request.headers["range"]["bytes=".len()..];
+                    let (first, last) = range.split_once('-').unwrap();
+                    let first = first.parse::<usize>().unwrap().min(blob.len());
+                    let end = last.parse::<usize>().map_or(blob.len(), |last| last + 1);
+                    let bytes = blob[first..end.clamp(first, blob.len())].to_vec();
+                    let content_range = match bytes.is_empty() {
+                        true => format!("bytes */{}", blob.len()),
+                        false => {
+                            format!("bytes {first}-{}/{}", first + bytes.len() - 1, blob.len())
+                        }
+                    };
+                    let status = match bytes.is_empty() {
+                        true => "416 Range Not Satisfiable",
+                        false => "206 Partial Content",
+                    };
+                    (status, vec![("content-range", content_range)], bytes)
+                }
+                ("GET", Some(blob)) => ("200 OK", headers, blob.clone()),
+                ("HEAD", Some(_)) => ("200 OK", headers, vec![]),
+                _ => ("404 Not Found", vec![], vec![]),
+            }
+        })
+    }
 }
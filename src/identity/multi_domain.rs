@@ -0,0 +1,162 @@
+//! Assignment of names to one identifier in several domains at once.
+
+use async_generic::async_generic;
+
+use crate::Error;
+
+use super::Identity;
+use super::population::Population;
+use super::storage::{ConnectionBridge, RemoteStore};
+
+/// The identity of `identifier` in the domain of each population of `domains`, in the same
+/// order, or none of them. If a domain fails to assign a name, the names which this call
+/// assigned in the others are removed again, as though they had never been assigned, and the
+/// error is returned, so that a system in which a user needs a name in every tenant domain at
+/// signup can retry a signup which failed. Names which the identifier already had are kept.
+///
+/// Each domain is kept by its own store, since a [`super::KeyTemplate`] places the blobs of
+/// one domain. This is not a transaction: a name may be seen by other readers before it is
+/// removed, names which another writer assigns to the same identifier during this call may be
+/// removed with them, and names which cannot be removed, such as while a store is unreachable,
+/// are kept. The latter are logged as warnings with the `tracing` feature, and are removed
+/// when the call is retried and fails again, or kept when it succeeds.
+#[async_generic]
+#[allow(unused_assignments)]
+pub fn identities_in_domains<'p, B>(
+    identifier: &str,
+    domains: &mut [(&'p Population<'_>, &mut RemoteStore<B>)],
+) -> Result<Vec<Identity<'p>>, Error>
+where
+    B: ConnectionBridge + Send,
+{
+    // the indices of the domains in which the identifier had no name
+    let mut assigned = vec![];
+    let mut result = Ok(vec![]);
+    if _async {
+        result = assign_all_async(identifier, domains, &mut assigned).await;
+    } else {
+        result = assign_all(identifier, domains, &mut assigned);
+    }
+    let Err(error) = result else {
+        return result;
+    };
+
+    for &index in assigned.iter().rev() {
+        let (population, store) = &mut domains[index];
+        let storage = population.storage_object(identifier);
+        let mut retracted = Ok(false);
+        if _async {
+            retracted = store.retract_async(population.domain, &storage).await;
+        } else {
+            retracted = store.retract(population.domain, &storage);
+        }
+        if retracted.is_err() {
+            event!(WARN, domain = population.domain, error = ?retracted.err(), "could not roll back identity");
+        }
+    }
+    Err(error)
+}
+
+#[async_generic]
+#[allow(unused_assignments)]
+fn assign_all<'p, B>(
+    identifier: &str,
+    domains: &mut [(&'p Population<'_>, &mut RemoteStore<B>)],
+    assigned: &mut Vec<usize>,
+) -> Result<Vec<Identity<'p>>, Error>
+where
+    B: ConnectionBridge + Send,
+{
+    let mut identities = Vec::with_capacity(domains.len());
+    for (index, (population, store)) in domains.iter_mut().enumerate() {
+        let storage = population.storage_object(identifier);
+        let mut stored = Ok(0);
+        if _async {
+            stored = store.lookup_async(population.domain, &storage).await?;
+        } else {
+            stored = store.lookup(population.domain, &storage)?;
+        }
+        // recorded before assigning, since a failed assignment may still have been stored
+        if stored.is_err() {
+            assigned.push(index);
+        }
+        let mut identity = None;
+        if _async {
+            identity = Some(population.identity_async(identifier, *store).await?);
+        } else {
+            identity = Some(population.identity(identifier, *store)?);
+        }
+        identities.extend(identity);
+    }
+    Ok(identities)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use async_generic::async_generic;
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::identity::storage::BridgeResult;
+    use crate::identity::{StorageState, tests::*};
+
+    // fails every write while it is read only
+    #[derive(Default)]
+    struct ReadOnlyBridge {
+        inner: MockBridge,
+        read_only: bool,
+    }
+
+    impl ConnectionBridge for ReadOnlyBridge {
+        #[async_generic]
+        fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+            self.inner.get(key)
+        }
+
+        #[async_generic]
+        fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+            match self.read_only {
+                true => Err(io::ErrorKind::PermissionDenied.into()),
+                false => self.inner.put(key, body),
+            }
+        }
+    }
+
+    #[test]
+    fn test_identities_in_domains() -> Result<(), Error> {
+        let secret = b"0123456789abcdef0123456789abcdef";
+        let brazil = Population::new("br", secret, &PERFUME_INGREDIENTS)?;
+        let portugal = Population::new("pt", secret, &PERFUME_INGREDIENTS)?;
+        let angola = Population::new("ao", secret, &PERFUME_INGREDIENTS)?;
+        let mut br = RemoteStore::new(ReadOnlyBridge::default());
+        let mut pt = RemoteStore::new(ReadOnlyBridge::default());
+        let mut ao = RemoteStore::new(ReadOnlyBridge::default());
+        let existing = brazil.identity("c@d.br", &mut br)?;
+        ao.bridge.read_only = true;
+
+        // a failure in one domain removes the names assigned in the others
+        let mut domains = [(&brazil, &mut br), (&portugal, &mut pt), (&angola, &mut ao)];
+        assert!(identities_in_domains("a@b.br", &mut domains).is_err());
+        assert!(identities_in_domains("c@d.br", &mut domains).is_err());
+        for (population, store) in &mut domains[..2] {
+            let storage = population.storage_object("a@b.br");
+            assert!(store.lookup(population.domain, &storage)?.is_err());
+        }
+        // but keeps those which were assigned before
+        assert_eq!(brazil.identity("c@d.br", &mut br)?, existing);
+
+        ao.bridge.read_only = false;
+        let mut domains = [(&brazil, &mut br), (&portugal, &mut pt), (&angola, &mut ao)];
+        let identities = identities_in_domains("a@b.br", &mut domains)?;
+        assert_eq!(identities.len(), 3);
+        assert_eq!(identities[0].domain, "br");
+        assert_eq!(identities[0], brazil.identity("a@b.br", &mut br)?);
+        assert_eq!(
+            pt.digest_offset("pt", &identities[1].storage)?,
+            identities[1].offset
+        );
+        Ok(())
+    }
+}
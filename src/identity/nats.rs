@@ -0,0 +1,217 @@
+//! A [`ConnectionBridge`] which keeps blobs as keys of a NATS JetStream key-value bucket.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::sync::Mutex;
+
+use async_nats::jetstream::kv::{CreateErrorKind, Operation, Store, UpdateErrorKind};
+use bytes::Bytes;
+
+use super::storage::{BridgeResult, ConnectionBridge, Validated};
+
+/// Stores each blob as the value of a key of a JetStream key-value bucket, such as one
+/// created with `jetstream.create_key_value`. Keys of the bucket cannot contain characters
+/// other than letters, digits, `-`, `/`, `_`, `=` and `.`, which a
+/// [`crate::identity::KeyTemplate`] such as `{domain}/{key}` keeps to.
+///
+/// Each blob is replaced only if its key has not been modified since the bridge last read it,
+/// by checking its revision, so that a [`super::RemoteStore`] never overwrites offsets assigned
+/// by another writer. A put which loses this race fails with [`io::ErrorKind::AlreadyExists`],
+/// which is a [`crate::ErrorKind::Conflict`] that can be retried. Keys which the bridge has not
+/// read are written without a check. Revisions are also the validators of
+/// [`ConnectionBridge::get_validated`].
+///
+/// The connection of the client must be polled by a Tokio runtime. Blocking calls run the
+/// request on a new thread with its own runtime, so that they can be made from within a
+/// runtime, while another thread polls the connection.
+#[derive(Debug)]
+pub struct NatsKvBridge {
+    store: Store,
+    // key -> revision of the last read or write of the bridge, None if the key was absent
+    revisions: Mutex<HashMap<String, Option<u64>>>,
+}
+
+impl NatsKvBridge {
+    /// Keep blobs in the bucket of `store`.
+    pub fn new(store: Store) -> Self {
+        Self {
+            store,
+            revisions: Mutex::default(),
+        }
+    }
+
+    /// The bucket holding the blobs.
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    // the value and revision of `key`, if it is present
+    async fn entry(&self, key: &str) -> BridgeResult<Option<(Bytes, u64)>> {
+        let entry = self.store.entry(key).await.map_err(|e| {
+            io::Error::other(format!(
+                "NATS KV entry {key} of {} failed: {e}",
+                self.store.name
+            ))
+        })?;
+        // deleted and purged keys are absent, and written as new keys
+        let entry = entry
+            .filter(|entry| entry.operation == Operation::Put)
+            .map(|entry| (entry.value, entry.revision));
+        let revision = entry.as_ref().map(|(_value, revision)| *revision);
+        self.revisions
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), revision);
+        Ok(entry)
+    }
+}
+
+fn conflict(key: &str) -> io::Error {
+    let message = format!("NATS KV key {key} was modified by another writer");
+    io::Error::new(io::ErrorKind::AlreadyExists, message)
+}
+
+// runs `request` to completion on a runtime of its own
+fn block_on<T>(request: impl Future<Output = BridgeResult<T>> + Send) -> BridgeResult<T>
+where
+    T: Send,
+{
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?
+                    .block_on(request)
+            })
+            .join()
+            .expect("request should complete without panicking")
+    })
+}
+
+impl ConnectionBridge for NatsKvBridge {
+    fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        block_on(self.get_async(key))
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        block_on(self.put_async(key, body))
+    }
+
+    async fn get_async(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        let entry = self.entry(key).await?;
+        Ok(entry.map(|(value, _revision)| value))
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        let bucket = &self.store.name;
+        let read = self.revisions.lock().unwrap().get(key).copied();
+        let written = match read {
+            None => self.store.put(key, body).await.map_err(|e| {
+                io::Error::other(format!("NATS KV put {key} of {bucket} failed: {e}"))
+            }),
+            Some(None) => self.store.create(key, body).await.map_err(|e| {
+                if e.kind() == CreateErrorKind::AlreadyExists {
+                    return conflict(key);
+                }
+                io::Error::other(format!("NATS KV create {key} of {bucket} failed: {e}"))
+            }),
+            Some(Some(revision)) => self.store.update(key, body, revision).await.map_err(|e| {
+                if e.kind() == UpdateErrorKind::WrongLastRevision {
+                    return conflict(key);
+                }
+                io::Error::other(format!("NATS KV update {key} of {bucket} failed: {e}"))
+            }),
+        };
+        let mut revisions = self.revisions.lock().unwrap();
+        match written {
+            Ok(revision) => {
+                revisions.insert(key.to_string(), Some(revision));
+                Ok(())
+            }
+            Err(e) => {
+                revisions.remove(key);
+                Err(e)
+            }
+        }
+    }
+
+    fn get_validated(&self, key: &str, validator: Option<&str>) -> BridgeResult<Validated> {
+        block_on(self.get_validated_async(key, validator))
+    }
+
+    async fn get_validated_async(
+        &self,
+        key: &str,
+        validator: Option<&str>,
+    ) -> BridgeResult<Validated> {
+        let Some((value, revision)) = self.entry(key).await? else {
+            return Ok(Validated::Modified {
+                body: None,
+                validator: None,
+            });
+        };
+        let revision = revision.to_string();
+        if validator == Some(revision.as_str()) {
+            return Ok(Validated::NotModified);
+        }
+        Ok(Validated::Modified {
+            body: Some(value),
+            validator: Some(revision),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a server with JetStream enabled, such as "localhost:4222", which is skipped without one
+    const SERVER: Option<&str> = option_env!("PERFUME_TEST_NATS");
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_nats_kv_bridge() -> Result<(), crate::Error> {
+        let Some(server) = SERVER else {
+            return Ok(());
+        };
+        let client = async_nats::connect(server).await.unwrap();
+        let jetstream = async_nats::jetstream::new(client);
+        let bucket = format!("perfume_test_{}", std::process::id());
+        let store = jetstream
+            .create_key_value(async_nats::jetstream::kv::Config {
+                bucket: bucket.clone(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let first = NatsKvBridge::new(store.clone());
+        let second = NatsKvBridge::new(store);
+        assert_eq!(first.get_async("br/abc").await?, None);
+        assert_eq!(second.get_async("br/abc").await?, None);
+        first.put_async("br/abc", Bytes::from("first\n")).await?;
+
+        // the second writer has not seen the first blob
+        let e = second
+            .put_async("br/abc", Bytes::from("second\n"))
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        let blob = second.get_async("br/abc").await?;
+        assert_eq!(blob.as_deref(), Some(&b"first\n"[..]));
+        second.put_async("br/abc", Bytes::from("second\n")).await?;
+        // the first writer has not seen the second blob
+        assert!(first.put("br/abc", Bytes::from("third\n")).is_err());
+
+        let Validated::Modified { validator, .. } = first.get_validated("br/abc", None)? else {
+            panic!("the blob should be modified");
+        };
+        assert!(matches!(
+            first.get_validated("br/abc", validator.as_deref())?,
+            Validated::NotModified
+        ));
+        jetstream.delete_key_value(&bucket).await.unwrap();
+        Ok(())
+    }
+}
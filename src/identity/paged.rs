@@ -0,0 +1,299 @@
+//! A [`ConnectionBridge`] which splits large blobs across pages of another.
+
+use bytes::{Bytes, BytesMut};
+
+use super::storage::{BridgeResult, ConnectionBridge, Validated};
+
+// the first line of an index page. Smaller blobs which begin with it are paged anyway
+const INDEX_HEADER: &[u8] = b"perfume pages v1\n";
+
+// a page may be replaced between reading the index and reading the page
+const READ_ATTEMPTS: usize = 3;
+
+/// Stores blobs larger than `page_size` as fixed-size pages (`key/p0`, `key/p1`, …) of another
+/// bridge, so that backends with small object size limits can host populations as large as
+/// Brazil's. The blob key then holds an index page, which lists the length and hash of each
+/// page. Smaller blobs are stored as they are, so an existing bridge can be wrapped in place.
+///
+/// Pages end on record boundaries where possible, and are written before the index page.
+/// A page which was replaced while it was being read no longer matches its hash in the index,
+/// and the whole blob is read again. Pages left over from a longer version of a blob are not
+/// removed, since bridges cannot delete keys.
+#[derive(Debug)]
+pub struct PagedBridge<B> {
+    bridge: B,
+    page_size: usize,
+}
+
+impl<B: ConnectionBridge> PagedBridge<B> {
+    /// Split blobs into pages of at most `page_size` bytes of `bridge`.
+    pub fn new(bridge: B, page_size: usize) -> Self {
+        Self {
+            bridge,
+            page_size: page_size.max(1),
+        }
+    }
+
+    /// The paged bridge.
+    pub fn inner(&self) -> &B {
+        &self.bridge
+    }
+
+    // the pages of `body`, or `None` if it can be stored as it is
+    fn split(&self, body: &Bytes) -> Option<Vec<Bytes>> {
+        if body.len() <= self.page_size && !body.starts_with(INDEX_HEADER) {
+            return None;
+        }
+        let mut pages = Vec::with_capacity(body.len() / self.page_size + 1);
+        let mut rest = body.clone();
+        while rest.len() > self.page_size {
+            let end = match rest[..self.page_size].iter().rposition(|&b| b == b'\n') {
+                Some(newline) => newline + 1,
+                None => self.page_size,
+            };
+            pages.push(rest.split_to(end));
+        }
+        pages.push(rest);
+        Some(pages)
+    }
+}
+
+fn page_key(key: &str, page: usize) -> String {
+    format!("{key}/p{page}")
+}
+
+fn index_page(pages: &[Bytes]) -> Bytes {
+    let mut index = BytesMut::from(INDEX_HEADER);
+    for page in pages {
+        let line = format!("{} {}\n", blake3::hash(page).to_hex(), page.len());
+        index.extend_from_slice(line.as_bytes());
+    }
+    index.freeze()
+}
+
+// the (hash, length) of each page listed by `body`, or `None` if it is not an index page
+fn parse_index(body: &[u8]) -> Option<BridgeResult<Vec<(blake3::Hash, usize)>>> {
+    let lines = body.strip_prefix(INDEX_HEADER)?;
+    let pages = std::str::from_utf8(lines)
+        .ok()
+        .and_then(|lines| {
+            lines
+                .lines()
+                .map(|line| {
+                    let (hash, length) = line.split_once(' ')?;
+                    Some((blake3::Hash::from_hex(hash).ok()?, length.parse().ok()?))
+                })
+                .collect::<Option<Vec<_>>>()
+        })
+        .ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "malformed index page")
+        });
+    Some(pages)
+}
+
+// appends `page` to `blob`, or returns false if it does not match the index
+fn append_page(
+    blob: &mut BytesMut,
+    page: Option<Bytes>,
+    (hash, length): &(blake3::Hash, usize),
+) -> bool {
+    match page {
+        Some(page) if page.len() == *length && blake3::hash(&page) == *hash => {
+            blob.extend_from_slice(&page);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn torn(key: &str) -> std::io::Error {
+    std::io::Error::other(format!("pages of {key} changed while they were read"))
+}
+
+impl<B: ConnectionBridge + Sync> PagedBridge<B> {
+    // the blob indexed by `index`, or `None` if a page did not match
+    fn read_pages(
+        &self,
+        key: &str,
+        index: &[(blake3::Hash, usize)],
+    ) -> BridgeResult<Option<Bytes>> {
+        let mut blob = BytesMut::with_capacity(index.iter().map(|(_, length)| length).sum());
+        for (number, entry) in index.iter().enumerate() {
+            if !append_page(&mut blob, self.bridge.get(&page_key(key, number))?, entry) {
+                return Ok(None);
+            }
+        }
+        Ok(Some(blob.freeze()))
+    }
+
+    async fn read_pages_async(
+        &self,
+        key: &str,
+        index: &[(blake3::Hash, usize)],
+    ) -> BridgeResult<Option<Bytes>> {
+        let mut blob = BytesMut::with_capacity(index.iter().map(|(_, length)| length).sum());
+        for (number, entry) in index.iter().enumerate() {
+            let page = self.bridge.get_async(&page_key(key, number)).await?;
+            if !append_page(&mut blob, page, entry) {
+                return Ok(None);
+            }
+        }
+        Ok(Some(blob.freeze()))
+    }
+}
+
+impl<B: ConnectionBridge + Sync> ConnectionBridge for PagedBridge<B> {
+    fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        for _ in 0..READ_ATTEMPTS {
+            let Some(body) = self.bridge.get(key)? else {
+                return Ok(None);
+            };
+            let Some(index) = parse_index(&body) else {
+                return Ok(Some(body));
+            };
+            if let Some(blob) = self.read_pages(key, &index?)? {
+                return Ok(Some(blob));
+            }
+        }
+        Err(torn(key))
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        let Some(pages) = self.split(&body) else {
+            return self.bridge.put(key, body);
+        };
+        let index = index_page(&pages);
+        for (number, page) in pages.into_iter().enumerate() {
+            self.bridge.put(&page_key(key, number), page)?;
+        }
+        self.bridge.put(key, index)
+    }
+
+    async fn get_async(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        for _ in 0..READ_ATTEMPTS {
+            let Some(body) = self.bridge.get_async(key).await? else {
+                return Ok(None);
+            };
+            let Some(index) = parse_index(&body) else {
+                return Ok(Some(body));
+            };
+            if let Some(blob) = self.read_pages_async(key, &index?).await? {
+                return Ok(Some(blob));
+            }
+        }
+        Err(torn(key))
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        let Some(pages) = self.split(&body) else {
+            return self.bridge.put_async(key, body).await;
+        };
+        let index = index_page(&pages);
+        for (number, page) in pages.into_iter().enumerate() {
+            self.bridge.put_async(&page_key(key, number), page).await?;
+        }
+        self.bridge.put_async(key, index).await
+    }
+
+    fn exists(&self, key: &str) -> BridgeResult<bool> {
+        self.bridge.exists(key)
+    }
+
+    async fn exists_async(&self, key: &str) -> BridgeResult<bool> {
+        self.bridge.exists_async(key).await
+    }
+
+    // the index page changes whenever a page does, so its validator stands for the blob
+    fn get_validated(&self, key: &str, validator: Option<&str>) -> BridgeResult<Validated> {
+        for _ in 0..READ_ATTEMPTS {
+            let validated = self.bridge.get_validated(key, validator)?;
+            let Validated::Modified {
+                body: Some(body),
+                validator,
+            } = validated
+            else {
+                return Ok(validated);
+            };
+            let Some(index) = parse_index(&body) else {
+                let body = Some(body);
+                return Ok(Validated::Modified { body, validator });
+            };
+            if let Some(blob) = self.read_pages(key, &index?)? {
+                let body = Some(blob);
+                return Ok(Validated::Modified { body, validator });
+            }
+        }
+        Err(torn(key))
+    }
+
+    async fn get_validated_async(
+        &self,
+        key: &str,
+        validator: Option<&str>,
+    ) -> BridgeResult<Validated> {
+        for _ in 0..READ_ATTEMPTS {
+            let validated = self.bridge.get_validated_async(key, validator).await?;
+            let Validated::Modified {
+                body: Some(body),
+                validator,
+            } = validated
+            else {
+                return Ok(validated);
+            };
+            let Some(index) = parse_index(&body) else {
+                let body = Some(body);
+                return Ok(Validated::Modified { body, validator });
+            };
+            if let Some(blob) = self.read_pages_async(key, &index?).await? {
+                let body = Some(blob);
+                return Ok(Validated::Modified { body, validator });
+            }
+        }
+        Err(torn(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use crate::hex_string::HexString;
+    use crate::identity::tests::*;
+    use crate::identity::{RemoteStore, Storage, StorageState};
+    use crate::testing::bridge_conformance;
+
+    #[test]
+    fn test_paged_bridge() -> Result<(), Error> {
+        bridge_conformance(|| PagedBridge::new(MockBridge::default(), 1000));
+
+        let bridge = PagedBridge::new(MockBridge::default(), 150);
+        bridge.put("fff", Bytes::from_static(b"small\n"))?;
+        assert_eq!(bridge.inner().get("fff")?.as_deref(), Some(&b"small\n"[..]));
+
+        let mut store = RemoteStore::new(bridge);
+        let mut offsets = vec![];
+        for _ in 0..10 {
+            let storage = Storage {
+                key: HexString::from(&b"abc"[..]),
+                digest: random_hex_string(),
+            };
+            offsets.push((store.digest_offset("", &storage)?, storage));
+        }
+        for (offset, storage) in &offsets {
+            assert_eq!(store.digest_offset("", storage)?, *offset);
+        }
+
+        // 10 records of 68 bytes, two to a page
+        let bridge = &store.bridge;
+        let index = bridge.inner().get("abc")?.unwrap();
+        assert_eq!(parse_index(&index).unwrap()?.len(), 5);
+        let page = bridge.inner().get("abc/p4")?.unwrap();
+        assert_eq!(page.len(), 136);
+        assert!(page.ends_with(b"\n"));
+
+        bridge.inner().put("abc/p2", page)?;
+        let e = bridge.get("abc").unwrap_err();
+        assert!(e.to_string().contains("changed while they were read"));
+        Ok(())
+    }
+}
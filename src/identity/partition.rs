@@ -0,0 +1,269 @@
+//! A [`StorageState`] which keeps new assignments apart from those of earlier periods.
+
+use async_generic::async_generic;
+use bytes::Bytes;
+
+use crate::{Error, Operation};
+
+use super::storage::{
+    ConnectionBridge, KeyTemplate, RemoteStore, Storage, StorageState, deleted_digest, search_blob,
+};
+
+/// Coarse periods of time which partition the blobs of a [`PartitionedStore`],
+/// from the current period back to the earliest one which is kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeBuckets(Vec<String>);
+
+impl TimeBuckets {
+    /// Name each bucket, from the current one back to the earliest. Names are placed in keys of
+    /// the bridge, see [`PartitionedStore::new`].
+    pub fn new<I, S>(buckets: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let buckets = buckets.into_iter().map(Into::into).collect::<Vec<String>>();
+        let invalid = |message: &str| {
+            let message = format!("invalid time buckets {buckets:?}: {message}");
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, message).into()
+        };
+        if buckets.is_empty() {
+            return Err(invalid("there must be a current bucket"));
+        }
+        if buckets.iter().any(|bucket| bucket.contains(['{', '}'])) {
+            return Err(invalid("buckets cannot contain placeholders"));
+        }
+        if (1..buckets.len()).any(|i| buckets[..i].contains(&buckets[i])) {
+            return Err(invalid("buckets must be distinct"));
+        }
+        Ok(Self(buckets))
+    }
+
+    /// A bucket for each year, from the current year (in UTC) back to `since`.
+    pub fn yearly(since: i64) -> Self {
+        let current = current_year().max(since);
+        Self(
+            (since..=current)
+                .rev()
+                .map(|year| year.to_string())
+                .collect(),
+        )
+    }
+
+    /// The bucket which new assignments are stored in.
+    pub fn current(&self) -> &str {
+        &self.0[0]
+    }
+
+    /// Every bucket, from the current one back to the earliest.
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+}
+
+// the proleptic Gregorian year of the system time, see
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn current_year() -> i64 {
+    let seconds = match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Ok(since_epoch) => since_epoch.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    };
+    let days = seconds.div_euclid(86_400) + 719_468;
+    let (era, day_of_era) = (days.div_euclid(146_097), days.rem_euclid(146_097));
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // months are counted from March, so that January and February end the year
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    year_of_era + era * 400 + i64::from(month_from_march >= 10)
+}
+
+/// Assigns offsets in the blobs of the current [`TimeBuckets`] bucket, so that domains with
+/// unbounded growth can expire each earlier bucket with the lifecycle or retention policy of
+/// their backend. Digests are looked up in each bucket in turn, from the current one back to
+/// the earliest, so that identities assigned in an earlier bucket keep their names while it is
+/// kept. Digests of an expired bucket are assigned again, as new identities.
+///
+/// Names only depend on offsets, so the blobs of each storage key share their offsets across
+/// buckets. The first assignment of a blob in a new bucket continues from the offsets of the
+/// earlier buckets, and keeps them from being assigned again, even once they expire, with a
+/// [`crate::identity::RecordFlag::Compacted`] record. Buckets do not add names, since
+/// [`crate::identity::Population::blob_capacity`] is shared by all of them.
+///
+/// Every store of a domain should change to a new bucket at about the same time, since an
+/// assignment in an earlier bucket after a blob was started in the next could reuse an offset.
+#[derive(Debug)]
+pub struct PartitionedStore<B: ConnectionBridge> {
+    store: RemoteStore<B>,
+    // earlier buckets, from the latest to the earliest
+    earlier: Vec<KeyTemplate>,
+}
+
+impl<B: ConnectionBridge> PartitionedStore<B> {
+    /// Keep the blobs of `store` at keys made from `template` for each of `buckets`, in which
+    /// `{bucket}` is replaced by the name of the bucket, as well as `{domain}` and `{key}` as in
+    /// [`KeyTemplate`], such as `perfume/{domain}/{bucket}/{key}`. Any key template of `store`
+    /// is replaced with the template of the current bucket.
+    pub fn new(
+        store: RemoteStore<B>,
+        template: &str,
+        domain: &str,
+        buckets: &TimeBuckets,
+    ) -> Result<Self, Error> {
+        if !template.contains("{bucket}") {
+            let message = format!("invalid key template {template:?}: {{bucket}} is missing");
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, message).into());
+        }
+        let mut templates = buckets
+            .iter()
+            .map(|bucket| KeyTemplate::new(&template.replace("{bucket}", bucket), domain))
+            .collect::<Result<Vec<_>, _>>()?;
+        let current = templates.remove(0);
+        Ok(Self {
+            store: store.with_key_template(current),
+            earlier: templates,
+        })
+    }
+
+    /// The store of the current bucket.
+    pub fn store(&self) -> &RemoteStore<B> {
+        &self.store
+    }
+
+    /// The store of the current bucket, such as for [`RemoteStore::flush`].
+    pub fn store_mut(&mut self) -> &mut RemoteStore<B> {
+        &mut self.store
+    }
+}
+
+impl<B> StorageState for PartitionedStore<B>
+where
+    B: ConnectionBridge + Send,
+{
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn digest_offset(&mut self, domain: &str, storage: &Storage) -> Result<usize, Error> {
+        let key = storage.key.as_str();
+        let digest = storage.digest.as_str();
+        let mut current = Ok(0);
+        if _async {
+            current = self.store.lookup_async(domain, storage).await?;
+        } else {
+            current = self.store.lookup(domain, storage)?;
+        }
+        let mut next_offset = match current {
+            Ok(offset) => return Ok(offset),
+            Err(next_offset) => next_offset,
+        };
+
+        let format = self.store.blob_format();
+        for template in &self.earlier {
+            let resource = template.apply(key);
+            let get = |e| Error::storage(e, key, Operation::Get).in_domain(domain);
+            let mut body: Option<Bytes> = None;
+            if _async {
+                body = self.store.bridge.get_async(&resource).await.map_err(get)?;
+            } else {
+                body = self.store.bridge.get(&resource).map_err(get)?;
+            }
+            let Some(body) = body else {
+                continue;
+            };
+            let parse = |e| Error::storage(e, key, Operation::Parse).in_domain(domain);
+            let blob = format.decode(&body).map_err(parse)?;
+            match search_blob(&blob, digest).map_err(parse)? {
+                Ok((offset, flag)) if !flag.is_deleted() => return Ok(offset),
+                // a digest deleted in an earlier bucket stays deleted, see RemoteStore::delete
+                Ok(_) => return Err(get(deleted_digest())),
+                Err(next) => next_offset = next_offset.max(next),
+            }
+        }
+
+        if _async {
+            self.store
+                .reserve_offsets_async(domain, key, next_offset)
+                .await?;
+            self.store.digest_offset_async(domain, storage).await
+        } else {
+            self.store.reserve_offsets(domain, key, next_offset)?;
+            self.store.digest_offset(domain, storage)
+        }
+    }
+
+    #[async_generic]
+    fn health_check(&mut self) -> Result<(), Error> {
+        if _async {
+            self.store.health_check_async().await
+        } else {
+            self.store.health_check()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::{Population, tests::*};
+
+    #[test]
+    fn test_partitioned_store() -> Result<(), Error> {
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let template = "{domain}/{bucket}/{key}";
+        let last_year = TimeBuckets::new(["2025"])?;
+        let mut store = PartitionedStore::new(
+            RemoteStore::new(MockBridge::default()),
+            template,
+            "br",
+            &last_year,
+        )?;
+        let old = population.identity("old@b.br", &mut store)?;
+        let key = old.storage.key.as_str();
+        assert!(
+            store
+                .store()
+                .bridge
+                .get(&format!("br/2025/{key}"))?
+                .is_some()
+        );
+
+        // a new year, with the blobs of the last one
+        let bridge = std::mem::take(&mut store.store_mut().bridge);
+        let buckets = TimeBuckets::new(["2026", "2025"])?;
+        let mut store = PartitionedStore::new(RemoteStore::new(bridge), template, "br", &buckets)?;
+        assert_eq!(population.identity("old@b.br", &mut store)?, old);
+        assert!(
+            store
+                .store()
+                .bridge
+                .get(&format!("br/2026/{key}"))?
+                .is_none()
+        );
+
+        // the new blob continues from the offsets of the last year, even once they expire
+        let storage = Storage {
+            key: old.storage.key.clone(),
+            digest: random_hex_string(),
+        };
+        assert_eq!(store.digest_offset("br", &storage)?, old.offset + 1);
+        store
+            .store()
+            .bridge
+            .put(&format!("br/2025/{key}"), Bytes::new())?;
+        assert_eq!(store.digest_offset("br", &storage)?, old.offset + 1);
+        let another = Storage {
+            key: old.storage.key.clone(),
+            digest: random_hex_string(),
+        };
+        assert_eq!(store.digest_offset("br", &another)?, old.offset + 2);
+
+        assert!(TimeBuckets::new(["2026", "2026"]).is_err());
+        let yearly = TimeBuckets::yearly(2024);
+        assert!(yearly.current().parse::<i64>().unwrap() >= 2026);
+        assert_eq!(yearly.iter().last(), Some("2024"));
+        Ok(())
+    }
+}
@@ -1,12 +1,14 @@
 use async_generic::async_generic;
 use base16ct::lower::encode as base16_encode;
+use hkdf::Hkdf;
+use sha2::Sha256;
 
 use crate::hex_string::HexString;
-use crate::random::randomized;
-use crate::{Error, STORAGE_KEY_LENGTH};
+use crate::random::randomized_prefix;
+use crate::{Error, Operation, STORAGE_KEY_LENGTH};
 
 use super::Identity;
-use super::storage::{Storage, StorageState};
+use super::storage::{Storage, StorageState, storage_keys};
 
 // NOTE: implemented with external types to enable codegen before running unit tests. see codegen.rs
 /// Compiled data used for random name generation. See [`crate::codegen::ingredients`].
@@ -17,25 +19,93 @@ pub type Ingredients = (
     &'static [&'static str],
 );
 
+// the HKDF info of secrets shorter than 32 bytes, changed only with a new derivation
+const KEY_DERIVATION_V1: &[u8] = b"perfume population key v1";
+
 /// Persistent random name generator.
 pub struct Population<'dom> {
     /// A unique identifier, needed for associating identities with populations.
     pub domain: &'dom str,
     /// Used to generate a keyed hash function, and to randomize word selection.
+    /// Should have at least [`super::MIN_SECRET_LENGTH`] bytes. The first 32 bytes of a secret
+    /// of at least 32 bytes are the key of the hash function, and a shorter secret is stretched
+    /// to a key with HKDF-SHA256.
     pub secret: &'dom [u8],
     /// Words to use for generating names. Created at compile-time with [`crate::codegen::ingredients`].
     pub ingredients: &'static Ingredients,
 }
 
+impl std::fmt::Debug for Population<'_> {
+    /// Shows the length of the secret, never its contents.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Population")
+            .field("domain", &self.domain)
+            .field("secret", &format_args!("[REDACTED; {}]", self.secret.len()))
+            .field("population_size", &self.ingredients.0)
+            .finish_non_exhaustive()
+    }
+}
+
 impl<'dom> Population<'dom> {
+    /// A population of `domain`, checking that `ingredients` can name every offset within
+    /// [`Population::blob_capacity`]: each storage key needs a prefix, and the colors and
+    /// animals need to make enough names for a blob. Ingredients which do not are an error of
+    /// kind [`crate::ErrorKind::InvalidInput`]. Populations can also be constructed directly,
+    /// without these checks.
+    pub fn new(
+        domain: &'dom str,
+        secret: &'dom [u8],
+        ingredients: &'static Ingredients,
+    ) -> Result<Self, Error> {
+        let population = Self {
+            domain,
+            secret,
+            ingredients,
+        };
+        let (_population_size, prefixes, colors, animals) = ingredients;
+        let invalid = |message: String| {
+            Error::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                message,
+            ))
+        };
+        if let Some(key) = storage_keys().find(|key| !prefixes.contains_key(key.as_str())) {
+            return Err(invalid(format!(
+                "ingredients have no prefix for storage key {key}"
+            )));
+        }
+        let names = colors.len() * animals.len();
+        let capacity = population.blob_capacity();
+        if capacity == 0 || names < capacity {
+            return Err(invalid(format!(
+                "{} colors and {} animals make {names} names per blob, which is fewer than the {capacity} needed",
+                colors.len(),
+                animals.len(),
+            )));
+        }
+        Ok(population)
+    }
+
+    /// The number of offsets which each storage blob may assign: the population size divided
+    /// among the storage keys. Assigning a larger offset is an [`Error::PopulationExhausted`].
+    pub fn blob_capacity(&self) -> usize {
+        self.ingredients.0 / 16usize.pow(STORAGE_KEY_LENGTH as u32)
+    }
+
     /// Generate a unique friendly name from `identifier` which has been persisted using `state`.
     #[async_generic]
     #[allow(unused_assignments)]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(domain = self.domain))
+    )]
     pub fn identity(
         &self,
         identifier: &str,
         state: &mut impl StorageState,
     ) -> Result<Identity<'_>, Error> {
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
         let storage = self.storage_object(identifier);
 
         let mut offset = 0usize;
@@ -44,74 +114,178 @@ impl<'dom> Population<'dom> {
         } else {
             offset = state.digest_offset(self.domain, &storage)?;
         }
+        let identity = self.resolved_identity(storage, offset)?;
+        #[cfg(feature = "metrics")]
+        metrics::histogram!("perfume_resolution_seconds").record(start.elapsed());
 
-        let friendly_name = self.friendly_name(&storage, offset);
+        Ok(identity)
+    }
+
+    /// Generate a friendly name for each of `identifiers`, in the same order. Their offsets are
+    /// resolved together, see [`StorageState::digest_offsets`].
+    pub fn identities<I, S>(
+        &self,
+        identifiers: I,
+        state: &mut impl StorageState,
+    ) -> Result<Vec<Identity<'_>>, Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let storages = self.storage_objects(identifiers);
+        let offsets = state.digest_offsets(self.domain, &storages)?;
+        self.resolved_identities(storages, offsets)
+    }
+
+    /// The async version of [`Population::identities`], for stores which are `Send`.
+    pub async fn identities_async<I, S>(
+        &self,
+        identifiers: I,
+        state: &mut (impl StorageState + Send),
+    ) -> Result<Vec<Identity<'_>>, Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let storages = self.storage_objects(identifiers);
+        let offsets = state.digest_offsets_async(self.domain, &storages).await?;
+        self.resolved_identities(storages, offsets)
+    }
+
+    fn storage_objects<I, S>(&self, identifiers: I) -> Vec<Storage>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        identifiers
+            .into_iter()
+            .map(|identifier| self.storage_object(identifier.as_ref()))
+            .collect()
+    }
+
+    fn resolved_identities(
+        &self,
+        storages: Vec<Storage>,
+        offsets: Vec<usize>,
+    ) -> Result<Vec<Identity<'_>>, Error> {
+        storages
+            .into_iter()
+            .zip(offsets)
+            .map(|(storage, offset)| self.resolved_identity(storage, offset))
+            .collect()
+    }
 
+    // the identity of `storage` at its persisted `offset`
+    fn resolved_identity(&self, storage: Storage, offset: usize) -> Result<Identity<'_>, Error> {
+        if offset >= self.blob_capacity() {
+            let key = storage.key.as_str().to_string();
+            return Err(Error::PopulationExhausted { key });
+        }
+        let friendly_name = self.friendly_name(&storage, offset).ok_or_else(|| {
+            let key = storage.key.as_str();
+            let message = format!("offset {offset} is beyond the names of blob {key}");
+            let error = std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+            Error::storage(error, key, Operation::Parse).in_domain(self.domain)
+        })?;
         Ok(Identity {
             domain: self.domain,
             friendly_name,
             storage,
+            offset,
         })
     }
 
-    fn storage_object(&self, identifier: &str) -> Storage {
-        let mut hasher = blake3::Hasher::new_keyed(self.secret[..32].try_into().unwrap());
+    /// Generate `count` names which this population could assign, without using any storage.
+    /// Each name is chosen using a random storage key and offset, so names may repeat.
+    pub fn sample_names(&self, count: usize) -> Vec<String> {
+        use rand::Rng;
+
+        let mut rng = rand::rng();
+        (0..count)
+            .map(|_| {
+                let identifier: u128 = rng.random();
+                let storage = self.storage_object(&identifier.to_string());
+                let offset = rng.random_range(0..self.blob_capacity());
+                self.friendly_name(&storage, offset)
+                    .expect("offset should be within capacity")
+            })
+            .collect()
+    }
+
+    /// The [`Storage`] object which `identifier` is persisted as, without using any storage.
+    pub fn storage_object(&self, identifier: &str) -> Storage {
+        let mut hasher = blake3::Hasher::new_keyed(&self.hasher_key());
         hasher.update(identifier.as_bytes());
         let output = hasher.finalize();
         let mut buf = [0; 64];
         let bytes = base16_encode(output.as_bytes(), &mut buf).unwrap();
-        Storage::from(bytes)
+        Storage {
+            key: bytes[..STORAGE_KEY_LENGTH].into(),
+            digest: bytes[STORAGE_KEY_LENGTH..].into(),
+        }
+    }
+
+    /// The key of the keyed hash function. The first 32 bytes of secrets have always been used
+    /// as keys, so they are not derived, and populations using them keep their names.
+    fn hasher_key(&self) -> [u8; 32] {
+        if let Some(key) = self.secret.first_chunk::<32>() {
+            return *key;
+        }
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(None, self.secret)
+            .expand(KEY_DERIVATION_V1, &mut key)
+            .expect("32 bytes should be a valid length for HKDF-SHA256");
+        key
     }
 
-    fn friendly_name(&self, storage: &Storage, digest_offset: usize) -> String {
+    /// None if `digest_offset` is beyond the names available to the blob of `storage`.
+    pub(crate) fn friendly_name(&self, storage: &Storage, digest_offset: usize) -> Option<String> {
         let (_population_size, prefixes, _colors, _animals) = self.ingredients;
 
         // prefix comes from a compiled PHF of storage.key -> gerund
         // randomness is provided by the hash function that was used to derive the storage key
-        let prefix = prefixes.get(storage.key.as_str()).cloned().unwrap();
+        let prefix = prefixes.get(storage.key.as_str())?;
 
         // color and animal are randomly generated by using the storage key and population secret
         // to generate a random u64 value, which is used to select from a compiled list of words
-        let animals = self.color_animals(storage);
-        let (color, animal) = animals.get(digest_offset).unwrap();
+        let (colors, animals) = self.color_animals(storage);
+        let color = colors.get(digest_offset.checked_div(animals.len())?)?;
+        let animal = animals[digest_offset % animals.len()];
 
-        format!("{prefix}-{color}-{animal}")
+        Some(format!("{prefix}-{color}-{animal}"))
     }
 
-    fn color_animals(&self, storage: &Storage) -> Vec<(&str, &str)> {
+    /// Colors and animals for names in the blob of `storage`.
+    /// Offsets are assigned to each color in turn, with every animal, see `friendly_name`.
+    fn color_animals(&self, storage: &Storage) -> (Vec<&str>, Vec<&str>) {
         let (population_size, _prefixes, colors, animals) = self.ingredients;
 
         let required_color_animals = *population_size as u32 / 16u32.pow(STORAGE_KEY_LENGTH as u32);
 
         // use all of the few available colors
-        let colors = self.randomize(colors, storage, false);
+        let colors = self.randomize(colors, storage, false, colors.len());
 
         // ensure that animals are evenly distributed over colors
         // by using only enough animals to fill a color.
         // NOTE: this implies that the population size can only be chosen once
         let animals_per_color = required_color_animals.div_ceil(colors.len() as u32);
-        let animals = self
-            .randomize(animals, storage, true)
-            .into_iter()
-            .take(animals_per_color as usize)
-            .collect::<Vec<_>>();
+        let animals = self.randomize(animals, storage, true, animals_per_color as usize);
 
-        // fill each color with all available animals before using the next color
-        let mut results = vec![];
-        for color in colors {
-            for &animal in &animals {
-                results.push((color, animal))
-            }
-        }
-        results
+        (colors, animals)
     }
 
-    fn randomize<'a>(&self, words: &'a [&str], storage: &Storage, reverse: bool) -> Vec<&'a str> {
+    fn randomize<'a>(
+        &self,
+        words: &'a [&str],
+        storage: &Storage,
+        reverse: bool,
+        count: usize,
+    ) -> Vec<&'a str> {
         // randomization is idempotent because random number seed is based on population "secret"
 
         // randomized between populations
         let mut buf = [0; 64];
-        let pop_seed = base16_encode(&self.secret[..32], &mut buf).unwrap();
+        let pop_seed = base16_encode(&self.hasher_key(), &mut buf).unwrap();
         let pop_seed: u16 = HexString::<4>::from(&pop_seed[..4]).into();
 
         // randomized between storage blobs
@@ -133,7 +307,7 @@ impl<'dom> Population<'dom> {
             rng_seed = rng_seed.reverse_bits();
         }
 
-        randomized(words, rng_seed)
+        randomized_prefix(words, rng_seed, count)
     }
 }
 
@@ -144,10 +318,197 @@ mod tests {
     cargo test population -- --nocapture
     */
 
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Mutex;
     use std::time::Instant;
 
+    use bytes::Bytes;
+
     use super::*;
-    use crate::identity::{storage::RemoteStore, tests::*};
+    use crate::identity::{ConnectionBridge, storage::RemoteStore, tests::*};
+
+    #[test]
+    fn test_identities() -> Result<(), Error> {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let mut store = RemoteStore::new(MockBridge::default());
+        let identifiers = ["a@b.br", "c@d.br", "a@b.br"];
+        let identities = brazilian.identities(identifiers, &mut store)?;
+        assert_eq!(identities.len(), 3);
+        assert_eq!(identities[0], identities[2]);
+        assert_eq!(identities[1], brazilian.identity("c@d.br", &mut store)?);
+        Ok(())
+    }
+
+    // counts the calls for each storage key
+    #[derive(Default)]
+    struct CountingBridge {
+        inner: MockBridge,
+        gets: Mutex<HashMap<String, usize>>,
+        puts: Mutex<HashMap<String, usize>>,
+    }
+
+    impl ConnectionBridge for CountingBridge {
+        #[async_generic]
+        fn get(&self, key: &str) -> std::io::Result<Option<Bytes>> {
+            *self
+                .gets
+                .lock()
+                .unwrap()
+                .entry(key.to_string())
+                .or_default() += 1;
+            self.inner.get(key)
+        }
+
+        #[async_generic]
+        fn put(&self, key: &str, body: Bytes) -> std::io::Result<()> {
+            *self
+                .puts
+                .lock()
+                .unwrap()
+                .entry(key.to_string())
+                .or_default() += 1;
+            self.inner.put(key, body)
+        }
+    }
+
+    #[test]
+    fn test_identities_batched() -> Result<(), Error> {
+        let brazilian = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let identifiers = (0..2000).map(|i| format!("{i}@b.br")).collect::<Vec<_>>();
+        let keys = identifiers
+            .iter()
+            .map(|identifier| brazilian.storage_object(identifier).key.to_string())
+            .collect::<HashSet<_>>();
+        assert!(keys.len() < identifiers.len());
+
+        // each blob is fetched and written once for the whole batch
+        let mut store = RemoteStore::new(CountingBridge::default());
+        let identities = brazilian.identities(&identifiers, &mut store)?;
+        for calls in [&store.bridge.gets, &store.bridge.puts] {
+            let calls = calls.lock().unwrap();
+            assert_eq!(calls.keys().cloned().collect::<HashSet<_>>(), keys);
+            assert!(calls.values().all(|&count| count == 1));
+        }
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        let mut store = RemoteStore::new(CountingBridge::default());
+        let found = runtime.block_on(brazilian.identities_async(&identifiers, &mut store))?;
+        assert_eq!(found, identities);
+        for calls in [&store.bridge.gets, &store.bridge.puts] {
+            let calls = calls.lock().unwrap();
+            assert_eq!(calls.len(), keys.len());
+            assert!(calls.values().all(|&count| count == 1));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_debug_redacts_secret() {
+        let secret = b"0123456789abcdef0123456789abcdef";
+        let brazilian = Population {
+            domain: "br",
+            secret,
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let secret_bytes = format!("{:?}", &secret[..]);
+        let secret_bytes = &secret_bytes[1..secret_bytes.len() - 1];
+        let debug = format!("{brazilian:?}");
+        assert!(debug.contains("secret: [REDACTED; 32]"));
+        let memoized = format!("{:#?}", brazilian.memoized(8));
+        for debug in [debug, memoized] {
+            assert!(debug.contains("br"));
+            assert!(!debug.contains("0123456789abcdef"));
+            assert!(!debug.contains(secret_bytes));
+        }
+    }
+
+    #[test]
+    fn test_secret_lengths() -> Result<(), Error> {
+        let secret = b"0123456789abcdef0123456789abcdef";
+        let brazilian = |secret| Population {
+            domain: "br",
+            secret,
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        // secrets of at least 32 bytes are used as they always were
+        assert_eq!(brazilian(secret).hasher_key(), *secret);
+        let storage = brazilian(secret).storage_object("a@b.br");
+        let expected = blake3::keyed_hash(secret, b"a@b.br").to_hex();
+        assert_eq!(storage.to_string(), expected.as_str());
+        let long = [&secret[..], b"0123"].concat();
+        assert_eq!(brazilian(&long).hasher_key(), *secret);
+        assert_eq!(brazilian(&long).storage_object("a@b.br"), storage);
+
+        let keys = [&secret[..16], &secret[..31]].map(|secret| brazilian(secret).hasher_key());
+        for (i, key) in keys.iter().enumerate() {
+            assert_ne!(key, secret);
+            assert_ne!(key, &keys[(i + 1) % keys.len()]);
+        }
+        let mut store = RemoteStore::new(MockBridge::default());
+        let short = brazilian(&secret[..16]);
+        let identity = short.identity("a@b.br", &mut store)?;
+        assert_eq!(identity, short.identity("a@b.br", &mut store)?);
+        assert_ne!(identity.storage, storage);
+        Ok(())
+    }
+
+    #[test]
+    fn test_population_bounds() -> Result<(), Error> {
+        let secret = b"0123456789abcdef0123456789abcdef";
+        let brazilian = Population::new("br", secret, &PERFUME_INGREDIENTS)?;
+        assert_eq!(brazilian.blob_capacity(), 203080756 / 4096);
+
+        let tiny = crate::testing::tiny_population("tiny");
+        let tiny = Population::new(tiny.domain, tiny.secret, tiny.ingredients)?;
+        assert_eq!(tiny.blob_capacity(), 4);
+        let storage = tiny.storage_object("a@b.br");
+        let full = ["0", "1", "2", "3"]
+            .iter()
+            .enumerate()
+            .map(|(offset, d)| format!("{} {offset:>5}\n", d.repeat(61)))
+            .collect::<String>();
+        let mut store = RemoteStore::new(MockBridge::default());
+        store.bridge.put(storage.key.as_str(), full.into())?;
+        let error = tiny.identity("a@b.br", &mut store).unwrap_err();
+        assert!(
+            matches!(&error, Error::PopulationExhausted { key } if key == storage.key.as_str())
+        );
+        assert_eq!(error.kind(), crate::ErrorKind::Exhausted);
+
+        static UNPREFIXED: Ingredients = (
+            4 * 4096,
+            phf::Map {
+                key: 0,
+                disps: &[],
+                entries: &[],
+            },
+            &["red"],
+            &["cat"],
+        );
+        let error = Population::new("br", secret, &UNPREFIXED).unwrap_err();
+        assert_eq!(error.kind(), crate::ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample_names() {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let names = brazilian.sample_names(20);
+        assert_eq!(names.len(), 20);
+        assert!(names.iter().all(|n| n.split('-').count() == 3));
+    }
 
     #[test]
     fn test_distinct_names() -> Result<(), Error> {
@@ -160,9 +521,7 @@ mod tests {
             secret: b"0123456789abcdef0123456789abcdef",
             ingredients: &PERFUME_INGREDIENTS,
         };
-        let mut store = RemoteStore {
-            bridge: MockBridge::default(),
-        };
+        let mut store = RemoteStore::new(MockBridge::default());
 
         let start = Instant::now();
         let identities: Vec<Identity> = (0..test_identity_count)
@@ -207,7 +566,7 @@ mod tests {
                 _ => unimplemented!(),
             }
         }
-        diff_scores.sort_by(|a, b| a.0.cmp(&b.0));
+        diff_scores.sort_by_key(|a| a.0);
 
         let lowest_diff_score = diff_scores.first().unwrap().0;
         let least_diff_pairs: Vec<(u8, (usize, usize))> = diff_scores
@@ -0,0 +1,222 @@
+//! A [`StorageState`] which keeps offsets in a table of a PostgreSQL database.
+
+use std::future::Future;
+
+use tokio_postgres::Client;
+
+use crate::{Error, Operation};
+
+use super::storage::{PING_KEY, Storage, StorageState};
+
+// instances which create the table at once would otherwise fail on its catalog entries
+const CREATE_TABLE: &str = "BEGIN;
+SELECT pg_advisory_xact_lock(hashtextextended('perfume_offsets', 0));
+CREATE TABLE IF NOT EXISTS perfume_offsets (
+    domain TEXT NOT NULL,
+    storage_key TEXT NOT NULL,
+    digest TEXT NOT NULL,
+    digest_offset BIGINT NOT NULL,
+    PRIMARY KEY (domain, digest),
+    UNIQUE (domain, storage_key, digest_offset)
+);
+COMMIT;";
+
+const SELECT_OFFSET: &str =
+    "SELECT digest_offset FROM perfume_offsets WHERE domain = $1 AND digest = $2";
+
+// held until the transaction ends, by every instance assigning an offset to the storage key
+const LOCK_KEY: &str = "SELECT pg_advisory_xact_lock(hashtextextended($1, 0))";
+
+// the offset of a new digest follows the last offset of its storage key
+const INSERT_DIGEST: &str =
+    "INSERT INTO perfume_offsets (domain, storage_key, digest, digest_offset)
+    SELECT $1, $2, $3, COALESCE(MAX(digest_offset) + 1, 0) FROM perfume_offsets
+    WHERE domain = $1 AND storage_key = $2
+    RETURNING digest_offset";
+
+/// Implements [`StorageState`] with a table of a PostgreSQL database, for many instances of
+/// an application which assign offsets at once. Digests which are stored are read without
+/// locking. A new digest is inserted in a transaction which holds an advisory lock of its
+/// domain and storage key, so that instances take turns to give each one the next offset,
+/// instead of racing to write a blob as [`super::RemoteStore`]s do.
+///
+/// Offsets are counted from the table rather than drawn from a sequence, since values of a
+/// sequence are lost by transactions which roll back, and offsets must be continuous.
+/// The table `perfume_offsets` holds the digests of every domain, and is created if it does not
+/// exist. The connection of the client must be polled by a Tokio runtime, as in
+/// [`tokio_postgres::connect`]. Blocking calls run the query on a new thread with its own
+/// runtime, so that they can be made from within a runtime, while another thread polls the
+/// connection.
+#[derive(Debug)]
+pub struct PostgresStore {
+    client: Client,
+}
+
+impl PostgresStore {
+    /// Keep offsets in the database of `client`.
+    pub async fn new(client: Client) -> Result<Self, Error> {
+        client
+            .batch_execute(CREATE_TABLE)
+            .await
+            .map_err(|e| database_error(e, PING_KEY, Operation::Put))?;
+        Ok(Self { client })
+    }
+
+    /// The client of the database.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// The offset of the digest of `storage`, if it is stored.
+    pub async fn stored_offset(
+        &self,
+        domain: &str,
+        storage: &Storage,
+    ) -> Result<Option<usize>, Error> {
+        let key = storage.key.as_str();
+        let row = self
+            .client
+            .query_opt(SELECT_OFFSET, &[&domain, &storage.digest.as_str()])
+            .await
+            .map_err(|e| database_error(e, key, Operation::Get).in_domain(domain))?;
+        Ok(row.map(|row| row.get::<_, i64>(0) as usize))
+    }
+}
+
+// runs `query` to completion on a runtime of its own
+fn block_on<T>(query: impl Future<Output = Result<T, Error>> + Send) -> Result<T, Error>
+where
+    T: Send,
+{
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .map_err(|e| Error::storage(e, PING_KEY, Operation::Get))?
+                    .block_on(query)
+            })
+            .join()
+            .expect("query should complete without panicking")
+    })
+}
+
+fn database_error(e: tokio_postgres::Error, key: &str, operation: Operation) -> Error {
+    Error::storage(std::io::Error::other(e), key, operation)
+}
+
+impl StorageState for PostgresStore {
+    fn digest_offset(&mut self, domain: &str, storage: &Storage) -> Result<usize, Error> {
+        block_on(self.digest_offset_async(domain, storage))
+    }
+
+    async fn digest_offset_async(
+        &mut self,
+        domain: &str,
+        storage: &Storage,
+    ) -> Result<usize, Error> {
+        if let Some(offset) = self.stored_offset(domain, storage).await? {
+            return Ok(offset);
+        }
+        let key = storage.key.as_str();
+        let digest = storage.digest.as_str();
+        let put = |e| database_error(e, key, Operation::Put).in_domain(domain);
+        let transaction = self.client.transaction().await.map_err(put)?;
+        transaction
+            .execute(LOCK_KEY, &[&format!("{domain}/{key}")])
+            .await
+            .map_err(put)?;
+        // another instance may have inserted the digest before the lock was taken
+        let row = match transaction
+            .query_opt(SELECT_OFFSET, &[&domain, &digest])
+            .await
+            .map_err(put)?
+        {
+            Some(row) => row,
+            None => transaction
+                .query_one(INSERT_DIGEST, &[&domain, &key, &digest])
+                .await
+                .map_err(put)?,
+        };
+        transaction.commit().await.map_err(put)?;
+        Ok(row.get::<_, i64>(0) as usize)
+    }
+
+    /// Queries the table.
+    fn health_check(&mut self) -> Result<(), Error> {
+        block_on(self.health_check_async())
+    }
+
+    async fn health_check_async(&mut self) -> Result<(), Error> {
+        self.client
+            .batch_execute("SELECT 1 FROM perfume_offsets LIMIT 1")
+            .await
+            .map_err(|e| database_error(e, PING_KEY, Operation::Get))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::{Population, tests::*};
+
+    // a database for the test, such as "host=localhost user=postgres", which is skipped without one
+    const DATABASE: Option<&str> = option_env!("PERFUME_TEST_POSTGRES");
+
+    async fn connect(config: &str) -> PostgresStore {
+        let (client, connection) = tokio_postgres::connect(config, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        PostgresStore::new(client).await.unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_postgres_store() -> Result<(), Error> {
+        let Some(config) = DATABASE else {
+            return Ok(());
+        };
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let domain = format!("test-{}", random_hex_string::<8>().as_str());
+        let mut storage = population.storage_object("a@b.br");
+
+        // instances take turns to assign the offsets of a storage key
+        let workers = (0..4)
+            .map(|_| {
+                let domain = domain.clone();
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    let mut store = connect(config).await;
+                    let mut offsets = vec![];
+                    for _ in 0..25 {
+                        let mut next = storage.clone();
+                        next.digest = random_hex_string();
+                        offsets.push(store.digest_offset_async(&domain, &next).await.unwrap());
+                    }
+                    offsets
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut offsets = vec![];
+        for worker in workers {
+            offsets.extend(worker.await.unwrap());
+        }
+        offsets.sort();
+        assert_eq!(offsets, (0..100).collect::<Vec<_>>());
+
+        let mut store = connect(config).await;
+        store.health_check_async().await?;
+        assert_eq!(store.stored_offset(&domain, &storage).await?, None);
+        assert_eq!(store.digest_offset_async(&domain, &storage).await?, 100);
+        // blocking calls can be made from within the runtime
+        assert_eq!(store.digest_offset(&domain, &storage)?, 100);
+        storage.digest = random_hex_string();
+        assert_eq!(store.digest_offset(&domain, &storage)?, 101);
+        Ok(())
+    }
+}
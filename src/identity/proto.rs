@@ -0,0 +1,95 @@
+//! Messages of proto/perfume.proto, see [`super::BlobFormat::Protobuf`].
+
+use bytes::Bytes;
+use prost::Message;
+
+use super::format::is_valid_record;
+use super::storage::{RecordFlag, malformed, text_record};
+
+/// The offsets assigned to digests sharing a storage key.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StorageBlob {
+    /// Sorted by digest.
+    #[prost(message, repeated, tag = "1")]
+    pub records: Vec<StorageRecord>,
+}
+
+/// A digest and the position of the name assigned to it.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StorageRecord {
+    /// Lowercase hex, of equal length within a blob.
+    #[prost(string, tag = "1")]
+    pub digest: String,
+    /// Unique within a blob.
+    #[prost(uint32, tag = "2")]
+    pub offset: u32,
+    /// Absent from blobs written before tombstones were introduced, which are all live.
+    #[prost(enumeration = "Flag", tag = "3")]
+    pub flag: i32,
+}
+
+/// The [`RecordFlag`] of a [`StorageRecord`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Flag {
+    /// See [`RecordFlag::Live`].
+    Live = 0,
+    /// See [`RecordFlag::Tombstone`].
+    Tombstone = 1,
+    /// See [`RecordFlag::Compacted`].
+    Compacted = 2,
+}
+
+impl From<RecordFlag> for Flag {
+    fn from(flag: RecordFlag) -> Self {
+        match flag {
+            RecordFlag::Live => Self::Live,
+            RecordFlag::Tombstone => Self::Tombstone,
+            RecordFlag::Compacted => Self::Compacted,
+        }
+    }
+}
+
+impl From<Flag> for RecordFlag {
+    fn from(flag: Flag) -> Self {
+        match flag {
+            Flag::Live => Self::Live,
+            Flag::Tombstone => Self::Tombstone,
+            Flag::Compacted => Self::Compacted,
+        }
+    }
+}
+
+pub(super) fn decode(blob: &[u8]) -> std::io::Result<Bytes> {
+    let mut message = StorageBlob::decode(blob)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    for (number, record) in message.records.iter().enumerate() {
+        if !is_valid_record(&record.digest, record.offset as usize)
+            || Flag::try_from(record.flag).is_err()
+        {
+            return Err(malformed(number, format!("malformed record {number}")));
+        }
+    }
+    message
+        .records
+        .sort_unstable_by(|a, b| a.digest.cmp(&b.digest));
+    Ok(message
+        .records
+        .iter()
+        .map(|r| text_record(&r.digest, r.flag().into(), r.offset as usize))
+        .collect::<String>()
+        .into())
+}
+
+pub(super) fn encode(records: impl Iterator<Item = (String, RecordFlag, usize)>) -> Bytes {
+    let message = StorageBlob {
+        records: records
+            .map(|(digest, flag, offset)| StorageRecord {
+                digest,
+                offset: offset as u32,
+                flag: Flag::from(flag).into(),
+            })
+            .collect(),
+    };
+    message.encode_to_vec().into()
+}
@@ -0,0 +1,375 @@
+//! A [`ConnectionBridge`] which limits the rate of requests to another.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::future::Future;
+use std::ops::{ControlFlow, Range};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use super::storage::{BridgeResult, ConnectionBridge, Validated};
+
+/// Limits the operations and bytes per second passed to another bridge, such as to keep a
+/// backfill job within the request quota of an object store which is shared with production.
+///
+/// Each limit is a token bucket which holds one second of tokens, so that short bursts are
+/// allowed. Callers wait until there are enough tokens: blocking calls sleep the thread, and
+/// async calls are woken by a timer thread, so that no particular runtime is required.
+/// The size of a fetched blob is only known once it arrives, so it delays the next operation.
+/// Relies on the system clock, which is unavailable on `wasm32-unknown-unknown`.
+#[derive(Debug)]
+pub struct RateLimitedBridge<B> {
+    bridge: B,
+    ops: Mutex<Bucket>,
+    bytes: Option<Mutex<Bucket>>,
+}
+
+impl<B: ConnectionBridge> RateLimitedBridge<B> {
+    /// Pass at most `ops_per_second` operations to `bridge`.
+    pub fn new(bridge: B, ops_per_second: u32) -> Self {
+        Self {
+            bridge,
+            ops: Mutex::new(Bucket::new(ops_per_second.max(1) as f64)),
+            bytes: None,
+        }
+    }
+
+    /// Also limit the blob bytes sent or received to `bytes_per_second`.
+    pub fn with_bytes_per_second(mut self, bytes_per_second: u64) -> Self {
+        self.bytes = Some(Mutex::new(Bucket::new(bytes_per_second.max(1) as f64)));
+        self
+    }
+
+    /// The limited bridge.
+    pub fn inner(&self) -> &B {
+        &self.bridge
+    }
+
+    // reserves an operation and `bytes`, returning how long to wait before using them
+    fn acquire(&self, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let wait = self.ops.lock().unwrap().take(1.0, now);
+        match &self.bytes {
+            Some(bucket) => wait.max(bucket.lock().unwrap().take(bytes as f64, now)),
+            None => wait,
+        }
+    }
+
+    // accounts for `bytes` which were received, delaying later operations
+    fn charge(&self, bytes: usize) {
+        if let Some(bucket) = &self.bytes {
+            bucket.lock().unwrap().take(bytes as f64, Instant::now());
+        }
+    }
+}
+
+impl<B: ConnectionBridge + Sync> ConnectionBridge for RateLimitedBridge<B> {
+    fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        std::thread::sleep(self.acquire(0));
+        let body = self.bridge.get(key)?;
+        self.charge(body.as_ref().map_or(0, Bytes::len));
+        Ok(body)
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        std::thread::sleep(self.acquire(body.len()));
+        self.bridge.put(key, body)
+    }
+
+    async fn get_async(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        sleep(self.acquire(0)).await;
+        let body = self.bridge.get_async(key).await?;
+        self.charge(body.as_ref().map_or(0, Bytes::len));
+        Ok(body)
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        sleep(self.acquire(body.len())).await;
+        self.bridge.put_async(key, body).await
+    }
+
+    fn put_if_match(&self, key: &str, body: Bytes, validator: Option<&str>) -> BridgeResult<()> {
+        std::thread::sleep(self.acquire(body.len()));
+        self.bridge.put_if_match(key, body, validator)
+    }
+
+    async fn put_if_match_async(
+        &self,
+        key: &str,
+        body: Bytes,
+        validator: Option<&str>,
+    ) -> BridgeResult<()> {
+        sleep(self.acquire(body.len())).await;
+        self.bridge.put_if_match_async(key, body, validator).await
+    }
+
+    fn append(&self, key: &str, bytes: Bytes) -> BridgeResult<()> {
+        std::thread::sleep(self.acquire(bytes.len()));
+        self.bridge.append(key, bytes)
+    }
+
+    async fn append_async(&self, key: &str, bytes: Bytes) -> BridgeResult<()> {
+        sleep(self.acquire(bytes.len())).await;
+        self.bridge.append_async(key, bytes).await
+    }
+
+    fn exists(&self, key: &str) -> BridgeResult<bool> {
+        std::thread::sleep(self.acquire(0));
+        self.bridge.exists(key)
+    }
+
+    async fn exists_async(&self, key: &str) -> BridgeResult<bool> {
+        sleep(self.acquire(0)).await;
+        self.bridge.exists_async(key).await
+    }
+
+    fn get_validated(&self, key: &str, validator: Option<&str>) -> BridgeResult<Validated> {
+        std::thread::sleep(self.acquire(0));
+        let validated = self.bridge.get_validated(key, validator)?;
+        self.charge(validated_len(&validated));
+        Ok(validated)
+    }
+
+    async fn get_validated_async(
+        &self,
+        key: &str,
+        validator: Option<&str>,
+    ) -> BridgeResult<Validated> {
+        sleep(self.acquire(0)).await;
+        let validated = self.bridge.get_validated_async(key, validator).await?;
+        self.charge(validated_len(&validated));
+        Ok(validated)
+    }
+
+    fn get_chunks(
+        &self,
+        key: &str,
+        visit: &mut dyn FnMut(&[u8]) -> ControlFlow<()>,
+    ) -> BridgeResult<bool> {
+        std::thread::sleep(self.acquire(0));
+        let mut bytes = 0;
+        let found = self.bridge.get_chunks(key, &mut |chunk: &[u8]| {
+            bytes += chunk.len();
+            visit(chunk)
+        });
+        self.charge(bytes);
+        found
+    }
+
+    async fn get_chunks_async(
+        &self,
+        key: &str,
+        visit: &mut (dyn FnMut(&[u8]) -> ControlFlow<()> + Send),
+    ) -> BridgeResult<bool> {
+        sleep(self.acquire(0)).await;
+        let mut bytes = 0;
+        let found = self
+            .bridge
+            .get_chunks_async(key, &mut |chunk: &[u8]| {
+                bytes += chunk.len();
+                visit(chunk)
+            })
+            .await;
+        self.charge(bytes);
+        found
+    }
+
+    fn get_range(&self, key: &str, range: Range<u64>) -> BridgeResult<Option<(Bytes, u64)>> {
+        std::thread::sleep(self.acquire(0));
+        let read = self.bridge.get_range(key, range)?;
+        self.charge(read.as_ref().map_or(0, |(bytes, _length)| bytes.len()));
+        Ok(read)
+    }
+
+    async fn get_range_async(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> BridgeResult<Option<(Bytes, u64)>> {
+        sleep(self.acquire(0)).await;
+        let read = self.bridge.get_range_async(key, range).await?;
+        self.charge(read.as_ref().map_or(0, |(bytes, _length)| bytes.len()));
+        Ok(read)
+    }
+}
+
+fn validated_len(validated: &Validated) -> usize {
+    match validated {
+        Validated::Modified {
+            body: Some(body), ..
+        } => body.len(),
+        _ => 0,
+    }
+}
+
+// holds up to one second of tokens, which may be overdrawn
+#[derive(Debug)]
+struct Bucket {
+    per_second: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(per_second: f64) -> Self {
+        Self {
+            per_second,
+            tokens: per_second,
+            updated: Instant::now(),
+        }
+    }
+
+    // removes `amount` tokens, returning how long until the bucket is no longer overdrawn
+    fn take(&mut self, amount: f64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.per_second) - amount;
+        self.updated = self.updated.max(now);
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.per_second)
+        }
+    }
+}
+
+// completes after `duration`, woken by a timer thread so that it works with any async runtime
+pub(crate) fn sleep(duration: Duration) -> impl Future<Output = ()> + Send {
+    let deadline = Instant::now() + duration;
+    let waker: Arc<Mutex<Option<Waker>>> = Arc::default();
+    let mut timer_started = false;
+    std::future::poll_fn(move |cx| {
+        if Instant::now() >= deadline {
+            return Poll::Ready(());
+        }
+        *waker.lock().unwrap() = Some(cx.waker().clone());
+        if !timer_started {
+            timer_started = true;
+            Timer::shared().wake_at(deadline, waker.clone());
+        }
+        Poll::Pending
+    })
+}
+
+type WakerSlot = Arc<Mutex<Option<Waker>>>;
+
+// wakes sleeping futures at their deadlines, from one thread which is started with the first
+struct Timer {
+    // deadline, order of registration and the waker of a future
+    wakers: Mutex<BinaryHeap<Reverse<(Instant, u64, Wake)>>>,
+    changed: Condvar,
+    registered: AtomicU64,
+}
+
+// a waker slot which is ordered by its deadline alone
+struct Wake(WakerSlot);
+
+impl PartialEq for Wake {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Wake {}
+
+impl PartialOrd for Wake {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Wake {
+    fn cmp(&self, _: &Self) -> std::cmp::Ordering {
+        std::cmp::Ordering::Equal
+    }
+}
+
+impl Timer {
+    fn shared() -> &'static Timer {
+        static TIMER: OnceLock<&'static Timer> = OnceLock::new();
+        TIMER.get_or_init(|| {
+            let timer: &'static Timer = Box::leak(Box::new(Timer {
+                wakers: Mutex::default(),
+                changed: Condvar::new(),
+                registered: AtomicU64::new(0),
+            }));
+            std::thread::Builder::new()
+                .name("perfume-timer".to_string())
+                .spawn(|| timer.run())
+                .expect("failed to start the timer thread");
+            timer
+        })
+    }
+
+    fn wake_at(&self, deadline: Instant, waker: WakerSlot) {
+        let order = self.registered.fetch_add(1, Ordering::Relaxed);
+        let mut wakers = self.wakers.lock().unwrap();
+        let earliest = wakers
+            .peek()
+            .is_none_or(|Reverse((next, ..))| deadline < *next);
+        wakers.push(Reverse((deadline, order, Wake(waker))));
+        if earliest {
+            self.changed.notify_one();
+        }
+    }
+
+    fn run(&self) {
+        let mut wakers = self.wakers.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            while let Some(Reverse((deadline, ..))) = wakers.peek() {
+                if *deadline > now {
+                    break;
+                }
+                let Some(Reverse((_, _, Wake(waker)))) = wakers.pop() else {
+                    break;
+                };
+                if let Some(waker) = waker.lock().unwrap().take() {
+                    waker.wake();
+                }
+            }
+            wakers = match wakers.peek() {
+                Some(Reverse((deadline, ..))) => {
+                    let timeout = deadline.saturating_duration_since(now);
+                    self.changed.wait_timeout(wakers, timeout).unwrap().0
+                }
+                None => self.changed.wait(wakers).unwrap(),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::tests::*;
+
+    #[tokio::test]
+    async fn test_rate_limited_bridge() -> std::io::Result<()> {
+        let bridge = RateLimitedBridge::new(MockBridge::default(), 100);
+        let start = Instant::now();
+        // the first second of operations is a burst
+        for _ in 0..150 {
+            bridge.get("abc")?;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(450));
+
+        let bridge =
+            RateLimitedBridge::new(MockBridge::default(), 1000).with_bytes_per_second(1000);
+        let start = Instant::now();
+        bridge
+            .put_async("abc", Bytes::from(vec![b'a'; 1500]))
+            .await?;
+        assert!(start.elapsed() >= Duration::from_millis(450));
+        let start = Instant::now();
+        // waits for the bytes of the fetched blob
+        assert!(bridge.get_async("abc").await?.is_some());
+        bridge.get_async("abc").await?;
+        assert!(start.elapsed() >= Duration::from_millis(1400));
+        assert!(!bridge.inner().is_empty());
+        Ok(())
+    }
+}
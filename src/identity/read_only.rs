@@ -0,0 +1,90 @@
+//! A [`StorageState`] which never assigns offsets.
+
+use async_generic::async_generic;
+
+use crate::Error;
+
+use super::storage::{ConnectionBridge, RemoteStore, Storage, StorageState};
+
+/// Serves the identities which are already assigned in the blobs of a [`RemoteStore`], and
+/// returns an [`Error::ReadOnly`] for digests which are not, instead of inserting them, so
+/// that dashboards and analytics jobs can resolve names without ever changing them.
+///
+/// Blobs are only read, so the bridge can use credentials without write access. Digests which
+/// were deleted are errors, as for [`RemoteStore`]. Offsets kept by other stores, such as an
+/// [`crate::identity::InMemoryStore`] or a `SqliteStore`, are read with their `stored_offset`
+/// methods instead.
+#[derive(Debug)]
+pub struct ReadOnlyStore<B: ConnectionBridge> {
+    store: RemoteStore<B>,
+}
+
+impl<B: ConnectionBridge> ReadOnlyStore<B> {
+    /// Look up digests in the blobs of `store`.
+    pub fn new(store: RemoteStore<B>) -> Self {
+        Self { store }
+    }
+
+    /// The store which blobs are read from.
+    pub fn store(&self) -> &RemoteStore<B> {
+        &self.store
+    }
+}
+
+impl<B> StorageState for ReadOnlyStore<B>
+where
+    B: ConnectionBridge + Send,
+{
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn digest_offset(&mut self, domain: &str, storage: &Storage) -> Result<usize, Error> {
+        let mut stored = Ok(0);
+        if _async {
+            stored = self.store.lookup_async(domain, storage).await?;
+        } else {
+            stored = self.store.lookup(domain, storage)?;
+        }
+        stored.map_err(|_next_offset| Error::ReadOnly {
+            domain: domain.to_string(),
+            key: storage.key.to_string(),
+        })
+    }
+
+    #[async_generic]
+    fn health_check(&mut self) -> Result<(), Error> {
+        if _async {
+            self.store.health_check_async().await
+        } else {
+            self.store.health_check()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorKind;
+    use crate::identity::{Population, tests::*};
+
+    #[test]
+    fn test_read_only_store() -> Result<(), Error> {
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let mut store = RemoteStore::new(MockBridge::default());
+        let identity = population.identity("a@b.br", &mut store)?;
+
+        let bridge = std::mem::take(&mut store.bridge);
+        let mut read_only = ReadOnlyStore::new(RemoteStore::new(bridge));
+        assert_eq!(population.identity("a@b.br", &mut read_only)?, identity);
+        let error = population.identity("c@d.br", &mut read_only).unwrap_err();
+        assert!(matches!(&error, Error::ReadOnly { domain, .. } if domain == "br"));
+        assert_eq!(error.kind(), ErrorKind::ReadOnly);
+        assert!(!error.is_retryable());
+        assert_eq!(read_only.store().bridge.len(), 1);
+        read_only.health_check()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,292 @@
+//! A [`LockProvider`] which keeps its locks in Redis.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::lock::{
+    LockProvider, lock_lost, lock_not_held, lock_token, wait_for_lock, wait_for_lock_async,
+};
+use super::storage::BridgeResult;
+
+// deletes a lock only if it still holds the token of its holder
+const RELEASE_SCRIPT: &str =
+    "if redis.call('get', KEYS[1]) == ARGV[1] then return redis.call('del', KEYS[1]) end return 0";
+
+/// Takes a lock by setting the Redis key `{key}.lock` if it is not set, with
+/// `SET ... NX PX <expiry>`, and releases it by deleting the key if it still holds the token
+/// which was set, so that a lock which expired and was taken by another writer is kept.
+///
+/// Locks expire after 30 seconds by default, so that a lock is not held forever by a process
+/// which stopped without releasing it. The expiry should be much longer than an insert takes.
+/// A single Redis server is used, with a blocking connection which is opened with the first
+/// lock and opened again after an error. Redis can lose a lock which was not yet replicated
+/// when it fails over, so assignments should also be protected by conditional writes, see
+/// [`crate::identity::ConnectionBridge::put_if_match`].
+#[derive(Debug)]
+pub struct RedisLock {
+    address: String,
+    password: Option<String>,
+    expiry: Duration,
+    timeout: Duration,
+    connection: Mutex<Option<BufReader<TcpStream>>>,
+    // key -> token of the lock
+    held: Mutex<HashMap<String, String>>,
+}
+
+// a reply of the Redis protocol, without arrays, which locks do not use
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+}
+
+impl RedisLock {
+    /// Connect to the Redis server at `address`, such as "localhost:6379".
+    pub fn new(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            password: None,
+            expiry: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+            connection: Mutex::default(),
+            held: Mutex::default(),
+        }
+    }
+
+    /// Authenticate with `AUTH <password>` after connecting.
+    pub fn with_password(mut self, password: &str) -> Self {
+        self.password = Some(password.to_string());
+        self
+    }
+
+    /// Let Redis remove locks which were held for longer than `expiry`.
+    pub fn with_expiry(mut self, expiry: Duration) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    /// Wait at most `timeout` for a lock, 10 seconds by default. Also the timeout of
+    /// each request to Redis.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // true if the lock was taken
+    fn try_acquire(&self, key: &str) -> BridgeResult<bool> {
+        let token = lock_token();
+        let expiry = self.expiry.as_millis().max(1).to_string();
+        let lock_key = format!("{key}.lock");
+        let args = ["SET", &lock_key, &token, "NX", "PX", &expiry];
+        match self.command(&args)? {
+            Reply::Status(status) if status == "OK" => {
+                self.held.lock().unwrap().insert(key.to_string(), token);
+                Ok(true)
+            }
+            Reply::Bulk(None) => Ok(false),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    // sends a command on the connection, which is opened again after an error
+    fn command(&self, args: &[&str]) -> BridgeResult<Reply> {
+        let mut connection = self.connection.lock().unwrap();
+        let stream = match connection.take() {
+            Some(stream) => stream,
+            None => self.connect()?,
+        };
+        let (stream, reply) = request(stream, args)?;
+        *connection = Some(stream);
+        match reply {
+            Reply::Status(error) if error.starts_with('-') => Err(io::Error::other(error)),
+            reply => Ok(reply),
+        }
+    }
+
+    fn connect(&self) -> BridgeResult<BufReader<TcpStream>> {
+        let stream = TcpStream::connect(&self.address)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let stream = BufReader::new(stream);
+        let Some(password) = &self.password else {
+            return Ok(stream);
+        };
+        match request(stream, &["AUTH", password])? {
+            (stream, Reply::Status(status)) if status == "OK" => Ok(stream),
+            (_, Reply::Status(error)) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                error.trim_start_matches('-').to_string(),
+            )),
+            (_, reply) => Err(unexpected(reply)),
+        }
+    }
+}
+
+// sends a command, returning the connection with the reply, or else dropping it.
+// Errors replied by Redis are statuses beginning with "-".
+fn request(
+    mut stream: BufReader<TcpStream>,
+    args: &[&str],
+) -> BridgeResult<(BufReader<TcpStream>, Reply)> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n{arg}\r\n", arg.len()).as_bytes());
+    }
+    stream.get_mut().write_all(&command)?;
+    let reply = read_reply(&mut stream)?;
+    Ok((stream, reply))
+}
+
+fn read_reply(stream: &mut impl BufRead) -> BridgeResult<Reply> {
+    let mut line = String::new();
+    stream.read_line(&mut line)?;
+    let Some(line) = line.strip_suffix("\r\n") else {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    };
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("reply {line:?}"));
+    let (kind, value) = line.split_at_checked(1).ok_or_else(invalid)?;
+    match kind {
+        "+" => Ok(Reply::Status(value.to_string())),
+        "-" => Ok(Reply::Status(line.to_string())),
+        ":" => Ok(Reply::Integer(value.parse().map_err(|_| invalid())?)),
+        "$" => {
+            let Ok(length) = value.parse::<usize>() else {
+                return match value {
+                    "-1" => Ok(Reply::Bulk(None)),
+                    _ => Err(invalid()),
+                };
+            };
+            let mut body = vec![0; length + 2];
+            stream.read_exact(&mut body)?;
+            body.truncate(length);
+            Ok(Reply::Bulk(Some(body)))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn unexpected(reply: Reply) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected reply {reply:?}"),
+    )
+}
+
+impl LockProvider for RedisLock {
+    fn acquire(&self, key: &str) -> BridgeResult<()> {
+        wait_for_lock(key, self.timeout, || self.try_acquire(key))
+    }
+
+    /// Fails if the lock expired, and perhaps was taken by another writer.
+    fn release(&self, key: &str) -> BridgeResult<()> {
+        let token = self.held.lock().unwrap().remove(key);
+        let Some(token) = token else {
+            return Err(lock_not_held(key));
+        };
+        let lock_key = format!("{key}.lock");
+        match self.command(&["EVAL", RELEASE_SCRIPT, "1", &lock_key, &token])? {
+            Reply::Integer(1) => Ok(()),
+            Reply::Integer(_) => Err(lock_lost(key)),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    fn acquire_async<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> Pin<Box<dyn Future<Output = BridgeResult<()>> + Send + 'a>> {
+        Box::pin(wait_for_lock_async(key, self.timeout, || {
+            self.try_acquire(key)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::Error;
+    use crate::identity::{Population, RemoteStore, tests::*};
+
+    // serves SET NX and the release script, with keys which never expire
+    fn redis_server() -> io::Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?.to_string();
+        let keys: Arc<Mutex<HashMap<String, String>>> = Arc::default();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let keys = keys.clone();
+                std::thread::spawn(move || -> io::Result<()> {
+                    let mut stream = BufReader::new(stream?);
+                    loop {
+                        let mut line = String::new();
+                        if stream.read_line(&mut line)? == 0 {
+                            return Ok(());
+                        }
+                        let count = line.trim_end()[1..].parse::<usize>().unwrap();
+                        let mut args = vec![];
+                        for _ in 0..count {
+                            let Reply::Bulk(Some(arg)) = read_reply(&mut stream)? else {
+                                panic!("not a bulk string");
+                            };
+                            args.push(String::from_utf8(arg).unwrap());
+                        }
+                        let mut keys = keys.lock().unwrap();
+                        let reply = match args[0].as_str() {
+                            "SET" if keys.contains_key(&args[1]) => "$-1\r\n",
+                            "SET" => {
+                                keys.insert(args[1].clone(), args[2].clone());
+                                "+OK\r\n"
+                            }
+                            "EVAL" if keys.get(&args[3]) == Some(&args[4]) => {
+                                keys.remove(&args[3]);
+                                ":1\r\n"
+                            }
+                            "EVAL" => ":0\r\n",
+                            _ => "-ERR unknown command\r\n",
+                        };
+                        stream.get_mut().write_all(reply.as_bytes())?;
+                    }
+                });
+            }
+        });
+        Ok(address)
+    }
+
+    #[test]
+    fn test_redis_lock() -> Result<(), Error> {
+        let address = redis_server()?;
+        let locks = Arc::new(RedisLock::new(&address).with_timeout(Duration::from_millis(50)));
+        locks.acquire("br/abc")?;
+        let other = RedisLock::new(&address).with_timeout(Duration::from_millis(50));
+        let error = other.acquire("br/abc").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        locks.release("br/abc")?;
+        other.acquire("br/abc")?;
+        other.release("br/abc")?;
+        assert!(other.release("br/abc").is_err());
+
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let mut store = RemoteStore::new(MockBridge::default()).with_lock_provider(locks.clone());
+        let identity = population.identity("a@b.br", &mut store)?;
+        assert_eq!(population.identity("a@b.br", &mut store)?, identity);
+        let error = RedisLock::new(&address)
+            .with_password("secret")
+            .acquire("br/abc")
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        Ok(())
+    }
+}
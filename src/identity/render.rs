@@ -0,0 +1,135 @@
+//! Rendering of friendly names for terminals.
+
+use super::Identity;
+
+/// Colors of the built-in word list and other common color names, in alphabetical order.
+const COLOR_RGB: &[(&str, [u8; 3])] = &[
+    ("amber", [0xff, 0xbf, 0x00]),
+    ("amethyst", [0x99, 0x66, 0xcc]),
+    ("aqua", [0x00, 0xff, 0xff]),
+    ("azure", [0xf0, 0xff, 0xff]),
+    ("bay", [0x9b, 0x5b, 0x2e]),
+    ("beige", [0xf5, 0xf5, 0xdc]),
+    ("bisque", [0xff, 0xe4, 0xc4]),
+    ("black", [0x00, 0x00, 0x00]),
+    ("blue", [0x00, 0x00, 0xff]),
+    ("bronze", [0xcd, 0x7f, 0x32]),
+    ("brown", [0xa5, 0x2a, 0x2a]),
+    ("cerulean", [0x00, 0x7b, 0xa7]),
+    ("chartreuse", [0x7f, 0xff, 0x00]),
+    ("cobalt", [0x00, 0x47, 0xab]),
+    ("copper", [0xb8, 0x73, 0x33]),
+    ("coral", [0xff, 0x7f, 0x50]),
+    ("cream", [0xff, 0xfd, 0xd0]),
+    ("crimson", [0xdc, 0x14, 0x3c]),
+    ("cyan", [0x00, 0xff, 0xff]),
+    ("ebony", [0x55, 0x5d, 0x50]),
+    ("ecru", [0xc2, 0xb2, 0x80]),
+    ("emerald", [0x50, 0xc8, 0x78]),
+    ("fawn", [0xe5, 0xaa, 0x70]),
+    ("flax", [0xee, 0xdc, 0x82]),
+    ("fuchsia", [0xff, 0x00, 0xff]),
+    ("gold", [0xff, 0xd7, 0x00]),
+    ("gray", [0x80, 0x80, 0x80]),
+    ("green", [0x00, 0x80, 0x00]),
+    ("grey", [0x80, 0x80, 0x80]),
+    ("indigo", [0x4b, 0x00, 0x82]),
+    ("ivory", [0xff, 0xff, 0xf0]),
+    ("jade", [0x00, 0xa8, 0x6b]),
+    ("jet", [0x34, 0x34, 0x34]),
+    ("khaki", [0xf0, 0xe6, 0x8c]),
+    ("lavender", [0xe6, 0xe6, 0xfa]),
+    ("lilac", [0xc8, 0xa2, 0xc8]),
+    ("lime", [0x00, 0xff, 0x00]),
+    ("linen", [0xfa, 0xf0, 0xe6]),
+    ("magenta", [0xff, 0x00, 0xff]),
+    ("maroon", [0x80, 0x00, 0x00]),
+    ("mauve", [0xe0, 0xb0, 0xff]),
+    ("mint", [0x3e, 0xb4, 0x89]),
+    ("mustard", [0xff, 0xdb, 0x58]),
+    ("navy", [0x00, 0x00, 0x80]),
+    ("ochre", [0xcc, 0x77, 0x22]),
+    ("olive", [0x80, 0x80, 0x00]),
+    ("opal", [0xa8, 0xc3, 0xbc]),
+    ("orange", [0xff, 0xa5, 0x00]),
+    ("orchid", [0xda, 0x70, 0xd6]),
+    ("peach", [0xff, 0xe5, 0xb4]),
+    ("pear", [0xd1, 0xe2, 0x31]),
+    ("pearl", [0xea, 0xe0, 0xc8]),
+    ("pink", [0xff, 0xc0, 0xcb]),
+    ("plum", [0xdd, 0xa0, 0xdd]),
+    ("puce", [0xcc, 0x88, 0x99]),
+    ("purple", [0x80, 0x00, 0x80]),
+    ("red", [0xff, 0x00, 0x00]),
+    ("rose", [0xff, 0x00, 0x7f]),
+    ("ruby", [0xe0, 0x11, 0x5f]),
+    ("rust", [0xb7, 0x41, 0x0e]),
+    ("saffron", [0xf4, 0xc4, 0x30]),
+    ("salmon", [0xfa, 0x80, 0x72]),
+    ("sapphire", [0x0f, 0x52, 0xba]),
+    ("scarlet", [0xff, 0x24, 0x00]),
+    ("sepia", [0x70, 0x42, 0x14]),
+    ("sienna", [0xa0, 0x52, 0x2d]),
+    ("silver", [0xc0, 0xc0, 0xc0]),
+    ("snow", [0xff, 0xfa, 0xfa]),
+    ("tan", [0xd2, 0xb4, 0x8c]),
+    ("taupe", [0x48, 0x3c, 0x32]),
+    ("teal", [0x00, 0x80, 0x80]),
+    ("thistle", [0xd8, 0xbf, 0xd8]),
+    ("tomato", [0xff, 0x63, 0x47]),
+    ("turquoise", [0x40, 0xe0, 0xd0]),
+    ("umber", [0x63, 0x51, 0x47]),
+    ("vermilion", [0xe3, 0x42, 0x34]),
+    ("violet", [0xee, 0x82, 0xee]),
+    ("wheat", [0xf5, 0xde, 0xb3]),
+    ("white", [0xff, 0xff, 0xff]),
+    ("wine", [0x72, 0x2f, 0x37]),
+    ("yellow", [0xff, 0xff, 0x00]),
+];
+
+/// The red, green and blue components of the color `name`, if it is known.
+fn color_rgb(name: &str) -> Option<[u8; 3]> {
+    let found = COLOR_RGB.binary_search_by_key(&name, |(color, _rgb)| color);
+    found.ok().map(|index| COLOR_RGB[index].1)
+}
+
+impl Identity<'_> {
+    /// The friendly name, with its color word in its own color, as a 24-bit ANSI escape
+    /// sequence for terminals which support truecolor. Names with color words which are not
+    /// known are returned as they are.
+    pub fn render_ansi(&self) -> String {
+        let mut words = self.friendly_name.splitn(3, '-');
+        let (Some(prefix), Some(color), Some(animal)) = (words.next(), words.next(), words.next())
+        else {
+            return self.friendly_name.clone();
+        };
+        match color_rgb(color) {
+            Some([r, g, b]) => format!("{prefix}-\x1b[38;2;{r};{g};{b}m{color}\x1b[0m-{animal}"),
+            None => self.friendly_name.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_ansi() {
+        assert!(COLOR_RGB.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        for color in include_str!("../../data/colors.txt").lines() {
+            assert!(color_rgb(color).is_some(), "{color} has no rgb value");
+        }
+
+        let mut identity = Identity {
+            friendly_name: "knitting-teal-otter".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            identity.render_ansi(),
+            "knitting-\x1b[38;2;0;128;128mteal\x1b[0m-otter"
+        );
+        identity.friendly_name = "knitting-unknown-otter".to_string();
+        assert_eq!(identity.render_ansi(), "knitting-unknown-otter");
+    }
+}
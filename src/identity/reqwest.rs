@@ -0,0 +1,334 @@
+//! A [`ConnectionBridge`] which keeps blobs on an HTTP server, using an async client.
+
+use std::future::Future;
+use std::ops::Range;
+
+use bytes::Bytes;
+use reqwest::header::{CONTENT_RANGE, ETAG, HeaderMap, HeaderName, IF_MATCH, IF_NONE_MATCH, RANGE};
+use reqwest::{Client, StatusCode};
+
+use super::storage::{
+    BridgeResult, ConnectionBridge, Validated, content_range_length, range_header, slice_range,
+};
+
+/// Stores blobs on an HTTP server with `GET`, `HEAD` and `PUT` requests for the url of each
+/// key, as described by openapi/perfume.json and served by `perfume::server::router`. A key
+/// template of `{domain}/{key}` matches the paths of the protocol.
+///
+/// Blobs which are not found are absent, and any other unexpected status is an error. Blobs are
+/// revalidated with their ETags, see [`ConnectionBridge::get_validated`], and written on the
+/// condition that they still have them, see [`ConnectionBridge::put_if_match`]. TLS, proxies and
+/// timeouts are configured on the [`Client`] given to [`ReqwestBridge::with_client`].
+/// Blocking calls run the request on a new thread with its own Tokio runtime, so that they can
+/// be made from within a runtime.
+#[derive(Debug, Clone)]
+pub struct ReqwestBridge {
+    client: Client,
+    url: String,
+    headers: HeaderMap,
+}
+
+impl ReqwestBridge {
+    /// Keep blobs under the base `url`, such as `https://example.com/blobs`.
+    pub fn new(url: &str) -> BridgeResult<Self> {
+        let parsed = reqwest::Url::parse(url).map_err(|e| {
+            let message = format!("invalid store url {url}: {e}");
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+        })?;
+        if parsed.cannot_be_a_base() {
+            let message = format!("invalid store url {url}: it cannot be a base");
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                message,
+            ));
+        }
+        Ok(Self {
+            client: Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            headers: HeaderMap::new(),
+        })
+    }
+
+    /// Send requests with `client`, such as one built with the root certificates or client
+    /// identity of a private server.
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Send `headers` with every request, such as for authorization.
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// The url of the blob of `key`.
+    pub fn resource_url(&self, key: &str) -> String {
+        format!("{}/{key}", self.url)
+    }
+
+    // stores `body`, only if the blob matches `condition` when there is one
+    async fn send_put(
+        &self,
+        key: &str,
+        body: Bytes,
+        condition: Option<(HeaderName, &str)>,
+    ) -> BridgeResult<()> {
+        let resource_url = self.resource_url(key);
+        let mut request = self.client.put(&resource_url).headers(self.headers.clone());
+        if let Some((name, value)) = condition {
+            request = request.header(name, value);
+        }
+        let response = request
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| request_error(&resource_url, e))?;
+        match response.status() {
+            StatusCode::OK => Ok(()),
+            StatusCode::PRECONDITION_FAILED => {
+                let message = format!("blob at {resource_url} was modified by another writer");
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    message,
+                ))
+            }
+            unexpected => Err(unexpected_status(&resource_url, unexpected)),
+        }
+    }
+
+    // runs `request` to completion on a runtime of its own
+    fn block_on<T>(&self, request: impl Future<Output = BridgeResult<T>> + Send) -> BridgeResult<T>
+    where
+        T: Send,
+    {
+        std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()?
+                        .block_on(request)
+                })
+                .join()
+                .expect("request should complete without panicking")
+        })
+    }
+}
+
+fn request_error(resource_url: &str, e: reqwest::Error) -> std::io::Error {
+    let kind = if e.is_timeout() {
+        std::io::ErrorKind::TimedOut
+    } else if e.is_connect() {
+        std::io::ErrorKind::ConnectionRefused
+    } else {
+        std::io::ErrorKind::Other
+    };
+    std::io::Error::new(
+        kind,
+        format!("IO failure on request to {resource_url}: {e}"),
+    )
+}
+
+// responses of servers which are overloaded or restarting are retryable, see `RetryBridge`
+fn unexpected_status(resource_url: &str, status: StatusCode) -> std::io::Error {
+    let kind = match status {
+        StatusCode::TOO_MANY_REQUESTS
+        | StatusCode::BAD_GATEWAY
+        | StatusCode::SERVICE_UNAVAILABLE
+        | StatusCode::GATEWAY_TIMEOUT => std::io::ErrorKind::ResourceBusy,
+        _ => std::io::ErrorKind::Other,
+    };
+    let message = format!("unexpected HTTP response on request to {resource_url}: {status}");
+    std::io::Error::new(kind, message)
+}
+
+impl ConnectionBridge for ReqwestBridge {
+    fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        self.block_on(self.get_async(key))
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.block_on(self.put_async(key, body))
+    }
+
+    async fn get_async(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        match self.get_validated_async(key, None).await? {
+            Validated::Modified { body, .. } => Ok(body),
+            Validated::NotModified => unreachable!("blobs without a validator are modified"),
+        }
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.send_put(key, body, None).await
+    }
+
+    fn put_if_match(&self, key: &str, body: Bytes, validator: Option<&str>) -> BridgeResult<()> {
+        self.block_on(self.put_if_match_async(key, body, validator))
+    }
+
+    async fn put_if_match_async(
+        &self,
+        key: &str,
+        body: Bytes,
+        validator: Option<&str>,
+    ) -> BridgeResult<()> {
+        let condition = match validator {
+            Some(etag) => (IF_MATCH, etag),
+            None => (IF_NONE_MATCH, "*"),
+        };
+        self.send_put(key, body, Some(condition)).await
+    }
+
+    fn exists(&self, key: &str) -> BridgeResult<bool> {
+        self.block_on(self.exists_async(key))
+    }
+
+    async fn exists_async(&self, key: &str) -> BridgeResult<bool> {
+        let resource_url = self.resource_url(key);
+        let response = self
+            .client
+            .head(&resource_url)
+            .headers(self.headers.clone())
+            .send()
+            .await
+            .map_err(|e| request_error(&resource_url, e))?;
+        match response.status() {
+            StatusCode::OK => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            unexpected => Err(unexpected_status(&resource_url, unexpected)),
+        }
+    }
+
+    fn get_validated(&self, key: &str, validator: Option<&str>) -> BridgeResult<Validated> {
+        self.block_on(self.get_validated_async(key, validator))
+    }
+
+    async fn get_validated_async(
+        &self,
+        key: &str,
+        validator: Option<&str>,
+    ) -> BridgeResult<Validated> {
+        let resource_url = self.resource_url(key);
+        let mut request = self.client.get(&resource_url).headers(self.headers.clone());
+        if let Some(etag) = validator {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| request_error(&resource_url, e))?;
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        match response.status() {
+            StatusCode::NOT_MODIFIED => Ok(Validated::NotModified),
+            StatusCode::OK => {
+                let body = response
+                    .bytes()
+                    .await
+                    .map_err(|e| request_error(&resource_url, e))?;
+                Ok(Validated::Modified {
+                    body: Some(body),
+                    validator: etag,
+                })
+            }
+            StatusCode::NOT_FOUND => Ok(Validated::Modified {
+                body: None,
+                validator: None,
+            }),
+            unexpected => Err(unexpected_status(&resource_url, unexpected)),
+        }
+    }
+
+    fn get_range(&self, key: &str, range: Range<u64>) -> BridgeResult<Option<(Bytes, u64)>> {
+        self.block_on(self.get_range_async(key, range))
+    }
+
+    async fn get_range_async(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> BridgeResult<Option<(Bytes, u64)>> {
+        let resource_url = self.resource_url(key);
+        let response = self
+            .client
+            .get(&resource_url)
+            .headers(self.headers.clone())
+            .header(RANGE, range_header(&range))
+            .send()
+            .await
+            .map_err(|e| request_error(&resource_url, e))?;
+        let length = response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|v| content_range_length(v.to_str().ok()?));
+        match (response.status(), length) {
+            (StatusCode::PARTIAL_CONTENT, Some(length)) => {
+                let body = response
+                    .bytes()
+                    .await
+                    .map_err(|e| request_error(&resource_url, e))?;
+                let requested = 0..range.end.saturating_sub(range.start);
+                Ok(Some((slice_range(&body, &requested), length)))
+            }
+            (StatusCode::RANGE_NOT_SATISFIABLE, Some(length)) => Ok(Some((Bytes::new(), length))),
+            // the server sent the whole blob
+            (StatusCode::OK, _) => {
+                let body = response
+                    .bytes()
+                    .await
+                    .map_err(|e| request_error(&resource_url, e))?;
+                Ok(Some((slice_range(&body, &range), body.len() as u64)))
+            }
+            (StatusCode::NOT_FOUND, _) => Ok(None),
+            (unexpected, _) => Err(unexpected_status(&resource_url, unexpected)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use crate::identity::{KeyTemplate, Population, RemoteStore, tests::*};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reqwest_bridge() -> Result<(), Error> {
+        let url = blob_server(0);
+        let bridge = ReqwestBridge::new(&format!("{url}/"))?;
+        assert_eq!(bridge.resource_url("br/abc"), format!("{url}/br/abc"));
+        assert!(ReqwestBridge::new("data:text/plain,blobs").is_err());
+
+        assert_eq!(bridge.get_async("br/abc").await?, None);
+        assert!(!bridge.exists_async("br/abc").await?);
+        bridge.put_async("br/abc", Bytes::from("blob\n")).await?;
+        assert!(bridge.exists_async("br/abc").await?);
+        let Validated::Modified { body, validator } =
+            bridge.get_validated_async("br/abc", None).await?
+        else {
+            panic!("the blob should be modified");
+        };
+        assert_eq!(body.as_deref(), Some(&b"blob\n"[..]));
+        assert!(matches!(
+            bridge
+                .get_validated_async("br/abc", validator.as_deref())
+                .await?,
+            Validated::NotModified
+        ));
+
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let template = KeyTemplate::new("{domain}/{key}", "br")?;
+        let mut store = RemoteStore::new(bridge).with_key_template(template);
+        let identity = population.identity_async("a@b.br", &mut store).await?;
+        // blocking calls can be made from within the runtime
+        assert_eq!(population.identity("a@b.br", &mut store)?, identity);
+        Ok(())
+    }
+}
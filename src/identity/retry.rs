@@ -0,0 +1,364 @@
+//! A [`ConnectionBridge`] which retries the failed requests of another.
+
+use std::future::Future;
+use std::io;
+use std::ops::{ControlFlow, Range};
+use std::time::Duration;
+
+use bytes::Bytes;
+use rand::Rng;
+
+use super::rate_limit::sleep;
+use super::storage::{BridgeResult, ConnectionBridge, Validated};
+
+/// Retries the operations of another bridge which fail with a transient error, such as a reset
+/// connection, a timeout, or an HTTP 429, 502, 503 or 504 response of a `UreqBridge` or a
+/// `ReqwestBridge`, see [`RetryBridge::with_retry_if`].
+///
+/// Each retry waits twice as long as the one before, from 100 milliseconds up to 5 seconds by
+/// default, less a random part of the delay, half by default, so that writers which failed
+/// together do not retry together. Blocking calls sleep the thread, and async calls are woken
+/// by a timer thread, so that no particular runtime is required.
+///
+/// Blobs are written whole, so writes are retried as well, and a conditional write which
+/// succeeded before its response was lost fails as a conflict when it is retried, which
+/// [`crate::identity::RemoteStore`] resolves. Appends are never retried, since a retried
+/// append which had succeeded would store its record twice, and streamed reads are only
+/// retried before their first chunk is received.
+#[derive(Debug)]
+pub struct RetryBridge<B> {
+    bridge: B,
+    attempts: u32,
+    initial_delay: Duration,
+    max_delay: Duration,
+    jitter: f64,
+    retry_if: fn(&io::Error) -> bool,
+}
+
+/// The errors retried by a [`RetryBridge`] by default: timeouts, interruptions, lost
+/// connections and busy servers.
+pub fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::TimedOut
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ResourceBusy
+    )
+}
+
+impl<B: ConnectionBridge> RetryBridge<B> {
+    /// Make up to 3 attempts of each operation of `bridge`.
+    pub fn new(bridge: B) -> Self {
+        Self {
+            bridge,
+            attempts: 3,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            jitter: 0.5,
+            retry_if: is_transient,
+        }
+    }
+
+    /// Make up to `attempts` attempts of each operation, at least one.
+    pub fn with_attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    /// Wait `initial` before the first retry, doubling the delay before each of the next ones
+    /// up to `max`.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_delay = initial;
+        self.max_delay = max.max(initial);
+        self
+    }
+
+    /// Wait a random part of up to `jitter` less than each delay, from 0.0 for exact delays to
+    /// 1.0 for delays anywhere between zero and the backoff.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Retry the errors for which `retry_if` is true, instead of those of [`is_transient`].
+    pub fn with_retry_if(mut self, retry_if: fn(&io::Error) -> bool) -> Self {
+        self.retry_if = retry_if;
+        self
+    }
+
+    /// The bridge which is retried.
+    pub fn inner(&self) -> &B {
+        &self.bridge
+    }
+
+    // the delay before attempt `attempt` + 1
+    fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_delay);
+        backoff.mul_f64(1.0 - self.jitter * rand::rng().random::<f64>())
+    }
+
+    // true if `result` should be retried after attempt `attempt`
+    fn retries<T>(&self, result: &BridgeResult<T>, attempt: u32) -> bool {
+        let retry = attempt < self.attempts && result.as_ref().is_err_and(self.retry_if);
+        if retry {
+            counter!("perfume_bridge_retries_total", 1);
+            event!(DEBUG, attempt, error = ?result.as_ref().err(), "retrying bridge operation");
+        }
+        retry
+    }
+
+    fn retry<T>(&self, mut operation: impl FnMut() -> BridgeResult<T>) -> BridgeResult<T> {
+        let mut attempt = 1;
+        loop {
+            let result = operation();
+            if !self.retries(&result, attempt) {
+                return result;
+            }
+            std::thread::sleep(self.delay(attempt));
+            attempt += 1;
+        }
+    }
+
+    async fn retry_async<T, F>(&self, mut operation: impl FnMut() -> F) -> BridgeResult<T>
+    where
+        F: Future<Output = BridgeResult<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let result = operation().await;
+            if !self.retries(&result, attempt) {
+                return result;
+            }
+            sleep(self.delay(attempt)).await;
+            attempt += 1;
+        }
+    }
+}
+
+impl<B: ConnectionBridge + Sync> ConnectionBridge for RetryBridge<B> {
+    fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        self.retry(|| self.bridge.get(key))
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.retry(|| self.bridge.put(key, body.clone()))
+    }
+
+    async fn get_async(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        self.retry_async(|| self.bridge.get_async(key)).await
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.retry_async(|| self.bridge.put_async(key, body.clone()))
+            .await
+    }
+
+    fn put_if_match(&self, key: &str, body: Bytes, validator: Option<&str>) -> BridgeResult<()> {
+        self.retry(|| self.bridge.put_if_match(key, body.clone(), validator))
+    }
+
+    async fn put_if_match_async(
+        &self,
+        key: &str,
+        body: Bytes,
+        validator: Option<&str>,
+    ) -> BridgeResult<()> {
+        self.retry_async(|| self.bridge.put_if_match_async(key, body.clone(), validator))
+            .await
+    }
+
+    fn append(&self, key: &str, bytes: Bytes) -> BridgeResult<()> {
+        self.bridge.append(key, bytes)
+    }
+
+    async fn append_async(&self, key: &str, bytes: Bytes) -> BridgeResult<()> {
+        self.bridge.append_async(key, bytes).await
+    }
+
+    fn exists(&self, key: &str) -> BridgeResult<bool> {
+        self.retry(|| self.bridge.exists(key))
+    }
+
+    async fn exists_async(&self, key: &str) -> BridgeResult<bool> {
+        self.retry_async(|| self.bridge.exists_async(key)).await
+    }
+
+    fn ping(&self) -> BridgeResult<()> {
+        self.retry(|| self.bridge.ping())
+    }
+
+    async fn ping_async(&self) -> BridgeResult<()> {
+        self.retry_async(|| self.bridge.ping_async()).await
+    }
+
+    fn get_validated(&self, key: &str, validator: Option<&str>) -> BridgeResult<Validated> {
+        self.retry(|| self.bridge.get_validated(key, validator))
+    }
+
+    async fn get_validated_async(
+        &self,
+        key: &str,
+        validator: Option<&str>,
+    ) -> BridgeResult<Validated> {
+        self.retry_async(|| self.bridge.get_validated_async(key, validator))
+            .await
+    }
+
+    fn get_chunks(
+        &self,
+        key: &str,
+        visit: &mut dyn FnMut(&[u8]) -> ControlFlow<()>,
+    ) -> BridgeResult<bool> {
+        let mut attempt = 1;
+        loop {
+            let mut visited = false;
+            let result = self.bridge.get_chunks(key, &mut |chunk: &[u8]| {
+                visited = true;
+                visit(chunk)
+            });
+            if visited || !self.retries(&result, attempt) {
+                return result;
+            }
+            std::thread::sleep(self.delay(attempt));
+            attempt += 1;
+        }
+    }
+
+    async fn get_chunks_async(
+        &self,
+        key: &str,
+        visit: &mut (dyn FnMut(&[u8]) -> ControlFlow<()> + Send),
+    ) -> BridgeResult<bool> {
+        let mut attempt = 1;
+        loop {
+            let mut visited = false;
+            let result = self
+                .bridge
+                .get_chunks_async(key, &mut |chunk: &[u8]| {
+                    visited = true;
+                    visit(chunk)
+                })
+                .await;
+            if visited || !self.retries(&result, attempt) {
+                return result;
+            }
+            sleep(self.delay(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    fn get_range(&self, key: &str, range: Range<u64>) -> BridgeResult<Option<(Bytes, u64)>> {
+        self.retry(|| self.bridge.get_range(key, range.clone()))
+    }
+
+    async fn get_range_async(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> BridgeResult<Option<(Bytes, u64)>> {
+        self.retry_async(|| self.bridge.get_range_async(key, range.clone()))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use async_generic::async_generic;
+
+    use super::*;
+    use crate::Error;
+    use crate::identity::{Population, RemoteStore, tests::*};
+    use crate::testing::bridge_conformance;
+
+    // fails the first `failures` operations with `kind`
+    #[derive(Default)]
+    struct FlakyBridge {
+        inner: MockBridge,
+        failures: AtomicU32,
+        calls: AtomicU32,
+        kind: Option<io::ErrorKind>,
+    }
+
+    impl FlakyBridge {
+        fn fail(&self) -> BridgeResult<()> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            let failures = self.failures.load(Ordering::Relaxed);
+            if failures > 0 {
+                self.failures.store(failures - 1, Ordering::Relaxed);
+                return Err(self.kind.unwrap_or(io::ErrorKind::TimedOut).into());
+            }
+            Ok(())
+        }
+    }
+
+    impl ConnectionBridge for FlakyBridge {
+        #[async_generic]
+        fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+            self.fail()?;
+            self.inner.get(key)
+        }
+
+        #[async_generic]
+        fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+            self.fail()?;
+            self.inner.put(key, body)
+        }
+    }
+
+    #[test]
+    fn test_retry_bridge() -> Result<(), Error> {
+        bridge_conformance(|| RetryBridge::new(MockBridge::default()));
+
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let flaky = FlakyBridge {
+            failures: AtomicU32::new(2),
+            ..Default::default()
+        };
+        let bridge = RetryBridge::new(flaky).with_backoff(Duration::ZERO, Duration::ZERO);
+        let mut store = RemoteStore::new(bridge);
+        let identity = population.identity("a@b.br", &mut store)?;
+        assert_eq!(store.bridge.inner().calls.load(Ordering::Relaxed), 4);
+
+        // gives up after the last attempt
+        store.bridge.inner().failures.store(3, Ordering::Relaxed);
+        let error = population.identity("a@b.br", &mut store).unwrap_err();
+        assert!(error.is_retryable());
+        assert_eq!(population.identity("a@b.br", &mut store)?, identity);
+
+        // and does not retry other errors
+        let flaky = FlakyBridge {
+            failures: AtomicU32::new(1),
+            kind: Some(io::ErrorKind::PermissionDenied),
+            ..Default::default()
+        };
+        let bridge = RetryBridge::new(flaky);
+        assert!(bridge.get("abc").is_err());
+        assert_eq!(bridge.inner().calls.load(Ordering::Relaxed), 1);
+
+        // delays double up to the maximum, less the jitter
+        let bridge = RetryBridge::new(MockBridge::default())
+            .with_backoff(Duration::from_millis(100), Duration::from_millis(300))
+            .with_jitter(0.0);
+        let delays = (1..5).map(|attempt| bridge.delay(attempt).as_millis());
+        assert_eq!(delays.collect::<Vec<_>>(), [100, 200, 300, 300]);
+        let bridge = bridge.with_jitter(0.5);
+        assert!((50..=100).contains(&bridge.delay(1).as_millis()));
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+//! A [`ConnectionBridge`] which keeps blobs as objects of an S3 bucket.
+
+use std::future::Future;
+
+use aws_sdk_s3::error::{DisplayErrorContext, SdkError};
+use aws_sdk_s3::primitives::ByteStream;
+use bytes::Bytes;
+
+use super::storage::{BridgeResult, ConnectionBridge, Validated};
+
+/// Stores each blob as an object of an S3 bucket, at its key after an optional prefix.
+/// The client is configured by the caller, such as with `aws-config`, and can reach any
+/// service implementing the S3 API by setting its endpoint.
+///
+/// Objects which do not exist are absent blobs, and any other failure is an error. Objects are
+/// revalidated with their ETags, see [`ConnectionBridge::get_validated`], and written on the
+/// condition that they still have them, see [`ConnectionBridge::put_if_match`]. Blocking calls run
+/// the request on a new thread with its own Tokio runtime, so that they can be made from
+/// within a runtime.
+#[derive(Debug, Clone)]
+pub struct S3Bridge {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    prefix: String,
+}
+
+impl S3Bridge {
+    /// Keep blobs in `bucket`, using `client`.
+    pub fn new(client: aws_sdk_s3::Client, bucket: &str) -> Self {
+        Self {
+            client,
+            bucket: bucket.to_string(),
+            prefix: String::new(),
+        }
+    }
+
+    /// Place `prefix` before the key of each object, such as `perfume/`. Keys can also be
+    /// placed by a [`crate::identity::KeyTemplate`].
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// The key of the object of `key`.
+    pub fn object_key(&self, key: &str) -> String {
+        format!("{}{key}", self.prefix)
+    }
+
+    // runs `request` to completion on a runtime of its own
+    fn block_on<T>(&self, request: impl Future<Output = BridgeResult<T>> + Send) -> BridgeResult<T>
+    where
+        T: Send,
+    {
+        std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()?
+                        .block_on(request)
+                })
+                .join()
+                .expect("request should complete without panicking")
+        })
+    }
+}
+
+fn request_error<E, R>(operation: &str, object_key: &str, e: SdkError<E, R>) -> std::io::Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+    R: std::fmt::Debug,
+{
+    let message = format!(
+        "S3 {operation} of {object_key} failed: {}",
+        DisplayErrorContext(&e)
+    );
+    std::io::Error::other(message)
+}
+
+async fn collect(object_key: &str, body: ByteStream) -> BridgeResult<Bytes> {
+    let body = body.collect().await.map_err(|e| {
+        std::io::Error::other(format!("S3 GetObject of {object_key} was interrupted: {e}"))
+    })?;
+    Ok(body.into_bytes())
+}
+
+impl ConnectionBridge for S3Bridge {
+    fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        self.block_on(self.get_async(key))
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.block_on(self.put_async(key, body))
+    }
+
+    async fn get_async(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        match self.get_validated_async(key, None).await? {
+            Validated::Modified { body, .. } => Ok(body),
+            Validated::NotModified => unreachable!("objects without a validator are modified"),
+        }
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        let object_key = self.object_key(key);
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(&object_key)
+            .content_type("application/octet-stream")
+            .body(ByteStream::from(body))
+            .send()
+            .await
+            .map_err(|e| request_error("PutObject", &object_key, e))?;
+        Ok(())
+    }
+
+    fn put_if_match(&self, key: &str, body: Bytes, validator: Option<&str>) -> BridgeResult<()> {
+        self.block_on(self.put_if_match_async(key, body, validator))
+    }
+
+    async fn put_if_match_async(
+        &self,
+        key: &str,
+        body: Bytes,
+        validator: Option<&str>,
+    ) -> BridgeResult<()> {
+        let object_key = self.object_key(key);
+        let request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(&object_key)
+            .content_type("application/octet-stream")
+            .body(ByteStream::from(body));
+        let request = match validator {
+            Some(etag) => request.if_match(etag),
+            None => request.if_none_match("*"),
+        };
+        match request.send().await {
+            Ok(_) => Ok(()),
+            // the object changed, or a concurrent conditional write is in progress
+            Err(e)
+                if e.raw_response()
+                    .is_some_and(|r| matches!(r.status().as_u16(), 409 | 412)) =>
+            {
+                let message = format!("S3 object {object_key} was modified by another writer");
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    message,
+                ))
+            }
+            Err(e) => Err(request_error("PutObject", &object_key, e)),
+        }
+    }
+
+    fn exists(&self, key: &str) -> BridgeResult<bool> {
+        self.block_on(self.exists_async(key))
+    }
+
+    async fn exists_async(&self, key: &str) -> BridgeResult<bool> {
+        let object_key = self.object_key(key);
+        let result = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(&object_key)
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(false),
+            Err(e) => Err(request_error("HeadObject", &object_key, e)),
+        }
+    }
+
+    fn get_validated(&self, key: &str, validator: Option<&str>) -> BridgeResult<Validated> {
+        self.block_on(self.get_validated_async(key, validator))
+    }
+
+    async fn get_validated_async(
+        &self,
+        key: &str,
+        validator: Option<&str>,
+    ) -> BridgeResult<Validated> {
+        let object_key = self.object_key(key);
+        let result = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&object_key)
+            .set_if_none_match(validator.map(str::to_string))
+            .send()
+            .await;
+        match result {
+            Ok(output) => Ok(Validated::Modified {
+                validator: output.e_tag.clone(),
+                body: Some(collect(&object_key, output.body).await?),
+            }),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => {
+                Ok(Validated::Modified {
+                    body: None,
+                    validator: None,
+                })
+            }
+            // the SDK has no error for an unmodified object
+            Err(e) if e.raw_response().is_some_and(|r| r.status().as_u16() == 304) => {
+                Ok(Validated::NotModified)
+            }
+            Err(e) => Err(request_error("GetObject", &object_key, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_s3_bridge() {
+        let config = aws_sdk_s3::Config::builder()
+            .behavior_version(aws_sdk_s3::config::BehaviorVersion::latest())
+            .region(aws_sdk_s3::config::Region::new("us-east-1"))
+            .endpoint_url("http://127.0.0.1:9")
+            .build();
+        let bridge =
+            S3Bridge::new(aws_sdk_s3::Client::from_conf(config), "names").with_prefix("perfume/");
+        assert_eq!(bridge.object_key("br/abc"), "perfume/br/abc");
+
+        // failures are never mistaken for absent blobs
+        let e = bridge.get("br/abc").unwrap_err();
+        assert!(e.to_string().contains("GetObject of perfume/br/abc"), "{e}");
+        assert!(bridge.exists("br/abc").is_err());
+    }
+}
@@ -0,0 +1,376 @@
+//! Sources of the secret of a [`Population`], loaded at runtime.
+
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use async_generic::async_generic;
+
+use crate::Error;
+
+use super::{Ingredients, Population};
+
+/// The fewest bytes which a population secret may have, 16 (128 bits).
+/// See [`Population::secret`] for how secrets shorter than 32 bytes are used.
+pub const MIN_SECRET_LENGTH: usize = 16;
+
+/// Loads the secret of a [`Population`], such as from the environment, a file mounted by an
+/// orchestrator, or a key management service. Closures returning the secret are providers.
+/// Wrap a provider in a [`CachedSecret`] so that it is loaded once, and borrowed by populations.
+/// At least one of the methods should be implemented.
+pub trait SecretProvider {
+    /// Load the secret.
+    fn load(&self) -> Result<Vec<u8>, Error>;
+    /// The async version of `load`. The default implementation calls `load`.
+    fn load_async(&self) -> impl Future<Output = Result<Vec<u8>, Error>> + Send {
+        std::future::ready(self.load())
+    }
+}
+
+impl<F> SecretProvider for F
+where
+    F: Fn() -> Result<Vec<u8>, Error>,
+{
+    fn load(&self) -> Result<Vec<u8>, Error> {
+        self()
+    }
+}
+
+/// Reads the secret from an environment variable.
+#[derive(Debug, Clone)]
+pub struct EnvSecret {
+    name: String,
+}
+
+impl EnvSecret {
+    /// Read the variable `name`.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+        }
+    }
+}
+
+impl SecretProvider for EnvSecret {
+    fn load(&self) -> Result<Vec<u8>, Error> {
+        match std::env::var_os(&self.name) {
+            Some(secret) => Ok(secret.into_encoded_bytes()),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("environment variable {} is not set", self.name),
+            )
+            .into()),
+        }
+    }
+}
+
+/// Reads the secret from a file, such as a Kubernetes secret mounted as a volume.
+/// A trailing line break is removed.
+#[derive(Debug, Clone)]
+pub struct FileSecret {
+    path: PathBuf,
+}
+
+impl FileSecret {
+    /// Read the file at `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl SecretProvider for FileSecret {
+    fn load(&self) -> Result<Vec<u8>, Error> {
+        let mut secret = std::fs::read(&self.path).map_err(|e| {
+            let message = format!("failed to read secret {}: {e}", self.path.display());
+            io::Error::new(e.kind(), message)
+        })?;
+        if secret.ends_with(b"\n") {
+            secret.pop();
+            if secret.ends_with(b"\r") {
+                secret.pop();
+            }
+        }
+        Ok(secret)
+    }
+}
+
+/// A secret which is loaded from its provider when first used, and kept for the life of the
+/// process. Populations borrow it, see [`Population::from_secret`].
+pub struct CachedSecret<P> {
+    provider: P,
+    secret: OnceLock<Box<[u8]>>,
+}
+
+impl<P> std::fmt::Debug for CachedSecret<P> {
+    /// Shows whether the secret is loaded, never its contents.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CachedSecret")
+            .field("loaded", &self.secret.get().is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<P: SecretProvider> CachedSecret<P> {
+    /// A secret to be loaded from `provider`.
+    pub const fn new(provider: P) -> Self {
+        Self {
+            provider,
+            secret: OnceLock::new(),
+        }
+    }
+
+    /// The secret, loaded if this is the first use. A secret of fewer than
+    /// [`MIN_SECRET_LENGTH`] bytes is an error of kind [`crate::ErrorKind::InvalidInput`],
+    /// and is loaded again on the next use, as is one which failed to load.
+    #[async_generic]
+    #[allow(unused_assignments)]
+    pub fn get(&self) -> Result<&[u8], Error> {
+        if let Some(secret) = self.secret.get() {
+            return Ok(secret);
+        }
+        let mut secret = vec![];
+        if _async {
+            secret = self.provider.load_async().await?;
+        } else {
+            secret = self.provider.load()?;
+        }
+        if secret.len() < MIN_SECRET_LENGTH {
+            let message = format!(
+                "the secret has {} bytes, fewer than {MIN_SECRET_LENGTH}",
+                secret.len()
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+        }
+        // a concurrent load may have finished first, and its secret is kept
+        Ok(self.secret.get_or_init(|| secret.into_boxed_slice()))
+    }
+}
+
+impl<'dom> Population<'dom> {
+    /// A population of `domain` using the secret of `secret`, loading it if this is its first
+    /// use, rather than a secret compiled into the program. The ingredients are checked as by
+    /// [`Population::new`].
+    #[async_generic]
+    #[allow(unused_assignments)]
+    pub fn from_secret<P: SecretProvider + Sync>(
+        domain: &'dom str,
+        secret: &'dom CachedSecret<P>,
+        ingredients: &'static Ingredients,
+    ) -> Result<Self, Error> {
+        let mut loaded: &[u8] = &[];
+        if _async {
+            loaded = secret.get_async().await?;
+        } else {
+            loaded = secret.get()?;
+        }
+        Population::new(domain, loaded, ingredients)
+    }
+}
+
+#[cfg(feature = "aws-kms")]
+pub use aws::AwsKmsSecret;
+
+#[cfg(feature = "aws-kms")]
+mod aws {
+    use aws_sdk_kms::primitives::Blob;
+
+    use super::*;
+
+    /// Decrypts the secret with AWS KMS, so that only its ciphertext is deployed with the
+    /// program. The client is configured by the caller, such as with `aws-config`.
+    ///
+    /// Blocking loads run the request on a new thread with its own Tokio runtime,
+    /// so that they can be made from within a runtime.
+    #[derive(Debug, Clone)]
+    pub struct AwsKmsSecret {
+        client: aws_sdk_kms::Client,
+        ciphertext: Vec<u8>,
+        key_id: Option<String>,
+    }
+
+    impl AwsKmsSecret {
+        /// Decrypt `ciphertext`, as returned by the `Encrypt` operation of KMS.
+        pub fn new(client: aws_sdk_kms::Client, ciphertext: Vec<u8>) -> Self {
+            Self {
+                client,
+                ciphertext,
+                key_id: None,
+            }
+        }
+
+        /// Require the ciphertext to have been encrypted with `key_id`, as recommended for
+        /// asymmetric keys.
+        pub fn with_key_id(mut self, key_id: &str) -> Self {
+            self.key_id = Some(key_id.to_string());
+            self
+        }
+    }
+
+    impl SecretProvider for AwsKmsSecret {
+        fn load(&self) -> Result<Vec<u8>, Error> {
+            std::thread::scope(|scope| {
+                scope
+                    .spawn(|| {
+                        tokio::runtime::Builder::new_current_thread()
+                            .enable_all()
+                            .build()?
+                            .block_on(self.load_async())
+                    })
+                    .join()
+                    .expect("secret should load without panicking")
+            })
+        }
+
+        async fn load_async(&self) -> Result<Vec<u8>, Error> {
+            let output = self
+                .client
+                .decrypt()
+                .ciphertext_blob(Blob::new(self.ciphertext.clone()))
+                .set_key_id(self.key_id.clone())
+                .send()
+                .await
+                .map_err(io::Error::other)?;
+            match output.plaintext {
+                Some(plaintext) => Ok(plaintext.into_inner()),
+                None => Err(io::Error::other("AWS KMS returned no plaintext").into()),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "gcp-kms")]
+pub use gcp::GcpKmsSecret;
+
+#[cfg(feature = "gcp-kms")]
+mod gcp {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    const METADATA_TOKEN_URL: &str = "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+    /// Decrypts the secret with Google Cloud KMS, using its REST API, so that only its
+    /// ciphertext is deployed with the program. Requests are blocking, and are authorized by
+    /// the service account of the instance, found with the metadata server, unless an access
+    /// token is given.
+    #[derive(Clone)]
+    pub struct GcpKmsSecret {
+        key_name: String,
+        ciphertext: Vec<u8>,
+        access_token: Option<String>,
+        endpoint: String,
+    }
+
+    impl std::fmt::Debug for GcpKmsSecret {
+        /// Omits the access token.
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("GcpKmsSecret")
+                .field("key_name", &self.key_name)
+                .field("endpoint", &self.endpoint)
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl GcpKmsSecret {
+        /// Decrypt `ciphertext` with the key named like
+        /// `projects/P/locations/L/keyRings/R/cryptoKeys/K`.
+        pub fn new(key_name: &str, ciphertext: Vec<u8>) -> Self {
+            Self {
+                key_name: key_name.to_string(),
+                ciphertext,
+                access_token: None,
+                endpoint: "https://cloudkms.googleapis.com".to_string(),
+            }
+        }
+
+        /// Authorize with `access_token`, such as one printed by `gcloud auth print-access-token`.
+        pub fn with_access_token(mut self, access_token: &str) -> Self {
+            self.access_token = Some(access_token.to_string());
+            self
+        }
+
+        /// Send requests to `endpoint` rather than `https://cloudkms.googleapis.com`.
+        pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+            self.endpoint = endpoint.trim_end_matches('/').to_string();
+            self
+        }
+
+        fn access_token(&self) -> Result<String, Error> {
+            if let Some(token) = &self.access_token {
+                return Ok(token.clone());
+            }
+            let response: Value = ureq::get(METADATA_TOKEN_URL)
+                .header("Metadata-Flavor", "Google")
+                .call()
+                .and_then(|response| response.into_body().read_json())
+                .map_err(|e| io::Error::other(format!("failed to get an access token: {e}")))?;
+            match response["access_token"].as_str() {
+                Some(token) => Ok(token.to_string()),
+                None => {
+                    Err(io::Error::other("the metadata server returned no access token").into())
+                }
+            }
+        }
+    }
+
+    impl SecretProvider for GcpKmsSecret {
+        fn load(&self) -> Result<Vec<u8>, Error> {
+            let url = format!("{}/v1/{}:decrypt", self.endpoint, self.key_name);
+            let request = json!({ "ciphertext": STANDARD.encode(&self.ciphertext) });
+            let response: Value = ureq::post(&url)
+                .header("Authorization", format!("Bearer {}", self.access_token()?))
+                .send_json(request)
+                .and_then(|response| response.into_body().read_json())
+                .map_err(|e| io::Error::other(format!("failed to decrypt with {url}: {e}")))?;
+            let plaintext = response["plaintext"]
+                .as_str()
+                .ok_or_else(|| io::Error::other("Google Cloud KMS returned no plaintext"))?;
+            STANDARD
+                .decode(plaintext)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::tests::*;
+
+    #[tokio::test]
+    async fn test_secret_providers() -> Result<(), Error> {
+        let secret = b"0123456789abcdef0123456789abcdef";
+        let tmp_dir = std::env::var("TMPDIR").unwrap_or("/tmp".to_string());
+        let path = std::path::Path::new(&tmp_dir).join("perfume_test_secret");
+        std::fs::write(&path, [&secret[..], b"\n"].concat())?;
+        let from_file = CachedSecret::new(FileSecret::new(&path));
+        assert!(format!("{from_file:?}").contains("loaded: false"));
+        let population = Population::from_secret("br", &from_file, &PERFUME_INGREDIENTS)?;
+        assert_eq!(population.secret, secret);
+
+        // loaded only once
+        std::fs::remove_file(&path)?;
+        let population =
+            Population::from_secret_async("br", &from_file, &PERFUME_INGREDIENTS).await?;
+        assert_eq!(population.secret, secret);
+        assert!(FileSecret::new(&path).load().is_err());
+
+        let loads = std::sync::atomic::AtomicUsize::new(0);
+        let short = CachedSecret::new(|| {
+            loads.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Ok(b"short".to_vec())
+        });
+        for _ in 0..2 {
+            let error = short.get().unwrap_err();
+            assert_eq!(error.kind(), crate::ErrorKind::InvalidInput);
+        }
+        assert_eq!(loads.into_inner(), 2);
+
+        let unset = EnvSecret::new("PERFUME_TEST_UNSET_SECRET").load();
+        assert_eq!(unset.unwrap_err().kind(), crate::ErrorKind::NotFound);
+        Ok(())
+    }
+}
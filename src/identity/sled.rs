@@ -0,0 +1,107 @@
+//! A [`ConnectionBridge`] which keeps blobs in an embedded sled database.
+
+use bytes::Bytes;
+
+use super::storage::{BridgeResult, ConnectionBridge};
+
+// the tree of the database which holds blobs, apart from any other data of the application
+const TREE_NAME: &str = "perfume";
+
+/// Stores each blob as a value of a tree of a [sled](https://crates.io/crates/sled) database,
+/// so that a single binary can persist identities without any external service.
+///
+/// Every stored blob is flushed to disk before `put` returns, so that an assignment which was
+/// returned survives a crash. The async methods block on the database, except for flushing.
+#[derive(Debug, Clone)]
+pub struct SledBridge {
+    tree: sled::Tree,
+}
+
+impl SledBridge {
+    /// Open or create the database at `path`, keeping blobs in its `perfume` tree.
+    pub fn open(path: impl AsRef<std::path::Path>) -> BridgeResult<Self> {
+        let db = sled::open(path)?;
+        Ok(Self::new(db.open_tree(TREE_NAME)?))
+    }
+
+    /// Keep blobs in `tree`, of a database which is opened by the caller.
+    pub fn new(tree: sled::Tree) -> Self {
+        Self { tree }
+    }
+
+    /// The tree holding the blobs.
+    pub fn tree(&self) -> &sled::Tree {
+        &self.tree
+    }
+}
+
+impl ConnectionBridge for SledBridge {
+    fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        let value = self.tree.get(key)?;
+        Ok(value.map(|value| Bytes::copy_from_slice(&value)))
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.tree.insert(key, &body[..])?;
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    async fn get_async(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        self.get(key)
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.tree.insert(key, &body[..])?;
+        self.tree.flush_async().await?;
+        Ok(())
+    }
+
+    fn exists(&self, key: &str) -> BridgeResult<bool> {
+        Ok(self.tree.contains_key(key)?)
+    }
+
+    async fn exists_async(&self, key: &str) -> BridgeResult<bool> {
+        self.exists(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::Error;
+    use crate::identity::{Population, RemoteStore, tests::*};
+    use crate::testing::bridge_conformance;
+
+    #[test]
+    fn test_sled_bridge() -> Result<(), Error> {
+        let tmp_dir = std::env::var("TMPDIR").unwrap_or("/tmp".to_string());
+        let root = std::path::Path::new(&tmp_dir);
+        let root = root.join(format!("perfume_test_sled_{}", std::process::id()));
+        let bridges = AtomicU64::new(0);
+        bridge_conformance(|| {
+            let bridge = bridges.fetch_add(1, Ordering::Relaxed);
+            SledBridge::open(root.join(bridge.to_string())).unwrap()
+        });
+
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let path = root.join("store");
+        let mut store = RemoteStore::new(SledBridge::open(&path)?);
+        let identity = population.identity("a@b.br", &mut store)?;
+        drop(store);
+
+        // only one process can hold the database
+        let mut reopened = RemoteStore::new(SledBridge::open(&path)?);
+        assert_eq!(population.identity("a@b.br", &mut reopened)?, identity);
+        drop(reopened);
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use async_generic::async_generic;
+use bytes::Bytes;
+
+use crate::hex_string::HexString;
+use crate::{Error, Operation, STORAGE_KEY_LENGTH};
+
+use super::format::BlobFormat;
+use super::storage::{ConnectionBridge, Records, RemoteStore, storage_keys};
+
+/// A copy of every storage blob belonging to a single domain.
+/// Used to back up name assignments, or to move them between bridges.
+#[derive(Debug, Default, Clone)]
+pub struct Snapshot {
+    /// Storage blobs in ascending order of storage key. Keys without a blob are omitted.
+    pub blobs: Vec<(HexString<STORAGE_KEY_LENGTH>, Bytes)>,
+}
+
+impl Snapshot {
+    /// The number of offsets assigned within each blob, in the same order as `blobs`, which
+    /// are in `format`, see [`RemoteStore::blob_format`].
+    /// Includes the tombstones of deleted identities, whose offsets are not assigned again.
+    pub fn identity_counts(&self, format: BlobFormat) -> Result<Vec<usize>, Error> {
+        self.blobs
+            .iter()
+            .map(|(key, bytes)| {
+                let parse = |e| Error::storage(e, key.as_str(), Operation::Parse);
+                let text = format.decode(bytes).map_err(parse)?;
+                let records = Records::new(&text).map_err(parse)?;
+                Ok(records.len())
+            })
+            .collect()
+    }
+
+    /// The BLAKE3 hash of each blob as hex, in the same order as `blobs`.
+    /// Used to detect blobs which were damaged after export.
+    pub fn checksums(&self) -> Vec<String> {
+        self.blobs
+            .iter()
+            .map(|(_key, bytes)| blake3::hash(bytes).to_hex().to_string())
+            .collect()
+    }
+}
+
+impl<B> RemoteStore<B>
+where
+    B: ConnectionBridge + Send,
+{
+    /// Fetch every storage blob which can be reached through the bridge.
+    #[async_generic]
+    #[allow(unused_assignments)]
+    pub fn export(&self) -> Result<Snapshot, Error> {
+        let mut blobs = vec![];
+        for key in storage_keys() {
+            let mut stored_bytes: Option<Bytes> = None;
+            if _async {
+                stored_bytes = self
+                    .bridge
+                    .get_async(&self.bridge_key(key.as_str()))
+                    .await?;
+            } else {
+                stored_bytes = self.bridge.get(&self.bridge_key(key.as_str()))?;
+            }
+            if let Some(stored_bytes) = stored_bytes {
+                blobs.push((key, stored_bytes));
+            }
+        }
+        Ok(Snapshot { blobs })
+    }
+
+    /// Store every blob from `snapshot`, replacing any existing blob with the same key.
+    #[async_generic]
+    pub fn import(&self, snapshot: &Snapshot) -> Result<(), Error> {
+        for (key, bytes) in &snapshot.blobs {
+            if _async {
+                let resource = self.bridge_key(key.as_str());
+                self.bridge.put_async(&resource, bytes.clone()).await?;
+            } else {
+                self.bridge
+                    .put(&self.bridge_key(key.as_str()), bytes.clone())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<B> RemoteStore<B>
+where
+    B: ConnectionBridge + Sync,
+{
+    /// The same as [`RemoteStore::export`], fetching up to `parallelism` blobs at a time.
+    pub fn export_parallel(&self, parallelism: usize) -> Result<Snapshot, Error> {
+        let keys = storage_keys().collect::<Vec<_>>();
+        let mut blobs = in_parallel(&keys, parallelism, |key| {
+            let stored_bytes = self.bridge.get(&self.bridge_key(key.as_str()))?;
+            Ok(stored_bytes.map(|bytes| (key.clone(), bytes)))
+        })?;
+        blobs.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+        Ok(Snapshot { blobs })
+    }
+
+    /// The same as [`RemoteStore::import`], storing up to `parallelism` blobs at a time.
+    pub fn import_parallel(&self, snapshot: &Snapshot, parallelism: usize) -> Result<(), Error> {
+        in_parallel(&snapshot.blobs, parallelism, |(key, bytes)| {
+            let resource = self.bridge_key(key.as_str());
+            self.bridge.put(&resource, bytes.clone()).map(Some)
+        })?;
+        Ok(())
+    }
+}
+
+/// Call `f` on each of `items` using up to `parallelism` threads, collecting the results in
+/// no particular order. Stops at the first error.
+fn in_parallel<T, R, F>(items: &[T], parallelism: usize, f: F) -> std::io::Result<Vec<R>>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> std::io::Result<Option<R>> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        let workers = (0..parallelism.max(1))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = vec![];
+                    while !failed.load(Ordering::Relaxed) {
+                        let Some(item) = items.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                            break;
+                        };
+                        match f(item) {
+                            Ok(result) => results.extend(result),
+                            Err(e) => {
+                                failed.store(true, Ordering::Relaxed);
+                                return Err(e);
+                            }
+                        }
+                    }
+                    Ok(results)
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut results = vec![];
+        for worker in workers {
+            results.extend(worker.join().expect("worker should not panic")?);
+        }
+        Ok(results)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::{Population, tests::*};
+
+    #[test]
+    fn test_export_import() -> Result<(), Error> {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let mut store = RemoteStore::new(MockBridge::default());
+        let identifiers = ["a@b.br", "c@d.br", "e@f.br"];
+        for identifier in identifiers {
+            brazilian.identity(identifier, &mut store)?;
+        }
+
+        let snapshot = store.export()?;
+        assert!(!snapshot.blobs.is_empty());
+        assert_eq!(
+            snapshot
+                .identity_counts(store.blob_format())?
+                .iter()
+                .sum::<usize>(),
+            identifiers.len()
+        );
+        assert!(
+            snapshot
+                .blobs
+                .windows(2)
+                .all(|w| w[0].0.as_str() < w[1].0.as_str())
+        );
+
+        // only records are counted, in the format of the store
+        for mut store in [
+            RemoteStore::new(MockBridge::default())
+                .with_format_header()
+                .with_blob_versions(),
+            RemoteStore::new(MockBridge::default()).with_blob_format(BlobFormat::Binary),
+        ] {
+            for identifier in identifiers {
+                brazilian.identity(identifier, &mut store)?;
+            }
+            let counts = store.export()?.identity_counts(store.blob_format())?;
+            assert_eq!(counts.iter().sum::<usize>(), identifiers.len());
+        }
+
+        let parallel = store.export_parallel(4)?;
+        assert_eq!(parallel.blobs, snapshot.blobs);
+        assert_eq!(parallel.checksums(), snapshot.checksums());
+
+        let mut restored = RemoteStore::new(MockBridge::default());
+        restored.import_parallel(&snapshot, 4)?;
+        for identifier in identifiers {
+            assert_eq!(
+                brazilian.identity(identifier, &mut restored)?,
+                brazilian.identity(identifier, &mut store)?
+            );
+        }
+
+        Ok(())
+    }
+}
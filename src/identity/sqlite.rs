@@ -0,0 +1,177 @@
+//! A [`StorageState`] which keeps offsets in a table of a SQLite database.
+
+use std::future::Future;
+use std::path::Path;
+use std::time::Duration;
+
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::{Error, Operation};
+
+use super::storage::{PING_KEY, Storage, StorageState};
+
+// how long to wait for another connection which is writing to the database
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS perfume_offsets (
+    domain TEXT NOT NULL,
+    storage_key TEXT NOT NULL,
+    digest TEXT NOT NULL,
+    offset INTEGER NOT NULL,
+    PRIMARY KEY (domain, digest),
+    UNIQUE (domain, storage_key, offset)
+)";
+
+// the offset of a new digest is the number of digests of its storage key
+const INSERT_DIGEST: &str = "INSERT INTO perfume_offsets (domain, storage_key, digest, offset)
+    SELECT ?1, ?2, ?3, COUNT(*) FROM perfume_offsets WHERE domain = ?1 AND storage_key = ?2
+    ON CONFLICT (domain, digest) DO NOTHING";
+
+const SELECT_OFFSET: &str =
+    "SELECT offset FROM perfume_offsets WHERE domain = ?1 AND storage_key = ?2 AND digest = ?3";
+
+/// Implements [`StorageState`] with a table of a SQLite database, instead of the blobs of a
+/// [`super::RemoteStore`], for applications which embed their storage. A digest is given its
+/// offset by a single statement, which inserts it unless it is stored, so that every
+/// connection to the database, from any thread or process, agrees on the offsets without
+/// reading and writing them back.
+///
+/// The table `perfume_offsets` holds the digests of every domain, and is created if it does
+/// not exist. The async methods block on the database.
+#[derive(Debug)]
+pub struct SqliteStore {
+    connection: Connection,
+}
+
+impl SqliteStore {
+    /// Open or create the database at `path`, waiting up to five seconds for other
+    /// connections which are writing to it.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let context = |e| database_error(e, PING_KEY, Operation::Get);
+        let connection = Connection::open(path).map_err(context)?;
+        connection.busy_timeout(BUSY_TIMEOUT).map_err(context)?;
+        Self::new(connection)
+    }
+
+    /// Keep offsets in the database of `connection`, which is configured by the caller.
+    pub fn new(connection: Connection) -> Result<Self, Error> {
+        connection
+            .execute(CREATE_TABLE, [])
+            .map_err(|e| database_error(e, PING_KEY, Operation::Put))?;
+        Ok(Self { connection })
+    }
+
+    /// The connection to the database.
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// The offset of the digest of `storage`, if it is stored.
+    pub fn stored_offset(&self, domain: &str, storage: &Storage) -> Result<Option<usize>, Error> {
+        let key = storage.key.as_str();
+        let digest = storage.digest.as_str();
+        let offset = self
+            .connection
+            .prepare_cached(SELECT_OFFSET)
+            .and_then(|mut select| {
+                select
+                    .query_row(params![domain, key, digest], |row| row.get::<_, i64>(0))
+                    .optional()
+            })
+            .map_err(|e| database_error(e, key, Operation::Get).in_domain(domain))?;
+        Ok(offset.map(|offset| offset as usize))
+    }
+}
+
+fn database_error(e: rusqlite::Error, key: &str, operation: Operation) -> Error {
+    Error::storage(std::io::Error::other(e), key, operation)
+}
+
+impl StorageState for SqliteStore {
+    fn digest_offset(&mut self, domain: &str, storage: &Storage) -> Result<usize, Error> {
+        let key = storage.key.as_str();
+        let digest = storage.digest.as_str();
+        self.connection
+            .prepare_cached(INSERT_DIGEST)
+            .and_then(|mut insert| insert.execute(params![domain, key, digest]))
+            .map_err(|e| database_error(e, key, Operation::Put).in_domain(domain))?;
+        let offset = self.stored_offset(domain, storage)?;
+        Ok(offset.expect("digests should be stored once inserted"))
+    }
+
+    fn digest_offset_async(
+        &mut self,
+        domain: &str,
+        storage: &Storage,
+    ) -> impl Future<Output = Result<usize, Error>> + Send {
+        std::future::ready(self.digest_offset(domain, storage))
+    }
+
+    /// Reads the table.
+    fn health_check(&mut self) -> Result<(), Error> {
+        self.connection
+            .execute_batch("SELECT 1 FROM perfume_offsets LIMIT 1")
+            .map_err(|e| database_error(e, PING_KEY, Operation::Get))
+    }
+
+    fn health_check_async(&mut self) -> impl Future<Output = Result<(), Error>> + Send {
+        std::future::ready(self.health_check())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::{Population, tests::*};
+
+    #[test]
+    fn test_sqlite_store() -> Result<(), Error> {
+        let tmp_dir = std::env::var("TMPDIR").unwrap_or("/tmp".to_string());
+        let path = Path::new(&tmp_dir).join(format!("perfume_test_{}.sqlite", std::process::id()));
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+
+        // connections of separate threads share the offsets of each storage key
+        let storage = population.storage_object("a@b.br");
+        let mut offsets = std::thread::scope(|scope| {
+            let workers = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut store = SqliteStore::open(&path).unwrap();
+                        (0..25)
+                            .map(|_| {
+                                let mut next = storage.clone();
+                                next.digest = random_hex_string();
+                                store.digest_offset("br", &next).unwrap()
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .flat_map(|w| w.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        offsets.sort();
+        assert_eq!(offsets, (0..100).collect::<Vec<_>>());
+
+        let mut store = SqliteStore::open(&path)?;
+        store.health_check()?;
+        assert_eq!(store.stored_offset("br", &storage)?, None);
+        let identity = population.identity("a@b.br", &mut store)?;
+        assert_eq!(identity.offset, 100);
+        drop(store);
+        let mut reopened = SqliteStore::open(&path)?;
+        assert_eq!(population.identity("a@b.br", &mut reopened)?, identity);
+        // domains have their own offsets
+        assert_eq!(reopened.digest_offset("uy", &storage)?, 0);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
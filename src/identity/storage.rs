@@ -1,14 +1,29 @@
-use std::io::BufRead;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::ops::{ControlFlow, Range};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use async_generic::async_generic;
-use bytes::Bytes;
-use std::future::Future;
+use bytes::{Bytes, BytesMut};
 
+use super::audit::{Audit, AuditAction, AuditSink};
+use super::format::BlobFormat;
+use super::fsck::{BlobIssue, RecoveryReport, check_blob};
+use super::lock::{LockProvider, Locks};
+use crate::bloom::Bloom;
 use crate::hex_string::HexString;
-use crate::{STORAGE_DIGEST_LENGTH, STORAGE_KEY_LENGTH};
+use crate::lru::{Lru, MemoryBudget};
+use crate::{MIN_STORAGE_DIGEST_LENGTH, Operation, STORAGE_DIGEST_LENGTH, STORAGE_KEY_LENGTH};
 
 /// Persisted identity data necessary to implement [`StorageState`].
-#[derive(Debug, Clone)]
+/// Formatted as the key followed by the digest, 64 hex characters.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "diesel",
+    derive(diesel::expression::AsExpression, diesel::deserialize::FromSqlRow),
+    diesel(sql_type = diesel::sql_types::Text)
+)]
 pub struct Storage {
     /// Used to determine the first word of a friendly name.
     pub key: HexString<STORAGE_KEY_LENGTH>,
@@ -16,15 +31,49 @@ pub struct Storage {
     pub digest: HexString<STORAGE_DIGEST_LENGTH>,
 }
 
-impl From<&[u8]> for Storage {
-    fn from(value: &[u8]) -> Self {
-        Self {
-            key: value[..STORAGE_KEY_LENGTH].into(),
-            digest: value[STORAGE_KEY_LENGTH..].into(),
-        }
+impl TryFrom<&[u8]> for Storage {
+    type Error = crate::Error;
+
+    /// Parse 64 hex characters, of either case, as in [`std::str::FromStr`].
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        std::str::from_utf8(value)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+            .parse()
+    }
+}
+
+impl std::fmt::Display for Storage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", self.key, self.digest)
+    }
+}
+
+impl std::str::FromStr for Storage {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((key, digest)) = s.split_at_checked(STORAGE_KEY_LENGTH) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("expected a storage object, found {s:?}"),
+            )
+            .into());
+        };
+        Ok(Self {
+            key: key.parse()?,
+            digest: digest.parse()?,
+        })
     }
 }
 
+/// Every possible [`Storage::key`], in ascending order.
+pub fn storage_keys() -> impl Iterator<Item = HexString<STORAGE_KEY_LENGTH>> {
+    (0..16usize.pow(STORAGE_KEY_LENGTH as u32)).map(|i| {
+        let key = format!("{i:0width$x}", width = STORAGE_KEY_LENGTH);
+        HexString::from(key.as_bytes())
+    })
+}
+
 /// Persistence scheme for [`Storage`] objects.
 /// At least one of the required methods should be implemented.
 pub trait StorageState {
@@ -39,13 +88,61 @@ pub trait StorageState {
         domain: &str,
         storage: &Storage,
     ) -> impl std::future::Future<Output = Result<usize, crate::Error>> + Send;
+
+    /// The offsets of the digests of `storages`, in the same order, as `digest_offset` would
+    /// return them one by one. The default implementation calls it for each of them, while
+    /// stores such as [`RemoteStore`] resolve the digests of each storage key together.
+    fn digest_offsets(
+        &mut self,
+        domain: &str,
+        storages: &[Storage],
+    ) -> Result<Vec<usize>, crate::Error> {
+        storages
+            .iter()
+            .map(|storage| self.digest_offset(domain, storage))
+            .collect()
+    }
+    /// The async version of `digest_offsets`.
+    fn digest_offsets_async(
+        &mut self,
+        domain: &str,
+        storages: &[Storage],
+    ) -> impl std::future::Future<Output = Result<Vec<usize>, crate::Error>> + Send
+    where
+        Self: Send,
+    {
+        async move {
+            let mut offsets = Vec::with_capacity(storages.len());
+            for storage in storages {
+                offsets.push(self.digest_offset_async(domain, storage).await?);
+            }
+            Ok(offsets)
+        }
+    }
+
+    /// Check that offsets can be resolved, such as for the readiness probe of a service.
+    /// The default implementation succeeds, as stores held in memory always can.
+    /// See the [`RemoteStore`] implementation.
+    fn health_check(&mut self) -> Result<(), crate::Error> {
+        Ok(())
+    }
+    /// The async version of `health_check`.
+    fn health_check_async(
+        &mut self,
+    ) -> impl std::future::Future<Output = Result<(), crate::Error>> + Send {
+        std::future::ready(Ok(()))
+    }
 }
 
+/// The key read by [`ConnectionBridge::ping`], the first of [`storage_keys`].
+pub const PING_KEY: &str = "000";
+
 pub(crate) type BridgeResult<B> = std::result::Result<B, std::io::Error>;
 
 /// Data persistence interface used by [`RemoteStore`].
 /// At least one pair of methods should be implemented: `get`+`put` or `get_async`+`put_async`.
-/// See examples/remote_store_ureq.rs for a simple implementation to start with.
+/// See [`FsBridge`](crate::identity::FsBridge) for a simple implementation to start with, and
+/// `testing::bridge_conformance` (with the `testing` feature) for checking one.
 pub trait ConnectionBridge {
     /// Fetch the storage blob associated with `key`.
     fn get(&self, key: &str) -> BridgeResult<Option<Bytes>>;
@@ -55,17 +152,773 @@ pub trait ConnectionBridge {
     fn get_async(&self, key: &str) -> impl Future<Output = BridgeResult<Option<Bytes>>> + Send;
     /// The async version of `put`.
     fn put_async(&self, key: &str, body: Bytes) -> impl Future<Output = BridgeResult<()>> + Send;
+
+    /// Update the storage blob associated with `key` to `body`, only if it is unchanged since
+    /// `validator` was issued by `get_validated`, or if there is no blob when `validator` is
+    /// `None`. A blob changed by another writer is an error of kind
+    /// [`std::io::ErrorKind::AlreadyExists`], after which [`RemoteStore`] fetches the blob again
+    /// and retries its insert. Bridges which support conditional writes (such as HTTP If-Match)
+    /// can implement this. The default implementation calls `put`, without any condition.
+    fn put_if_match(&self, key: &str, body: Bytes, validator: Option<&str>) -> BridgeResult<()> {
+        let _ = validator;
+        self.put(key, body)
+    }
+    /// The async version of `put_if_match`.
+    fn put_if_match_async(
+        &self,
+        key: &str,
+        body: Bytes,
+        validator: Option<&str>,
+    ) -> impl Future<Output = BridgeResult<()>> + Send {
+        let _ = validator;
+        self.put_async(key, body)
+    }
+
+    /// Add `bytes` to the end of the storage blob associated with `key`, which exists, without
+    /// transferring the rest of it. [`RemoteStore`] appends the record of a new digest which
+    /// sorts after every stored one, unless its write is conditional, see
+    /// [`ConnectionBridge::put_if_match`]. Bridges whose backend appends in place, so that
+    /// readers still see either the whole blob or the whole blob before it, can implement this.
+    /// The default implementation returns an error of kind [`std::io::ErrorKind::Unsupported`],
+    /// after which the whole blob is written with `put`.
+    fn append(&self, key: &str, bytes: Bytes) -> BridgeResult<()> {
+        let _ = (key, bytes);
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+    /// The async version of `append`.
+    fn append_async(
+        &self,
+        key: &str,
+        bytes: Bytes,
+    ) -> impl Future<Output = BridgeResult<()>> + Send {
+        let _ = (key, bytes);
+        std::future::ready(Err(std::io::ErrorKind::Unsupported.into()))
+    }
+
+    /// True if a storage blob is associated with `key`. Bridges which can check this without
+    /// transferring the blob, such as with an HTTP HEAD request, can implement this.
+    /// The default implementation calls `get`.
+    fn exists(&self, key: &str) -> BridgeResult<bool> {
+        self.get(key).map(|body| body.is_some())
+    }
+    /// The async version of `exists`.
+    fn exists_async(&self, key: &str) -> impl Future<Output = BridgeResult<bool>> + Send {
+        let body = self.get_async(key);
+        async move { body.await.map(|body| body.is_some()) }
+    }
+
+    /// Check that the backend is reachable, with a lightweight read of [`PING_KEY`].
+    /// The default implementation calls `exists`. Bridges which can check their backend more
+    /// cheaply can implement this.
+    fn ping(&self) -> BridgeResult<()> {
+        self.exists(PING_KEY).map(|_| ())
+    }
+    /// The async version of `ping`.
+    fn ping_async(&self) -> impl Future<Output = BridgeResult<()>> + Send {
+        let exists = self.exists_async(PING_KEY);
+        async move { exists.await.map(|_| ()) }
+    }
+
+    /// Fetch the storage blob associated with `key`, unless it is unchanged since `validator`
+    /// was issued. Bridges which support validators (such as HTTP ETags with If-None-Match)
+    /// can implement this to avoid transferring unchanged blobs. The default implementation
+    /// calls `get`, and never returns a validator.
+    fn get_validated(&self, key: &str, validator: Option<&str>) -> BridgeResult<Validated> {
+        let _ = validator;
+        self.get(key).map(|body| Validated::Modified {
+            body,
+            validator: None,
+        })
+    }
+    /// The async version of `get_validated`.
+    fn get_validated_async(
+        &self,
+        key: &str,
+        validator: Option<&str>,
+    ) -> impl Future<Output = BridgeResult<Validated>> + Send {
+        let _ = validator;
+        let body = self.get_async(key);
+        async move {
+            body.await.map(|body| Validated::Modified {
+                body,
+                validator: None,
+            })
+        }
+    }
+
+    /// Pass the storage blob associated with `key` to `visit` in consecutive chunks, until
+    /// `visit` breaks. Returns false if there is no blob. Bridges which receive blobs
+    /// incrementally can implement this to avoid holding whole blobs in memory, see
+    /// [`RemoteStore::with_streaming`]. The default implementation calls `get`.
+    fn get_chunks(
+        &self,
+        key: &str,
+        visit: &mut dyn FnMut(&[u8]) -> ControlFlow<()>,
+    ) -> BridgeResult<bool> {
+        let body = self.get(key)?;
+        if let Some(body) = &body {
+            let _ = visit(body);
+        }
+        Ok(body.is_some())
+    }
+    /// The async version of `get_chunks`.
+    fn get_chunks_async(
+        &self,
+        key: &str,
+        visit: &mut (dyn FnMut(&[u8]) -> ControlFlow<()> + Send),
+    ) -> impl Future<Output = BridgeResult<bool>> + Send {
+        let body = self.get_async(key);
+        async move {
+            let body = body.await?;
+            if let Some(body) = &body {
+                let _ = visit(body);
+            }
+            Ok(body.is_some())
+        }
+    }
+
+    /// Fetch the bytes of `range` of the storage blob associated with `key`, which are fewer at
+    /// the end of the blob, along with the length of the whole blob. Returns `None` if there is
+    /// no blob. Bridges which can read part of a blob, such as with an HTTP Range request, can
+    /// implement this, see [`RemoteStore::with_ranged_reads`]. The default implementation
+    /// returns an error of kind [`std::io::ErrorKind::Unsupported`], after which the blob is
+    /// read whole.
+    fn get_range(&self, key: &str, range: Range<u64>) -> BridgeResult<Option<(Bytes, u64)>> {
+        let _ = (key, range);
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+    /// The async version of `get_range`.
+    fn get_range_async(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> impl Future<Output = BridgeResult<Option<(Bytes, u64)>>> + Send {
+        let _ = (key, range);
+        std::future::ready(Err(std::io::ErrorKind::Unsupported.into()))
+    }
+}
+
+/// The value of an HTTP Range header for the bytes of `range`, at least one.
+#[cfg(any(feature = "ureq", feature = "reqwest"))]
+pub(crate) fn range_header(range: &Range<u64>) -> String {
+    format!(
+        "bytes={}-{}",
+        range.start,
+        range.end.max(range.start + 1) - 1
+    )
+}
+
+/// The length of the whole blob given by an HTTP Content-Range header, such as
+/// `bytes 0-67/6800` or `bytes */6800`.
+#[cfg(any(feature = "ureq", feature = "reqwest"))]
+pub(crate) fn content_range_length(content_range: &str) -> Option<u64> {
+    let (_range, length) = content_range.strip_prefix("bytes ")?.rsplit_once('/')?;
+    length.parse().ok()
+}
+
+/// The bytes of `range` of the whole `body`, which are fewer at its end.
+#[cfg(any(test, feature = "ureq", feature = "reqwest", feature = "server"))]
+pub(crate) fn slice_range(body: &Bytes, range: &Range<u64>) -> Bytes {
+    let length = body.len() as u64;
+    let start = range.start.min(length);
+    body.slice(start as usize..range.end.min(length).max(start) as usize)
+}
+
+/// The result of [`ConnectionBridge::get_validated`].
+#[derive(Debug, Clone)]
+pub enum Validated {
+    /// The blob has not changed since the validator was issued.
+    NotModified,
+    /// The current blob, if there is one.
+    Modified {
+        /// The contents of the blob.
+        body: Option<Bytes>,
+        /// Identifies this version of the blob, such as an HTTP ETag.
+        validator: Option<String>,
+    },
 }
 
 /// Implements [`StorageState`] using binary search to find digests within storage blobs.
 /// Retrieved storage blobs are assumed to contain lines of *sorted* digests.
 /// Each digest is postfixed with a space-padded offset followed by '\n'.
-/// Each line is 68 bytes.
+/// Each line is 68 bytes, unless digests are truncated, see [`RemoteStore::with_digest_length`].
 /// example: "9e3b2749dcca704cad379adf3c6894a59c3363f2d78a4a5155555781e69cc     9\n"
+///
+/// Blobs can optionally be cached, see [`RemoteStore::with_blob_cache`],
+/// [`RemoteStore::with_offset_index`] and [`RemoteStore::with_bloom_filter`],
+/// within a [`MemoryBudget`], see [`RemoteStore::with_memory_budget`].
+/// Writes can optionally be coalesced, see [`RemoteStore::with_write_behind`].
+/// Blobs can optionally be searched as they are received, see [`RemoteStore::with_streaming`],
+/// or in place, see [`RemoteStore::with_ranged_reads`].
+/// Blobs can optionally be stored in another format, see [`RemoteStore::with_blob_format`].
+/// Corrupt blobs can optionally be moved aside and rebuilt, see [`RemoteStore::with_quarantine`].
+/// Assignments can optionally be audited, see [`RemoteStore::with_audit_sink`].
+/// Blobs can optionally be kept at other keys of the bridge, see [`RemoteStore::with_key_template`].
+/// Deleted digests are kept as tombstones, see [`RemoteStore::delete`] and [`RecordFlag`].
+/// Blobs can optionally count their changes, see [`RemoteStore::with_blob_versions`].
+/// Writers can optionally take turns changing a blob, see [`RemoteStore::with_lock_provider`].
 #[derive(Debug)]
 pub struct RemoteStore<B: ConnectionBridge> {
     #[allow(missing_docs)]
     pub bridge: B,
+    // storage key -> (validator, blob)
+    blob_cache: Option<Lru<String, (String, Bytes)>>,
+    // storage key -> (validator, index)
+    offset_index: Option<Lru<String, (String, OffsetIndex)>>,
+    bloom_index: Option<BloomIndex>,
+    // keys missing from the Bloom index have no blob
+    empty_keys: bool,
+    pending_writes: Option<PendingWrites>,
+    digest_length: usize,
+    streaming: bool,
+    ranged_reads: bool,
+    memory_budget: Option<MemoryBudget>,
+    blob_format: BlobFormat,
+    quarantine: bool,
+    recoveries: Vec<RecoveryReport>,
+    audit: Option<Audit>,
+    key_template: Option<KeyTemplate>,
+    blob_versions: bool,
+    format_header: bool,
+    locks: Option<Locks>,
+}
+
+/// Where a [`RemoteStore`] keeps each blob in its bridge, such as under a common prefix of
+/// a shared bucket. Parsed from a template of the key passed to the bridge, in which `{key}`
+/// is replaced by the storage key and `{domain}` by the domain of the store, such as
+/// `perfume/v1/{domain}/{key}.blob`. See [`RemoteStore::with_key_template`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyTemplate {
+    template: String,
+    prefix: String,
+    suffix: String,
+}
+
+impl KeyTemplate {
+    /// Parse `template` for the store of `domain`. It must contain `{key}` exactly once,
+    /// and no other placeholders than `{domain}`.
+    pub fn new(template: &str, domain: &str) -> Result<Self, crate::Error> {
+        let invalid = |message: &str| {
+            let message = format!("invalid key template {template:?}: {message}");
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, message).into()
+        };
+        let Some((prefix, suffix)) = template.split_once("{key}") else {
+            return Err(invalid("{key} is missing"));
+        };
+        if suffix.contains("{key}") {
+            return Err(invalid("{key} appears more than once"));
+        }
+        let [prefix, suffix] = [prefix, suffix].map(|part| part.replace("{domain}", domain));
+        if [&prefix, &suffix]
+            .iter()
+            .any(|part| part.contains(['{', '}']))
+        {
+            return Err(invalid("only {key} and {domain} can be replaced"));
+        }
+        Ok(Self {
+            template: template.to_string(),
+            prefix,
+            suffix,
+        })
+    }
+
+    /// The same template, for the store of `domain`.
+    pub fn for_domain(&self, domain: &str) -> Self {
+        Self::new(&self.template, domain).expect("template should have been checked")
+    }
+
+    /// The key of the bridge for the blob of storage key `key`.
+    pub fn apply(&self, key: &str) -> String {
+        format!("{}{key}{}", self.prefix, self.suffix)
+    }
+}
+
+// the key passed to the bridge for `key`
+fn bridge_key<'k>(template: Option<&KeyTemplate>, key: &'k str) -> Cow<'k, str> {
+    match template {
+        Some(template) => Cow::Owned(template.apply(key)),
+        None => Cow::Borrowed(key),
+    }
+}
+
+/// Blobs with inserts which have not been written yet, see [`RemoteStore::with_write_behind`].
+#[derive(Debug)]
+struct PendingWrites {
+    window: Duration,
+    // storage key -> (time of the first unwritten insert, blob)
+    blobs: HashMap<String, (Instant, Bytes)>,
+}
+
+/// Per-key Bloom filters of stored digests, along with the blob each filter was built from.
+/// Only the first [`MIN_STORAGE_DIGEST_LENGTH`] characters of each digest are used,
+/// so that blobs with truncated digests are filtered consistently.
+#[derive(Debug)]
+struct BloomIndex {
+    items_per_key: usize,
+    false_positive_rate: f64,
+    // storage key -> (filter, last known blob, or `None` if there was no blob)
+    keys: Lru<String, (Bloom, Option<Bytes>)>,
+    // keys which had a filter, including those evicted since, which are not assumed to be empty
+    seen: HashSet<String>,
+}
+
+impl BloomIndex {
+    /// The last known blob for `key`, or `Some(None)` if there was none, if `digest` is
+    /// certainly not stored in it.
+    fn blob_without(&mut self, key: &str, digest: &str) -> Option<Option<Bytes>> {
+        let prefix = &digest.as_bytes()[..MIN_STORAGE_DIGEST_LENGTH];
+        self.keys
+            .get_with(key, |(bloom, blob)| {
+                (!bloom.contains(prefix)).then(|| blob.clone())
+            })
+            .flatten()
+    }
+
+    /// Rebuild the filter for `key` from all digests in `blob`, which is `None` if there is no
+    /// blob.
+    fn rebuild(&mut self, key: &str, blob: Option<&Bytes>) -> std::io::Result<()> {
+        let records = Records::new(blob.map_or(&[][..], |blob| blob))?;
+        let mut bloom = Bloom::new(
+            self.items_per_key.max(records.len()),
+            self.false_positive_rate,
+        );
+        for index in 0..records.len() {
+            bloom.insert(&records.digest(index)[..MIN_STORAGE_DIGEST_LENGTH]);
+        }
+        self.keys.insert(key.to_string(), (bloom, blob.cloned()));
+        self.seen.insert(key.to_string());
+        Ok(())
+    }
+
+    /// Record that `digest` was inserted or deleted, producing `blob`.
+    fn inserted(&mut self, key: &str, digest: &str, blob: Bytes) {
+        let updated = self.keys.update(key, |(bloom, last_blob)| {
+            bloom.insert(&digest.as_bytes()[..MIN_STORAGE_DIGEST_LENGTH]);
+            *last_blob = Some(blob.clone());
+        });
+        // the blob of a key which was assumed to be empty, see `RemoteStore::with_empty_keys`,
+        // or whose filter was evicted
+        if !updated {
+            let _ = self.rebuild(key, Some(&blob));
+        }
+    }
+}
+
+impl<B: ConnectionBridge> RemoteStore<B> {
+    /// Store blobs using `bridge`, without caching.
+    pub fn new(bridge: B) -> Self {
+        Self {
+            bridge,
+            blob_cache: None,
+            offset_index: None,
+            bloom_index: None,
+            empty_keys: false,
+            pending_writes: None,
+            digest_length: STORAGE_DIGEST_LENGTH,
+            streaming: false,
+            ranged_reads: false,
+            memory_budget: None,
+            blob_format: BlobFormat::Text,
+            quarantine: false,
+            recoveries: vec![],
+            audit: None,
+            key_template: None,
+            blob_versions: false,
+            format_header: false,
+            locks: None,
+        }
+    }
+
+    /// Pass keys made from `template` to the bridge, rather than bare storage keys,
+    /// including the keys of quarantined blobs. Caches and errors still use storage keys.
+    pub fn with_key_template(mut self, template: KeyTemplate) -> Self {
+        self.key_template = Some(template);
+        self
+    }
+
+    /// The key passed to the bridge for the blob of storage key `key`,
+    /// see [`RemoteStore::with_key_template`].
+    pub fn bridge_key<'k>(&self, key: &'k str) -> Cow<'k, str> {
+        bridge_key(self.key_template.as_ref(), key)
+    }
+
+    pub(crate) fn key_template(&self) -> Option<&KeyTemplate> {
+        self.key_template.as_ref()
+    }
+
+    /// Store only the first `length` characters of each digest in new blobs, between
+    /// [`MIN_STORAGE_DIGEST_LENGTH`] and [`STORAGE_DIGEST_LENGTH`] (the default).
+    /// Existing blobs keep the length they were written with, which is read from their first
+    /// record. See [`narrow_blob`] for shortening existing blobs.
+    ///
+    /// The last [`DIGEST_CHECK_LENGTH`] stored characters of a truncated digest only detect
+    /// collisions: a new digest which shares the characters before them with a stored digest
+    /// fails with [`crate::ErrorKind::InvalidInput`], rather than being inserted next to it.
+    /// Even at the minimum length, this is expected in fewer than one in 10^10 blobs of 50000
+    /// records, and can be resolved by widening the blob again, such as with the `migrate`
+    /// command. Only identifiers whose stored digests are equal are given the same name, which
+    /// is expected in fewer than one in 10^19 blobs.
+    pub fn with_digest_length(mut self, length: usize) -> Self {
+        assert!(
+            (MIN_STORAGE_DIGEST_LENGTH..=STORAGE_DIGEST_LENGTH).contains(&length),
+            "digest length should be between {MIN_STORAGE_DIGEST_LENGTH} and {STORAGE_DIGEST_LENGTH}"
+        );
+        self.digest_length = length;
+        self
+    }
+
+    /// Store blobs in `format`, instead of [`BlobFormat::Text`]. Blobs in other formats are
+    /// converted to text whenever they are fetched, and can not be streamed.
+    /// Snapshots hold blobs as they are stored, in this format, see [`BlobFormat::convert`].
+    pub fn with_blob_format(mut self, format: BlobFormat) -> Self {
+        self.blob_format = format;
+        self
+    }
+
+    /// The format which blobs are stored in, see [`RemoteStore::with_blob_format`].
+    pub fn blob_format(&self) -> BlobFormat {
+        self.blob_format
+    }
+
+    /// Search blobs as they are received, using [`ConnectionBridge::get_chunks`], so that
+    /// finding a stored digest holds at most one record in memory rather than the whole blob.
+    /// Inserting a new digest still fetches the whole blob, after it was not found.
+    /// Streamed blobs are not cached, so this is best combined with a bridge which streams.
+    pub fn with_streaming(mut self) -> Self {
+        self.streaming = true;
+        self
+    }
+
+    /// Binary search text blobs in place, reading one record at a time with
+    /// [`ConnectionBridge::get_range`], so that finding a stored digest in a blob of `n` records
+    /// transfers about `log2(n)` records rather than the whole blob. Inserting a new digest still
+    /// fetches the whole blob, after it was not found, as do bridges which cannot read ranges.
+    /// Each read is a round trip, so this suits large blobs behind a fast connection.
+    pub fn with_ranged_reads(mut self) -> Self {
+        self.ranged_reads = true;
+        self
+    }
+
+    /// Keep up to `capacity` blobs in memory, along with the validator that the bridge returned
+    /// for them. Cached blobs are revalidated on each use, see [`ConnectionBridge::get_validated`].
+    /// This has no effect unless the bridge returns validators.
+    pub fn with_blob_cache(mut self, capacity: usize) -> Self {
+        self.blob_cache = Some(Lru::new(capacity));
+        self.apply_memory_budget()
+    }
+
+    /// Keep an index of digest prefixes to offsets for up to `capacity` blobs, along with the
+    /// validator that the bridge returned for them. Lookups of indexed digests only need the
+    /// blob to be revalidated, instead of fetched and searched.
+    /// Indexes use 12 bytes for each 68 byte record. This has no effect unless the bridge
+    /// returns validators, see [`ConnectionBridge::get_validated`].
+    pub fn with_offset_index(mut self, capacity: usize) -> Self {
+        self.offset_index = Some(Lru::new(capacity));
+        self.apply_memory_budget()
+    }
+
+    /// Limit the memory held by the blob cache, offset index and Bloom filters, together with
+    /// any other caches which share `budget`. See [`RemoteStore::with_blob_cache`] and
+    /// [`RemoteStore::with_offset_index`], which still limit the number of entries, and
+    /// [`RemoteStore::with_bloom_filter`].
+    pub fn with_memory_budget(mut self, budget: &MemoryBudget) -> Self {
+        self.memory_budget = Some(budget.clone());
+        self.apply_memory_budget()
+    }
+
+    fn apply_memory_budget(mut self) -> Self {
+        let Some(budget) = &self.memory_budget else {
+            return self;
+        };
+        if let Some(cache) = self.blob_cache.as_mut() {
+            cache.set_budget(budget, |key, (validator, blob)| {
+                key.len() + validator.len() + blob.len()
+            });
+        }
+        if let Some(cache) = self.offset_index.as_mut() {
+            cache.set_budget(budget, |key, (validator, index)| {
+                key.len() + validator.len() + std::mem::size_of_val(index.0.as_slice())
+            });
+        }
+        if let Some(index) = self.bloom_index.as_mut() {
+            index.keys.set_budget(budget, |key, (bloom, blob)| {
+                key.len() + bloom.size() + blob.as_ref().map_or(0, Bytes::len)
+            });
+        }
+        self
+    }
+
+    /// Keep a Bloom filter of the digests stored with each key, sized for `items_per_key`
+    /// digests with the given `false_positive_rate`. Filters are built from fetched blobs.
+    /// When a digest is certainly new, it is inserted into the blob which this store last
+    /// fetched or wrote for that key, skipping the fetch entirely, along with any streamed
+    /// search, see [`RemoteStore::with_streaming`]. A key which had no blob is known to be
+    /// empty, so its first digest is written without fetching or searching, on condition that
+    /// no other writer created the blob meanwhile, see [`ConnectionBridge::put_if_match`].
+    ///
+    /// Filters hold the last known blob of each key, so they are limited by
+    /// [`RemoteStore::with_memory_budget`], if any. A key whose filter was evicted is fetched
+    /// again when it is next used.
+    ///
+    /// **This is only correct while this store is the only writer to its domain**,
+    /// such as during a bulk import. Otherwise, concurrent assignments will be overwritten.
+    pub fn with_bloom_filter(mut self, items_per_key: usize, false_positive_rate: f64) -> Self {
+        self.bloom_index = Some(BloomIndex {
+            items_per_key,
+            false_positive_rate,
+            keys: Lru::new(usize::MAX),
+            seen: HashSet::default(),
+        });
+        self.apply_memory_budget()
+    }
+
+    /// Assume that keys which this store has not fetched or written have no blob, as in a new
+    /// domain, so that the first digest of each key is written without fetching its blob.
+    /// Only takes effect with a Bloom filter, see [`RemoteStore::with_bloom_filter`].
+    ///
+    /// The writes are conditional on the blob still being absent, so a blob which did exist
+    /// is a conflict, after which it is fetched and the digest is resolved as usual, see
+    /// [`ConnectionBridge::put_if_match`]. **Bridges which do not support conditional writes
+    /// replace existing blobs**, so only use those with a domain which has no blobs.
+    pub fn with_empty_keys(mut self) -> Self {
+        self.empty_keys = true;
+        self
+    }
+
+    /// Hold inserted blobs in memory, writing each one at most once per `window`, so that
+    /// bursts of inserts to the same key are merged into a single `put`.
+    /// Pending blobs are written by [`RemoteStore::flush`], by the next insert after their
+    /// window has passed, and when the store is dropped (ignoring errors, using the blocking
+    /// `put`). Call `flush` to handle errors.
+    ///
+    /// **Async code must call [`RemoteStore::flush_async`] before dropping the store**: the
+    /// blocking `put` would stall the runtime, so with the `tokio` feature, pending blobs which
+    /// are dropped within a Tokio runtime are not written, and a warning is logged instead.
+    ///
+    /// **This is only correct while this store is the only writer to its domain**,
+    /// such as during a bulk import. Returned offsets are not persisted until they are written,
+    /// and concurrent assignments will be overwritten.
+    pub fn with_write_behind(mut self, window: Duration) -> Self {
+        self.pending_writes = Some(PendingWrites {
+            window,
+            blobs: HashMap::default(),
+        });
+        self
+    }
+
+    /// Recover from a corrupt blob by copying it to "<key>.corrupt-<seconds since the epoch>",
+    /// then replacing it with the records which could be salvaged, see [`check_blob`].
+    /// The digest which found the corruption is then resolved again, so one damaged blob
+    /// does not prevent its identities from being resolved.
+    ///
+    /// Identities whose records were discarded are assigned new offsets, and so new names.
+    /// The salvaged blob is written as an insert would be, under the lock of
+    /// [`RemoteStore::with_lock_provider`] and only if the corrupt blob was not changed since.
+    /// Blobs in a format other than [`BlobFormat::Text`] which can not be decoded at all
+    /// are not replaced, and remain an error. Each recovery is reported by
+    /// [`RemoteStore::take_recoveries`]. Relies on the system clock, which is unavailable
+    /// on `wasm32-unknown-unknown`.
+    pub fn with_quarantine(mut self) -> Self {
+        self.quarantine = true;
+        self
+    }
+
+    /// Send an [`AuditRecord`](super::AuditRecord) to `sink` for each offset assigned or deleted
+    /// by this store, naming `actor` as the one who assigned it, such as the name of a service.
+    /// With [`RemoteStore::with_write_behind`], records are sent before blobs are written.
+    /// Relies on the system clock, which is unavailable on `wasm32-unknown-unknown`.
+    pub fn with_audit_sink(mut self, sink: impl AuditSink + 'static, actor: &str) -> Self {
+        self.audit = Some(Audit::new(std::sync::Arc::new(sink), actor.to_string()));
+        self
+    }
+
+    /// Begin each blob with a line counting its changes, "version <n>\n". Before replacing a
+    /// blob which the bridge returned without a validator, the store reads the first line of
+    /// the stored blob again, with [`ConnectionBridge::get_chunks`], and if another writer
+    /// changed its version since it was fetched, the insert is retried as for a conflicting
+    /// [`ConnectionBridge::put_if_match`]. Lost updates are then detected by bridges without
+    /// validators, although two writers can still replace each other's blobs between the check
+    /// and the write, which only a conditional write prevents.
+    ///
+    /// Versions are only kept by [`BlobFormat::Text`] blobs, and are counted by every store
+    /// which changes a blob that has one, whether or not this is set.
+    pub fn with_blob_versions(mut self) -> Self {
+        self.blob_versions = true;
+        self
+    }
+
+    /// Begin each blob with a line naming the format of its records, "perfume/<n>\n" where `n`
+    /// is [`FORMAT_VERSION`], before any version line. Releases which change the format of
+    /// records raise the format version, and stores refuse to read blobs of a later format than
+    /// they know, rather than misreading them and changing the names of stored identities.
+    /// Blobs without a format line are of format 0, and are read as before; they gain one
+    /// when they are next changed, or with [`migrate_blob`]. Releases before format lines were
+    /// introduced can not read blobs which have one.
+    ///
+    /// Format lines are only kept by [`BlobFormat::Text`] blobs, and are kept by every store
+    /// which changes a blob that has one, whether or not this is set.
+    pub fn with_format_header(mut self) -> Self {
+        self.format_header = true;
+        self
+    }
+
+    /// Hold the lock of a blob from `provider` while inserting a digest into it, or deleting
+    /// one, so that writers sharing the provider never replace each other's changes, even
+    /// through bridges without conditional writes. Lookups of stored digests also wait for the
+    /// lock, since they can not be told apart from inserts before the blob is read. A lock which
+    /// can not be taken is an error of kind [`crate::ErrorKind::Timeout`], which is retryable.
+    ///
+    /// Locks do not cover the writes of [`RemoteStore::with_write_behind`], which are made later.
+    pub fn with_lock_provider(mut self, provider: impl LockProvider + 'static) -> Self {
+        self.locks = Some(Locks::new(std::sync::Arc::new(provider)));
+        self
+    }
+
+    /// Reports of the corrupt blobs recovered since the last call,
+    /// see [`RemoteStore::with_quarantine`].
+    pub fn take_recoveries(&mut self) -> Vec<RecoveryReport> {
+        std::mem::take(&mut self.recoveries)
+    }
+
+    /// Write every pending blob, see [`RemoteStore::with_write_behind`].
+    #[async_generic]
+    pub fn flush(&mut self) -> Result<(), crate::Error> {
+        if _async {
+            self.write_pending_async(true).await
+        } else {
+            self.write_pending(true)
+        }
+    }
+
+    /// Each blob which is not written yet, with the time of its first unwritten insert.
+    pub(crate) fn pending_blobs(&self) -> impl Iterator<Item = (Instant, &Bytes)> {
+        self.pending_writes
+            .iter()
+            .flat_map(|pending| pending.blobs.values())
+            .map(|(since, blob)| (*since, blob))
+    }
+
+    /// Write pending blobs whose window has passed, or all of them.
+    /// Blobs which could not be written remain pending.
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn write_pending(&mut self, all: bool) -> Result<(), crate::Error> {
+        let Some(pending) = self.pending_writes.as_mut() else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let due = pending
+            .blobs
+            .iter()
+            .filter(|(_, (since, _))| all || now.duration_since(*since) >= pending.window)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        for key in due {
+            let (since, blob) = pending.blobs.remove(&key).unwrap();
+            let put = |e| crate::Error::storage(e, &key, Operation::Put);
+            let encoded = self.blob_format.encode(&blob).map_err(put)?;
+            counter!("perfume_blob_bytes_total", encoded.len(), "direction" => "sent");
+            let mut update_result: Result<(), std::io::Error> = Ok(());
+            let resource = bridge_key(self.key_template.as_ref(), &key);
+            if _async {
+                update_result = self.bridge.put_async(&resource, encoded).await;
+            } else {
+                update_result = self.bridge.put(&resource, encoded);
+            }
+            event!(DEBUG, key, bytes = blob.len(), error = ?update_result.as_ref().err(), "stored blob");
+            if let Err(e) = update_result {
+                let error = put(e);
+                pending.blobs.insert(key, (since, blob));
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// What a fetched blob must still be when it is replaced.
+    fn precondition(&self, body: Option<&Bytes>, validator: Option<&str>) -> Option<Precondition> {
+        let versioned = self.blob_versions && self.blob_format == BlobFormat::Text;
+        match (body, validator) {
+            (None, _) => Some(Precondition::Validator(None)),
+            (Some(_), Some(validator)) => {
+                Some(Precondition::Validator(Some(validator.to_string())))
+            }
+            (Some(_), None) if versioned => Some(Precondition::Version),
+            (Some(_), None) => None,
+        }
+    }
+
+    /// A fetched blob as text, see [`RemoteStore::with_blob_format`].
+    fn decoded(&self, body: Option<Bytes>) -> std::io::Result<Option<Bytes>> {
+        match body {
+            Some(body) if self.blob_format != BlobFormat::Text => {
+                self.blob_format.decode(&body).map(Some)
+            }
+            body => Ok(body),
+        }
+    }
+
+    /// Fetch the blob stored at `key` and its validator, revalidating any cached copy.
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn fetch(&mut self, key: &str) -> BridgeResult<(Option<Bytes>, Option<String>)> {
+        let cached = self.blob_cache.as_mut().and_then(|cache| cache.get(key));
+        let validator = cached.as_ref().map(|(validator, _blob)| validator.as_str());
+
+        let resource = bridge_key(self.key_template.as_ref(), key);
+        let mut validated = Validated::NotModified;
+        if _async {
+            validated = self
+                .bridge
+                .get_validated_async(&resource, validator)
+                .await?;
+        } else {
+            validated = self.bridge.get_validated(&resource, validator)?;
+        }
+
+        match (validated, cached) {
+            (Validated::NotModified, Some((validator, blob))) => {
+                counter!("perfume_cache_requests_total", 1, "cache" => "blob", "outcome" => "hit");
+                event!(
+                    DEBUG,
+                    key,
+                    bytes = blob.len(),
+                    outcome = "not_modified",
+                    "fetched blob"
+                );
+                Ok((Some(blob), Some(validator)))
+            }
+            (Validated::NotModified, None) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("bridge returned not modified for uncached key {key}"),
+            )),
+            (Validated::Modified { body, validator }, _) => {
+                event!(
+                    DEBUG,
+                    key,
+                    bytes = body.as_ref().map_or(0, Bytes::len),
+                    outcome = if body.is_some() { "modified" } else { "absent" },
+                    "fetched blob"
+                );
+                counter!(
+                    "perfume_blob_bytes_total",
+                    body.as_ref().map_or(0, Bytes::len),
+                    "direction" => "received"
+                );
+                if let Some(cache) = self.blob_cache.as_mut() {
+                    counter!("perfume_cache_requests_total", 1, "cache" => "blob", "outcome" => "miss");
+                    match (&validator, &body) {
+                        (Some(validator), Some(blob)) => {
+                            cache.insert(key.to_string(), (validator.clone(), blob.clone()))
+                        }
+                        _ => cache.remove(key),
+                    }
+                }
+                Ok((body, validator))
+            }
+        }
+    }
 }
 
 impl<B> StorageState for RemoteStore<B>
@@ -76,92 +929,3122 @@ where
     #[allow(unused_assignments)]
     fn digest_offset(
         &mut self,
-        _domain: &str,
+        domain: &str,
         storage: &Storage,
     ) -> std::result::Result<usize, crate::Error> {
         let key = storage.key.as_str();
-        let digest = storage.digest.as_str();
-
-        let mut stored_bytes: Option<Bytes> = None;
+        let mut result = Ok(0);
         if _async {
-            stored_bytes = self.bridge.get_async(key).await?;
+            self.lock_async(domain, key).await?;
+            result = self.assign_offset_async(domain, storage).await;
+            result = self.unlock_async(domain, key, result).await;
         } else {
-            stored_bytes = self.bridge.get(key)?;
+            self.lock(domain, key)?;
+            result = self.assign_offset(domain, storage);
+            result = self.unlock(domain, key, result);
         }
+        result
+    }
 
-        // "<digest> <offset>"
-        let mut lines: Vec<String> = match stored_bytes {
-            None => Vec::default(),
-            Some(stored_bytes) => stored_bytes.lines().map_while(|l| l.ok()).collect(),
-        };
-        // "<digest>"
-        let search_lines: Vec<&str> = lines.iter().map(|s| &s[..digest.len()]).collect();
-
-        match search_lines.binary_search(&digest) {
-            // return <offset>
-            Ok(found_at) => {
-                let found_line = &lines[found_at];
-                let found_offset: usize = found_line[(digest.len() + 1)..].trim().parse().unwrap();
-                Ok(found_offset)
+    /// Each blob is fetched once, and written once with all of its missing digests, which suits
+    /// bulk jobs such as anonymizing a table. Digests are inserted in the order they are given.
+    ///
+    /// Blobs are changed one at a time, so when this fails, the digests of the blobs which were
+    /// written before are stored.
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn digest_offsets(
+        &mut self,
+        domain: &str,
+        storages: &[Storage],
+    ) -> std::result::Result<Vec<usize>, crate::Error> {
+        // indexes of distinct `storages` by key, in the order the keys are first given
+        let mut groups: Vec<Vec<usize>> = vec![];
+        let mut by_key: HashMap<&str, usize> = HashMap::new();
+        let mut seen: HashSet<&Storage> = HashSet::new();
+        for (index, storage) in storages.iter().enumerate() {
+            let group = *by_key.entry(storage.key.as_str()).or_insert_with(|| {
+                groups.push(vec![]);
+                groups.len() - 1
+            });
+            if seen.insert(storage) {
+                groups[group].push(index);
             }
-            Err(insert_at) => {
-                let next_offset = lines.len();
-
-                // each line is expected to be 68 bytes, to enable HTTP range requests
-                lines.insert(insert_at, format!("{digest} {next_offset:>5}"));
-                let mut resource = lines.join("\n");
-                resource.push('\n');
-                let resource_bytes = Bytes::from(resource);
-
-                let mut update_result: Result<(), std::io::Error> = Ok(());
-                if _async {
-                    update_result = self.bridge.put_async(key, resource_bytes).await;
-                } else {
-                    update_result = self.bridge.put(key, resource_bytes);
-                }
+        }
 
-                update_result.map(|_| next_offset).map_err(|e| e.into())
+        let mut offsets: HashMap<&Storage, usize> = HashMap::with_capacity(storages.len());
+        for group in groups {
+            let batch = group
+                .iter()
+                .map(|&index| storages[index].clone())
+                .collect::<Vec<_>>();
+            let mut assigned = Ok(vec![]);
+            if _async {
+                assigned = self.assign_offsets_async(domain, &batch).await;
+            } else {
+                assigned = self.assign_offsets(domain, &batch);
+            }
+            for (index, offset) in group.into_iter().zip(assigned?) {
+                offsets.insert(&storages[index], offset);
             }
         }
+        Ok(storages.iter().map(|storage| offsets[storage]).collect())
     }
-}
 
-#[cfg(test)]
-pub(crate) mod tests {
-    /*
-    cargo run -F codegen
-    cargo test storage -- --nocapture
-    */
+    /// Pings the bridge, see [`ConnectionBridge::ping`].
+    #[async_generic]
+    fn health_check(&mut self) -> Result<(), crate::Error> {
+        let get = |e| crate::Error::storage(e, PING_KEY, Operation::Get);
+        if _async {
+            self.bridge.ping_async().await.map_err(get)
+        } else {
+            self.bridge.ping().map_err(get)
+        }
+    }
+}
 
-    use async_generic::async_generic;
+impl<B> RemoteStore<B>
+where
+    B: ConnectionBridge + Send,
+{
+    /// Copy the blob at `key` aside and replace it with its salvageable records, while the
+    /// lock of the blob is held, see [`RemoteStore::lock`].
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn quarantine(&mut self, domain: &str, key: &str) -> Result<(), crate::Error> {
+        let mut fetched: BridgeResult<(Option<Bytes>, Option<String>)> = Ok((None, None));
+        if _async {
+            fetched = self.fetch_async(key).await;
+        } else {
+            fetched = self.fetch(key);
+        }
+        let (stored, validator) =
+            fetched.map_err(|e| crate::Error::storage(e, key, Operation::Get))?;
+        // replaced since it was found to be corrupt
+        let Some(stored) = stored else {
+            return Ok(());
+        };
 
-    use super::*;
-    use crate::identity::{Identity, Population, tests::*};
-    use crate::{Error, STORAGE_DIGEST_LENGTH};
+        // a blob which can not be decoded has no records to salvage
+        let text = self
+            .blob_format
+            .decode(&stored)
+            .map_err(|e| crate::Error::storage(e, key, Operation::Parse))?;
+        let check = check_blob(&text);
+        // blobs of a later format are not corrupt, and are never rewritten
+        if matches!(check.issues[..], [BlobIssue::UnsupportedFormat(_)]) {
+            return Ok(());
+        }
+        let put = |e| crate::Error::storage(e, key, Operation::Put);
+        let precondition = self.precondition(Some(&stored), validator.as_deref());
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let quarantined_as = format!("{key}.corrupt-{seconds}");
+        let quarantined_resource = self.bridge_key(&quarantined_as);
 
-    #[tokio::test]
-    async fn test_remote_store_async() -> Result<(), Error> {
-        impl_test_remote_store_async().await?;
-        Ok(())
-    }
+        // the corrupt blob is kept as it was stored before it is replaced
+        if _async {
+            self.bridge
+                .put_async(&quarantined_resource, stored)
+                .await
+                .map_err(put)?;
+            self.store_blob_async(domain, key, &check.canonical, precondition.as_ref(), None)
+                .await?;
+        } else {
+            self.bridge
+                .put(&quarantined_resource, stored)
+                .map_err(put)?;
+            self.store_blob(domain, key, &check.canonical, precondition.as_ref(), None)?;
+        }
+        if let Some(index) = self.bloom_index.as_mut() {
+            index.keys.remove(key);
+        }
 
-    #[test]
-    fn test_remote_store_blocking() -> Result<(), Error> {
-        impl_test_remote_store()?;
+        let salvaged = Records::new(&check.canonical).map_or(0, |records| records.len());
+        // format and version lines which were kept are not records
+        let preamble = split_version(&check.canonical).map_or(0, |(_version, length)| {
+            preamble_lines(&check.canonical[..length])
+        });
+        let lines = String::from_utf8_lossy(&text).lines().count() - preamble;
+        event!(
+            WARN,
+            key,
+            quarantined_as,
+            salvaged,
+            "quarantined corrupt blob"
+        );
+        self.recoveries.push(RecoveryReport {
+            key: HexString::from(key.as_bytes()),
+            quarantined_as,
+            issues: check.issues,
+            salvaged,
+            discarded: lines.saturating_sub(salvaged),
+        });
         Ok(())
     }
 
+    /// Take the lock of the blob of `key`, if the store has a lock provider.
     #[async_generic]
     #[allow(unused_assignments)]
-    fn impl_test_remote_store() -> Result<(), Error> {
+    fn lock(&mut self, domain: &str, key: &str) -> std::result::Result<(), crate::Error> {
+        let Some(locks) = self.locks.clone() else {
+            return Ok(());
+        };
+        let resource = self.bridge_key(key).into_owned();
+        let mut acquired = Ok(());
+        if _async {
+            acquired = locks.provider().acquire_async(&resource).await;
+        } else {
+            acquired = locks.provider().acquire(&resource);
+        }
+        acquired.map_err(|e| crate::Error::storage(e, key, Operation::Lock).in_domain(domain))
+    }
+
+    /// Release the lock taken by [`RemoteStore::lock`], then return `result`, or else the
+    /// failure to release the lock.
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn unlock<T>(
+        &mut self,
+        domain: &str,
+        key: &str,
+        result: std::result::Result<T, crate::Error>,
+    ) -> std::result::Result<T, crate::Error> {
+        let Some(locks) = self.locks.clone() else {
+            return result;
+        };
+        let resource = self.bridge_key(key).into_owned();
+        let mut released = Ok(());
+        if _async {
+            released = locks.provider().release_async(&resource).await;
+        } else {
+            released = locks.provider().release(&resource);
+        }
+        let value = result?;
+        released.map_err(|e| crate::Error::storage(e, key, Operation::Lock).in_domain(domain))?;
+        Ok(value)
+    }
+
+    /// The offset of the digest of `storage`, inserting it if it is not stored, and
+    /// recovering its blob if it is corrupt, see [`RemoteStore::with_quarantine`].
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn assign_offset(
+        &mut self,
+        domain: &str,
+        storage: &Storage,
+    ) -> std::result::Result<usize, crate::Error> {
+        let mut result = Ok(0);
+        if _async {
+            result = self.find_or_insert_retrying_async(domain, storage).await;
+        } else {
+            result = self.find_or_insert_retrying(domain, storage);
+        }
+        match result {
+            Err(crate::Error::CorruptBlob { .. }) if self.quarantine => {
+                let key = storage.key.as_str();
+                if _async {
+                    self.quarantine_async(domain, key)
+                        .await
+                        .map_err(|e| e.in_domain(domain))?;
+                    self.find_or_insert_retrying_async(domain, storage).await
+                } else {
+                    self.quarantine(domain, key)
+                        .map_err(|e| e.in_domain(domain))?;
+                    self.find_or_insert_retrying(domain, storage)
+                }
+            }
+            result => result,
+        }
+    }
+
+    /// [`RemoteStore::find_or_insert`], with errors reporting how many times the blob was
+    /// written.
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn find_or_insert_retrying(
+        &mut self,
+        domain: &str,
+        storage: &Storage,
+    ) -> std::result::Result<usize, crate::Error> {
+        let mut attempt = 1;
+        let mut result = Ok(0);
+        if _async {
+            result = self
+                .find_or_insert_async(domain, storage, &mut attempt)
+                .await;
+        } else {
+            result = self.find_or_insert(domain, storage, &mut attempt);
+        }
+        result.map_err(|e| e.at_attempt(attempt))
+    }
+
+    /// The offset of the digest of `storage`, inserting it if it is not stored.
+    /// When another writer changed the blob first, the new record is merged into a fresh copy
+    /// of the blob, see [`merge_blobs`], which is written again, up to [`CONFLICT_ATTEMPTS`]
+    /// times in all, counted by `attempt`.
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn find_or_insert(
+        &mut self,
+        domain: &str,
+        storage: &Storage,
+        attempt: &mut u32,
+    ) -> std::result::Result<usize, crate::Error> {
+        let key = storage.key.as_str();
+        let resource = bridge_key(self.key_template.as_ref(), key);
+        let digest = storage.digest.as_str();
+        let context =
+            |operation| move |e| crate::Error::storage(e, key, operation).in_domain(domain);
+
+        // a pending blob is newer than the stored one
+        let pending_blob = self
+            .pending_writes
+            .as_ref()
+            .and_then(|pending| pending.blobs.get(key))
+            .map(|(_since, blob)| blob.clone());
+
+        // an indexed digest only needs its blob to be revalidated
+        let indexed = self
+            .offset_index
+            .as_mut()
+            .filter(|_| pending_blob.is_none());
+        let indexed = indexed.and_then(|cache| {
+            cache
+                .get_with(key, |(validator, index)| {
+                    Some((validator.clone(), index.get(digest)?))
+                })
+                .flatten()
+        });
+        if indexed.is_none() && self.offset_index.is_some() {
+            counter!("perfume_cache_requests_total", 1, "cache" => "offset_index", "outcome" => "miss");
+        }
+        let mut fetched: Option<(Option<Bytes>, Option<String>)> = None;
+        if let Some((validator, offset)) = indexed {
+            let mut validated = Validated::NotModified;
+            if _async {
+                validated = self
+                    .bridge
+                    .get_validated_async(&resource, Some(&validator))
+                    .await
+                    .map_err(context(Operation::Get))?;
+            } else {
+                validated = self
+                    .bridge
+                    .get_validated(&resource, Some(&validator))
+                    .map_err(context(Operation::Get))?;
+            }
+            match validated {
+                Validated::NotModified => {
+                    counter!("perfume_cache_requests_total", 1, "cache" => "offset_index", "outcome" => "hit");
+                    counter!("perfume_assignments_total", 1, "outcome" => "existing");
+                    event!(
+                        DEBUG,
+                        domain,
+                        key,
+                        offset,
+                        outcome = "indexed",
+                        "resolved digest"
+                    );
+                    return Ok(offset);
+                }
+                Validated::Modified { body, validator } => {
+                    counter!("perfume_cache_requests_total", 1, "cache" => "offset_index", "outcome" => "miss");
+                    counter!(
+                        "perfume_blob_bytes_total",
+                        body.as_ref().map_or(0, Bytes::len),
+                        "direction" => "received"
+                    );
+                    fetched = Some((body, validator));
+                }
+            }
+        }
+
+        // the last known blob, if the digest is certainly not stored in it
+        let empty_keys = self.empty_keys;
+        let known_blob =
+            self.bloom_index
+                .as_mut()
+                .and_then(|index| match index.keys.contains_key(key) {
+                    true => index.blob_without(key, digest),
+                    false => (empty_keys && !index.seen.contains(key)).then_some(None),
+                });
+        if self.bloom_index.is_some() && pending_blob.is_none() {
+            counter!(
+                "perfume_cache_requests_total",
+                1,
+                "cache" => "bloom",
+                "outcome" => if known_blob.is_some() { "hit" } else { "miss" }
+            );
+        }
+
+        // a stored digest can be found without holding its blob
+        let text = self.blob_format == BlobFormat::Text;
+        let unknown = pending_blob.is_none() && fetched.is_none() && known_blob.is_none();
+        let mut found = None;
+        let mut searched = false;
+        if self.ranged_reads && text && unknown {
+            let mut ranged = None;
+            if _async {
+                ranged = self
+                    .ranged_search_async(&resource, digest)
+                    .await
+                    .map_err(context(Operation::Get))?;
+            } else {
+                ranged = self
+                    .ranged_search(&resource, digest)
+                    .map_err(context(Operation::Get))?;
+            }
+            searched = ranged.is_some();
+            found = ranged.flatten();
+        }
+        if self.streaming && text && unknown && !searched {
+            let mut scanner = RecordScanner::new(digest.as_bytes());
+            let mut visit = |chunk: &[u8]| {
+                counter!("perfume_blob_bytes_total", chunk.len(), "direction" => "received");
+                scanner.visit(chunk)
+            };
+            if _async {
+                self.bridge
+                    .get_chunks_async(&resource, &mut visit)
+                    .await
+                    .map_err(context(Operation::Get))?;
+            } else {
+                self.bridge
+                    .get_chunks(&resource, &mut visit)
+                    .map_err(context(Operation::Get))?;
+            }
+            found = scanner.finish().map_err(context(Operation::Parse))?;
+        }
+        if let Some((offset, flag)) = found {
+            if flag.is_deleted() {
+                return Err(context(Operation::Get)(deleted_digest()));
+            }
+            counter!("perfume_assignments_total", 1, "outcome" => "existing");
+            event!(
+                DEBUG,
+                domain,
+                key,
+                offset,
+                outcome = if searched { "ranged" } else { "streamed" },
+                "resolved digest"
+            );
+            return Ok(offset);
+        }
+
+        let mut stored_bytes: Option<Bytes> = None;
+        // pending and filtered blobs are only written by this store, except for absent blobs,
+        // which must still be absent
+        let mut precondition: Option<Precondition> = None;
+        if pending_blob.is_some() {
+            stored_bytes = pending_blob;
+        } else if let Some(known_blob) = known_blob {
+            if known_blob.is_none() {
+                precondition = self.precondition(None, None);
+            }
+            stored_bytes = known_blob;
+        } else {
+            if fetched.is_none() {
+                if _async {
+                    fetched = Some(
+                        self.fetch_async(key)
+                            .await
+                            .map_err(context(Operation::Get))?,
+                    );
+                } else {
+                    fetched = Some(self.fetch(key).map_err(context(Operation::Get))?);
+                }
+            }
+            let (body, validator) = fetched.unwrap();
+            precondition = self.precondition(body.as_ref(), validator.as_deref());
+            let body = self.decoded(body).map_err(context(Operation::Parse))?;
+            let blob = body.clone().unwrap_or_default();
+            if let Some(index) = self.bloom_index.as_mut() {
+                index
+                    .rebuild(key, body.as_ref())
+                    .map_err(context(Operation::Parse))?;
+            }
+            if let (Some(cache), Some(validator)) = (self.offset_index.as_mut(), validator) {
+                let index = OffsetIndex::new(&blob).map_err(context(Operation::Parse))?;
+                cache.insert(key.to_string(), (validator, index));
+            }
+            stored_bytes = body;
+        }
+
+        // the blob which the new record is inserted into, for merging it after a conflict
+        let mut base = stored_bytes.clone().unwrap_or_default();
+        // the new record, if it can be appended to the stored blob
+        let (mut next_offset, mut resource_bytes, mut appended) = match stored_bytes {
+            // an absent blob needs no search, and becomes a single record
+            None => {
+                let digest = &digest[..self.digest_length];
+                let record = format!("{digest} {:>OFFSET_WIDTH$}\n", 0);
+                (0, Bytes::from(record), None)
+            }
+            Some(stored_bytes) => {
+                let records = Records::new(&stored_bytes).map_err(context(Operation::Parse))?;
+                event!(TRACE, key, records = records.len(), "parsed blob");
+                match records.search(digest.as_bytes()) {
+                    // return <offset>
+                    Ok(found_at) => {
+                        let offset = records
+                            .offset(found_at)
+                            .map_err(context(Operation::Parse))?;
+                        if records
+                            .flag(found_at)
+                            .map_err(context(Operation::Parse))?
+                            .is_deleted()
+                        {
+                            return Err(context(Operation::Get)(deleted_digest()));
+                        }
+                        counter!("perfume_assignments_total", 1, "outcome" => "existing");
+                        event!(
+                            DEBUG,
+                            domain,
+                            key,
+                            offset,
+                            outcome = "existing",
+                            "resolved digest"
+                        );
+                        return Ok(offset);
+                    }
+                    Err(insert_at) => {
+                        // tombstones keep the offsets of deleted digests from being reassigned
+                        let next_offset =
+                            records.next_offset().map_err(context(Operation::Parse))?;
+
+                        // every record of a blob has the same length, to enable HTTP range requests
+                        let digest_length = match records.len() {
+                            0 => self.digest_length,
+                            _ => records.digest_length,
+                        };
+                        let digest = &digest[..digest_length];
+                        let record = format!("{digest} {next_offset:>OFFSET_WIDTH$}\n");
+                        let blob = records.insert(insert_at, record.as_bytes());
+                        Records::new(&blob)
+                            .and_then(|inserted| inserted.check_collision(insert_at))
+                            .map_err(context(Operation::Put))?;
+                        let appended = (insert_at == records.len()).then_some(record);
+                        (next_offset, blob, appended)
+                    }
+                }
+            }
+        };
+
+        let mut stored = Bytes::new();
+        loop {
+            let mut result = Ok(Bytes::new());
+            if _async {
+                result = self
+                    .store_blob_async(
+                        domain,
+                        key,
+                        &resource_bytes,
+                        precondition.as_ref(),
+                        appended.as_deref(),
+                    )
+                    .await;
+            } else {
+                result = self.store_blob(
+                    domain,
+                    key,
+                    &resource_bytes,
+                    precondition.as_ref(),
+                    appended.as_deref(),
+                );
+            }
+            match result {
+                Ok(written) => {
+                    stored = written;
+                    break;
+                }
+                Err(e) if is_conflict(&e) && *attempt < CONFLICT_ATTEMPTS => {
+                    counter!("perfume_conflicts_total", 1);
+                    event!(DEBUG, domain, key, attempt = *attempt, "conflicting write");
+                    *attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+
+            // the new record is merged into the blob of the other writer
+            let mut fetched: (Option<Bytes>, Option<String>) = (None, None);
+            if _async {
+                fetched = self
+                    .fetch_async(key)
+                    .await
+                    .map_err(context(Operation::Get))?;
+            } else {
+                fetched = self.fetch(key).map_err(context(Operation::Get))?;
+            }
+            let (body, validator) = fetched;
+            precondition = self.precondition(body.as_ref(), validator.as_deref());
+            let theirs = self
+                .decoded(body)
+                .map_err(context(Operation::Parse))?
+                .unwrap_or_default();
+            if let Some(index) = self.bloom_index.as_mut() {
+                index
+                    .rebuild(key, Some(&theirs))
+                    .map_err(context(Operation::Parse))?;
+            }
+            let records = Records::new(&theirs).map_err(context(Operation::Parse))?;
+            if let Ok(found_at) = records.search(digest.as_bytes()) {
+                let offset = records
+                    .offset(found_at)
+                    .map_err(context(Operation::Parse))?;
+                if records
+                    .flag(found_at)
+                    .map_err(context(Operation::Parse))?
+                    .is_deleted()
+                {
+                    return Err(context(Operation::Get)(deleted_digest()));
+                }
+                counter!("perfume_assignments_total", 1, "outcome" => "existing");
+                return Ok(offset);
+            }
+            let merged =
+                merge_blobs(&base, &resource_bytes, &theirs).map_err(context(Operation::Parse))?;
+            let records = Records::new(&merged).map_err(context(Operation::Parse))?;
+            let found_at = records
+                .search(digest.as_bytes())
+                .map_err(|_| malformed(0, "merged blob lost its new record".into()))
+                .map_err(context(Operation::Parse))?;
+            records
+                .check_collision(found_at)
+                .map_err(context(Operation::Put))?;
+            next_offset = records
+                .offset(found_at)
+                .map_err(context(Operation::Parse))?;
+            (base, resource_bytes, appended) = (theirs, merged, None);
+        }
+        if let Some(audit) = &self.audit {
+            audit.record(AuditAction::Assigned, domain, key, digest, next_offset);
+        }
+        counter!("perfume_assignments_total", 1, "outcome" => "new");
+        event!(
+            DEBUG,
+            domain,
+            key,
+            offset = next_offset,
+            outcome = "new",
+            "resolved digest"
+        );
+        if let Some(index) = self.bloom_index.as_mut() {
+            index.inserted(key, digest, stored);
+        }
+        Ok(next_offset)
+    }
+
+    /// The offsets of the digests of `storages`, which share a storage key, inserting those
+    /// which are not stored with a single write of their blob, and recovering their blob if it
+    /// is corrupt, see [`RemoteStore::with_quarantine`].
+    #[async_generic]
+    #[allow(unused_assignments)]
+    pub(crate) fn assign_offsets(
+        &mut self,
+        domain: &str,
+        storages: &[Storage],
+    ) -> std::result::Result<Vec<usize>, crate::Error> {
+        let Some(first) = storages.first() else {
+            return Ok(vec![]);
+        };
+        let key = first.key.as_str();
+        let mut attempt = 1;
+        let mut result = Ok(vec![]);
+        if _async {
+            self.lock_async(domain, key).await?;
+            result = self
+                .insert_batch_async(domain, storages, &mut attempt)
+                .await;
+            if matches!(result, Err(crate::Error::CorruptBlob { .. })) && self.quarantine {
+                result = match self.quarantine_async(domain, key).await {
+                    Ok(()) => {
+                        attempt = 1;
+                        self.insert_batch_async(domain, storages, &mut attempt)
+                            .await
+                    }
+                    Err(e) => Err(e.in_domain(domain)),
+                };
+            }
+            result = self.unlock_async(domain, key, result).await;
+        } else {
+            self.lock(domain, key)?;
+            result = self.insert_batch(domain, storages, &mut attempt);
+            if matches!(result, Err(crate::Error::CorruptBlob { .. })) && self.quarantine {
+                result = match self.quarantine(domain, key) {
+                    Ok(()) => {
+                        attempt = 1;
+                        self.insert_batch(domain, storages, &mut attempt)
+                    }
+                    Err(e) => Err(e.in_domain(domain)),
+                };
+            }
+            result = self.unlock(domain, key, result);
+        }
+        result.map_err(|e| e.at_attempt(attempt))
+    }
+
+    /// [`RemoteStore::assign_offsets`]. When another writer changed the blob first, the missing
+    /// digests are inserted into a fresh copy of the blob, up to [`CONFLICT_ATTEMPTS`] times in
+    /// all, counted by `attempt`.
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn insert_batch(
+        &mut self,
+        domain: &str,
+        storages: &[Storage],
+        attempt: &mut u32,
+    ) -> std::result::Result<Vec<usize>, crate::Error> {
+        let key = storages[0].key.as_str();
+        debug_assert!(
+            storages
+                .iter()
+                .all(|storage| storage.key == storages[0].key)
+        );
+        let context =
+            |operation| move |e| crate::Error::storage(e, key, operation).in_domain(domain);
+
+        // a pending blob is newer than the stored one, and only written by this store
+        let pending_blob = self
+            .pending_writes
+            .as_ref()
+            .and_then(|pending| pending.blobs.get(key))
+            .map(|(_since, blob)| blob.clone());
+        let mut precondition: Option<Precondition> = None;
+        let mut fetched: Option<(Option<Bytes>, Option<String>)> = None;
+        let mut blob = pending_blob.unwrap_or_default();
+        loop {
+            if let Some((body, validator)) = fetched.take() {
+                precondition = self.precondition(body.as_ref(), validator.as_deref());
+                blob = self
+                    .decoded(body)
+                    .map_err(context(Operation::Parse))?
+                    .unwrap_or_default();
+                if let Some(index) = self.bloom_index.as_mut() {
+                    index
+                        .rebuild(key, Some(&blob))
+                        .map_err(context(Operation::Parse))?;
+                }
+            } else if blob.is_empty() {
+                if _async {
+                    fetched = Some(
+                        self.fetch_async(key)
+                            .await
+                            .map_err(context(Operation::Get))?,
+                    );
+                } else {
+                    fetched = Some(self.fetch(key).map_err(context(Operation::Get))?);
+                }
+                continue;
+            }
+
+            // the missing digests, in the order they were given
+            let records = Records::new(&blob).map_err(context(Operation::Parse))?;
+            let digest_length = match records.len() {
+                0 => self.digest_length,
+                _ => records.digest_length,
+            };
+            let mut missing: Vec<&str> = vec![];
+            for storage in storages {
+                let digest = &storage.digest.as_str()[..digest_length];
+                if records.search(digest.as_bytes()).is_err() && !missing.contains(&digest) {
+                    missing.push(digest);
+                }
+            }
+            let inserted = match missing.is_empty() {
+                true => blob.clone(),
+                false => {
+                    let mut ours = missing.iter().enumerate().collect::<Vec<_>>();
+                    ours.sort_by_key(|(_order, digest)| **digest);
+                    let ours = ours
+                        .into_iter()
+                        .map(|(order, digest)| text_record(digest, RecordFlag::Live, order))
+                        .collect::<String>();
+                    merge_blobs(&[], ours.as_bytes(), &blob).map_err(context(Operation::Parse))?
+                }
+            };
+
+            let records = Records::new(&inserted).map_err(context(Operation::Parse))?;
+            let mut offsets = Vec::with_capacity(storages.len());
+            for storage in storages {
+                let parse = context(Operation::Parse);
+                let found_at = records
+                    .search(storage.digest.as_str().as_bytes())
+                    .map_err(|_| malformed(0, "blob lost an inserted record".into()))
+                    .map_err(parse)?;
+                if records.flag(found_at).map_err(parse)?.is_deleted() {
+                    return Err(context(Operation::Get)(deleted_digest()));
+                }
+                offsets.push(records.offset(found_at).map_err(parse)?);
+            }
+            for digest in &missing {
+                if let Ok(found_at) = records.search(digest.as_bytes()) {
+                    records
+                        .check_collision(found_at)
+                        .map_err(context(Operation::Put))?;
+                }
+            }
+            counter!(
+                "perfume_assignments_total",
+                storages.len() - missing.len(),
+                "outcome" => "existing"
+            );
+            if missing.is_empty() {
+                return Ok(offsets);
+            }
+
+            let mut result = Ok(Bytes::new());
+            if _async {
+                result = self
+                    .store_blob_async(domain, key, &inserted, precondition.as_ref(), None)
+                    .await;
+            } else {
+                result = self.store_blob(domain, key, &inserted, precondition.as_ref(), None);
+            }
+            let stored = match result {
+                Ok(stored) => stored,
+                Err(e) if is_conflict(&e) && *attempt < CONFLICT_ATTEMPTS => {
+                    counter!("perfume_conflicts_total", 1);
+                    event!(DEBUG, domain, key, attempt = *attempt, "conflicting write");
+                    *attempt += 1;
+                    // the missing digests are inserted into the blob of the other writer
+                    if _async {
+                        fetched = Some(
+                            self.fetch_async(key)
+                                .await
+                                .map_err(context(Operation::Get))?,
+                        );
+                    } else {
+                        fetched = Some(self.fetch(key).map_err(context(Operation::Get))?);
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            counter!("perfume_assignments_total", missing.len(), "outcome" => "new");
+            let records = Records::new(&stored).map_err(context(Operation::Parse))?;
+            for digest in missing {
+                let offset = records
+                    .search(digest.as_bytes())
+                    .map_or(Ok(0), |found_at| records.offset(found_at))
+                    .map_err(context(Operation::Parse))?;
+                if let Some(audit) = &self.audit {
+                    audit.record(AuditAction::Assigned, domain, key, digest, offset);
+                }
+                if let Some(index) = self.bloom_index.as_mut() {
+                    index.inserted(key, digest, stored.clone());
+                }
+            }
+            event!(
+                DEBUG,
+                domain,
+                key,
+                digests = storages.len(),
+                "resolved digests"
+            );
+            return Ok(offsets);
+        }
+    }
+    /// Replace the record of the digest of `storage` with a tombstone, so that its offset is
+    /// never assigned again, and finding the digest fails with [`crate::ErrorKind::NotFound`].
+    /// Returns false if the digest is not stored, or was already deleted.
+    /// Tombstones remain until they are dropped by [`RemoteStore::compact`].
+    #[async_generic]
+    #[allow(unused_assignments)]
+    pub fn delete(&mut self, domain: &str, storage: &Storage) -> Result<bool, crate::Error> {
+        let key = storage.key.as_str();
+        let mut result = Ok(false);
+        if _async {
+            self.lock_async(domain, key).await?;
+            result = self.tombstone_async(domain, storage).await;
+            result = self.unlock_async(domain, key, result).await;
+        } else {
+            self.lock(domain, key)?;
+            result = self.tombstone(domain, storage);
+            result = self.unlock(domain, key, result);
+        }
+        result
+    }
+
+    /// Replace the record of the digest of `storage` with a tombstone, see
+    /// [`RemoteStore::delete`].
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn tombstone(&mut self, domain: &str, storage: &Storage) -> Result<bool, crate::Error> {
+        let key = storage.key.as_str();
+        let digest = storage.digest.as_str();
+        let context =
+            |operation| move |e| crate::Error::storage(e, key, operation).in_domain(domain);
+
+        let mut stored: Option<Bytes> = None;
+        if _async {
+            stored = self.stored_blob_async(domain, key).await?;
+        } else {
+            stored = self.stored_blob(domain, key)?;
+        }
+        let Some(stored) = stored else {
+            return Ok(false);
+        };
+
+        let records = Records::new(&stored).map_err(context(Operation::Parse))?;
+        let Ok(found_at) = records.search(digest.as_bytes()) else {
+            return Ok(false);
+        };
+        let parse = context(Operation::Parse);
+        if records.flag(found_at).map_err(parse)?.is_deleted() {
+            return Ok(false);
+        }
+        let offset = records.offset(found_at).map_err(parse)?;
+        let mut blob = records.flagged(found_at, RecordFlag::Tombstone);
+        if _async {
+            blob = self
+                .store_blob_async(domain, key, &blob, None, None)
+                .await?;
+        } else {
+            blob = self.store_blob(domain, key, &blob, None, None)?;
+        }
+
+        if let Some(audit) = &self.audit {
+            audit.record(AuditAction::Deleted, domain, key, digest, offset);
+        }
+        event!(DEBUG, domain, key, offset, "deleted digest");
+        if let Some(index) = self.bloom_index.as_mut() {
+            index.inserted(key, digest, blob);
+        }
+        Ok(true)
+    }
+
+    /// Remove the live record of the digest of `storage`, as though the digest had never been
+    /// assigned, so that it is assigned an offset again when it is next found, unlike after
+    /// [`RemoteStore::delete`]. Returns false if there was no such record. If its offset was the
+    /// largest of its blob, the offset may be assigned to another digest.
+    #[async_generic]
+    #[allow(unused_assignments)]
+    pub(crate) fn retract(
+        &mut self,
+        domain: &str,
+        storage: &Storage,
+    ) -> Result<bool, crate::Error> {
+        let key = storage.key.as_str();
+        let mut result = Ok(false);
+        if _async {
+            self.lock_async(domain, key).await?;
+            result = self.remove_record_async(domain, storage).await;
+            result = self.unlock_async(domain, key, result).await;
+        } else {
+            self.lock(domain, key)?;
+            result = self.remove_record(domain, storage);
+            result = self.unlock(domain, key, result);
+        }
+        result
+    }
+
+    /// Remove the record of the digest of `storage`, see [`RemoteStore::retract`].
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn remove_record(&mut self, domain: &str, storage: &Storage) -> Result<bool, crate::Error> {
+        let key = storage.key.as_str();
+        let digest = storage.digest.as_str();
+        let parse = |e| crate::Error::storage(e, key, Operation::Parse).in_domain(domain);
+
+        let mut stored: Option<Bytes> = None;
+        if _async {
+            stored = self.stored_blob_async(domain, key).await?;
+        } else {
+            stored = self.stored_blob(domain, key)?;
+        }
+        let Some(stored) = stored else {
+            return Ok(false);
+        };
+        let records = Records::new(&stored).map_err(parse)?;
+        let Ok(found_at) = records.search(digest.as_bytes()) else {
+            return Ok(false);
+        };
+        if records.flag(found_at).map_err(parse)?.is_deleted() {
+            return Ok(false);
+        }
+        let offset = records.offset(found_at).map_err(parse)?;
+        let mut blob = records.removed(found_at);
+        if _async {
+            blob = self
+                .store_blob_async(domain, key, &blob, None, None)
+                .await?;
+        } else {
+            blob = self.store_blob(domain, key, &blob, None, None)?;
+        }
+
+        if let Some(audit) = &self.audit {
+            audit.record(AuditAction::Deleted, domain, key, digest, offset);
+        }
+        event!(DEBUG, domain, key, offset, "retracted digest");
+        if let Some(index) = self.bloom_index.as_mut() {
+            let _ = index.rebuild(key, Some(&blob));
+        }
+        Ok(true)
+    }
+
+    /// The offset of the digest of `storage` if it is stored, without inserting it,
+    /// or else the next offset of its blob.
+    #[async_generic]
+    #[allow(unused_assignments)]
+    pub(crate) fn lookup(
+        &mut self,
+        domain: &str,
+        storage: &Storage,
+    ) -> Result<Result<usize, usize>, crate::Error> {
+        let key = storage.key.as_str();
+        let mut stored: Option<Bytes> = None;
+        if _async {
+            stored = self.stored_blob_async(domain, key).await?;
+        } else {
+            stored = self.stored_blob(domain, key)?;
+        }
+        let context =
+            |operation| move |e| crate::Error::storage(e, key, operation).in_domain(domain);
+        match search_blob(&stored.unwrap_or_default(), storage.digest.as_str())
+            .map_err(context(Operation::Parse))?
+        {
+            Ok((_offset, flag)) if flag.is_deleted() => {
+                Err(context(Operation::Get)(deleted_digest()))
+            }
+            Ok((offset, _flag)) => Ok(Ok(offset)),
+            Err(next_offset) => Ok(Err(next_offset)),
+        }
+    }
+
+    /// Keep the offsets below `next_offset` of the blob of `key` from being assigned,
+    /// with a compacted record of a digest of zeros, unless they are already assigned.
+    #[async_generic]
+    #[allow(unused_assignments)]
+    pub(crate) fn reserve_offsets(
+        &mut self,
+        domain: &str,
+        key: &str,
+        next_offset: usize,
+    ) -> Result<(), crate::Error> {
+        let mut result = Ok(());
+        if _async {
+            self.lock_async(domain, key).await?;
+            result = self.reserve_async(domain, key, next_offset).await;
+            result = self.unlock_async(domain, key, result).await;
+        } else {
+            self.lock(domain, key)?;
+            result = self.reserve(domain, key, next_offset);
+            result = self.unlock(domain, key, result);
+        }
+        result
+    }
+
+    /// Insert the record reserving the offsets below `next_offset`, see
+    /// [`RemoteStore::reserve_offsets`].
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn reserve(&mut self, domain: &str, key: &str, next_offset: usize) -> Result<(), crate::Error> {
+        let mut stored: Option<Bytes> = None;
+        if _async {
+            stored = self.stored_blob_async(domain, key).await?;
+        } else {
+            stored = self.stored_blob(domain, key)?;
+        }
+        let stored = stored.unwrap_or_default();
+        let parse = |e| crate::Error::storage(e, key, Operation::Parse).in_domain(domain);
+        let records = Records::new(&stored).map_err(parse)?;
+        if next_offset == 0 || records.next_offset().map_err(parse)? >= next_offset {
+            return Ok(());
+        }
+
+        let digest_length = match records.len() {
+            0 => self.digest_length,
+            _ => records.digest_length,
+        };
+        let marker = "0".repeat(digest_length);
+        let record = text_record(&marker, RecordFlag::Compacted, next_offset - 1);
+        let blob = match records.len() {
+            // an earlier marker is replaced, since records are sorted
+            1.. if records.digest(0) == marker.as_bytes() => {
+                let rest = &records.blob[records.record_length()..];
+                Bytes::from([records.header, record.as_bytes(), rest].concat())
+            }
+            _ => records.insert(0, record.as_bytes()),
+        };
+        if _async {
+            self.store_blob_async(domain, key, &blob, None, None)
+                .await?;
+        } else {
+            self.store_blob(domain, key, &blob, None, None)?;
+        }
+        Ok(())
+    }
+
+    /// The offset and flag of `digest` in the text blob at `resource`, found by a binary search
+    /// of ranged reads, see [`RemoteStore::with_ranged_reads`]. `Some(None)` if it is not
+    /// stored, and `None` if the bridge cannot read ranges, or the blob should be read whole to
+    /// find out, such as when it is malformed. A record never changes its digest or offset, so
+    /// a record which is found is correct even while other writers insert records.
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn ranged_search(
+        &mut self,
+        resource: &str,
+        digest: &str,
+    ) -> BridgeResult<Option<Option<(usize, RecordFlag)>>> {
+        // the first read holds a version line and the first record, see `Records::new`
+        let mut range = 0..RANGED_READ_HEAD as u64;
+        let mut read = Ok(None);
+        if _async {
+            read = self.bridge.get_range_async(resource, range.clone()).await;
+        } else {
+            read = self.bridge.get_range(resource, range.clone());
+        }
+        let (head, length) = match read {
+            Ok(Some(read)) => read,
+            Ok(None) => return Ok(Some(None)),
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        counter!("perfume_blob_bytes_total", head.len(), "direction" => "received");
+        let Ok((_version, header)) = split_version(&head) else {
+            return Ok(None);
+        };
+        let Some(stride) = head[header..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map(|n| n + 1)
+        else {
+            return Ok(None);
+        };
+        let body = (length as usize).saturating_sub(header);
+        if !body.is_multiple_of(stride) {
+            return Ok(None);
+        }
+
+        // narrowed to a window of records which is read at once
+        let (mut low, mut high) = (0, body / stride);
+        while low < high {
+            let window = high - low <= RANGED_READ_WINDOW;
+            let mid = low + (high - low) / 2;
+            let (start, end) = match window {
+                true => (low, high),
+                false => (mid, mid + 1),
+            };
+            range = (header + start * stride) as u64..(header + end * stride) as u64;
+            let (start_byte, end_byte) = (range.start as usize, range.end as usize);
+            let mut records = (end_byte <= head.len()).then(|| head.slice(start_byte..end_byte));
+            if records.is_none() {
+                if _async {
+                    read = self.bridge.get_range_async(resource, range.clone()).await;
+                } else {
+                    read = self.bridge.get_range(resource, range.clone());
+                }
+                // the blob was deleted or truncated
+                let Some((bytes, _length)) = read? else {
+                    return Ok(None);
+                };
+                counter!("perfume_blob_bytes_total", bytes.len(), "direction" => "received");
+                records = Some(bytes);
+            }
+            let records = records.unwrap_or_default();
+            let Ok(records) = Records::new(&records) else {
+                return Ok(None);
+            };
+            if records.len() != end - start || records.record_length() != stride {
+                return Ok(None);
+            }
+            match (records.search(digest.as_bytes()), window) {
+                (Ok(index), _) => {
+                    let found = records
+                        .offset(index)
+                        .and_then(|offset| Ok((offset, records.flag(index)?)));
+                    return Ok(found.ok().map(Some));
+                }
+                (Err(_), true) => return Ok(Some(None)),
+                (Err(0), false) => high = mid,
+                (Err(_), false) => low = mid + 1,
+            }
+        }
+        Ok(Some(None))
+    }
+
+    /// The text blob of `key`, which may be pending, see [`RemoteStore::with_write_behind`].
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn stored_blob(&mut self, domain: &str, key: &str) -> Result<Option<Bytes>, crate::Error> {
+        let context =
+            |operation| move |e| crate::Error::storage(e, key, operation).in_domain(domain);
+
+        // a pending blob is newer than the stored one
+        let pending = self
+            .pending_writes
+            .as_ref()
+            .and_then(|pending| pending.blobs.get(key))
+            .map(|(_since, blob)| blob.clone());
+        if pending.is_some() {
+            return Ok(pending);
+        }
+        let mut fetched: (Option<Bytes>, Option<String>) = (None, None);
+        if _async {
+            fetched = self
+                .fetch_async(key)
+                .await
+                .map_err(context(Operation::Get))?;
+        } else {
+            fetched = self.fetch(key).map_err(context(Operation::Get))?;
+        }
+        fetched
+            .0
+            .map(|body| self.blob_format.decode(&body))
+            .transpose()
+            .map_err(context(Operation::Parse))
+    }
+
+    /// The version of the blob stored at `resource`, reading no more than its first line, or
+    /// two with a format line, or `None` if there is no blob.
+    /// See [`RemoteStore::with_blob_versions`].
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn stored_version(&mut self, resource: &str) -> BridgeResult<Option<u64>> {
+        let mut first_lines = Vec::new();
+        let mut visit = |mut chunk: &[u8]| {
+            while let Some(newline) = chunk.iter().position(|&b| b == b'\n') {
+                first_lines.extend_from_slice(&chunk[..=newline]);
+                chunk = &chunk[newline + 1..];
+                // the format line precedes the version line, see RemoteStore::with_format_header
+                let lines = first_lines.iter().filter(|&&b| b == b'\n').count();
+                if lines > 1 || !first_lines.starts_with(FORMAT_PREFIX.as_bytes()) {
+                    return ControlFlow::Break(());
+                }
+            }
+            first_lines.extend_from_slice(chunk);
+            ControlFlow::Continue(())
+        };
+        let mut found = false;
+        if _async {
+            found = self.bridge.get_chunks_async(resource, &mut visit).await?;
+        } else {
+            found = self.bridge.get_chunks(resource, &mut visit)?;
+        }
+        match found {
+            true => split_version(&first_lines).map(|(version, _length)| Some(version)),
+            false => Ok(None),
+        }
+    }
+
+    /// Write the text `blob` of `key`, or leave it pending, see [`RemoteStore::with_write_behind`].
+    /// Unless it is pending, the blob is only written if the stored blob still meets
+    /// `precondition`, and the `appended` record which ends it may be appended to the stored
+    /// blob instead, see [`ConnectionBridge::append`]. Returns the blob as it is written, whose
+    /// version is one more than that of `blob` if it has one, see
+    /// [`RemoteStore::with_blob_versions`].
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn store_blob(
+        &mut self,
+        domain: &str,
+        key: &str,
+        blob: &Bytes,
+        precondition: Option<&Precondition>,
+        appended: Option<&str>,
+    ) -> Result<Bytes, crate::Error> {
+        let resource = bridge_key(self.key_template.as_ref(), key);
+        let context =
+            |operation| move |e| crate::Error::storage(e, key, operation).in_domain(domain);
+
+        let (_format, format_length) = split_format(blob).map_err(context(Operation::Parse))?;
+        let (version, version_length) = split_version(blob).map_err(context(Operation::Parse))?;
+        let text = self.blob_format == BlobFormat::Text;
+        let rewrites_version = version_length > format_length || self.blob_versions && text;
+        let adds_format = format_length == 0 && self.format_header && text;
+        let blob = match (rewrites_version, adds_format) {
+            (false, false) => blob.clone(),
+            _ => {
+                let format = match adds_format {
+                    true => format_line(),
+                    false => String::from_utf8_lossy(&blob[..format_length]).into_owned(),
+                };
+                let next_version = match rewrites_version {
+                    true => version_line(version + 1),
+                    false => String::new(),
+                };
+                let preamble = format + &next_version;
+                Bytes::from([preamble.as_bytes(), &blob[version_length..]].concat())
+            }
+        };
+
+        // the validator of the updated blob is not known until it is fetched again
+        if let Some(cache) = self.blob_cache.as_mut() {
+            cache.remove(key);
+        }
+        if let Some(cache) = self.offset_index.as_mut() {
+            cache.remove(key);
+        }
+
+        let mut update_result: Result<(), crate::Error> = Ok(());
+        if let Some(pending) = self.pending_writes.as_mut() {
+            let since = pending
+                .blobs
+                .get(key)
+                .map_or_else(Instant::now, |(since, _)| *since);
+            pending.blobs.insert(key.to_string(), (since, blob.clone()));
+            if _async {
+                update_result = self.write_pending_async(false).await;
+            } else {
+                update_result = self.write_pending(false);
+            }
+            update_result = update_result.map_err(|e| e.in_domain(domain));
+        } else {
+            if let Some(Precondition::Version) = precondition {
+                let mut stored_version = None;
+                if _async {
+                    stored_version = self
+                        .stored_version_async(&resource)
+                        .await
+                        .map_err(context(Operation::Get))?;
+                } else {
+                    stored_version = self
+                        .stored_version(&resource)
+                        .map_err(context(Operation::Get))?;
+                }
+                if stored_version != Some(version) {
+                    let message =
+                        format!("blob was changed by another writer since version {version}");
+                    let conflict = std::io::Error::new(std::io::ErrorKind::AlreadyExists, message);
+                    return Err(context(Operation::Put)(conflict));
+                }
+            }
+            // a conditional write, or one which changes the format or version line, replaces
+            // the blob
+            let appended = appended.filter(|_| {
+                precondition.is_none()
+                    && !rewrites_version
+                    && !adds_format
+                    && self.blob_format == BlobFormat::Text
+            });
+            let mut put_result: BridgeResult<()> = Err(std::io::ErrorKind::Unsupported.into());
+            if let Some(record) = appended {
+                let bytes = Bytes::copy_from_slice(record.as_bytes());
+                if _async {
+                    put_result = self.bridge.append_async(&resource, bytes).await;
+                } else {
+                    put_result = self.bridge.append(&resource, bytes);
+                }
+                if put_result.is_ok() {
+                    counter!("perfume_blob_bytes_total", record.len(), "direction" => "sent");
+                }
+            }
+            let unsupported = |e: &std::io::Error| e.kind() == std::io::ErrorKind::Unsupported;
+            if put_result.as_ref().is_err_and(unsupported) {
+                let encoded = match self.blob_format {
+                    BlobFormat::Text => blob.clone(),
+                    format => format.encode(&blob).map_err(context(Operation::Put))?,
+                };
+                counter!("perfume_blob_bytes_total", encoded.len(), "direction" => "sent");
+                if _async {
+                    put_result = match precondition {
+                        Some(Precondition::Validator(validator)) => {
+                            self.bridge
+                                .put_if_match_async(&resource, encoded, validator.as_deref())
+                                .await
+                        }
+                        _ => self.bridge.put_async(&resource, encoded).await,
+                    };
+                } else {
+                    put_result = match precondition {
+                        Some(Precondition::Validator(validator)) => {
+                            self.bridge
+                                .put_if_match(&resource, encoded, validator.as_deref())
+                        }
+                        _ => self.bridge.put(&resource, encoded),
+                    };
+                }
+            }
+            update_result = put_result.map_err(context(Operation::Put));
+        }
+
+        event!(DEBUG, key, bytes = blob.len(), error = ?update_result.as_ref().err(), "stored blob");
+        update_result.map(|()| blob)
+    }
+}
+
+impl<B: ConnectionBridge> Drop for RemoteStore<B> {
+    /// Writes pending blobs with the blocking `put`, see [`RemoteStore::with_write_behind`],
+    /// except within a Tokio runtime, whose worker it would block, where they are lost.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn drop(&mut self) {
+        #[cfg(feature = "tokio")]
+        if tokio::runtime::Handle::try_current().is_ok() {
+            let lost = self.pending_blobs().count();
+            if lost > 0 {
+                event!(
+                    WARN,
+                    blobs = lost,
+                    "pending blobs were dropped in an async runtime, without flush_async"
+                );
+            }
+            return;
+        }
+        let _ = self.write_pending(true);
+    }
+}
+
+/// How many times [`RemoteStore`] attempts to insert a digest into a blob which other writers
+/// keep changing, see [`ConnectionBridge::put_if_match`].
+pub const CONFLICT_ATTEMPTS: u32 = 5;
+
+/// What the stored blob must still be when [`RemoteStore`] replaces it.
+#[derive(Debug)]
+enum Precondition {
+    /// Unchanged since the bridge issued this validator, or absent for `None`,
+    /// see [`ConnectionBridge::put_if_match`].
+    Validator(Option<String>),
+    /// At the version of the blob which replaces it, see [`RemoteStore::with_blob_versions`].
+    Version,
+}
+
+// a write which failed because another writer changed the blob first
+fn is_conflict(e: &crate::Error) -> bool {
+    e.kind() == crate::ErrorKind::Conflict
+        && e.context().map(crate::ErrorContext::operation) == Some(Operation::Put)
+}
+
+/// Characters of the space-padded offset in each storage record.
+pub const OFFSET_WIDTH: usize = 5;
+/// The last characters of each truncated digest, which are only compared to detect digests
+/// colliding in the characters before them, see [`RemoteStore::with_digest_length`].
+pub const DIGEST_CHECK_LENGTH: usize = 8;
+// the largest offset which fits in OFFSET_WIDTH characters
+pub(crate) const MAX_OFFSET: usize = 10usize.pow(OFFSET_WIDTH as u32) - 1;
+// the bytes of the first ranged read of a blob, which hold its version line and first record
+const RANGED_READ_HEAD: usize = 256;
+// the records of a ranged search which are read together, rather than halved again
+const RANGED_READ_WINDOW: usize = 8;
+
+/// Bytes of each storage record holding a full digest, 68.
+pub const RECORD_LENGTH: usize = record_length(STORAGE_DIGEST_LENGTH);
+
+/// Bytes of each storage record holding `digest_length` characters of its digest:
+/// "<digest> <offset>\n". Record `i` of a blob starts at byte `i * record_length(..)`.
+/// See [`RemoteStore::with_digest_length`].
+pub const fn record_length(digest_length: usize) -> usize {
+    digest_length + 1 + OFFSET_WIDTH + 1
+}
+
+/// The state of a storage record, written as the character between its digest and offset:
+/// "<digest> <offset>" for a live record, "<digest>-<offset>" for a tombstone and
+/// "<digest>+<offset>" for a compacted tombstone. Blobs written before tombstones were
+/// introduced only hold live records.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum RecordFlag {
+    /// The digest is assigned its offset.
+    #[default]
+    Live,
+    /// The digest was deleted, see [`RemoteStore::delete`]. Its offset is never assigned again.
+    Tombstone,
+    /// A tombstone which was kept by [`RemoteStore::compact`] because it holds the largest
+    /// offset of any tombstone. Offsets below it which are unassigned were held by the
+    /// tombstones which compaction dropped.
+    Compacted,
+}
+
+impl RecordFlag {
+    /// The character between the digest and offset of a record with this flag.
+    pub const fn separator(self) -> u8 {
+        match self {
+            Self::Live => b' ',
+            Self::Tombstone => b'-',
+            Self::Compacted => b'+',
+        }
+    }
+
+    /// The flag of a record whose digest and offset are separated by `separator`.
+    pub fn from_separator(separator: u8) -> Option<Self> {
+        [Self::Live, Self::Tombstone, Self::Compacted]
+            .into_iter()
+            .find(|flag| flag.separator() == separator)
+    }
+
+    /// True for both kinds of tombstone.
+    pub fn is_deleted(self) -> bool {
+        self != Self::Live
+    }
+
+    /// The name of this flag in other blob formats: "live", "tombstone" or "compacted".
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Live => "live",
+            Self::Tombstone => "tombstone",
+            Self::Compacted => "compacted",
+        }
+    }
+}
+
+impl std::str::FromStr for RecordFlag {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Self::Live, Self::Tombstone, Self::Compacted]
+            .into_iter()
+            .find(|flag| flag.as_str() == s)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unknown record flag {s}"),
+                )
+            })
+    }
+}
+
+/// A text record of `digest`, without checking that it is valid.
+pub(crate) fn text_record(digest: &str, flag: RecordFlag, offset: usize) -> String {
+    let separator = flag.separator() as char;
+    format!("{digest}{separator}{offset:>OFFSET_WIDTH$}\n")
+}
+
+/// Begins the first line of a text blob which counts its changes, "version <n>\n",
+/// see [`RemoteStore::with_blob_versions`].
+pub(crate) const VERSION_PREFIX: &str = "version ";
+
+/// The first line of a text blob at `version`.
+pub(crate) fn version_line(version: u64) -> String {
+    format!("{VERSION_PREFIX}{version}\n")
+}
+
+/// The version of the text `blob` and the length of the lines before its records, its format
+/// line and version line. The version is 0 for blobs without a version line, and the length
+/// is 0 for blobs without either line, see [`RemoteStore::with_blob_versions`].
+pub(crate) fn split_version(blob: &[u8]) -> std::io::Result<(u64, usize)> {
+    let (format, format_length) = split_format(blob)?;
+    let Some(rest) = blob[format_length..].strip_prefix(VERSION_PREFIX.as_bytes()) else {
+        return Ok((0, format_length));
+    };
+    let version = rest.iter().position(|&b| b == b'\n').and_then(|newline| {
+        let version = std::str::from_utf8(&rest[..newline]).ok()?.parse().ok()?;
+        Some((version, format_length + VERSION_PREFIX.len() + newline + 1))
+    });
+    let line = usize::from(format > 0);
+    version.ok_or_else(|| malformed(line, "storage blob has an invalid version line".into()))
+}
+
+/// The format version of the text blobs which this release writes, and the latest which it
+/// reads, see [`RemoteStore::with_format_header`].
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Begins the first line of a text blob which names its format, "perfume/<n>\n",
+/// see [`RemoteStore::with_format_header`].
+pub(crate) const FORMAT_PREFIX: &str = "perfume/";
+
+/// The first line of a text blob of [`FORMAT_VERSION`].
+pub(crate) fn format_line() -> String {
+    format!("{FORMAT_PREFIX}{FORMAT_VERSION}\n")
+}
+
+/// The format version of the text `blob` and the length of its format line, which are both 0
+/// for blobs without one. Blobs of a later format than [`FORMAT_VERSION`] are an error of kind
+/// [`std::io::ErrorKind::Unsupported`], since they are not corrupt.
+pub(crate) fn split_format(blob: &[u8]) -> std::io::Result<(u32, usize)> {
+    let Some(rest) = blob.strip_prefix(FORMAT_PREFIX.as_bytes()) else {
+        return Ok((0, 0));
+    };
+    let format = rest.iter().position(|&b| b == b'\n').and_then(|newline| {
+        let format = std::str::from_utf8(&rest[..newline])
+            .ok()?
+            .parse::<u32>()
+            .ok()?;
+        Some((format, FORMAT_PREFIX.len() + newline + 1))
+    });
+    match format {
+        Some((format, length)) if (1..=FORMAT_VERSION).contains(&format) => Ok((format, length)),
+        Some((format, _length)) => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("storage blob is of format {format}, later than {FORMAT_VERSION}"),
+        )),
+        None => Err(malformed(
+            0,
+            "storage blob has an invalid format line".into(),
+        )),
+    }
+}
+
+/// Copy of the text `blob` in the format of [`FORMAT_VERSION`], beginning with its format
+/// line, see [`RemoteStore::with_format_header`]. Blobs of earlier formats are rewritten
+/// without changing the offsets of their digests, and so the names of their identities.
+pub fn migrate_blob(blob: &[u8]) -> std::io::Result<Bytes> {
+    match split_format(blob)? {
+        (FORMAT_VERSION, _length) => Ok(Bytes::copy_from_slice(blob)),
+        // format 0 differs only by its missing format line
+        (_format, length) => {
+            Records::new(blob)?;
+            Ok(Bytes::from(
+                [format_line().as_bytes(), &blob[length..]].concat(),
+            ))
+        }
+    }
+}
+
+/// Split a text record, without its newline, into its digest, flag and offset.
+/// The digest is not checked beyond being made of lowercase hex characters.
+pub(crate) fn parse_record(line: &str) -> Option<(&str, RecordFlag, usize)> {
+    let split = line
+        .bytes()
+        .position(|b| !matches!(b, b'0'..=b'9' | b'a'..=b'f'))?;
+    let flag = RecordFlag::from_separator(line.as_bytes()[split])?;
+    let offset = line[split + 1..].trim_start().parse().ok()?;
+    Some((&line[..split], flag, offset))
+}
+
+/// The offset and flag of `digest` in the text `blob`, or the next offset of the blob.
+pub(crate) fn search_blob(
+    blob: &[u8],
+    digest: &str,
+) -> std::io::Result<Result<(usize, RecordFlag), usize>> {
+    let records = Records::new(blob)?;
+    match records.search(digest.as_bytes()) {
+        Ok(found_at) => Ok(Ok((records.offset(found_at)?, records.flag(found_at)?))),
+        Err(_) => records.next_offset().map(Err),
+    }
+}
+
+/// Copy of the text `blob` with a live record of `digest` at `offset`, in sorted order.
+/// The digest is shortened to the length of the records of the blob, and the version of the
+/// blob is incremented if it has one, see [`RemoteStore::with_blob_versions`].
+#[cfg_attr(not(feature = "mmap"), allow(dead_code))]
+pub(crate) fn insert_record(blob: &[u8], digest: &str, offset: usize) -> std::io::Result<Bytes> {
+    let records = Records::new(blob)?;
+    let digest = &digest[..records.digest_length.min(digest.len())];
+    let insert_at = records
+        .search(digest.as_bytes())
+        .unwrap_or_else(|found_at| found_at);
+    let record = text_record(digest, RecordFlag::Live, offset);
+    let inserted = records.insert(insert_at, record.as_bytes());
+    Records::new(&inserted)?.check_collision(insert_at)?;
+    let (_format, format_length) = split_format(&inserted)?;
+    match split_version(&inserted)? {
+        (_version, length) if length == format_length => Ok(inserted),
+        (version, length) => {
+            let next_version = version_line(version + 1);
+            Ok(Bytes::from(
+                [
+                    &inserted[..format_length],
+                    next_version.as_bytes(),
+                    &inserted[length..],
+                ]
+                .concat(),
+            ))
+        }
+    }
+}
+
+/// The number of lines of `preamble`, the format and version lines which precede the records
+/// of a blob.
+pub(crate) fn preamble_lines(preamble: &[u8]) -> usize {
+    preamble.iter().filter(|&&b| b == b'\n').count()
+}
+
+/// A view of a storage blob as fixed length records, which are searched without copying.
+pub(crate) struct Records<'b> {
+    // the format and version lines, if the blob has them
+    header: &'b [u8],
+    // the records which follow it
+    blob: &'b [u8],
+    digest_length: usize,
+}
+
+impl<'b> Records<'b> {
+    /// The record length is read from the first record, see [`RemoteStore::with_digest_length`].
+    /// Format and version lines before the records are kept by copies of the blob, see
+    /// [`RemoteStore::with_format_header`] and [`RemoteStore::with_blob_versions`].
+    pub(crate) fn new(blob: &'b [u8]) -> std::io::Result<Self> {
+        let (_version, version_length) = split_version(blob)?;
+        let (header, blob) = blob.split_at(version_length);
+        let stride = match blob.iter().position(|&b| b == b'\n') {
+            Some(newline) => newline + 1,
+            None if blob.is_empty() => RECORD_LENGTH,
+            None => 0,
+        };
+        let digest_length = stride.saturating_sub(record_length(0));
+        let digest_lengths = MIN_STORAGE_DIGEST_LENGTH..=STORAGE_DIGEST_LENGTH;
+        if !digest_lengths.contains(&digest_length) || !blob.len().is_multiple_of(stride) {
+            // either the first record is malformed, or the last was truncated
+            let line = match digest_lengths.contains(&digest_length) {
+                true => blob.len() / stride,
+                false => 0,
+            } + preamble_lines(header);
+            return Err(malformed(
+                line,
+                format!(
+                    "storage blob of {} bytes is not made of {stride} byte records",
+                    blob.len()
+                ),
+            ));
+        }
+        Ok(Self {
+            header,
+            blob,
+            digest_length,
+        })
+    }
+
+    fn record_length(&self) -> usize {
+        record_length(self.digest_length)
+    }
+
+    // the line of the blob holding the record at `index`
+    fn line(&self, index: usize) -> usize {
+        index + preamble_lines(self.header)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.blob.len() / self.record_length()
+    }
+
+    fn digest(&self, index: usize) -> &'b [u8] {
+        let start = index * self.record_length();
+        &self.blob[start..start + self.digest_length]
+    }
+
+    // the characters of each digest which must be distinct, see DIGEST_CHECK_LENGTH
+    fn prefix_length(&self) -> usize {
+        match self.digest_length {
+            STORAGE_DIGEST_LENGTH => STORAGE_DIGEST_LENGTH,
+            truncated => truncated - DIGEST_CHECK_LENGTH,
+        }
+    }
+
+    /// An error of kind [`std::io::ErrorKind::InvalidInput`] if the digest of the record at
+    /// `index`, which was just inserted, collides with the digest of a neighbouring record.
+    /// Only truncated digests can collide, see [`RemoteStore::with_digest_length`].
+    fn check_collision(&self, index: usize) -> std::io::Result<()> {
+        let prefix_length = self.prefix_length();
+        let prefix = &self.digest(index)[..prefix_length];
+        let collides = [index.checked_sub(1), Some(index + 1)]
+            .into_iter()
+            .flatten()
+            .filter(|&neighbour| neighbour < self.len())
+            .any(|neighbour| &self.digest(neighbour)[..prefix_length] == prefix);
+        match collides {
+            false => Ok(()),
+            true => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "digest collides with a stored digest in its first {prefix_length} \
+                     characters, the blob should hold longer digests"
+                ),
+            )),
+        }
+    }
+
+    fn flag(&self, index: usize) -> std::io::Result<RecordFlag> {
+        let separator = self.blob[index * self.record_length() + self.digest_length];
+        RecordFlag::from_separator(separator).ok_or_else(|| {
+            malformed(
+                self.line(index),
+                "storage record has an invalid flag".into(),
+            )
+        })
+    }
+
+    /// One more than the largest offset, which is the number of records unless tombstones
+    /// were compacted.
+    fn next_offset(&self) -> std::io::Result<usize> {
+        let mut next = self.len();
+        for index in 0..self.len() {
+            next = next.max(self.offset(index)? + 1);
+        }
+        Ok(next)
+    }
+
+    /// Copy of the blob with the record at `index` flagged as `flag`.
+    fn flagged(&self, index: usize, flag: RecordFlag) -> Bytes {
+        let mut blob = BytesMut::with_capacity(self.header.len() + self.blob.len());
+        blob.extend_from_slice(self.header);
+        blob.extend_from_slice(self.blob);
+        blob[self.header.len() + index * self.record_length() + self.digest_length] =
+            flag.separator();
+        blob.freeze()
+    }
+
+    fn offset(&self, index: usize) -> std::io::Result<usize> {
+        let start = index * self.record_length() + self.digest_length + 1;
+        std::str::from_utf8(&self.blob[start..start + OFFSET_WIDTH])
+            .ok()
+            .and_then(|s| s.trim_start().parse().ok())
+            .ok_or_else(|| {
+                malformed(
+                    self.line(index),
+                    "storage record has an invalid offset".into(),
+                )
+            })
+    }
+
+    /// Copy of the blob without the record at `index`.
+    fn removed(&self, index: usize) -> Bytes {
+        let (prefix, suffix) = self.blob.split_at(index * self.record_length());
+        let mut blob = BytesMut::with_capacity(self.header.len() + self.blob.len());
+        blob.extend_from_slice(self.header);
+        blob.extend_from_slice(prefix);
+        blob.extend_from_slice(&suffix[self.record_length()..]);
+        blob.freeze()
+    }
+
+    /// Copy of the blob with `record` inserted at `index`.
+    fn insert(&self, index: usize, record: &[u8]) -> Bytes {
+        let (prefix, suffix) = self.blob.split_at(index * self.record_length());
+        let mut blob = BytesMut::with_capacity(self.header.len() + self.blob.len() + record.len());
+        blob.extend_from_slice(self.header);
+        blob.extend_from_slice(prefix);
+        blob.extend_from_slice(record);
+        blob.extend_from_slice(suffix);
+        blob.freeze()
+    }
+
+    /// Binary search for `digest`, with the same result as [`slice::binary_search`].
+    /// Only as much of `digest` as the records hold is compared.
+    fn search(&self, digest: &[u8]) -> Result<usize, usize> {
+        let digest = &digest[..self.digest_length.min(digest.len())];
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.digest(mid).cmp(digest) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Ok(mid),
+            }
+        }
+        Err(low)
+    }
+}
+
+/// Searches for a digest in a blob which is received in chunks, holding at most one record.
+/// Records are sorted, so the search stops at the first record which is not smaller.
+struct RecordScanner<'d> {
+    digest: &'d [u8],
+    record: Vec<u8>,
+    // known after the first newline
+    stride: Option<usize>,
+    // records which were passed over
+    skipped: usize,
+    // the blob began with a format line, see RemoteStore::with_format_header
+    formatted: bool,
+    // and a version line, see RemoteStore::with_blob_versions
+    versioned: bool,
+    result: std::io::Result<Option<(usize, RecordFlag)>>,
+}
+
+impl<'d> RecordScanner<'d> {
+    fn new(digest: &'d [u8]) -> Self {
+        Self {
+            digest,
+            record: Vec::with_capacity(RECORD_LENGTH),
+            stride: None,
+            skipped: 0,
+            formatted: false,
+            versioned: false,
+            result: Ok(None),
+        }
+    }
+
+    fn visit(&mut self, mut chunk: &[u8]) -> ControlFlow<()> {
+        while !chunk.is_empty() {
+            let wanted = match self.stride {
+                Some(stride) => stride - self.record.len(),
+                None => chunk
+                    .iter()
+                    .position(|&b| b == b'\n')
+                    .map_or(chunk.len(), |newline| newline + 1),
+            };
+            let (received, rest) = chunk.split_at(wanted.min(chunk.len()));
+            self.record.extend_from_slice(received);
+            chunk = rest;
+
+            if self.stride.is_none() && self.record.ends_with(b"\n") {
+                // the format line precedes the version line
+                let first_line = !self.formatted && !self.versioned;
+                if first_line && self.record.starts_with(FORMAT_PREFIX.as_bytes()) {
+                    if let Err(e) = split_format(&self.record) {
+                        self.result = Err(e);
+                        return ControlFlow::Break(());
+                    }
+                    self.formatted = true;
+                    self.record.clear();
+                    continue;
+                }
+                // and the version line precedes the first record
+                if !self.versioned && self.record.starts_with(VERSION_PREFIX.as_bytes()) {
+                    if let Err(e) = split_version(&self.record) {
+                        self.result = Err(e);
+                        return ControlFlow::Break(());
+                    }
+                    self.versioned = true;
+                    self.record.clear();
+                    continue;
+                }
+                self.stride = Some(self.record.len());
+            }
+            if self.stride != Some(self.record.len()) {
+                if self.record.len() > RECORD_LENGTH {
+                    let line =
+                        self.skipped + usize::from(self.formatted) + usize::from(self.versioned);
+                    self.result = Err(malformed(line, "storage record is too long".into()));
+                    return ControlFlow::Break(());
+                }
+                continue;
+            }
+
+            let line = self.skipped + usize::from(self.formatted) + usize::from(self.versioned);
+            let records = match Records::new(&self.record) {
+                Ok(records) => records,
+                Err(e) => {
+                    self.result = Err(malformed(line, e.to_string()));
+                    return ControlFlow::Break(());
+                }
+            };
+            match records.search(self.digest) {
+                Ok(found_at) => {
+                    self.result = records
+                        .offset(found_at)
+                        .and_then(|offset| Ok(Some((offset, records.flag(found_at)?))))
+                        .map_err(|e| malformed(line, e.to_string()));
+                    return ControlFlow::Break(());
+                }
+                // the digest would be inserted before this record
+                Err(0) => return ControlFlow::Break(()),
+                Err(_) => {
+                    self.record.clear();
+                    self.skipped += 1;
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// The offset and flag of the digest, if it was found.
+    fn finish(self) -> std::io::Result<Option<(usize, RecordFlag)>> {
+        self.result
+    }
+}
+
+/// Describes the first malformed record of a storage blob, see [`crate::Error::CorruptBlob`].
+#[derive(Debug)]
+pub(crate) struct MalformedLine {
+    pub(crate) line: usize,
+    message: String,
+}
+
+impl std::fmt::Display for MalformedLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for MalformedLine {}
+
+pub(crate) fn deleted_digest() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, "digest was deleted")
+}
+
+pub(crate) fn malformed(line: usize, message: String) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        MalformedLine { line, message },
+    )
+}
+
+/// Rewrite `blob` so that each digest holds only its first `digest_length` characters,
+/// or as many more as needed to keep every digest distinct before its last
+/// [`DIGEST_CHECK_LENGTH`] characters. Offsets are unchanged.
+/// Blobs which already hold `digest_length` characters or fewer are returned unchanged.
+/// See [`RemoteStore::with_digest_length`].
+pub fn narrow_blob(blob: &[u8], digest_length: usize) -> std::io::Result<Bytes> {
+    let records = Records::new(blob)?;
+    // digests are sorted, so the longest shared prefix is between neighbours
+    let shared_prefix = (1..records.len())
+        .map(|i| {
+            let (previous, digest) = (records.digest(i - 1), records.digest(i));
+            previous
+                .iter()
+                .zip(digest)
+                .take_while(|(a, b)| a == b)
+                .count()
+        })
+        .max()
+        .unwrap_or(0);
+    let digest_length = digest_length
+        .max(MIN_STORAGE_DIGEST_LENGTH)
+        .max(shared_prefix + 1 + DIGEST_CHECK_LENGTH);
+    if digest_length >= records.digest_length {
+        return Ok(Bytes::copy_from_slice(blob));
+    }
+
+    let mut narrowed = BytesMut::with_capacity(
+        records.header.len() + records.len() * record_length(digest_length),
+    );
+    narrowed.extend_from_slice(records.header);
+    for index in 0..records.len() {
+        let record =
+            &records.blob[index * records.record_length()..(index + 1) * records.record_length()];
+        narrowed.extend_from_slice(&record[..digest_length]);
+        narrowed.extend_from_slice(&record[records.digest_length..]);
+    }
+    Ok(narrowed.freeze())
+}
+
+/// Merge the changes which a writer made to `base`, giving `ours`, into `theirs`, which another
+/// writer stored in the meantime, such as after a conflicting [`ConnectionBridge::put_if_match`].
+/// The records of `theirs` keep their offsets. Digests which only `ours` inserted are added with
+/// the next offsets of `theirs`, in the order of their offsets in `ours`, and records which only
+/// `ours` flagged, such as by [`RemoteStore::delete`], are flagged the same way. The version line
+/// of `theirs` is kept. The digests of `ours` are shortened to the length of the digests of
+/// `theirs`, which fails with [`std::io::ErrorKind::InvalidInput`] if they are shorter.
+pub fn merge_blobs(base: &[u8], ours: &[u8], theirs: &[u8]) -> std::io::Result<Bytes> {
+    let (base, ours, theirs) = (
+        Records::new(base)?,
+        Records::new(ours)?,
+        Records::new(theirs)?,
+    );
+    let digest_length = match theirs.len() {
+        0 => ours.digest_length,
+        _ => theirs.digest_length,
+    };
+    let mut merged = std::collections::BTreeMap::new();
+    for index in 0..theirs.len() {
+        let flag_offset = (theirs.flag(index)?, theirs.offset(index)?);
+        merged.insert(theirs.digest(index), flag_offset);
+    }
+
+    let mut inserted = vec![];
+    for index in 0..ours.len() {
+        let digest = ours.digest(index);
+        let flag = ours.flag(index)?;
+        let base_flag = match base.search(digest) {
+            Ok(found_at) => Some(base.flag(found_at)?),
+            Err(_) => None,
+        };
+        let Some(digest) = digest.get(..digest_length) else {
+            let message = format!(
+                "digests of {} characters can not be merged into a blob of {digest_length}",
+                digest.len()
+            );
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                message,
+            ));
+        };
+        match (base_flag, merged.get_mut(digest)) {
+            (None, None) => inserted.push((ours.offset(index)?, digest, flag)),
+            (Some(base_flag), Some((their_flag, _))) if *their_flag == base_flag => {
+                *their_flag = flag
+            }
+            // either both inserted it, or `theirs` changed or dropped it
+            _ => {}
+        }
+    }
+    inserted.sort_by_key(|(offset, _, _)| *offset);
+    for (offset, (_offset, digest, flag)) in (theirs.next_offset()?..).zip(inserted) {
+        merged.insert(digest, (flag, offset));
+    }
+
+    let mut blob = BytesMut::with_capacity(theirs.header.len() + merged.len() * RECORD_LENGTH);
+    blob.extend_from_slice(theirs.header);
+    for (digest, (flag, offset)) in merged {
+        let digest = String::from_utf8_lossy(digest);
+        blob.extend_from_slice(text_record(&digest, flag, offset).as_bytes());
+    }
+    Ok(blob.freeze())
+}
+
+/// Rewrite `blob` without its tombstones, except the one holding the largest offset, which
+/// becomes [`RecordFlag::Compacted`] so that no offset below it is assigned again.
+/// Blobs without tombstones are returned unchanged. See [`RemoteStore::compact`].
+pub fn compact_blob(blob: &[u8]) -> std::io::Result<Bytes> {
+    let records = Records::new(blob)?;
+    let mut last_tombstone: Option<(usize, usize)> = None;
+    for index in 0..records.len() {
+        let offset = records.offset(index)?;
+        if records.flag(index)?.is_deleted() && last_tombstone.is_none_or(|(_, o)| o < offset) {
+            last_tombstone = Some((index, offset));
+        }
+    }
+    let Some((kept, _offset)) = last_tombstone else {
+        return Ok(Bytes::copy_from_slice(blob));
+    };
+
+    let mut compacted = BytesMut::with_capacity(blob.len());
+    compacted.extend_from_slice(records.header);
+    for index in 0..records.len() {
+        if index != kept && records.flag(index)?.is_deleted() {
+            continue;
+        }
+        let start = index * records.record_length();
+        compacted.extend_from_slice(&records.blob[start..start + records.record_length()]);
+        if index == kept {
+            // the separator precedes the offset and newline
+            let separator = compacted.len() - OFFSET_WIDTH - 2;
+            compacted[separator] = RecordFlag::Compacted.separator();
+        }
+    }
+    Ok(compacted.freeze())
+}
+
+/// Sorted pairs of (digest prefix, offset) of the live records of a storage blob.
+/// A 64 bit prefix is unique within a blob, unless it contains billions of digests.
+#[derive(Debug, Clone)]
+struct OffsetIndex(Vec<(u64, u32)>);
+
+impl OffsetIndex {
+    fn new(blob: &[u8]) -> std::io::Result<Self> {
+        let records = Records::new(blob)?;
+        let mut entries = Vec::with_capacity(records.len());
+        for index in 0..records.len() {
+            // deleted digests are looked up in their blob, which reports them
+            if records.flag(index)?.is_deleted() {
+                continue;
+            }
+            let prefix = digest_prefix(records.digest(index))
+                .ok_or_else(|| malformed(index, "storage record has an invalid digest".into()))?;
+            entries.push((prefix, records.offset(index)? as u32));
+        }
+        Ok(Self(entries))
+    }
+
+    fn get(&self, digest: &str) -> Option<usize> {
+        let prefix = digest_prefix(digest.as_bytes())?;
+        let found_at = self.0.binary_search_by_key(&prefix, |(p, _)| *p).ok()?;
+        Some(self.0[found_at].1 as usize)
+    }
+}
+
+fn digest_prefix(digest: &[u8]) -> Option<u64> {
+    let prefix = std::str::from_utf8(digest.get(..16)?).ok()?;
+    u64::from_str_radix(prefix, 16).ok()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    /*
+    cargo run -F codegen
+    cargo test storage -- --nocapture
+    */
+
+    use async_generic::async_generic;
+
+    use super::*;
+    use crate::identity::{Identity, Population, tests::*};
+    use crate::{Error, STORAGE_DIGEST_LENGTH};
+
+    #[test]
+    fn test_records_search() {
+        let blob = ["1", "5", "9"]
+            .iter()
+            .enumerate()
+            .map(|(offset, d)| format!("{} {offset:>5}\n", d.repeat(STORAGE_DIGEST_LENGTH)))
+            .collect::<String>();
+        let records = Records::new(blob.as_bytes()).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records.search("5".repeat(61).as_bytes()), Ok(1));
+        assert_eq!(records.offset(1).unwrap(), 1);
+        assert_eq!(records.search("0".repeat(61).as_bytes()), Err(0));
+        assert_eq!(records.search("7".repeat(61).as_bytes()), Err(2));
+        assert_eq!(records.search("a".repeat(61).as_bytes()), Err(3));
+        assert!(Records::new(b"truncated").is_err());
+
+        let record = format!("{}     3\n", "7".repeat(STORAGE_DIGEST_LENGTH));
+        let inserted = records.insert(2, record.as_bytes());
+        let inserted = Records::new(&inserted).unwrap();
+        assert_eq!(inserted.len(), 4);
+        assert_eq!(inserted.search("7".repeat(61).as_bytes()), Ok(2));
+        assert_eq!(inserted.search("9".repeat(61).as_bytes()), Ok(3));
+    }
+
+    #[test]
+    fn test_narrow_blob() -> Result<(), Error> {
+        let blob = ["0123456789abcdef0", "0123456789abcdef1", "f"]
+            .iter()
+            .enumerate()
+            .map(|(offset, d)| format!("{d:0<61} {offset:>5}\n"))
+            .collect::<String>();
+
+        // escalated to keep the first two digests distinct before their check characters
+        let narrowed = narrow_blob(blob.as_bytes(), MIN_STORAGE_DIGEST_LENGTH)?;
+        let records = Records::new(&narrowed)?;
+        assert_eq!(records.digest_length, 17 + DIGEST_CHECK_LENGTH);
+        assert_eq!(records.len(), 3);
+        assert_eq!(records.search(format!("{:0<61}", "f").as_bytes()), Ok(2));
+        assert_eq!(records.offset(1)?, 1);
+        assert_eq!(narrow_blob(&narrowed, 30)?, narrowed);
+
+        let mut store = RemoteStore::new(MockBridge::default()).with_digest_length(30);
+        let storage: Storage = format!("000{:0<61}", "f").parse()?;
+        assert_eq!(store.digest_offset("br", &storage)?, 0);
+        let blob = store.bridge.get("000")?.unwrap();
+        assert_eq!(blob.len(), record_length(30));
+        assert_eq!(next_stored_offset(&storage, &mut store)?, 1);
+        assert_eq!(store.digest_offset("br", &storage)?, 0);
+
+        // digests which share the characters before their check characters collide
+        let colliding: Storage = format!("000{:0<61}", "f0000000000000000000001").parse()?;
+        let error = store.digest_offset("br", &colliding).unwrap_err();
+        assert_eq!(error.kind(), crate::ErrorKind::InvalidInput);
+        let error = store.digest_offsets("br", &[colliding]).unwrap_err();
+        assert_eq!(error.kind(), crate::ErrorKind::InvalidInput);
+        let distinct: Storage = format!("000{:0<61}", "f000000000000000000001").parse()?;
+        assert_eq!(store.digest_offset("br", &distinct)?, 2);
+        assert_eq!(store.digest_offsets("br", &[storage, distinct])?, [0, 2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_blobs() -> Result<(), Error> {
+        let blob = |header: &str, records: &[(&str, RecordFlag, usize)]| {
+            let records = records
+                .iter()
+                .map(|(d, flag, offset)| text_record(&format!("{d:0<61}"), *flag, *offset));
+            Bytes::from(
+                std::iter::once(header.to_string())
+                    .chain(records)
+                    .collect::<String>(),
+            )
+        };
+        use RecordFlag::{Live, Tombstone};
+        let base = blob("version 1\n", &[("a", Live, 0), ("c", Live, 1)]);
+        // deletes a, and inserts b and d
+        let ours = blob(
+            "version 1\n",
+            &[
+                ("a", Tombstone, 0),
+                ("b", Live, 3),
+                ("c", Live, 1),
+                ("d", Live, 2),
+            ],
+        );
+        // inserts d and e, and deletes c
+        let theirs = blob(
+            "version 2\n",
+            &[
+                ("a", Live, 0),
+                ("c", Tombstone, 1),
+                ("d", Live, 2),
+                ("e", Live, 3),
+            ],
+        );
+        let merged = merge_blobs(&base, &ours, &theirs)?;
+        let expected = blob(
+            "version 2\n",
+            &[
+                ("a", Tombstone, 0),
+                ("b", Live, 4),
+                ("c", Tombstone, 1),
+                ("d", Live, 2),
+                ("e", Live, 3),
+            ],
+        );
+        assert_eq!(merged, expected);
+        assert_eq!(merge_blobs(&base, &base, &theirs)?, theirs);
+        assert_eq!(
+            merge_blobs(&[], &ours, &[])?,
+            blob(
+                "",
+                &[
+                    ("a", Tombstone, 0),
+                    ("b", Live, 3),
+                    ("c", Live, 1),
+                    ("d", Live, 2),
+                ]
+            )
+        );
+
+        // the digests of another writer can be shorter, but not longer
+        let narrowed = narrow_blob(&theirs, 20)?;
+        assert_eq!(
+            Records::new(&merge_blobs(&base, &ours, &narrowed)?)?.len(),
+            5
+        );
+        let error = merge_blobs(&narrowed, &narrow_blob(&ours, 20)?, &theirs).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    /// Delivers blobs in chunks which are not aligned with records.
+    #[derive(Default)]
+    struct ChunkingBridge {
+        inner: MockBridge,
+        delivered: std::sync::atomic::AtomicUsize,
+    }
+
+    impl ConnectionBridge for ChunkingBridge {
+        #[async_generic]
+        fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+            self.inner.get(key)
+        }
+        #[async_generic]
+        fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+            self.inner.put(key, body)
+        }
+        fn get_chunks(
+            &self,
+            key: &str,
+            visit: &mut dyn FnMut(&[u8]) -> ControlFlow<()>,
+        ) -> BridgeResult<bool> {
+            let Some(body) = self.inner.get(key)? else {
+                return Ok(false);
+            };
+            for chunk in body.chunks(10) {
+                self.delivered
+                    .fetch_add(chunk.len(), std::sync::atomic::Ordering::Relaxed);
+                if visit(chunk).is_break() {
+                    break;
+                }
+            }
+            Ok(true)
+        }
+        fn get_range(&self, key: &str, range: Range<u64>) -> BridgeResult<Option<(Bytes, u64)>> {
+            let Some(body) = self.inner.get(key)? else {
+                return Ok(None);
+            };
+            let bytes = slice_range(&body, &range);
+            self.delivered
+                .fetch_add(bytes.len(), std::sync::atomic::Ordering::Relaxed);
+            Ok(Some((bytes, body.len() as u64)))
+        }
+    }
+
+    #[test]
+    fn test_streaming() -> Result<(), Error> {
+        let mut store = RemoteStore::new(ChunkingBridge::default()).with_streaming();
+        let storages = ["1", "5", "9"].map(|d| {
+            let hex = format!("000{}", d.repeat(STORAGE_DIGEST_LENGTH));
+            Storage::try_from(hex.as_bytes()).unwrap()
+        });
+        for (offset, storage) in storages.iter().enumerate() {
+            assert_eq!(store.digest_offset("br", storage)?, offset);
+        }
+        let delivered = |store: &RemoteStore<ChunkingBridge>| {
+            let delivered = &store.bridge.delivered;
+            delivered.swap(0, std::sync::atomic::Ordering::Relaxed)
+        };
+        delivered(&store);
+
+        // the search stops in the chunk holding the record which was found
+        let chunks_of =
+            |records: usize| ((records * RECORD_LENGTH).div_ceil(10) * 10).min(3 * RECORD_LENGTH);
+        for (offset, storage) in storages.iter().enumerate() {
+            assert_eq!(store.digest_offset("br", storage)?, offset);
+            assert_eq!(delivered(&store), chunks_of(offset + 1));
+        }
+
+        // new digests are inserted after searching
+        let storage: Storage = format!("000{}", "7".repeat(61)).parse()?;
+        assert_eq!(store.digest_offset("br", &storage)?, 3);
+        assert_eq!(delivered(&store), chunks_of(3));
+        assert_eq!(store.digest_offset("br", &storage)?, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ranged_reads() -> Result<(), Error> {
+        let storages = (0..100)
+            .map(|i| format!("000{i:02}{}", "0".repeat(59)).parse())
+            .collect::<Result<Vec<Storage>, _>>()?;
+        let mut store = RemoteStore::new(ChunkingBridge::default()).with_blob_versions();
+        store.digest_offsets("br", &storages)?;
+        store.delete("br", &storages[3])?;
+        let mut store = RemoteStore::new(std::mem::take(&mut store.bridge)).with_ranged_reads();
+        let delivered = |store: &RemoteStore<ChunkingBridge>| {
+            let delivered = &store.bridge.delivered;
+            delivered.swap(0, std::sync::atomic::Ordering::Relaxed)
+        };
+
+        // stored digests are found reading a few records, past the version line
+        for offset in [0, 1, 57, 98, 99] {
+            assert_eq!(store.digest_offset("br", &storages[offset])?, offset);
+            let bound = RANGED_READ_HEAD + 10 * RECORD_LENGTH;
+            assert!((1..=bound).contains(&delivered(&store)));
+        }
+        let error = store.digest_offset("br", &storages[3]).unwrap_err();
+        assert_eq!(error.kind(), crate::ErrorKind::NotFound);
+
+        // new digests are inserted after searching
+        let storage: Storage = format!("000{}", "a".repeat(61)).parse()?;
+        assert_eq!(store.digest_offset("br", &storage)?, 100);
+        assert_eq!(store.digest_offset("br", &storage)?, 100);
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct ValidatingBridge {
+        inner: MockBridge,
+        transfers: std::sync::atomic::AtomicUsize,
+        puts: std::sync::atomic::AtomicUsize,
+        // stored by another writer just before the next conditional put
+        rival: std::sync::Mutex<Option<Bytes>>,
+    }
+
+    impl ConnectionBridge for ValidatingBridge {
+        #[async_generic]
+        fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+            self.inner.get(key)
+        }
+        #[async_generic]
+        fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+            self.puts.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            self.inner.put(key, body)
+        }
+        #[async_generic]
+        fn put_if_match(
+            &self,
+            key: &str,
+            body: Bytes,
+            validator: Option<&str>,
+        ) -> BridgeResult<()> {
+            if let Some(rival) = self.rival.lock().unwrap().take() {
+                self.inner.put(key, rival)?;
+            }
+            let current = self.inner.get(key)?.map(|b| b.len().to_string());
+            if current.as_deref() != validator {
+                let message = "blob was modified by another writer";
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    message,
+                ));
+            }
+            self.put(key, body)
+        }
+        fn get_validated(&self, key: &str, validator: Option<&str>) -> BridgeResult<Validated> {
+            let body = self.inner.get(key)?;
+            // blobs only grow, so their length identifies their version
+            let current = body.as_ref().map(|b| b.len().to_string());
+            if validator.is_some() && validator == current.as_deref() {
+                return Ok(Validated::NotModified);
+            }
+            if body.is_some() {
+                self.transfers
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+            Ok(Validated::Modified {
+                body,
+                validator: current,
+            })
+        }
+    }
+
+    #[test]
+    fn test_blob_cache_revalidation() -> Result<(), Error> {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let mut store = RemoteStore::new(ValidatingBridge::default()).with_blob_cache(8);
+
+        // inserted, then transferred and cached, then revalidated
+        let first = brazilian.identity("f@r.br", &mut store)?;
+        assert_eq!(brazilian.identity("f@r.br", &mut store)?, first);
+        assert_eq!(brazilian.identity("f@r.br", &mut store)?, first);
+        let transfers = store
+            .bridge
+            .transfers
+            .load(std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(transfers, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflicting_insert() -> Result<(), Error> {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let mut store = RemoteStore::new(ValidatingBridge::default());
+        let user1 = brazilian.identity("f@r.br", &mut store)?;
+        let key = user1.storage.key.as_str();
+
+        // another writer assigns the next offset to its digest while the blob is being updated
+        let mut rival = user1.storage.clone();
+        rival.digest = random_hex_string::<STORAGE_DIGEST_LENGTH>();
+        let mut rival_store = RemoteStore::new(MockBridge::default());
+        rival_store
+            .bridge
+            .put(key, store.bridge.get(key)?.unwrap())?;
+        assert_eq!(rival_store.digest_offset("br", &rival)?, 1);
+        *store.bridge.rival.lock().unwrap() = rival_store.bridge.get(key)?;
+
+        // the insert is retried on the blob of the other writer
+        assert_eq!(
+            store.bridge.puts.load(std::sync::atomic::Ordering::Relaxed),
+            1
+        );
+        let mut next = user1.storage.clone();
+        next.digest = random_hex_string::<STORAGE_DIGEST_LENGTH>();
+        assert_eq!(store.digest_offset("br", &next)?, 2);
+        assert_eq!(store.digest_offset("br", &rival)?, 1);
+        assert_eq!(store.digest_offset("br", &user1.storage)?, 0);
+        assert_eq!(
+            store.bridge.puts.load(std::sync::atomic::Ordering::Relaxed),
+            2
+        );
+
+        Ok(())
+    }
+
+    // a bridge without validators, to which another writer stores `rival` just before the
+    // first line of a blob is read again
+    #[derive(Default)]
+    struct UnvalidatedBridge {
+        inner: MockBridge,
+        rival: std::sync::Mutex<Option<Bytes>>,
+        appends: std::sync::atomic::AtomicUsize,
+    }
+
+    impl ConnectionBridge for UnvalidatedBridge {
+        #[async_generic]
+        fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+            self.inner.get(key)
+        }
+        #[async_generic]
+        fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+            self.inner.put(key, body)
+        }
+        #[async_generic]
+        fn append(&self, key: &str, bytes: Bytes) -> BridgeResult<()> {
+            self.appends
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            self.inner.append(key, bytes)
+        }
+        fn get_chunks(
+            &self,
+            key: &str,
+            visit: &mut dyn FnMut(&[u8]) -> ControlFlow<()>,
+        ) -> BridgeResult<bool> {
+            if let Some(rival) = self.rival.lock().unwrap().take() {
+                self.inner.put(key, rival)?;
+            }
+            self.inner.get_chunks(key, visit)
+        }
+    }
+
+    #[test]
+    fn test_blob_versions() -> Result<(), Error> {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let mut store = RemoteStore::new(UnvalidatedBridge::default()).with_blob_versions();
+        let user1 = brazilian.identity("f@r.br", &mut store)?;
+        let key = user1.storage.key.as_str();
+        let blob = store.bridge.get(key)?.unwrap();
+        assert!(blob.starts_with(b"version 1\n"));
+        assert_eq!(brazilian.identity("f@r.br", &mut store)?, user1);
+
+        // another writer assigns the next offset while the blob is being updated,
+        // and counts its change without having enabled versions
+        let mut rival = user1.storage.clone();
+        rival.digest = random_hex_string::<STORAGE_DIGEST_LENGTH>();
+        let mut rival_store = RemoteStore::new(MockBridge::default());
+        rival_store.bridge.put(key, blob)?;
+        assert_eq!(rival_store.digest_offset("br", &rival)?, 1);
+        let rival_blob = rival_store.bridge.get(key)?.unwrap();
+        assert!(rival_blob.starts_with(b"version 2\n"));
+        *store.bridge.rival.lock().unwrap() = Some(rival_blob);
+
+        // the stale insert is retried on the blob of the other writer
+        let mut next = user1.storage.clone();
+        next.digest = random_hex_string::<STORAGE_DIGEST_LENGTH>();
+        assert_eq!(store.digest_offset("br", &next)?, 2);
+        assert_eq!(store.digest_offset("br", &rival)?, 1);
+        let blob = store.bridge.get(key)?.unwrap();
+        assert!(blob.starts_with(b"version 3\n"));
+        let check = check_blob(&blob);
+        assert_eq!(check.issues, vec![]);
+        assert_eq!(check.canonical, blob);
+
+        // streamed blobs skip the version line
+        let bridge = std::mem::take(&mut store.bridge.inner);
+        let mut streaming = RemoteStore::new(bridge).with_streaming();
+        assert_eq!(streaming.digest_offset("br", &rival)?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_header() -> Result<(), Error> {
+        let storage = |digit: &str| format!("abc{}", digit.repeat(61)).parse::<Storage>();
+        let (first, second) = (storage("1")?, storage("2")?);
+
+        // blobs of format 0 are read, and gain a format line when they change
+        let mut legacy = RemoteStore::new(MockBridge::default());
+        assert_eq!(legacy.digest_offset("br", &first)?, 0);
+        let blob = legacy.bridge.get("abc")?.unwrap();
+        assert_eq!(split_format(&blob)?, (0, 0));
+        let mut store = RemoteStore::new(UnvalidatedBridge::default())
+            .with_format_header()
+            .with_blob_versions();
+        store.bridge.put("abc", blob.clone())?;
+        assert_eq!(store.digest_offset("br", &first)?, 0);
+        assert_eq!(store.digest_offset("br", &second)?, 1);
+        let migrated = store.bridge.get("abc")?.unwrap();
+        assert!(migrated.starts_with(b"perfume/1\nversion 1\n"));
+        assert_eq!(check_blob(&migrated).canonical, migrated);
+        assert_eq!(migrate_blob(&blob)?, [&b"perfume/1\n"[..], &blob].concat());
+        assert_eq!(migrate_blob(&migrated)?, migrated);
+
+        // and are kept by stores which do not write them, when read whole or streamed
+        let bridge = std::mem::take(&mut store.bridge.inner);
+        let mut streaming = RemoteStore::new(bridge).with_streaming();
+        assert_eq!(streaming.digest_offset("br", &second)?, 1);
+        assert_eq!(streaming.digest_offset("br", &storage("3")?)?, 2);
+        let blob = streaming.bridge.get("abc")?.unwrap();
+        assert!(blob.starts_with(b"perfume/1\nversion 2\n"));
+
+        // blobs of a later format are not misread
+        let later = [&b"perfume/2\n"[..], &blob[b"perfume/1\n".len()..]].concat();
+        streaming.bridge.put("abc", later.clone().into())?;
+        let e = streaming.digest_offset("br", &first).unwrap_err();
+        assert!(e.to_string().contains("format 2"), "{e}");
+        assert_eq!(e.kind(), crate::ErrorKind::Unsupported);
+        let mut store = RemoteStore::new(MockBridge::default()).with_quarantine();
+        store.bridge.put("abc", later.clone().into())?;
+        let e = store.digest_offset("br", &first).unwrap_err();
+        assert_eq!(e.kind(), crate::ErrorKind::Unsupported);
+        assert!(migrate_blob(&later).is_err());
+
+        // and are neither quarantined nor repaired, since they are not corrupt
+        assert!(store.take_recoveries().is_empty());
+        let check = check_blob(&later);
+        assert_eq!(check.issues, vec![BlobIssue::UnsupportedFormat(2)]);
+        assert_eq!(check.canonical, later);
+        assert_eq!(store.fsck(true)?.len(), 1);
+        assert_eq!(store.bridge.get("abc")?.unwrap(), later);
+        Ok(())
+    }
+
+    #[test]
+    fn test_append() -> Result<(), Error> {
+        let storage = |digit: &str| format!("abc{}", digit.repeat(61)).parse::<Storage>();
+        let (first, middle, last) = (storage("0")?, storage("8")?, storage("f")?);
+        let appends = |store: &RemoteStore<UnvalidatedBridge>| {
+            store
+                .bridge
+                .appends
+                .load(std::sync::atomic::Ordering::Relaxed)
+        };
+
+        // only a record which sorts after every other one is appended
+        let mut store = RemoteStore::new(UnvalidatedBridge::default());
+        assert_eq!(store.digest_offset("br", &middle)?, 0);
+        assert_eq!(store.digest_offset("br", &last)?, 1);
+        assert_eq!(appends(&store), 1);
+        assert_eq!(store.digest_offset("br", &first)?, 2);
+        assert_eq!(appends(&store), 1);
+        let blob = store.bridge.get("abc")?.unwrap();
+        assert_eq!(check_blob(&blob).issues, vec![]);
+        assert_eq!(store.digest_offset("br", &last)?, 1);
+
+        // the version line of a blob is rewritten
+        let mut store = RemoteStore::new(UnvalidatedBridge::default()).with_blob_versions();
+        assert_eq!(store.digest_offset("br", &middle)?, 0);
+        assert_eq!(store.digest_offset("br", &last)?, 1);
+        assert_eq!(appends(&store), 0);
+
+        // as is a blob which gains a format line
+        let mut store = RemoteStore::new(UnvalidatedBridge::default());
+        assert_eq!(store.digest_offset("br", &middle)?, 0);
+        let mut store = RemoteStore::new(std::mem::take(&mut store.bridge)).with_format_header();
+        assert_eq!(store.digest_offset("br", &last)?, 1);
+        assert_eq!(appends(&store), 0);
+        let blob = store.bridge.get("abc")?.unwrap();
+        assert_eq!(split_format(&blob)?.0, FORMAT_VERSION);
+        assert_eq!(check_blob(&blob).issues, vec![]);
+        assert_eq!(store.digest_offset("br", &first)?, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_offset_index() -> Result<(), Error> {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let mut store = RemoteStore::new(ValidatingBridge::default()).with_offset_index(8);
+        let user1 = brazilian.identity("f@r.br", &mut store)?;
+        for i in 1..5 {
+            assert_eq!(next_stored_offset(&user1.storage, &mut store)?, i);
+        }
+
+        // fetched once, then served from the index
+        for _ in 0..3 {
+            assert_eq!(brazilian.identity("f@r.br", &mut store)?, user1);
+        }
+        let transfers = store
+            .bridge
+            .transfers
+            .load(std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(transfers, 5);
+
+        let index = OffsetIndex::new(&store.bridge.get(user1.storage.key.as_str())?.unwrap())?;
+        assert_eq!(index.get(user1.storage.digest.as_str()), Some(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bloom_filter_skips_fetch() -> Result<(), Error> {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let mut store = RemoteStore::new(ValidatingBridge::default()).with_bloom_filter(16, 0.01);
+        let user1 = brazilian.identity("f@r.br", &mut store)?;
+
+        // new digests for the same key are inserted into the blob that was last written
+        for i in 1..10 {
+            assert_eq!(next_stored_offset(&user1.storage, &mut store)?, i);
+        }
+        let transfers = store
+            .bridge
+            .transfers
+            .load(std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(transfers, 0);
+
+        // known digests are always fetched
+        assert_eq!(brazilian.identity("f@r.br", &mut store)?, user1);
+        let transfers = store
+            .bridge
+            .transfers
+            .load(std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(transfers, 1);
+
+        // keys assumed to be empty are written on condition that they still are
+        let storage = |digit: &str| format!("abc{}", digit.repeat(61)).parse::<Storage>();
+        let mut store = RemoteStore::new(ValidatingBridge::default())
+            .with_bloom_filter(16, 0.01)
+            .with_empty_keys()
+            .with_streaming();
+        let rival = text_record(&"1".repeat(61), RecordFlag::Live, 0);
+        *store.bridge.rival.lock().unwrap() = Some(Bytes::from(rival));
+        assert_eq!(store.digest_offset("br", &storage("2")?)?, 1);
+        assert_eq!(store.digest_offset("br", &storage("1")?)?, 0);
+        assert_eq!(store.digest_offset("br", &storage("3")?)?, 2);
+        // only the blob of the other writer was fetched, and new digests skip streamed searches
+        let transfers = store
+            .bridge
+            .transfers
+            .load(std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(transfers, 1);
+        let blob = store.bridge.get("abc")?.unwrap();
+        assert_eq!(Records::new(&blob)?.len(), 3);
+
+        // filters are limited by the memory budget, and keys whose filter was evicted are
+        // fetched rather than assumed to be empty
+        let budget = MemoryBudget::new(150);
+        let mut store = RemoteStore::new(MockBridge::default())
+            .with_bloom_filter(16, 0.01)
+            .with_empty_keys()
+            .with_memory_budget(&budget);
+        assert_eq!(store.digest_offset("br", &storage("1")?)?, 0);
+        let other = format!("def{}", "1".repeat(61)).parse::<Storage>()?;
+        assert_eq!(store.digest_offset("br", &other)?, 0);
+        assert_eq!(store.bloom_index.as_ref().unwrap().keys.len(), 1);
+        assert!(budget.used() <= budget.limit());
+        assert_eq!(store.digest_offset("br", &storage("2")?)?, 1);
+        drop(store);
+        assert_eq!(budget.used(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_behind() -> Result<(), Error> {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let puts = |store: &RemoteStore<ValidatingBridge>| {
+            store.bridge.puts.load(std::sync::atomic::Ordering::Relaxed)
+        };
+
+        // inserts within the window are written by flush
+        let mut store = RemoteStore::new(ValidatingBridge::default())
+            .with_write_behind(Duration::from_secs(3600));
+        let user1 = brazilian.identity("f@r.br", &mut store)?;
+        for i in 1..10 {
+            assert_eq!(next_stored_offset(&user1.storage, &mut store)?, i);
+        }
+        assert_eq!(brazilian.identity("f@r.br", &mut store)?, user1);
+        assert_eq!(puts(&store), 0);
+        store.flush()?;
+        assert_eq!(puts(&store), 1);
+        let blob = store.bridge.get(user1.storage.key.as_str())?.unwrap();
+        assert_eq!(Records::new(&blob)?.len(), 10);
+
+        // inserts after the window are written immediately
+        let mut store =
+            RemoteStore::new(ValidatingBridge::default()).with_write_behind(Duration::ZERO);
+        brazilian.identity("f@r.br", &mut store)?;
+        next_stored_offset(&user1.storage, &mut store)?;
+        assert_eq!(puts(&store), 2);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_write_behind_dropped_in_runtime() -> Result<(), Error> {
+        let bridge = crate::testing::SimulatedBridge::default();
+        let storage: Storage = format!("abc{}", "1".repeat(61)).parse()?;
+        let write_behind =
+            || RemoteStore::new(bridge.clone()).with_write_behind(Duration::from_secs(3600));
+
+        // pending blobs are not written by a blocking put within the runtime
+        let mut store = write_behind();
+        store.digest_offset_async("br", &storage).await?;
+        drop(store);
+        assert!(bridge.blobs().is_empty());
+
+        // so they must be flushed first
+        let mut store = write_behind();
+        store.digest_offset_async("br", &storage).await?;
+        store.flush_async().await?;
+        drop(store);
+        assert_eq!(bridge.blobs().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_digest_offsets() -> Result<(), Error> {
+        let storage = |key: &str, digit: &str| format!("{key}{}", digit.repeat(61)).parse();
+        let storages: Vec<Storage> = vec![
+            storage("abc", "8")?,
+            storage("def", "1")?,
+            storage("abc", "2")?,
+            storage("abc", "8")?,
+            storage("def", "f")?,
+        ];
+        let puts = |store: &RemoteStore<ValidatingBridge>| {
+            store.bridge.puts.load(std::sync::atomic::Ordering::Relaxed)
+        };
+
+        // each blob is written once, with the offsets of single inserts
+        let mut store = RemoteStore::new(ValidatingBridge::default());
+        store.digest_offset("br", &storage("abc", "0")?)?;
+        let offsets = store.digest_offsets("br", &storages)?;
+        assert_eq!(offsets, vec![1, 0, 2, 1, 1]);
+        assert_eq!(puts(&store), 3);
+        let mut single = RemoteStore::new(ValidatingBridge::default());
+        single.digest_offset("br", &storage("abc", "0")?)?;
+        for (storage, offset) in storages.iter().zip(&offsets) {
+            assert_eq!(single.digest_offset("br", storage)?, *offset);
+        }
+
+        // stored digests are not written again
+        assert_eq!(store.digest_offsets("br", &storages)?, offsets);
+        assert_eq!(puts(&store), 3);
+        assert!(store.digest_offsets("br", &[])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage_keys() {
+        let keys: Vec<_> = storage_keys().collect();
+        assert_eq!(keys.len(), 4096);
+        assert_eq!(keys.first().unwrap().as_str(), "000");
+        assert_eq!(keys.last().unwrap().as_str(), "fff");
+        assert!(keys.windows(2).all(|w| w[0].as_str() < w[1].as_str()));
+    }
+
+    #[test]
+    fn test_storage_from_str() -> Result<(), Error> {
+        let hex = random_hex_string::<64>().to_string();
+        let storage: Storage = hex.parse()?;
+        assert_eq!(storage, Storage::try_from(hex.as_bytes())?);
+        assert!(Storage::try_from(&hex.as_bytes()[1..]).is_err());
+        assert_eq!(storage.to_string(), hex);
+        assert!(hex[1..].parse::<Storage>().is_err());
+        assert!("".parse::<Storage>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_context() -> Result<(), Error> {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let storage = brazilian.storage_object("f@r.br");
+        let key = storage.key.as_str();
+        let mut store = RemoteStore::new(MockBridge::default());
+        store.bridge.put(key, Bytes::from_static(b"corrupt\n"))?;
+
+        let error = brazilian.identity("f@r.br", &mut store).unwrap_err();
+        assert_eq!(error.kind(), crate::ErrorKind::Corrupt);
+        let context = error.context().unwrap();
+        assert_eq!(context.domain(), Some("br"));
+        assert_eq!(context.key(), Some(key));
+        assert_eq!(context.operation(), Operation::Parse);
+        assert_eq!(context.attempt(), 1);
+        assert!(
+            error
+                .to_string()
+                .contains(&format!("parse of blob {key} in domain br"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_blob() -> Result<(), Error> {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let storage = brazilian.storage_object("f@r.br");
+        let key = storage.key.as_str();
+        let record = |digest: &str, offset: &str| format!("{digest} {offset:>OFFSET_WIDTH$}\n");
+        let (zeros, fs) = ("0".repeat(61), "f".repeat(61));
+        let digest = storage.digest.as_str();
+        let blobs = [
+            (record(&zeros, "0") + &record(digest, "1")[..30], 1),
+            (
+                record(&zeros, "0") + &record(digest, "x") + &record(&fs, "2"),
+                1,
+            ),
+            ("\n".repeat(4), 0),
+        ];
+        for streaming in [false, true] {
+            for (blob, expected_line) in &blobs {
+                let mut store = RemoteStore::new(MockBridge::default());
+                if streaming {
+                    store = store.with_streaming();
+                }
+                store.bridge.put(key, Bytes::from(blob.clone()))?;
+                let error = brazilian.identity("f@r.br", &mut store).unwrap_err();
+                let Error::CorruptBlob {
+                    key: corrupt, line, ..
+                } = &error
+                else {
+                    panic!("expected a corrupt blob, found {error}");
+                };
+                assert_eq!((corrupt.as_str(), *line), (key, *expected_line));
+            }
+        }
+
+        // an offset beyond the names of the blob is an error, rather than a panic
+        let mut store = RemoteStore::new(MockBridge::default());
+        store
+            .bridge
+            .put(key, Bytes::from(record(digest, "99999")))?;
+        let error = brazilian.identity("f@r.br", &mut store).unwrap_err();
+        assert_eq!(error.kind(), crate::ErrorKind::Exhausted);
+        Ok(())
+    }
+
+    #[test]
+    fn test_quarantine() -> Result<(), Error> {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let storage = brazilian.storage_object("f@r.br");
+        let key = storage.key.as_str();
+        let record = |digest: &str, offset: usize| format!("{digest} {offset:>OFFSET_WIDTH$}\n");
+        let kept = record(&"0".repeat(61), 0);
+        let corrupt = kept.clone() + &record(storage.digest.as_str(), 1)[..30];
+
+        let mut store = RemoteStore::new(MockBridge::default()).with_quarantine();
+        store.bridge.put(key, Bytes::from(corrupt.clone()))?;
+        let identity = brazilian.identity("f@r.br", &mut store)?;
+        assert_eq!(identity.offset, 1);
+
+        let recoveries = store.take_recoveries();
+        assert_eq!(recoveries.len(), 1);
+        let report = &recoveries[0];
+        assert_eq!(report.key.as_str(), key);
+        assert_eq!(report.issues, vec![BlobIssue::MalformedLine(1)]);
+        assert_eq!((report.salvaged, report.discarded), (1, 1));
+        assert!(
+            report
+                .quarantined_as
+                .starts_with(&format!("{key}.corrupt-"))
+        );
+        assert_eq!(
+            store.bridge.get(&report.quarantined_as)?,
+            Some(corrupt.clone().into())
+        );
+        let rebuilt = store.bridge.get(key)?.unwrap();
+        assert_eq!(rebuilt, kept.clone() + &record(storage.digest.as_str(), 1));
+        assert!(store.take_recoveries().is_empty());
+
+        // the salvaged blob is written as an insert would be
+        let versioned = "version 3\n".to_string() + &corrupt;
+        let mut store = RemoteStore::new(MockBridge::default())
+            .with_quarantine()
+            .with_blob_versions();
+        store.bridge.put(key, Bytes::from(versioned))?;
+        assert_eq!(brazilian.identity("f@r.br", &mut store)?.offset, 1);
+        let rebuilt = store.bridge.get(key)?.unwrap();
+        assert!(rebuilt.starts_with(b"version 5\n"));
+
+        // and a blob which can not be decoded is not replaced
+        let mut store = RemoteStore::new(MockBridge::default())
+            .with_quarantine()
+            .with_blob_format(BlobFormat::JsonLines);
+        store.bridge.put(key, Bytes::from(corrupt.clone()))?;
+        let e = brazilian.identity("f@r.br", &mut store).unwrap_err();
+        assert_eq!(e.kind(), crate::ErrorKind::Corrupt);
+        assert_eq!(store.bridge.get(key)?, Some(corrupt.into()));
+        assert!(store.take_recoveries().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tombstones() -> Result<(), Error> {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let mut store = RemoteStore::new(MockBridge::default()).with_offset_index(4);
+        let first = brazilian.identity("f@r.br", &mut store)?;
+        let key = first.storage.key.as_str();
+        let mut offsets = vec![first.offset];
+        for _ in 0..3 {
+            offsets.push(next_stored_offset(&first.storage, &mut store)?);
+        }
+        assert_eq!(offsets, [0, 1, 2, 3]);
+
+        assert!(store.delete("br", &first.storage)?);
+        assert!(!store.delete("br", &first.storage)?);
+        let e = brazilian.identity("f@r.br", &mut store).unwrap_err();
+        assert_eq!(e.kind(), crate::ErrorKind::NotFound);
+        let blob = store.bridge.get(key)?.unwrap();
+        let records = Records::new(&blob)?;
+        let found_at = records
+            .search(first.storage.digest.as_str().as_bytes())
+            .unwrap();
+        assert_eq!(records.flag(found_at)?, RecordFlag::Tombstone);
+        assert_eq!(next_stored_offset(&first.storage, &mut store)?, 4);
+        let check = check_blob(&store.bridge.get(key)?.unwrap());
+        assert_eq!((check.issues, check.tombstones), (vec![], 1));
+
+        // the tombstone holding the largest offset is kept
+        let storage = Storage {
+            key: first.storage.key.clone(),
+            digest: store_digest(&store, key, 3),
+        };
+        assert!(store.delete("br", &storage)?);
+        assert_eq!(store.compact()?, 1);
+        assert_eq!(store.compact()?, 0);
+        let blob = store.bridge.get(key)?.unwrap();
+        let check = check_blob(&blob);
+        assert_eq!((check.issues, check.tombstones), (vec![], 1));
+        assert!(blob.contains(&RecordFlag::Compacted.separator()));
+        assert_eq!(next_stored_offset(&first.storage, &mut store)?, 5);
+        // a digest whose tombstone was dropped is a new digest
+        assert_eq!(brazilian.identity("f@r.br", &mut store)?.offset, 6);
+        Ok(())
+    }
+
+    // the digest stored with `offset` in the blob of `key`
+    fn store_digest(
+        store: &RemoteStore<MockBridge>,
+        key: &str,
+        offset: usize,
+    ) -> HexString<STORAGE_DIGEST_LENGTH> {
+        let blob = store.bridge.get(key).unwrap().unwrap();
+        let line = String::from_utf8_lossy(&blob)
+            .lines()
+            .find(|line| parse_record(line).is_some_and(|(_, _, o)| o == offset))
+            .unwrap()
+            .to_string();
+        HexString::from(&line.as_bytes()[..STORAGE_DIGEST_LENGTH])
+    }
+
+    #[test]
+    fn test_key_template() -> Result<(), Error> {
         let brazilian = Population {
             domain: "br",
             secret: b"0123456789abcdef0123456789abcdef",
             ingredients: &PERFUME_INGREDIENTS,
         };
-        let mut store = RemoteStore {
-            bridge: MockBridge::default(),
+        let template = KeyTemplate::new("perfume/v1/{domain}/{key}.blob", "br")?;
+        let mut store = RemoteStore::new(MockBridge::default()).with_key_template(template);
+        let identity = brazilian.identity("a@b.br", &mut store)?;
+        let key = identity.storage.key.as_str();
+        let resource = format!("perfume/v1/br/{key}.blob");
+        assert_eq!(store.bridge_key(key), resource);
+        assert!(store.bridge.get(key)?.is_none());
+        assert!(store.bridge.get(&resource)?.is_some());
+        assert_eq!(brazilian.identity("a@b.br", &mut store)?, identity);
+
+        let snapshot = store.export()?;
+        assert_eq!(snapshot.blobs.len(), 1);
+        assert_eq!(snapshot.blobs[0].0.as_str(), key);
+
+        for invalid in ["{domain}", "{key}/{key}", "{domain}/{key}.{format}"] {
+            assert!(KeyTemplate::new(invalid, "br").is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+
+    struct UnreachableBridge;
+
+    impl ConnectionBridge for UnreachableBridge {
+        #[async_generic]
+        fn get(&self, _key: &str) -> BridgeResult<Option<Bytes>> {
+            Err(std::io::ErrorKind::ConnectionRefused.into())
+        }
+        #[async_generic]
+        fn put(&self, _key: &str, _body: Bytes) -> BridgeResult<()> {
+            Err(std::io::ErrorKind::ConnectionRefused.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health_check() -> Result<(), Error> {
+        let mut store = RemoteStore::new(MockBridge::default());
+        store.health_check()?;
+        store.health_check_async().await?;
+        assert!(store.bridge.is_empty());
+
+        let mut store = RemoteStore::new(UnreachableBridge);
+        let error = store.health_check_async().await.unwrap_err();
+        assert!(error.is_retryable());
+        assert_eq!(error.context().unwrap().key(), Some(PING_KEY));
+        assert!(store.health_check().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remote_store_async() -> Result<(), Error> {
+        impl_test_remote_store_async().await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_remote_store_blocking() -> Result<(), Error> {
+        impl_test_remote_store()?;
+        Ok(())
+    }
+
+    #[async_generic]
+    #[allow(unused_assignments)]
+    fn impl_test_remote_store() -> Result<(), Error> {
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
         };
+        let mut store = RemoteStore::new(MockBridge::default());
 
         let mut user1 = Identity::default();
         let mut first_offset = usize::MAX;
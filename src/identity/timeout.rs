@@ -0,0 +1,245 @@
+//! A [`ConnectionBridge`] which bounds the duration of the async operations of another.
+
+use std::future::Future;
+use std::io;
+use std::ops::{ControlFlow, Range};
+use std::pin::pin;
+use std::task::Poll;
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use super::rate_limit::sleep;
+use super::storage::{BridgeResult, ConnectionBridge, Validated};
+
+/// Fails the async operations of another bridge which take longer than a timeout with
+/// [`io::ErrorKind::TimedOut`], which a [`super::RemoteStore`] returns as an
+/// [`crate::Error`] of kind [`crate::ErrorKind::Timeout`], so that a stalled backend does not
+/// stall identity generation.
+///
+/// An operation which times out is dropped, so it is cancelled at its next await point. A write
+/// may still reach the backend, in which case a later conditional write fails as a conflict,
+/// which [`super::RemoteStore`] resolves. Timeouts are measured by a timer thread, so that no
+/// particular runtime is required.
+///
+/// Blocking calls cannot be interrupted, so they are passed through: their duration is bounded by
+/// the timeouts of the wrapped bridge, such as those of a `UreqBridge`.
+#[derive(Debug)]
+pub struct TimeoutBridge<B> {
+    bridge: B,
+    timeout: Duration,
+    write_timeout: Duration,
+}
+
+impl<B: ConnectionBridge> TimeoutBridge<B> {
+    /// Fail the async operations of `bridge` which take longer than `timeout`.
+    pub fn new(bridge: B, timeout: Duration) -> Self {
+        Self {
+            bridge,
+            timeout,
+            write_timeout: timeout,
+        }
+    }
+
+    /// Give writes, which send whole blobs, `timeout` instead.
+    pub fn with_write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    /// The bridge whose operations are bounded.
+    pub fn inner(&self) -> &B {
+        &self.bridge
+    }
+
+    async fn within<T>(
+        &self,
+        timeout: Duration,
+        operation: &str,
+        key: &str,
+        future: impl Future<Output = BridgeResult<T>>,
+    ) -> BridgeResult<T> {
+        let mut future = pin!(future);
+        let mut timer = pin!(sleep(timeout));
+        let timed_out = std::future::poll_fn(|cx| {
+            if let Poll::Ready(result) = future.as_mut().poll(cx) {
+                return Poll::Ready(Some(result));
+            }
+            timer.as_mut().poll(cx).map(|()| None)
+        })
+        .await;
+        timed_out.unwrap_or_else(|| {
+            counter!("perfume_bridge_timeouts_total", 1);
+            let message = format!("{operation} of {key:?} took longer than {timeout:?}");
+            Err(io::Error::new(io::ErrorKind::TimedOut, message))
+        })
+    }
+}
+
+impl<B: ConnectionBridge + Sync> ConnectionBridge for TimeoutBridge<B> {
+    fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        self.bridge.get(key)
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.bridge.put(key, body)
+    }
+
+    async fn get_async(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        self.within(self.timeout, "get", key, self.bridge.get_async(key))
+            .await
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        let put = self.bridge.put_async(key, body);
+        self.within(self.write_timeout, "put", key, put).await
+    }
+
+    fn put_if_match(&self, key: &str, body: Bytes, validator: Option<&str>) -> BridgeResult<()> {
+        self.bridge.put_if_match(key, body, validator)
+    }
+
+    async fn put_if_match_async(
+        &self,
+        key: &str,
+        body: Bytes,
+        validator: Option<&str>,
+    ) -> BridgeResult<()> {
+        let put = self.bridge.put_if_match_async(key, body, validator);
+        self.within(self.write_timeout, "put", key, put).await
+    }
+
+    fn append(&self, key: &str, bytes: Bytes) -> BridgeResult<()> {
+        self.bridge.append(key, bytes)
+    }
+
+    async fn append_async(&self, key: &str, bytes: Bytes) -> BridgeResult<()> {
+        let append = self.bridge.append_async(key, bytes);
+        self.within(self.write_timeout, "append", key, append).await
+    }
+
+    fn exists(&self, key: &str) -> BridgeResult<bool> {
+        self.bridge.exists(key)
+    }
+
+    async fn exists_async(&self, key: &str) -> BridgeResult<bool> {
+        let exists = self.bridge.exists_async(key);
+        self.within(self.timeout, "exists", key, exists).await
+    }
+
+    fn ping(&self) -> BridgeResult<()> {
+        self.bridge.ping()
+    }
+
+    async fn ping_async(&self) -> BridgeResult<()> {
+        let ping = self.bridge.ping_async();
+        self.within(self.timeout, "ping", "", ping).await
+    }
+
+    fn get_validated(&self, key: &str, validator: Option<&str>) -> BridgeResult<Validated> {
+        self.bridge.get_validated(key, validator)
+    }
+
+    async fn get_validated_async(
+        &self,
+        key: &str,
+        validator: Option<&str>,
+    ) -> BridgeResult<Validated> {
+        let get = self.bridge.get_validated_async(key, validator);
+        self.within(self.timeout, "get", key, get).await
+    }
+
+    fn get_chunks(
+        &self,
+        key: &str,
+        visit: &mut dyn FnMut(&[u8]) -> ControlFlow<()>,
+    ) -> BridgeResult<bool> {
+        self.bridge.get_chunks(key, visit)
+    }
+
+    async fn get_chunks_async(
+        &self,
+        key: &str,
+        visit: &mut (dyn FnMut(&[u8]) -> ControlFlow<()> + Send),
+    ) -> BridgeResult<bool> {
+        let get = self.bridge.get_chunks_async(key, visit);
+        self.within(self.timeout, "get", key, get).await
+    }
+
+    fn get_range(&self, key: &str, range: Range<u64>) -> BridgeResult<Option<(Bytes, u64)>> {
+        self.bridge.get_range(key, range)
+    }
+
+    async fn get_range_async(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> BridgeResult<Option<(Bytes, u64)>> {
+        let get = self.bridge.get_range_async(key, range);
+        self.within(self.timeout, "get", key, get).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::identity::{Population, RemoteStore, StorageState, tests::*};
+    use crate::{Error, ErrorKind};
+
+    // never answers while it is stalled
+    #[derive(Default)]
+    struct StalledBridge {
+        inner: MockBridge,
+        stalled: AtomicBool,
+    }
+
+    impl ConnectionBridge for StalledBridge {
+        fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+            self.inner.get(key)
+        }
+
+        fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+            self.inner.put(key, body)
+        }
+
+        async fn get_async(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+            if self.stalled.load(Ordering::Relaxed) {
+                std::future::pending::<()>().await;
+            }
+            self.inner.get(key)
+        }
+
+        async fn put_async(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+            self.inner.put(key, body)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timeout_bridge() -> Result<(), Error> {
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let bridge = TimeoutBridge::new(StalledBridge::default(), Duration::from_millis(50));
+        let mut store = RemoteStore::new(bridge);
+        let identity = population.identity_async("a@b.br", &mut store).await?;
+
+        store.bridge.inner().stalled.store(true, Ordering::Relaxed);
+        let error = store
+            .digest_offset_async("br", &identity.storage)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Timeout);
+        assert!(error.is_retryable());
+
+        // blocking calls are passed through
+        assert_eq!(population.identity("a@b.br", &mut store)?, identity);
+        store.bridge.inner().stalled.store(false, Ordering::Relaxed);
+        let found = population.identity_async("a@b.br", &mut store).await?;
+        assert_eq!(found, identity);
+        Ok(())
+    }
+}
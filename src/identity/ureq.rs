@@ -0,0 +1,344 @@
+//! A [`ConnectionBridge`] which keeps blobs on an HTTP server, using a blocking client.
+
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::time::Duration;
+
+use bytes::Bytes;
+use ureq::Agent;
+use ureq::http::{Response, StatusCode, header};
+
+use super::storage::{
+    BridgeResult, ConnectionBridge, Validated, content_range_length, range_header, slice_range,
+};
+
+/// The default time allowed for connecting to the server.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The default time allowed for each request, from connecting to reading the whole response.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Stores blobs on an HTTP server with `GET`, `HEAD` and `PUT` requests for the url of each
+/// key, as described by openapi/perfume.json and served by `perfume::server::router`. A key
+/// template of `{domain}/{key}` matches the paths of the protocol.
+///
+/// Blobs which are not found are absent, and any other unexpected status is an error. Blobs are
+/// revalidated with their ETags, see [`ConnectionBridge::get_validated`], and written on the
+/// condition that they still have them, see [`ConnectionBridge::put_if_match`]. Requests whose
+/// connection is reset or closed before a response are sent again, up to twice by default,
+/// which is safe since each request of the protocol is idempotent. The async methods block.
+#[derive(Debug, Clone)]
+pub struct UreqBridge {
+    agent: Agent,
+    url: String,
+    connect_timeout: Duration,
+    request_timeout: Duration,
+    retries: u32,
+}
+
+// applies the timeouts of the bridge, and answers statuses as responses
+macro_rules! configure {
+    ($bridge:expr, $request:expr) => {
+        $request
+            .config()
+            .http_status_as_error(false)
+            .timeout_connect(Some($bridge.connect_timeout))
+            .timeout_global(Some($bridge.request_timeout))
+            .build()
+    };
+}
+
+impl UreqBridge {
+    /// Keep blobs under the base `url`, such as `https://example.com/blobs`.
+    pub fn new(url: &str) -> BridgeResult<Self> {
+        let _: ureq::http::Uri = url.try_into().map_err(|e| {
+            let message = format!("invalid store url {url}: {e}");
+            std::io::Error::new(ErrorKind::InvalidInput, message)
+        })?;
+        Ok(Self {
+            agent: Agent::new_with_defaults(),
+            url: url.trim_end_matches('/').to_string(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            retries: 2,
+        })
+    }
+
+    /// Send requests with `agent`, such as one configured with a proxy or the root
+    /// certificates of a private server. Statuses are answered by the bridge, whatever the
+    /// configuration of the agent.
+    pub fn with_agent(mut self, agent: Agent) -> Self {
+        self.agent = agent;
+        self
+    }
+
+    /// Allow `connect` for connecting to the server, and `request` for each request
+    /// as a whole, instead of [`DEFAULT_CONNECT_TIMEOUT`] and [`DEFAULT_REQUEST_TIMEOUT`].
+    pub fn with_timeouts(mut self, connect: Duration, request: Duration) -> Self {
+        self.connect_timeout = connect;
+        self.request_timeout = request;
+        self
+    }
+
+    /// Send a request again up to `retries` times when its connection is reset.
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// The url of the blob of `key`.
+    pub fn resource_url(&self, key: &str) -> String {
+        format!("{}/{key}", self.url)
+    }
+
+    // sends the request made by `request` until it is answered or fails for another reason
+    fn send<R>(&self, resource_url: &str, request: R) -> BridgeResult<Response<ureq::Body>>
+    where
+        R: Fn(&Agent, &str) -> Result<Response<ureq::Body>, ureq::Error>,
+    {
+        let mut attempt = 0;
+        loop {
+            match request(&self.agent, resource_url) {
+                Err(ureq::Error::Io(e)) if attempt < self.retries && is_reset(&e) => attempt += 1,
+                result => {
+                    return result.map_err(|e| {
+                        let message = format!("IO failure on request to {resource_url}: {e}");
+                        std::io::Error::new(error_kind(&e), message)
+                    });
+                }
+            }
+        }
+    }
+
+    // stores `body`, only if the blob matches `condition` when there is one
+    fn send_put(
+        &self,
+        key: &str,
+        body: &[u8],
+        condition: Option<(header::HeaderName, &str)>,
+    ) -> BridgeResult<()> {
+        let resource_url = self.resource_url(key);
+        let response = self.send(&resource_url, |agent, url| {
+            let mut request = agent.put(url);
+            if let Some((name, value)) = &condition {
+                request = request.header(name, *value);
+            }
+            configure!(self, request).send(body)
+        })?;
+        match response.status() {
+            StatusCode::OK => Ok(()),
+            StatusCode::PRECONDITION_FAILED => {
+                let message = format!("blob at {resource_url} was modified by another writer");
+                Err(std::io::Error::new(ErrorKind::AlreadyExists, message))
+            }
+            unexpected => Err(unexpected_status(&resource_url, unexpected)),
+        }
+    }
+
+    fn read_body(resource_url: &str, response: Response<ureq::Body>) -> BridgeResult<Bytes> {
+        let body = response.into_body().read_to_vec().map_err(|e| {
+            std::io::Error::other(format!(
+                "error parsing response body on request to {resource_url}: {e}"
+            ))
+        })?;
+        Ok(Bytes::from(body))
+    }
+}
+
+// the connection was lost before a response, as when a server closes an idle connection
+fn is_reset(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof
+    )
+}
+
+// keeps timeouts and lost connections distinguishable, see `RetryBridge`
+pub(crate) fn error_kind(e: &ureq::Error) -> ErrorKind {
+    match e {
+        ureq::Error::Io(e) => e.kind(),
+        ureq::Error::Timeout(_) => ErrorKind::TimedOut,
+        ureq::Error::ConnectionFailed => ErrorKind::ConnectionRefused,
+        _ => ErrorKind::Other,
+    }
+}
+
+// responses of servers which are overloaded or restarting
+pub(crate) fn status_kind(status: StatusCode) -> ErrorKind {
+    match status {
+        StatusCode::TOO_MANY_REQUESTS
+        | StatusCode::BAD_GATEWAY
+        | StatusCode::SERVICE_UNAVAILABLE
+        | StatusCode::GATEWAY_TIMEOUT => ErrorKind::ResourceBusy,
+        _ => ErrorKind::Other,
+    }
+}
+
+fn unexpected_status(resource_url: &str, status: StatusCode) -> std::io::Error {
+    let message = format!("unexpected HTTP response on request to {resource_url}: {status}");
+    std::io::Error::new(status_kind(status), message)
+}
+
+impl ConnectionBridge for UreqBridge {
+    fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        match self.get_validated(key, None)? {
+            Validated::Modified { body, .. } => Ok(body),
+            Validated::NotModified => unreachable!("blobs without a validator are modified"),
+        }
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.send_put(key, &body, None)
+    }
+
+    async fn get_async(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        self.get(key)
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.put(key, body)
+    }
+
+    fn put_if_match(&self, key: &str, body: Bytes, validator: Option<&str>) -> BridgeResult<()> {
+        let condition = match validator {
+            Some(etag) => (header::IF_MATCH, etag),
+            None => (header::IF_NONE_MATCH, "*"),
+        };
+        self.send_put(key, &body, Some(condition))
+    }
+
+    async fn put_if_match_async(
+        &self,
+        key: &str,
+        body: Bytes,
+        validator: Option<&str>,
+    ) -> BridgeResult<()> {
+        self.put_if_match(key, body, validator)
+    }
+
+    fn exists(&self, key: &str) -> BridgeResult<bool> {
+        let resource_url = self.resource_url(key);
+        let response = self.send(&resource_url, |agent, url| {
+            configure!(self, agent.head(url)).call()
+        })?;
+        match response.status() {
+            StatusCode::OK => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            unexpected => Err(unexpected_status(&resource_url, unexpected)),
+        }
+    }
+
+    async fn exists_async(&self, key: &str) -> BridgeResult<bool> {
+        self.exists(key)
+    }
+
+    fn get_validated(&self, key: &str, validator: Option<&str>) -> BridgeResult<Validated> {
+        let resource_url = self.resource_url(key);
+        let response = self.send(&resource_url, |agent, url| {
+            let mut request = agent.get(url);
+            if let Some(etag) = validator {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            configure!(self, request).call()
+        })?;
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        match response.status() {
+            StatusCode::NOT_MODIFIED => Ok(Validated::NotModified),
+            StatusCode::OK => Ok(Validated::Modified {
+                body: Some(Self::read_body(&resource_url, response)?),
+                validator: etag,
+            }),
+            StatusCode::NOT_FOUND => Ok(Validated::Modified {
+                body: None,
+                validator: None,
+            }),
+            unexpected => Err(unexpected_status(&resource_url, unexpected)),
+        }
+    }
+
+    async fn get_validated_async(
+        &self,
+        key: &str,
+        validator: Option<&str>,
+    ) -> BridgeResult<Validated> {
+        self.get_validated(key, validator)
+    }
+
+    fn get_range(&self, key: &str, range: Range<u64>) -> BridgeResult<Option<(Bytes, u64)>> {
+        let resource_url = self.resource_url(key);
+        let response = self.send(&resource_url, |agent, url| {
+            let request = agent.get(url).header(header::RANGE, range_header(&range));
+            configure!(self, request).call()
+        })?;
+        let length = response
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|v| content_range_length(v.to_str().ok()?));
+        match (response.status(), length) {
+            (StatusCode::PARTIAL_CONTENT, Some(length)) => {
+                let body = Self::read_body(&resource_url, response)?;
+                Ok(Some((
+                    slice_range(&body, &(0..range.end.saturating_sub(range.start))),
+                    length,
+                )))
+            }
+            (StatusCode::RANGE_NOT_SATISFIABLE, Some(length)) => Ok(Some((Bytes::new(), length))),
+            // the server sent the whole blob
+            (StatusCode::OK, _) => {
+                let body = Self::read_body(&resource_url, response)?;
+                Ok(Some((slice_range(&body, &range), body.len() as u64)))
+            }
+            (StatusCode::NOT_FOUND, _) => Ok(None),
+            (unexpected, _) => Err(unexpected_status(&resource_url, unexpected)),
+        }
+    }
+
+    async fn get_range_async(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> BridgeResult<Option<(Bytes, u64)>> {
+        self.get_range(key, range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use crate::identity::{KeyTemplate, Population, RemoteStore, tests::*};
+    use crate::testing::bridge_conformance;
+
+    #[test]
+    fn test_ureq_bridge() -> Result<(), Error> {
+        let url = blob_server(0);
+        let bridge = UreqBridge::new(&format!("{url}/"))?;
+        assert_eq!(bridge.resource_url("br/abc"), format!("{url}/br/abc"));
+        assert!(UreqBridge::new("not a url").is_err());
+        bridge_conformance(|| UreqBridge::new(&blob_server(0)).unwrap());
+
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let template = KeyTemplate::new("{domain}/{key}", "br")?;
+        let mut store = RemoteStore::new(bridge).with_key_template(template);
+        let identity = population.identity("a@b.br", &mut store)?;
+        assert_eq!(population.identity("a@b.br", &mut store)?, identity);
+
+        // requests are sent again when the connection is reset
+        let flaky = UreqBridge::new(&blob_server(2))?;
+        assert_eq!(flaky.get("br/abc")?, None);
+        let flaky = UreqBridge::new(&blob_server(2))?.with_retries(1);
+        let e = flaky.get("br/abc").unwrap_err();
+        assert!(e.to_string().starts_with("IO failure on request to"), "{e}");
+        Ok(())
+    }
+}
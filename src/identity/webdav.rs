@@ -0,0 +1,294 @@
+//! A [`ConnectionBridge`] which keeps blobs as resources of a WebDAV server.
+
+use std::io;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bytes::Bytes;
+use ureq::Agent;
+use ureq::http::{Request, Response, StatusCode, header};
+
+use super::storage::{BridgeResult, ConnectionBridge, Validated};
+use super::ureq::{error_kind, status_kind};
+
+/// Stores each blob as a resource under a collection of a WebDAV server, such as Nextcloud at
+/// `https://cloud.example.com/remote.php/dav/files/<user>/perfume`, at the path given by its
+/// key. Keys such as `br/abc` made by a [`crate::identity::KeyTemplate`] of `{domain}/{key}`
+/// keep the blobs of each domain in a collection, which is created with `MKCOL` when the
+/// first blob is stored in it.
+///
+/// Blobs which are not found are absent, and any other unexpected status is an error. Blobs are
+/// revalidated with their ETags, see [`ConnectionBridge::get_validated`], and checked for with
+/// `PROPFIND` requests which do not transfer them. The async methods block.
+#[derive(Debug, Clone)]
+pub struct WebDavBridge {
+    agent: Agent,
+    url: String,
+    authorization: Option<String>,
+}
+
+impl WebDavBridge {
+    /// Keep blobs under the collection at `url`, which must exist.
+    pub fn new(url: &str) -> BridgeResult<Self> {
+        let _: ureq::http::Uri = url.try_into().map_err(|e| {
+            let message = format!("invalid WebDAV url {url}: {e}");
+            io::Error::new(io::ErrorKind::InvalidInput, message)
+        })?;
+        Ok(Self {
+            agent: Agent::new_with_defaults(),
+            url: url.trim_end_matches('/').to_string(),
+            authorization: None,
+        })
+    }
+
+    /// Authenticate as `user` with `password`, such as an app password of Nextcloud.
+    pub fn with_basic_auth(mut self, user: &str, password: &str) -> Self {
+        let credentials = STANDARD.encode(format!("{user}:{password}"));
+        self.authorization = Some(format!("Basic {credentials}"));
+        self
+    }
+
+    /// Send requests with `agent`, such as one configured with timeouts or a proxy.
+    pub fn with_agent(mut self, agent: Agent) -> Self {
+        self.agent = agent;
+        self
+    }
+
+    /// The url of the resource of `key`.
+    pub fn resource_url(&self, key: &str) -> String {
+        format!("{}/{key}", self.url)
+    }
+
+    fn send(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(header::HeaderName, &str)],
+        body: &[u8],
+    ) -> BridgeResult<Response<ureq::Body>> {
+        let mut request = Request::builder().method(method).uri(url);
+        if let Some(authorization) = &self.authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        let request = request.body(body).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid {method} {url}: {e}"),
+            )
+        })?;
+        let request = self
+            .agent
+            .configure_request(request)
+            .http_status_as_error(false)
+            .allow_non_standard_methods(true)
+            .build();
+        self.agent.run(request).map_err(|e| {
+            let message = format!("IO failure on {method} request to {url}: {e}");
+            io::Error::new(error_kind(&e), message)
+        })
+    }
+
+    // creates each collection above the resource of `key`, from the top
+    fn make_collections(&self, key: &str) -> BridgeResult<()> {
+        let mut collection = self.url.clone();
+        let Some((parents, _name)) = key.rsplit_once('/') else {
+            return Ok(());
+        };
+        for part in parents.split('/') {
+            collection.push('/');
+            collection.push_str(part);
+            let response = self.send("MKCOL", &collection, &[], &[])?;
+            match response.status() {
+                // a collection which exists cannot be made again
+                StatusCode::CREATED | StatusCode::METHOD_NOT_ALLOWED => {}
+                unexpected => return Err(unexpected_status("MKCOL", &collection, unexpected)),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn unexpected_status(method: &str, url: &str, status: StatusCode) -> io::Error {
+    let message = format!("unexpected HTTP response on {method} request to {url}: {status}");
+    io::Error::new(status_kind(status), message)
+}
+
+impl ConnectionBridge for WebDavBridge {
+    fn get(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        match self.get_validated(key, None)? {
+            Validated::Modified { body, .. } => Ok(body),
+            Validated::NotModified => unreachable!("blobs without a validator are modified"),
+        }
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        let url = self.resource_url(key);
+        let mut response = self.send("PUT", &url, &[], &body)?;
+        // the collection of the resource does not exist
+        if response.status() == StatusCode::CONFLICT {
+            self.make_collections(key)?;
+            response = self.send("PUT", &url, &[], &body)?;
+        }
+        match response.status() {
+            StatusCode::OK | StatusCode::CREATED | StatusCode::NO_CONTENT => Ok(()),
+            unexpected => Err(unexpected_status("PUT", &url, unexpected)),
+        }
+    }
+
+    async fn get_async(&self, key: &str) -> BridgeResult<Option<Bytes>> {
+        self.get(key)
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> BridgeResult<()> {
+        self.put(key, body)
+    }
+
+    fn exists(&self, key: &str) -> BridgeResult<bool> {
+        let url = self.resource_url(key);
+        let depth = header::HeaderName::from_static("depth");
+        let response = self.send("PROPFIND", &url, &[(depth, "0")], &[])?;
+        match response.status() {
+            StatusCode::MULTI_STATUS => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            unexpected => Err(unexpected_status("PROPFIND", &url, unexpected)),
+        }
+    }
+
+    async fn exists_async(&self, key: &str) -> BridgeResult<bool> {
+        self.exists(key)
+    }
+
+    fn get_validated(&self, key: &str, validator: Option<&str>) -> BridgeResult<Validated> {
+        let url = self.resource_url(key);
+        let headers = validator.map(|etag| (header::IF_NONE_MATCH, etag));
+        let response = self.send("GET", &url, headers.as_slice(), &[])?;
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        match response.status() {
+            StatusCode::NOT_MODIFIED => Ok(Validated::NotModified),
+            StatusCode::OK => {
+                let body = response.into_body().read_to_vec().map_err(|e| {
+                    io::Error::other(format!("error reading response body of GET {url}: {e}"))
+                })?;
+                Ok(Validated::Modified {
+                    body: Some(Bytes::from(body)),
+                    validator: etag,
+                })
+            }
+            StatusCode::NOT_FOUND => Ok(Validated::Modified {
+                body: None,
+                validator: None,
+            }),
+            unexpected => Err(unexpected_status("GET", &url, unexpected)),
+        }
+    }
+
+    async fn get_validated_async(
+        &self,
+        key: &str,
+        validator: Option<&str>,
+    ) -> BridgeResult<Validated> {
+        self.get_validated(key, validator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use super::*;
+    use crate::Error;
+    use crate::identity::{KeyTemplate, Population, RemoteStore, tests::*};
+
+    // resources and collections under "/dav", for the user "perfume"
+    fn webdav_server() -> String {
+        let mut collections = HashSet::from(["/dav".to_string()]);
+        let mut resources = HashMap::<String, Vec<u8>>::new();
+        http_server(0, move |request| {
+            let authorization = format!("Basic {}", STANDARD.encode("perfume:secret"));
+            if request.headers.get("authorization") != Some(&authorization) {
+                return ("401 Unauthorized", vec![], vec![]);
+            }
+            let path = request.path;
+            let parent = path.rsplit_once('/').map_or("", |(parent, _name)| parent);
+            let resource = resources.get(&path);
+            let tag = resource.map(|blob| format!("\"{}\"", blake3::hash(blob).to_hex()));
+            match request.method.as_str() {
+                "PUT" if !collections.contains(parent) => ("409 Conflict", vec![], vec![]),
+                "PUT" => {
+                    let created = resources.insert(path, request.body).is_none();
+                    let status = if created {
+                        "201 Created"
+                    } else {
+                        "204 No Content"
+                    };
+                    (status, vec![], vec![])
+                }
+                "MKCOL" if collections.contains(&path) => {
+                    ("405 Method Not Allowed", vec![], vec![])
+                }
+                "MKCOL" if !collections.contains(parent) => ("409 Conflict", vec![], vec![]),
+                "MKCOL" => {
+                    collections.insert(path);
+                    ("201 Created", vec![], vec![])
+                }
+                "PROPFIND" if resource.is_some() || collections.contains(&path) => {
+                    assert_eq!(request.headers.get("depth").map(String::as_str), Some("0"));
+                    (
+                        "207 Multi-Status",
+                        vec![],
+                        b"<d:multistatus xmlns:d=\"DAV:\"/>".to_vec(),
+                    )
+                }
+                "GET" if tag.is_some() && request.headers.get("if-none-match") == tag.as_ref() => {
+                    ("304 Not Modified", vec![], vec![])
+                }
+                "GET" if resource.is_some() => {
+                    let headers = vec![("etag", tag.unwrap())];
+                    ("200 OK", headers, resource.unwrap().clone())
+                }
+                _ => ("404 Not Found", vec![], vec![]),
+            }
+        })
+    }
+
+    #[test]
+    fn test_webdav_bridge() -> Result<(), Error> {
+        let url = format!("{}/dav/", webdav_server());
+        let bridge = WebDavBridge::new(&url)?.with_basic_auth("perfume", "secret");
+        assert_eq!(bridge.resource_url("br/abc"), format!("{url}br/abc"));
+        assert!(WebDavBridge::new(&url)?.get("br/abc").is_err());
+
+        assert_eq!(bridge.get("br/abc")?, None);
+        assert!(!bridge.exists("br/abc")?);
+        bridge.put("br/v1/abc", Bytes::from("blob\n"))?;
+        assert!(bridge.exists("br/v1/abc")?);
+        assert!(bridge.exists("br/v1")?);
+        let Validated::Modified { body, validator } = bridge.get_validated("br/v1/abc", None)?
+        else {
+            panic!("the blob should be modified");
+        };
+        assert_eq!(body.as_deref(), Some(&b"blob\n"[..]));
+        assert!(matches!(
+            bridge.get_validated("br/v1/abc", validator.as_deref())?,
+            Validated::NotModified
+        ));
+
+        let population = Population::new(
+            "br",
+            b"0123456789abcdef0123456789abcdef",
+            &PERFUME_INGREDIENTS,
+        )?;
+        let template = KeyTemplate::new("{domain}/{key}", "br")?;
+        let mut store = RemoteStore::new(bridge).with_key_template(template);
+        let identity = population.identity("a@b.br", &mut store)?;
+        assert_eq!(population.identity("a@b.br", &mut store)?, identity);
+        Ok(())
+    }
+}
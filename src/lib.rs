@@ -16,7 +16,7 @@
 //! perfume = { version = "0.1", features = ["codegen"] }
 //!
 //! [dependencies]
-//! perfume = "0.1"
+//! perfume = { version = "0.1", features = ["ureq"] }
 //! phf = { version = "0.12", default-features = false }
 //! ```
 //!
@@ -37,6 +37,9 @@
 //!     out_path,
 //! ).unwrap_or_else(|e| panic!("{e}"));
 //! ```
+//! Use `codegen::ingredients_with_progress` to report progress, or to pass its warnings on to
+//! cargo with `println!("cargo:warning={warning}")`.
+//!
 //! Include the generated code in a module using `include!(concat!(env!("OUT_DIR"), "/perfume.rs"));`
 //!
 //! The word lists such as `gerunds.txt` can be found in the git repository.
@@ -46,21 +49,65 @@
 #![cfg_attr(feature = "nightly", feature(ascii_char))]
 #![cfg_attr(feature = "nightly", feature(ascii_char_variants))]
 
+// emits a `tracing` event at `$level` when the `tracing` feature is enabled
+macro_rules! event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::event!(tracing::Level::$level, $($arg)+);
+    };
+}
+
+// increments a `metrics` counter by `$increment` when the `metrics` feature is enabled
+macro_rules! counter {
+    ($name:literal, $increment:expr $(, $label:literal => $value:expr)*) => {
+        #[cfg(feature = "metrics")]
+        metrics::counter!($name $(, $label => $value)*).increment($increment as u64);
+    };
+}
+
 #[cfg(feature = "codegen")]
 #[cfg_attr(docsrs, doc(cfg(feature = "codegen")))]
 pub mod codegen;
 
+// code generation reads word lists from files, so it belongs in build-dependencies
+#[cfg(all(target_arch = "wasm32", feature = "codegen"))]
+compile_error!("the `codegen` feature is not supported on wasm32, use it in build.rs instead");
+
+pub mod diagnostics;
 pub mod hex_string;
 pub mod identity;
+#[cfg(feature = "tracing-subscriber")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tracing-subscriber")))]
+pub mod logging;
+pub mod migrate;
+#[cfg(feature = "pipeline")]
+#[cfg_attr(docsrs, doc(cfg(feature = "pipeline")))]
+pub mod pipeline;
+#[cfg(feature = "server")]
+#[cfg_attr(docsrs, doc(cfg(feature = "server")))]
+pub mod server;
+#[cfg(any(feature = "sqlx", feature = "diesel"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "sqlx", feature = "diesel"))))]
+pub mod sql;
+#[cfg(any(test, feature = "testing"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "testing")))]
+pub mod testing;
+#[cfg(any(feature = "axum", feature = "tower"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "axum", feature = "tower"))))]
+pub mod web;
 
+mod bloom;
+mod lru;
 mod random;
 
 use std::fs::{File, OpenOptions};
 use std::io::{self, BufRead, Write};
 use std::path::Path;
 
-/// All errors generated by this crate.
+/// All errors generated by this crate. Match on [`Error::kind`] to handle classes of failure,
+/// since variants are added by releases.
 #[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// Generated during code generation. See [`crate::codegen::ingredients`].
     #[error("perfume codegen error: {0}")]
@@ -68,6 +115,241 @@ pub enum Error {
     /// IO errors resulting from calls to [`crate::identity::Population::identity`].
     #[error("perfume io error: {0}")]
     Io(#[from] io::Error),
+    /// IO errors of a [`crate::identity::RemoteStore`], with the blob and operation which failed.
+    #[error("perfume storage error during {context}: {source}")]
+    Storage {
+        /// The error of the bridge or blob format.
+        source: io::Error,
+        /// See [`Error::context`].
+        context: Box<ErrorContext>,
+    },
+    /// A storage blob with a malformed or truncated record, which is never overwritten.
+    #[error("perfume corrupt blob {key} at line {line}, during {context}: {source}")]
+    CorruptBlob {
+        /// The storage key of the blob.
+        key: String,
+        /// The line of the first malformed record, counted from 0 as in
+        /// [`crate::identity::BlobIssue::MalformedLine`].
+        line: usize,
+        /// Describes the malformed record.
+        source: io::Error,
+        /// See [`Error::context`].
+        context: Box<ErrorContext>,
+    },
+    /// A storage blob was assigned an offset beyond the names available to it,
+    /// see [`crate::identity::Population::blob_capacity`].
+    #[error("perfume population exhausted: blob {key} has no names left")]
+    PopulationExhausted {
+        /// The storage key of the blob.
+        key: String,
+    },
+    /// A digest which is not assigned was looked up in a
+    /// [`crate::identity::ReadOnlyStore`], which never assigns offsets.
+    #[error("perfume read-only store: digest is not assigned in blob {key} of domain {domain}")]
+    ReadOnly {
+        /// The domain of the population.
+        domain: String,
+        /// The storage key of the blob.
+        key: String,
+    },
+}
+
+impl Error {
+    /// The class of this error, for handling failures without matching their messages.
+    /// IO errors are classified by their [`io::ErrorKind`], so implementations of
+    /// [`crate::identity::ConnectionBridge`] should construct them with the most specific kind.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Codegen(_) => ErrorKind::Codegen,
+            Error::CorruptBlob { .. } => ErrorKind::Corrupt,
+            Error::PopulationExhausted { .. } => ErrorKind::Exhausted,
+            Error::ReadOnly { .. } => ErrorKind::ReadOnly,
+            Error::Io(e) | Error::Storage { source: e, .. } => match e.kind() {
+                io::ErrorKind::NotFound => ErrorKind::NotFound,
+                io::ErrorKind::AlreadyExists => ErrorKind::Conflict,
+                io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => ErrorKind::Corrupt,
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ErrorKind::Timeout,
+                io::ErrorKind::InvalidInput => ErrorKind::InvalidInput,
+                io::ErrorKind::Unsupported => ErrorKind::Unsupported,
+                _ => ErrorKind::Backend,
+            },
+        }
+    }
+
+    /// True if the operation which failed may succeed when repeated: timeouts, conflicts
+    /// with concurrent writers, and interrupted or refused connections to the backend.
+    pub fn is_retryable(&self) -> bool {
+        match self.io_error() {
+            None => false,
+            Some(e) => {
+                matches!(self.kind(), ErrorKind::Timeout | ErrorKind::Conflict)
+                    || matches!(
+                        e.kind(),
+                        io::ErrorKind::Interrupted
+                            | io::ErrorKind::ConnectionRefused
+                            | io::ErrorKind::ConnectionReset
+                            | io::ErrorKind::ConnectionAborted
+                            | io::ErrorKind::NotConnected
+                            | io::ErrorKind::BrokenPipe
+                            | io::ErrorKind::ResourceBusy
+                    )
+            }
+        }
+    }
+
+    /// Where a storage error occurred, if known.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            Error::Storage { context, .. } | Error::CorruptBlob { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// The underlying IO error, if any.
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match self {
+            Error::Codegen(_) | Error::PopulationExhausted { .. } | Error::ReadOnly { .. } => None,
+            Error::Io(e)
+            | Error::Storage { source: e, .. }
+            | Error::CorruptBlob { source: e, .. } => Some(e),
+        }
+    }
+
+    pub(crate) fn storage(source: io::Error, key: &str, operation: Operation) -> Self {
+        let context = Box::new(ErrorContext {
+            domain: None,
+            key: Some(key.to_string()),
+            operation,
+            attempt: 1,
+        });
+        let malformed = source
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<identity::MalformedLine>());
+        match malformed {
+            Some(malformed) => Error::CorruptBlob {
+                key: key.to_string(),
+                line: malformed.line,
+                source,
+                context,
+            },
+            None => Error::Storage { source, context },
+        }
+    }
+
+    // records the attempt of a storage operation which was repeated
+    pub(crate) fn at_attempt(mut self, attempt: u32) -> Self {
+        if let Error::Storage { context, .. } | Error::CorruptBlob { context, .. } = &mut self {
+            context.attempt = attempt;
+        }
+        self
+    }
+
+    // records the domain of a storage error which was raised without it
+    pub(crate) fn in_domain(mut self, domain: &str) -> Self {
+        if let Error::Storage { context, .. } | Error::CorruptBlob { context, .. } = &mut self {
+            context.domain.get_or_insert_with(|| domain.to_string());
+        }
+        self
+    }
+}
+
+/// The domain, storage key, operation and attempt of a failed storage operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorContext {
+    domain: Option<String>,
+    key: Option<String>,
+    operation: Operation,
+    attempt: u32,
+}
+
+impl ErrorContext {
+    /// The domain of the population which was being resolved, if any.
+    pub fn domain(&self) -> Option<&str> {
+        self.domain.as_deref()
+    }
+
+    /// The storage key of the blob, see [`crate::identity::Storage::key`].
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    /// The operation which failed.
+    pub fn operation(&self) -> Operation {
+        self.operation
+    }
+
+    /// How many times the operation was attempted, starting at 1.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+}
+
+impl std::fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.operation)?;
+        if let Some(key) = &self.key {
+            write!(f, " of blob {key}")?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, " in domain {domain}")?;
+        }
+        if self.attempt > 1 {
+            write!(f, " (attempt {})", self.attempt)?;
+        }
+        Ok(())
+    }
+}
+
+/// Storage operations which may fail, see [`ErrorContext::operation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Operation {
+    /// Fetching a blob through [`crate::identity::ConnectionBridge`].
+    Get,
+    /// Parsing a fetched blob.
+    Parse,
+    /// Encoding a blob or storing it through [`crate::identity::ConnectionBridge`].
+    Put,
+    /// Taking or releasing the lock of a blob, see [`crate::identity::LockProvider`].
+    Lock,
+}
+
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Operation::Get => "get",
+            Operation::Parse => "parse",
+            Operation::Put => "put",
+            Operation::Lock => "lock",
+        })
+    }
+}
+
+/// Classes of [`Error`], see [`Error::kind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// A file or remote resource does not exist.
+    NotFound,
+    /// A concurrent writer changed the resource which was being written.
+    Conflict,
+    /// Stored data, such as a storage blob, could not be parsed.
+    Corrupt,
+    /// The storage backend did not respond in time.
+    Timeout,
+    /// Any other failure of the storage backend or the operating system.
+    Backend,
+    /// See [`Error::Codegen`].
+    Codegen,
+    /// An argument, identifier or configuration value was rejected.
+    InvalidInput,
+    /// See [`Error::PopulationExhausted`].
+    Exhausted,
+    /// See [`Error::ReadOnly`].
+    ReadOnly,
+    /// Stored data, such as a storage blob, is of a later format than this version can read,
+    /// see [`crate::identity::FORMAT_VERSION`]. It is not corrupt, and is never rewritten.
+    Unsupported,
 }
 
 /// The number of hex characters to use to use in each [`crate::identity::Storage`] object key, 3.
@@ -75,6 +357,10 @@ pub enum Error {
 pub const STORAGE_KEY_LENGTH: usize = 3;
 /// The number of hex characters to use to use in each [`crate::identity::Storage`] object digest, 61.
 pub const STORAGE_DIGEST_LENGTH: usize = 64 - STORAGE_KEY_LENGTH;
+/// The fewest hex characters of each digest which a storage blob may hold, 24 (96 bits), of which
+/// the last [`crate::identity::DIGEST_CHECK_LENGTH`] only detect collisions.
+/// See [`crate::identity::RemoteStore::with_digest_length`].
+pub const MIN_STORAGE_DIGEST_LENGTH: usize = 24;
 
 #[allow(dead_code)]
 fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
@@ -101,3 +387,37 @@ where
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_kind() {
+        let io_error = |kind| Error::from(io::Error::new(kind, "test"));
+        assert_eq!(
+            io_error(io::ErrorKind::NotFound).kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            io_error(io::ErrorKind::InvalidData).kind(),
+            ErrorKind::Corrupt
+        );
+        assert_eq!(
+            Error::from(io::Error::other("test")).kind(),
+            ErrorKind::Backend
+        );
+        assert_eq!(Error::Codegen("test".into()).kind(), ErrorKind::Codegen);
+        assert_eq!(
+            io_error(io::ErrorKind::Unsupported).kind(),
+            ErrorKind::Unsupported
+        );
+
+        assert!(io_error(io::ErrorKind::TimedOut).is_retryable());
+        assert!(io_error(io::ErrorKind::AlreadyExists).is_retryable());
+        assert!(io_error(io::ErrorKind::ConnectionReset).is_retryable());
+        assert!(io_error(io::ErrorKind::ResourceBusy).is_retryable());
+        assert!(!io_error(io::ErrorKind::InvalidData).is_retryable());
+        assert!(!io_error(io::ErrorKind::PermissionDenied).is_retryable());
+    }
+}
@@ -0,0 +1,197 @@
+//! Pseudonymization of log fields with [tracing-subscriber](https://crates.io/crates/tracing-subscriber).
+//! Requires the `tracing-subscriber` feature.
+//!
+//! ```no_run
+//! # fn example(population: perfume::identity::Population<'static>) {
+//! use perfume::identity::ConcurrentStore;
+//! use perfume::logging::PseudonymFields;
+//! use tracing_subscriber::prelude::*;
+//!
+//! let fields = PseudonymFields::new(population, ConcurrentStore::new(), ["user_email"]);
+//! tracing_subscriber::registry()
+//!     .with(tracing_subscriber::fmt::layer().fmt_fields(fields))
+//!     .init();
+//!
+//! // logged as user_email=<friendly name>
+//! tracing::info!(user_email = "alice@example.com", "signed in");
+//! # }
+//! ```
+
+use std::fmt;
+use std::sync::Mutex;
+
+use tracing::field::{Field, Visit};
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::fmt::FormatFields;
+use tracing_subscriber::fmt::format::Writer;
+
+use crate::identity::{Population, StorageState};
+
+// written in place of a value which could not be pseudonymized, so that it is never leaked
+const UNRESOLVED: &str = "<unresolved>";
+
+/// Formats the fields of spans and events like the default format of `tracing_subscriber::fmt`,
+/// replacing the values of the configured fields by the friendly names of their identities.
+pub struct PseudonymFields<S> {
+    population: Population<'static>,
+    state: Mutex<S>,
+    names: Vec<String>,
+}
+
+impl<S> std::fmt::Debug for PseudonymFields<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PseudonymFields")
+            .field("population", &self.population)
+            .field("names", &self.names)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> PseudonymFields<S>
+where
+    S: StorageState,
+{
+    /// Replace the values of fields called any of `names` using `population`, which persists
+    /// identities using `state`.
+    pub fn new<I, N>(population: Population<'static>, state: S, names: I) -> Self
+    where
+        I: IntoIterator<Item = N>,
+        N: Into<String>,
+    {
+        Self {
+            population,
+            state: Mutex::new(state),
+            names: names.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    fn friendly_name(&self, identifier: &str) -> String {
+        let mut state = self.state.lock().unwrap();
+        self.population
+            .identity(identifier, &mut *state)
+            .map_or_else(|_| UNRESOLVED.to_string(), |i| i.friendly_name)
+    }
+}
+
+impl<'writer, S> FormatFields<'writer> for PseudonymFields<S>
+where
+    S: StorageState + Send + 'static,
+{
+    fn format_fields<R: RecordFields>(&self, writer: Writer<'writer>, fields: R) -> fmt::Result {
+        let mut visitor = Visitor {
+            fields: self,
+            writer,
+            result: Ok(()),
+            first: true,
+        };
+        fields.record(&mut visitor);
+        visitor.result
+    }
+}
+
+struct Visitor<'a, 'writer, S> {
+    fields: &'a PseudonymFields<S>,
+    writer: Writer<'writer>,
+    result: fmt::Result,
+    first: bool,
+}
+
+impl<S> Visitor<'_, '_, S>
+where
+    S: StorageState,
+{
+    fn write(&mut self, field: &Field, value: &str) {
+        if self.result.is_err() {
+            return;
+        }
+        let separator = if std::mem::take(&mut self.first) {
+            ""
+        } else {
+            " "
+        };
+        self.result = if self.fields.names.iter().any(|n| n == field.name()) {
+            let name = self.fields.friendly_name(value);
+            write!(self.writer, "{separator}{}={name}", field.name())
+        } else if field.name() == "message" {
+            write!(self.writer, "{separator}{value}")
+        } else {
+            write!(self.writer, "{separator}{}={value}", field.name())
+        };
+    }
+}
+
+impl<S> Visit for Visitor<'_, '_, S>
+where
+    S: StorageState,
+{
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" || self.fields.names.iter().any(|n| n == field.name()) {
+            self.write(field, value);
+        } else {
+            self.write(field, &format!("{value:?}"));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.write(field, &format!("{value:?}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+    use crate::identity::{ConcurrentStore, tests::PERFUME_INGREDIENTS};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pseudonym_fields() {
+        let population = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let mut store = ConcurrentStore::new();
+        let expected = population
+            .identity("a@b.br", &mut store)
+            .unwrap()
+            .friendly_name;
+
+        let buffer = Buffer::default();
+        let fields = PseudonymFields::new(population, store, ["user_email"]);
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .fmt_fields(fields)
+                .with_writer(move || writer.clone())
+                .with_ansi(false),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(user_email = "a@b.br", attempts = 2, "signed in");
+            tracing::info!(user_email = %"a@b.br", "signed out");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        let field = format!("user_email={expected}");
+        assert!(lines[0].ends_with(&format!("signed in {field} attempts=2")));
+        assert!(lines[1].ends_with(&format!("signed out {field}")));
+        assert!(!output.contains("a@b.br"));
+    }
+}
@@ -0,0 +1,410 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+/// A limit on the memory used by caches, shared between all caches it is given to.
+/// When an insert takes the total above the limit, the least recently used entries of all of
+/// those caches are evicted, oldest first, until the total is within the limit again, or the
+/// caches are empty. Sizes are estimates of the heap memory held by each entry.
+/// See [`crate::identity::RemoteStore::with_memory_budget`] and
+/// [`crate::identity::MemoizedPopulation::with_memory_budget`].
+#[derive(Clone)]
+pub struct MemoryBudget(Arc<Budget>);
+
+struct Budget {
+    limit: usize,
+    used: AtomicUsize,
+    // orders the uses of entries across caches
+    clock: AtomicU64,
+    // the entries of each cache, which are dropped along with their cache
+    caches: Mutex<Vec<Weak<dyn Evict>>>,
+}
+
+impl std::fmt::Debug for MemoryBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryBudget")
+            .field("limit", &self.limit())
+            .field("used", &self.used())
+            .finish_non_exhaustive()
+    }
+}
+
+impl MemoryBudget {
+    /// Allow caches to hold up to `limit` bytes in total.
+    pub fn new(limit: usize) -> Self {
+        Self(Arc::new(Budget {
+            limit,
+            used: AtomicUsize::new(0),
+            clock: AtomicU64::new(0),
+            caches: Mutex::default(),
+        }))
+    }
+
+    /// The most bytes that caches may hold.
+    pub fn limit(&self) -> usize {
+        self.0.limit
+    }
+
+    /// The bytes held by caches.
+    pub fn used(&self) -> usize {
+        self.0.used.load(Ordering::Relaxed)
+    }
+
+    fn charge(&self, size: usize) {
+        self.0.used.fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn release(&self, size: usize) {
+        self.0.used.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    fn exceeded(&self) -> bool {
+        self.used() > self.0.limit
+    }
+
+    fn tick(&self) -> u64 {
+        self.0.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn register(&self, entries: Weak<dyn Evict>) {
+        let mut caches = self.0.caches.lock().unwrap();
+        caches.retain(|cache| cache.strong_count() > 0);
+        caches.push(entries);
+    }
+
+    /// Evict the least recently used entry of any cache until the total is within the limit.
+    /// The caller must not hold the entries of any cache.
+    fn reclaim(&self) {
+        let caches = self.0.caches.lock().unwrap();
+        while self.exceeded() {
+            let oldest = caches
+                .iter()
+                .filter_map(Weak::upgrade)
+                .filter_map(|cache| Some((cache.oldest()?, cache)))
+                .min_by_key(|(tick, _cache)| *tick);
+            let Some((_tick, cache)) = oldest else {
+                break;
+            };
+            cache.evict_oldest();
+        }
+    }
+}
+
+/// The entries of a cache, as seen by the [`MemoryBudget`] it shares.
+trait Evict: Send + Sync {
+    /// When the least recently used entry was last used, if there are any entries.
+    fn oldest(&self) -> Option<u64>;
+    /// Drop the least recently used entry.
+    fn evict_oldest(&self);
+}
+
+/// Estimates the heap memory held by an entry.
+type Weigher<K, V> = fn(&K, &V) -> usize;
+
+#[derive(Debug)]
+struct Entries<K, V> {
+    tick: u64,
+    // key -> (value, tick of last use, size)
+    map: HashMap<K, (V, u64, usize)>,
+    // tick of last use -> key
+    order: BTreeMap<u64, K>,
+    budget: Option<MemoryBudget>,
+}
+
+impl<K, V> Entries<K, V>
+where
+    K: Hash + Eq,
+{
+    // ticks are shared by the caches of a budget, so that their entries can be compared
+    fn next_tick(&mut self) -> u64 {
+        match &self.budget {
+            Some(budget) => budget.tick(),
+            None => {
+                self.tick += 1;
+                self.tick
+            }
+        }
+    }
+
+    fn pop_oldest(&mut self) -> Option<()> {
+        let (_tick, oldest) = self.order.pop_first()?;
+        let (_value, _last_used, size) = self.map.remove(&oldest).unwrap();
+        self.release(size);
+        Some(())
+    }
+
+    fn release(&self, size: usize) {
+        if let Some(budget) = &self.budget {
+            budget.release(size);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.release(self.map.values().map(|(_, _, size)| size).sum());
+        self.map.clear();
+        self.order.clear();
+    }
+}
+
+impl<K, V> Evict for Mutex<Entries<K, V>>
+where
+    K: Hash + Eq + Send,
+    V: Send,
+{
+    fn oldest(&self) -> Option<u64> {
+        self.lock().unwrap().order.keys().next().copied()
+    }
+
+    fn evict_oldest(&self) {
+        self.lock().unwrap().pop_oldest();
+    }
+}
+
+/// A map which holds up to `capacity` entries, evicting the least recently used entry first.
+/// Entries are held apart from the map, so that caches sharing a [`MemoryBudget`] can evict
+/// each other's entries.
+#[derive(Debug)]
+pub(crate) struct Lru<K, V> {
+    capacity: usize,
+    entries: Arc<Mutex<Entries<K, V>>>,
+    weigher: Option<Weigher<K, V>>,
+}
+
+impl<K, V> Lru<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Arc::new(Mutex::new(Entries {
+                tick: 0,
+                map: HashMap::default(),
+                order: BTreeMap::default(),
+                budget: None,
+            })),
+            weigher: None,
+        }
+    }
+
+    /// Account for the size of each entry in `budget`, as estimated by `size`.
+    pub fn set_budget(&mut self, budget: &MemoryBudget, size: Weigher<K, V>)
+    where
+        K: Send + 'static,
+        V: Send + 'static,
+    {
+        self.clear();
+        // entries are replaced, so that any budget they were shared with forgets them
+        self.entries = Arc::new(Mutex::new(Entries {
+            tick: 0,
+            map: HashMap::default(),
+            order: BTreeMap::default(),
+            budget: Some(budget.clone()),
+        }));
+        self.weigher = Some(size);
+        let entries: Arc<dyn Evict> = self.entries.clone();
+        budget.register(Arc::downgrade(&entries));
+    }
+
+    /// Apply `f` to the value of `key`, marking it as the most recently used entry.
+    pub fn get_with<Q, R>(&mut self, key: &Q, f: impl FnOnce(&V) -> R) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut entries = self.entries.lock().unwrap();
+        let tick = entries.next_tick();
+        let entries = &mut *entries;
+        let (value, last_used, _size) = entries.map.get_mut(key)?;
+        // the stored key is reused, so that lookups by a borrowed key don't allocate
+        let key = entries.order.remove(last_used).unwrap();
+        *last_used = tick;
+        entries.order.insert(tick, key);
+        Some(f(value))
+    }
+
+    pub fn get<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.get_with(key, V::clone)
+    }
+
+    /// Change the value of `key` with `f`, marking it as the most recently used entry, and
+    /// accounting for its new size. Returns false if there is no entry for `key`.
+    pub fn update<Q>(&mut self, key: &Q, f: impl FnOnce(&mut V)) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut guard = self.entries.lock().unwrap();
+        let tick = guard.next_tick();
+        let entries = &mut *guard;
+        let Some((value, last_used, size)) = entries.map.get_mut(key) else {
+            return false;
+        };
+        f(value);
+        let key = entries.order.remove(last_used).unwrap();
+        *last_used = tick;
+        if let (Some(budget), Some(weigher)) = (&entries.budget, self.weigher) {
+            let resized = weigher(&key, value);
+            budget.charge(resized);
+            budget.release(*size);
+            *size = resized;
+        }
+        entries.order.insert(tick, key);
+        let budget = entries.budget.clone();
+        drop(guard);
+        if let Some(budget) = budget {
+            budget.reclaim();
+        }
+        true
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        let size = self.weigher.map_or(0, |size| size(&key, &value));
+        let mut guard = self.entries.lock().unwrap();
+        let tick = guard.next_tick();
+        let entries = &mut *guard;
+        if let Some(budget) = &entries.budget {
+            budget.charge(size);
+        }
+        if let Some((_value, last_used, size)) =
+            entries.map.insert(key.clone(), (value, tick, size))
+        {
+            entries.order.remove(&last_used);
+            entries.release(size);
+        }
+        entries.order.insert(tick, key);
+        while entries.map.len() > self.capacity {
+            entries.pop_oldest();
+        }
+        let budget = entries.budget.clone();
+        drop(guard);
+        if let Some(budget) = budget {
+            budget.reclaim();
+        }
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some((_value, last_used, size)) = entries.map.remove(key) {
+            entries.order.remove(&last_used);
+            entries.release(size);
+        }
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.entries.lock().unwrap().map.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().map.len()
+    }
+
+    /// Copies of the entries, from the least to the most recently used.
+    #[cfg_attr(not(feature = "bincode"), allow(dead_code))]
+    pub fn entries(&self) -> Vec<(K, V)>
+    where
+        V: Clone,
+    {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .order
+            .values()
+            .map(|key| (key.clone(), entries.map[key].0.clone()))
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+impl<K, V> Drop for Lru<K, V> {
+    fn drop(&mut self) {
+        // a budget which is evicting entries may still hold them
+        if let Ok(mut entries) = self.entries.lock() {
+            let size = entries.map.values().map(|(_, _, size)| size).sum();
+            if let Some(budget) = &entries.budget {
+                budget.release(size);
+            }
+            entries.map = HashMap::new();
+            entries.order = BTreeMap::new();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut lru = Lru::new(2);
+        lru.insert("a", 1);
+        lru.insert("b", 2);
+        assert_eq!(lru.get(&"a"), Some(1));
+        lru.insert("c", 3);
+        assert_eq!(lru.len(), 2);
+        assert_eq!(lru.get(&"b"), None);
+        assert_eq!(lru.get(&"a"), Some(1));
+        assert_eq!(lru.get(&"c"), Some(3));
+    }
+
+    #[test]
+    fn test_replace_existing() {
+        let mut lru = Lru::new(2);
+        lru.insert("a", 1);
+        lru.insert("a", 2);
+        assert_eq!(lru.len(), 1);
+        assert_eq!(lru.get(&"a"), Some(2));
+    }
+
+    #[test]
+    fn test_shared_budget() {
+        let budget = MemoryBudget::new(10);
+        let mut first = Lru::new(100);
+        first.set_budget(&budget, |_k: &&str, v: &usize| *v);
+        let mut second = Lru::new(100);
+        second.set_budget(&budget, |_k: &&str, v: &usize| *v);
+
+        first.insert("a", 4);
+        first.insert("b", 4);
+        assert_eq!(budget.used(), 8);
+
+        // the least recently used entry of any cache is evicted
+        second.insert("c", 4);
+        assert_eq!((first.len(), second.len()), (1, 1));
+        assert_eq!(first.get(&"a"), None);
+        assert_eq!(budget.used(), 8);
+
+        assert_eq!(second.get(&"c"), Some(4));
+        first.insert("d", 3);
+        assert_eq!(first.get(&"b"), None);
+        assert_eq!(budget.used(), 7);
+
+        // values which grow are charged again
+        assert!(second.update(&"c", |v| *v = 8));
+        assert_eq!((first.len(), second.len()), (0, 1));
+        assert_eq!(budget.used(), 8);
+        assert!(!first.update(&"a", |v| *v = 1));
+        drop(second);
+        assert_eq!(budget.used(), 0);
+    }
+}
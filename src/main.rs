@@ -1,23 +1,50 @@
 #[cfg(feature = "codegen")]
 use perfume::codegen;
 
-#[cfg(feature = "codegen")]
+#[cfg(feature = "cli")]
+mod cli;
+
 fn main() {
+    #[cfg(feature = "cli")]
+    if std::env::args_os().len() > 1 {
+        cli::run().unwrap_or_else(|e| {
+            eprintln!("{e}");
+            std::process::exit(1)
+        });
+        return;
+    }
+
+    #[cfg(feature = "codegen")]
+    generate_ingredients();
+}
+
+#[cfg(feature = "codegen")]
+fn generate_ingredients() {
     let tmp_dir = std::env::var("TMPDIR").unwrap_or("/tmp".to_string());
     let output_path = format!("{tmp_dir}/perfume.rs");
 
     // normally this is in build.rs
     // implemented for the purpose of automated testing
-    codegen::ingredients(
+    codegen::ingredients_with_progress(
         "PERFUME_INGREDIENTS",
         perfume::codegen::PopulationSize::Brazil,
         "data/gerunds.txt",
         "data/colors.txt",
         "data/animals.txt",
-        output_path,
+        &output_path,
+        |progress| match progress {
+            codegen::Progress::FileRead { path, words } => {
+                eprintln!("read {words} words from {}", path.display())
+            }
+            codegen::Progress::WordsProcessed { count, total } => {
+                eprint!("\rwrote {count}/{total} words")
+            }
+            codegen::Progress::BytesWritten(bytes) => {
+                eprintln!("\nwrote {bytes} bytes to {output_path}")
+            }
+            codegen::Progress::Warning(warning) => eprintln!("warning: {warning}"),
+            _ => {}
+        },
     )
     .unwrap_or_else(|e| panic!("{e}"));
 }
-
-#[cfg(not(feature = "codegen"))]
-fn main() {}
@@ -0,0 +1,355 @@
+//! Consolidation of the name assignments of domains which share a store.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use async_generic::async_generic;
+use bytes::Bytes;
+
+use crate::hex_string::HexString;
+use crate::identity::{
+    ConnectionBridge, RecordFlag, RemoteStore, malformed, parse_record, preamble_lines,
+    split_format, split_version, storage_keys, text_record, version_line,
+};
+use crate::{Error, Operation, STORAGE_KEY_LENGTH};
+
+/// Why a digest of the source domain has another offset in the target domain, see [`Remap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemapReason {
+    /// The digest was already assigned another offset in the target domain, so its identifier
+    /// keeps the name it has there.
+    Collision,
+    /// The digest was deleted in the target domain, and stays deleted.
+    Deleted,
+    /// The offset was assigned to another digest in the target domain, or would leave offsets
+    /// unassigned before it, so the digest is assigned the next offset of the target blob.
+    OffsetUnavailable,
+}
+
+/// A digest of the source domain whose offset, and so its name, changes in the target domain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Remap {
+    /// The storage key of the blob holding the digest.
+    pub key: HexString<STORAGE_KEY_LENGTH>,
+    /// The digest, with as many characters as the target blob holds.
+    pub digest: String,
+    /// The offset of the digest in the source domain.
+    pub from: usize,
+    /// The offset of the digest in the target domain.
+    pub to: usize,
+    /// Why the offset changed.
+    pub reason: RemapReason,
+}
+
+/// The outcome of [`merge`] or [`plan_merge`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergeReport {
+    /// Blobs of the target domain which gained records.
+    pub blobs: usize,
+    /// Digests of the source domain which were added with the same offset, and so keep their names.
+    pub kept: usize,
+    /// Digests which were already assigned the same offset in both domains.
+    pub shared: usize,
+    /// Digests of the source domain whose offsets change.
+    pub remapped: Vec<Remap>,
+    /// Tombstones of the source domain, which are not merged.
+    pub tombstones: usize,
+}
+
+/// Add the assignments of `source_domain` to those of `target_domain`, for domains which were
+/// split by mistake and share their secret and ingredients, so that the names of an identifier
+/// only depend on its offset. Both domains are read from the bridge of `store`, whose
+/// [`crate::identity::KeyTemplate`] must place the blobs of each domain apart, such as with
+/// `{domain}/{key}`. The blobs of `store` are expected in its blob format.
+///
+/// Assignments of the target domain never change. A digest of the source domain keeps its
+/// offset when that is the next offset of its target blob, and is otherwise assigned the next
+/// offset, see [`MergeReport::remapped`]. The source domain is left as it was. Stores of the
+/// target domain should not assign offsets during the merge, since their writes could be lost.
+#[async_generic]
+pub fn merge<B>(
+    source_domain: &str,
+    target_domain: &str,
+    store: &RemoteStore<B>,
+) -> Result<MergeReport, Error>
+where
+    B: ConnectionBridge + Send,
+{
+    if _async {
+        merge_domains_async(source_domain, target_domain, store, true).await
+    } else {
+        merge_domains(source_domain, target_domain, store, true)
+    }
+}
+
+/// The report of [`merge`], without writing any blobs.
+#[async_generic]
+pub fn plan_merge<B>(
+    source_domain: &str,
+    target_domain: &str,
+    store: &RemoteStore<B>,
+) -> Result<MergeReport, Error>
+where
+    B: ConnectionBridge + Send,
+{
+    if _async {
+        merge_domains_async(source_domain, target_domain, store, false).await
+    } else {
+        merge_domains(source_domain, target_domain, store, false)
+    }
+}
+
+#[async_generic]
+#[allow(unused_assignments)]
+fn merge_domains<B>(
+    source_domain: &str,
+    target_domain: &str,
+    store: &RemoteStore<B>,
+    write: bool,
+) -> Result<MergeReport, Error>
+where
+    B: ConnectionBridge + Send,
+{
+    let invalid = |message: &str| -> Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, message.to_string()).into()
+    };
+    let Some(template) = store.key_template() else {
+        return Err(invalid(
+            "domains can only be merged by a store with a key template",
+        ));
+    };
+    let (source, target) = (
+        template.for_domain(source_domain),
+        template.for_domain(target_domain),
+    );
+    if source.apply(PROBE_KEY) == target.apply(PROBE_KEY) {
+        return Err(invalid(
+            "the key template places both domains at the same keys",
+        ));
+    }
+
+    let format = store.blob_format();
+    let mut report = MergeReport::default();
+    for key in storage_keys() {
+        let key = key.as_str();
+        let (source_key, target_key) = (source.apply(key), target.apply(key));
+        let (mut source_blob, mut target_blob): (Option<Bytes>, Option<Bytes>) = (None, None);
+        let get = |e| Error::storage(e, key, Operation::Get);
+        if _async {
+            source_blob = store.bridge.get_async(&source_key).await.map_err(get)?;
+            if source_blob.is_some() {
+                target_blob = store.bridge.get_async(&target_key).await.map_err(get)?;
+            }
+        } else {
+            source_blob = store.bridge.get(&source_key).map_err(get)?;
+            if source_blob.is_some() {
+                target_blob = store.bridge.get(&target_key).map_err(get)?;
+            }
+        }
+        let Some(source_blob) = source_blob else {
+            continue;
+        };
+
+        let parse = |e| Error::storage(e, key, Operation::Parse).in_domain(source_domain);
+        let source_records =
+            records(&format.decode(&source_blob).map_err(parse)?).map_err(parse)?;
+        let parse = |e| Error::storage(e, key, Operation::Parse).in_domain(target_domain);
+        let target_text = match &target_blob {
+            Some(blob) => format.decode(blob).map_err(parse)?,
+            None => Bytes::new(),
+        };
+        let Some(merged) = merge_blob(key, &source_records, &target_text, &mut report)? else {
+            continue;
+        };
+        report.blobs += 1;
+        if !write {
+            continue;
+        }
+
+        let put = |e| Error::storage(e, key, Operation::Put).in_domain(target_domain);
+        let encoded = format.encode(&merged).map_err(put)?;
+        if _async {
+            store
+                .bridge
+                .put_async(&target_key, encoded)
+                .await
+                .map_err(put)?;
+        } else {
+            store.bridge.put(&target_key, encoded).map_err(put)?;
+        }
+    }
+    Ok(report)
+}
+
+// a storage key for comparing the keys of two domains
+const PROBE_KEY: &str = "000";
+
+type Record = (String, RecordFlag, usize);
+
+fn records(text: &[u8]) -> std::io::Result<Vec<Record>> {
+    let (_version, version_length) = split_version(text)?;
+    let first_record = preamble_lines(&text[..version_length]);
+    String::from_utf8_lossy(&text[version_length..])
+        .lines()
+        .enumerate()
+        .map(|(number, line)| {
+            let number = number + first_record;
+            parse_record(line)
+                .map(|(digest, flag, offset)| (digest.to_string(), flag, offset))
+                .ok_or_else(|| malformed(number, format!("malformed record on line {number}")))
+        })
+        .collect()
+}
+
+// the target blob with the records of the source blob, or None if it is unchanged
+fn merge_blob(
+    key: &str,
+    source: &[Record],
+    target_text: &[u8],
+    report: &mut MergeReport,
+) -> Result<Option<Bytes>, Error> {
+    let parse = |e| Error::storage(e, key, Operation::Parse);
+    let target = records(target_text).map_err(parse)?;
+    // every record of a blob has the same length, see RemoteStore::with_digest_length
+    let digest_length = target
+        .first()
+        .or(source.first())
+        .map_or(0, |(digest, _, _)| digest.len());
+    if source
+        .iter()
+        .any(|(digest, _, _)| digest.len() < digest_length)
+    {
+        let message = format!(
+            "blob {key} of the source domain holds shorter digests than the target domain, \
+            which can be narrowed to the same length with narrow_blob"
+        );
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, message).into());
+    }
+
+    let mut offsets: BTreeSet<usize> = target.iter().map(|(_, _, offset)| *offset).collect();
+    let mut merged: BTreeMap<String, (RecordFlag, usize)> = target
+        .into_iter()
+        .map(|(digest, flag, offset)| (digest, (flag, offset)))
+        .collect();
+    let mut next = offsets.last().map_or(0, |offset| offset + 1);
+    let mut changed = false;
+
+    let mut source = source.iter().collect::<Vec<_>>();
+    source.sort_by_key(|(_, _, offset)| *offset);
+    for (digest, flag, from) in source {
+        if flag.is_deleted() {
+            report.tombstones += 1;
+            continue;
+        }
+        let digest = &digest[..digest_length];
+        let (to, reason) = match merged.get(digest) {
+            Some((_, to)) if to == from => {
+                report.shared += 1;
+                continue;
+            }
+            Some((flag, to)) if flag.is_deleted() => (*to, RemapReason::Deleted),
+            Some((_, to)) => (*to, RemapReason::Collision),
+            None => {
+                let to = next;
+                next += 1;
+                offsets.insert(to);
+                merged.insert(digest.to_string(), (RecordFlag::Live, to));
+                changed = true;
+                if to == *from {
+                    report.kept += 1;
+                    continue;
+                }
+                (to, RemapReason::OffsetUnavailable)
+            }
+        };
+        report.remapped.push(Remap {
+            key: HexString::from(key.as_bytes()),
+            digest: digest.to_string(),
+            from: *from,
+            to,
+            reason,
+        });
+    }
+
+    // a versioned target blob counts the merge as a change, and keeps its format line
+    let (_format, format_length) = split_format(target_text).map_err(parse)?;
+    let (version, version_length) = split_version(target_text).map_err(parse)?;
+    let mut header = String::from_utf8_lossy(&target_text[..format_length]).into_owned();
+    if version_length > format_length {
+        header.push_str(&version_line(version + 1));
+    }
+    Ok(changed.then(|| {
+        let records = merged
+            .iter()
+            .map(|(digest, (flag, offset))| text_record(digest, *flag, *offset));
+        std::iter::once(header)
+            .chain(records)
+            .collect::<String>()
+            .into()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::{ConnectionBridge, KeyTemplate, Population, StorageState, tests::*};
+
+    #[test]
+    fn test_merge() -> Result<(), Error> {
+        let secret = b"0123456789abcdef0123456789abcdef";
+        let template = |domain| KeyTemplate::new("{domain}/{key}", domain);
+        let mut staging =
+            RemoteStore::new(MockBridge::default()).with_key_template(template("staging")?);
+        let staging_population = Population::new("staging", secret, &PERFUME_INGREDIENTS)?;
+        let shared = staging_population.identity("shared@b.br", &mut staging)?;
+        let moved = staging_population.identity("moved@b.br", &mut staging)?;
+
+        let mut production =
+            RemoteStore::new(MockBridge::default()).with_key_template(template("production")?);
+        for (key, blob) in staging.export()?.blobs {
+            let key = staging.bridge_key(key.as_str());
+            production.bridge.put(&key, blob)?;
+        }
+        let population = Population::new("production", secret, &PERFUME_INGREDIENTS)?;
+        let existing = population.identity("existing@b.br", &mut production)?;
+        assert_eq!(
+            population
+                .identity("shared@b.br", &mut production)?
+                .friendly_name,
+            shared.friendly_name
+        );
+
+        let plan = plan_merge("staging", "production", &production)?;
+        assert_eq!(plan.shared, 1);
+        assert_eq!(plan_merge("staging", "production", &production)?, plan);
+        let report = merge("staging", "production", &production)?;
+        assert_eq!(report, plan);
+        assert_eq!(report.blobs, 1);
+
+        // names of the target domain never change
+        assert_eq!(
+            population.identity("existing@b.br", &mut production)?,
+            existing
+        );
+        let merged = population.identity("moved@b.br", &mut production)?;
+        match report.remapped.as_slice() {
+            [] => assert_eq!((report.kept, merged.offset), (1, moved.offset)),
+            [remap] => {
+                assert_eq!(remap.reason, RemapReason::OffsetUnavailable);
+                assert_eq!((remap.from, remap.to), (moved.offset, merged.offset));
+            }
+            remapped => panic!("unexpected remaps {remapped:?}"),
+        }
+
+        // merging again changes nothing
+        let again = merge("staging", "production", &production)?;
+        assert_eq!((again.blobs, again.shared), (0, 2));
+
+        let unplaced = RemoteStore::new(MockBridge::default());
+        assert!(merge("staging", "production", &unplaced).is_err());
+        assert_eq!(
+            production.digest_offset("production", &merged.storage)?,
+            merged.offset
+        );
+        Ok(())
+    }
+}
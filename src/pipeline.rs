@@ -0,0 +1,254 @@
+//! Pseudonymization of JSON record streams, such as the events of a Kafka topic.
+//! Requires the `pipeline` feature.
+//!
+//! ```no_run
+//! # fn example(
+//! #     population: perfume::identity::Population,
+//! #     records: Vec<serde_json::Value>,
+//! # ) -> Result<(), perfume::Error> {
+//! use perfume::identity::ConcurrentStore;
+//! use perfume::pipeline::Pseudonymizer;
+//!
+//! let mut pseudonymizer = Pseudonymizer::new(population, ConcurrentStore::new(), "/user/email");
+//! for record in records {
+//!     for record in pseudonymizer.push(record)? {
+//!         println!("{record}");
+//!     }
+//! }
+//! for record in pseudonymizer.flush()? {
+//!     println!("{record}");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+
+use async_generic::async_generic;
+use serde_json::Value;
+
+use crate::Error;
+use crate::identity::{Population, StorageState};
+
+/// Records are rewritten in batches of this many by default.
+pub const DEFAULT_BATCH_SIZE: usize = 1000;
+
+/// Rewrites one field of each record to the friendly name of its value.
+/// Records are held until a batch is complete, and each distinct identifier of a batch is
+/// resolved once. Records are returned in the order they were pushed.
+pub struct Pseudonymizer<'dom, S> {
+    population: Population<'dom>,
+    state: S,
+    pointer: String,
+    batch_size: usize,
+    batch: Vec<Value>,
+}
+
+impl<S> std::fmt::Debug for Pseudonymizer<'_, S> {
+    /// Shows the number of pending records, never their contents.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pseudonymizer")
+            .field("population", &self.population)
+            .field("pointer", &self.pointer)
+            .field("batch_size", &self.batch_size)
+            .field("pending", &self.batch.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'dom, S> Pseudonymizer<'dom, S>
+where
+    S: StorageState + Send,
+{
+    /// Rewrite the field at `pointer`, a JSON pointer such as "/user/email", using `population`,
+    /// which persists identities using `state`. Records without the field, or where it is null,
+    /// are passed on unchanged. Other values which are not strings are identified by their JSON.
+    pub fn new(population: Population<'dom>, state: S, pointer: &str) -> Self {
+        Self {
+            population,
+            state,
+            pointer: pointer.to_string(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            batch: vec![],
+        }
+    }
+
+    /// Rewrite records in batches of `batch_size`.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Add `record` to the current batch, returning the rewritten batch once it is complete.
+    #[async_generic]
+    pub fn push(&mut self, record: Value) -> Result<Vec<Value>, Error> {
+        self.batch.push(record);
+        if self.batch.len() < self.batch_size {
+            return Ok(vec![]);
+        }
+        if _async {
+            self.flush_async().await
+        } else {
+            self.flush()
+        }
+    }
+
+    /// Rewrite and return the current batch, even if it is incomplete. Its identifiers are
+    /// resolved together, see [`StorageState::digest_offsets`].
+    /// If this fails, the batch is kept so that it can be retried.
+    #[async_generic]
+    #[allow(unused_assignments)]
+    pub fn flush(&mut self) -> Result<Vec<Value>, Error> {
+        let mut identifiers = self
+            .batch
+            .iter()
+            .filter_map(|record| identifier(record.pointer(&self.pointer)?))
+            .collect::<Vec<_>>();
+        identifiers.sort_unstable();
+        identifiers.dedup();
+
+        let mut identities = vec![];
+        if _async {
+            identities = self
+                .population
+                .identities_async(&identifiers, &mut self.state)
+                .await?;
+        } else {
+            identities = self.population.identities(&identifiers, &mut self.state)?;
+        }
+        let names = identifiers
+            .into_iter()
+            .zip(identities.into_iter().map(|i| i.friendly_name))
+            .collect::<HashMap<_, _>>();
+
+        let mut batch = std::mem::take(&mut self.batch);
+        for record in &mut batch {
+            let Some(field) = record.pointer_mut(&self.pointer) else {
+                continue;
+            };
+            if let Some(name) = identifier(field).and_then(|id| names.get(&id)) {
+                *field = Value::String(name.clone());
+            }
+        }
+        Ok(batch)
+    }
+}
+
+fn identifier(field: &Value) -> Option<String> {
+    match field {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use bytes::Bytes;
+    use serde_json::json;
+
+    use super::*;
+    use crate::identity::{ConcurrentStore, ConnectionBridge, RemoteStore, tests::*};
+
+    #[test]
+    fn test_pseudonymizer() -> Result<(), Error> {
+        let population = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let store = ConcurrentStore::new();
+        let expected = population.identity("a@b.br", &mut &store)?.friendly_name;
+
+        let mut pseudonymizer =
+            Pseudonymizer::new(population, &store, "/user/email").with_batch_size(3);
+        let records = [
+            json!({"user": {"email": "a@b.br"}, "n": 1}),
+            json!({"user": {"email": null}, "n": 2}),
+            json!({"n": 3}),
+            json!({"user": {"email": "a@b.br"}, "n": 4}),
+        ];
+        let mut rewritten = vec![];
+        for (i, record) in records.iter().cloned().enumerate() {
+            let batch = pseudonymizer.push(record)?;
+            assert_eq!(batch.len(), if i == 2 { 3 } else { 0 });
+            rewritten.extend(batch);
+        }
+        rewritten.extend(pseudonymizer.flush()?);
+        assert!(pseudonymizer.flush()?.is_empty());
+
+        assert_eq!(rewritten.len(), records.len());
+        assert_eq!(rewritten[0], json!({"user": {"email": expected}, "n": 1}));
+        assert_eq!(rewritten[1..3], records[1..3]);
+        assert_eq!(rewritten[3], json!({"user": {"email": expected}, "n": 4}));
+        assert_eq!(store.len(), 1);
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct CountingBridge {
+        inner: MockBridge,
+        puts: AtomicUsize,
+    }
+
+    impl ConnectionBridge for CountingBridge {
+        #[async_generic]
+        fn get(&self, key: &str) -> std::io::Result<Option<Bytes>> {
+            self.inner.get(key)
+        }
+
+        #[async_generic]
+        fn put(&self, key: &str, body: Bytes) -> std::io::Result<()> {
+            self.puts.fetch_add(1, Ordering::Relaxed);
+            self.inner.put(key, body)
+        }
+    }
+
+    #[test]
+    fn test_flush_writes_once() -> Result<(), Error> {
+        let brazilian = || {
+            Population::new(
+                "br",
+                b"0123456789abcdef0123456789abcdef",
+                &PERFUME_INGREDIENTS,
+            )
+        };
+        let population = brazilian()?;
+        // distinct identifiers which share a storage key
+        let key = population.storage_object("0@b.br").key;
+        let identifiers = (0..)
+            .map(|i| format!("{i}@b.br"))
+            .filter(|identifier| population.storage_object(identifier).key == key)
+            .take(5)
+            .collect::<Vec<_>>();
+        let records = identifiers
+            .iter()
+            .map(|identifier| json!({"user": {"email": identifier}}))
+            .collect::<Vec<_>>();
+
+        let store = RemoteStore::new(CountingBridge::default());
+        let mut pseudonymizer = Pseudonymizer::new(population, store, "/user/email");
+        for record in records.iter().cloned() {
+            assert!(pseudonymizer.push(record)?.is_empty());
+        }
+        let rewritten = pseudonymizer.flush()?;
+        assert_eq!(rewritten.len(), records.len());
+        assert_eq!(pseudonymizer.state.bridge.puts.load(Ordering::Relaxed), 1);
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        let store = RemoteStore::new(CountingBridge::default());
+        let mut pseudonymizer = Pseudonymizer::new(brazilian()?, store, "/user/email");
+        for record in records.iter().cloned() {
+            assert!(
+                runtime
+                    .block_on(pseudonymizer.push_async(record))?
+                    .is_empty()
+            );
+        }
+        assert_eq!(runtime.block_on(pseudonymizer.flush_async())?, rewritten);
+        assert_eq!(pseudonymizer.state.bridge.puts.load(Ordering::Relaxed), 1);
+        Ok(())
+    }
+}
@@ -1,18 +1,28 @@
+use std::collections::HashSet;
+
 use rand::distr::{Distribution, Uniform};
 use rand_chacha::{ChaCha12Rng, rand_core::SeedableRng};
 
 /// this function is idempotent. given the same parameters, always returns the same result
+#[cfg(any(feature = "codegen", test))]
 pub fn randomized<'a>(slices: &'a [&str], rng_seed: u64) -> Vec<&'a str> {
+    randomized_prefix(slices, rng_seed, slices.len())
+}
+
+/// the first `count` words of `randomized(slices, rng_seed)`, without randomizing the rest
+pub fn randomized_prefix<'a>(slices: &'a [&str], rng_seed: u64, count: usize) -> Vec<&'a str> {
+    let count = count.min(slices.len());
     let mut rng = ChaCha12Rng::seed_from_u64(rng_seed);
     let mut idxs = Uniform::new(0, slices.len()).unwrap().sample_iter(&mut rng);
-    let mut randomized: Vec<&str> = Vec::with_capacity(slices.len());
+    let mut randomized: Vec<&str> = Vec::with_capacity(count);
+    let mut used: HashSet<&str> = HashSet::with_capacity(count);
 
     // idxs is from a uniform distribution, but can sample the same value more than once
     // therefore a loop is needed to ensure that every word is eventually used
-    while randomized.len() < slices.len() {
+    while randomized.len() < count {
         let idx = idxs.next().unwrap();
-        let word = &slices[idx];
-        if !randomized.contains(word) {
+        let word = slices[idx];
+        if used.insert(word) {
             randomized.push(word);
         }
     }
@@ -43,5 +53,9 @@ mod tests {
             }
             last_result = this_result;
         }
+        assert_eq!(
+            randomized_prefix(words.as_slice(), rng_seed, 100),
+            last_result[..100]
+        );
     }
 }
@@ -0,0 +1,402 @@
+//! The blob protocol described by openapi/perfume.json, served from any [`ConnectionBridge`]
+//! by an [axum](https://crates.io/crates/axum) router, so that a storage endpoint for
+//! `RemoteStore`s using HTTP can be hosted inside an existing service.
+//!
+//! ```no_run
+//! # fn example(bridge: impl perfume::identity::ConnectionBridge + Send + Sync + 'static) {
+//! use axum::Router;
+//!
+//! // blobs of every domain are stored at "<domain>/<key>" of the bridge
+//! let app: Router = Router::new().nest("/blobs", perfume::server::router(bridge));
+//! # }
+//! ```
+
+use std::ops::Range;
+use std::sync::Arc;
+
+use axum::Router;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use bytes::Bytes;
+use tokio::sync::Mutex;
+
+use crate::STORAGE_KEY_LENGTH;
+use crate::identity::{ConnectionBridge, Validated, slice_range};
+
+/// Routes `GET`, `HEAD` and `PUT` requests for `/{domain}/{key}` to `bridge`, which stores
+/// each blob at `{domain}/{key}`, as the command line interface expects of its store.
+///
+/// Blobs which `bridge` returns without a validator are given an ETag derived from their
+/// contents, so that clients can always revalidate their cached blobs. Writes with an
+/// `If-Match` ETag, or with `If-None-Match: *` for blobs which must not exist yet, fail with
+/// `412 Precondition Failed` once the blob has changed; they are checked one at a time, and
+/// passed on to [`ConnectionBridge::put_if_match`] so that bridges shared by several servers
+/// can check them too. Reads of a single range of bytes are answered with `206 Partial Content`
+/// from [`ConnectionBridge::get_range`], or from the whole blob if the bridge cannot read
+/// ranges. Requests for keys which are not storage keys are rejected. Other errors of `bridge`
+/// are logged, and answered with `500 Internal Server Error` without describing them. Blobs are
+/// limited to the size allowed by axum's `DefaultBodyLimit`, which can be raised by a layer of
+/// the router.
+pub fn router<B>(bridge: B) -> Router
+where
+    B: ConnectionBridge + Send + Sync + 'static,
+{
+    Router::new()
+        .route(
+            "/{domain}/{key}",
+            get(get_blob::<B>).head(head_blob::<B>).put(put_blob::<B>),
+        )
+        .with_state(Arc::new(Shared {
+            bridge,
+            conditional_writes: Mutex::new(()),
+        }))
+}
+
+struct Shared<B> {
+    bridge: B,
+    // held while the precondition of a write is checked and the blob is written
+    conditional_writes: Mutex<()>,
+}
+
+type BlobPath = Path<(String, String)>;
+
+async fn get_blob<B>(
+    State(shared): State<Arc<Shared<B>>>,
+    Path((domain, key)): BlobPath,
+    headers: HeaderMap,
+) -> Response
+where
+    B: ConnectionBridge + Send + Sync,
+{
+    let key = match bridge_key(&domain, &key) {
+        Ok(key) => key,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|value| byte_range(value.to_str().ok()?));
+    if let Some(range) = range {
+        return get_blob_range(&shared.bridge, &key, range).await;
+    }
+    let if_none_match = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok());
+    let (body, validator) = match shared.bridge.get_validated_async(&key, if_none_match).await {
+        Ok(Validated::NotModified) => return StatusCode::NOT_MODIFIED.into_response(),
+        Ok(Validated::Modified {
+            body: Some(body),
+            validator,
+        }) => (body, validator),
+        Ok(Validated::Modified { body: None, .. }) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => return internal_error(e),
+    };
+
+    let etag = validator.unwrap_or_else(|| content_etag(&body));
+    if if_none_match == Some(etag.as_str()) {
+        return StatusCode::NOT_MODIFIED.into_response();
+    }
+    let content_type = HeaderValue::from_static("application/octet-stream");
+    let mut response =
+        (StatusCode::OK, [(header::CONTENT_TYPE, content_type)], body).into_response();
+    if let Ok(etag) = HeaderValue::from_str(&etag) {
+        response.headers_mut().insert(header::ETAG, etag);
+    }
+    response
+}
+
+async fn get_blob_range<B>(bridge: &B, key: &str, range: Range<u64>) -> Response
+where
+    B: ConnectionBridge + Send + Sync,
+{
+    let read = match bridge.get_range_async(key, range.clone()).await {
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+            let body = bridge.get_async(key).await;
+            body.map(|body| body.map(|body| (slice_range(&body, &range), body.len() as u64)))
+        }
+        read => read,
+    };
+    let (bytes, length) = match read {
+        Ok(Some(read)) => read,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => return internal_error(e),
+    };
+    if bytes.is_empty() {
+        let content_range = format!("bytes */{length}");
+        return (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(header::CONTENT_RANGE, content_range)],
+        )
+            .into_response();
+    }
+    let last = range.start + bytes.len() as u64 - 1;
+    let headers = [
+        (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+        (
+            header::CONTENT_RANGE,
+            format!("bytes {}-{last}/{length}", range.start),
+        ),
+    ];
+    (StatusCode::PARTIAL_CONTENT, headers, bytes).into_response()
+}
+
+// the bytes requested by a Range header of one range, such as `bytes=0-67` or `bytes=68-`.
+// Other ranges are ignored, and answered with the whole blob.
+fn byte_range(value: &str) -> Option<Range<u64>> {
+    let (first, last) = value.strip_prefix("bytes=")?.split_once('-')?;
+    let first = first.trim().parse().ok()?;
+    let end = match last.trim() {
+        "" => u64::MAX,
+        last => last.parse::<u64>().ok()?.checked_add(1)?,
+    };
+    (first < end).then_some(first..end)
+}
+
+async fn head_blob<B>(
+    State(shared): State<Arc<Shared<B>>>,
+    Path((domain, key)): BlobPath,
+) -> Response
+where
+    B: ConnectionBridge + Send + Sync,
+{
+    let key = match bridge_key(&domain, &key) {
+        Ok(key) => key,
+        Err(rejection) => return rejection.into_response(),
+    };
+    match shared.bridge.exists_async(&key).await {
+        Ok(true) => StatusCode::OK.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+async fn put_blob<B>(
+    State(shared): State<Arc<Shared<B>>>,
+    Path((domain, key)): BlobPath,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response
+where
+    B: ConnectionBridge + Send + Sync,
+{
+    let key = match bridge_key(&domain, &key) {
+        Ok(key) => key,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let header_value = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let if_match = header_value(header::IF_MATCH);
+    let if_absent = header_value(header::IF_NONE_MATCH) == Some("*");
+    if if_match.is_none() && !if_absent {
+        return match shared.bridge.put_async(&key, body).await {
+            Ok(()) => StatusCode::OK.into_response(),
+            Err(e) => internal_error(e),
+        };
+    }
+
+    let _guard = shared.conditional_writes.lock().await;
+    let (current, validator) = match shared.bridge.get_validated_async(&key, None).await {
+        Ok(Validated::Modified { body, validator }) => (body, validator),
+        Ok(Validated::NotModified) => unreachable!("blobs without a validator are modified"),
+        Err(e) => return internal_error(e),
+    };
+    let etag = current
+        .as_ref()
+        .map(|current| validator.clone().unwrap_or_else(|| content_etag(current)));
+    let precondition = match if_match {
+        Some(if_match) => etag.as_deref() == Some(if_match),
+        None => etag.is_none(),
+    };
+    if !precondition {
+        return StatusCode::PRECONDITION_FAILED.into_response();
+    }
+    let result = match (&current, validator) {
+        (None, _) => shared.bridge.put_if_match_async(&key, body, None).await,
+        (Some(_), Some(validator)) => {
+            shared
+                .bridge
+                .put_if_match_async(&key, body, Some(&validator))
+                .await
+        }
+        (Some(_), None) => shared.bridge.put_async(&key, body).await,
+    };
+    match result {
+        Ok(()) => StatusCode::OK.into_response(),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            StatusCode::PRECONDITION_FAILED.into_response()
+        }
+        Err(e) => internal_error(e),
+    }
+}
+
+// the key of the blob in the bridge, if `key` matches the pattern of the protocol:
+// a storage key, or the copy of a corrupt blob, "<key>.corrupt-<seconds since the epoch>"
+fn bridge_key(domain: &str, key: &str) -> Result<String, (StatusCode, String)> {
+    let (storage_key, suffix) = key
+        .split_at_checked(STORAGE_KEY_LENGTH)
+        .unwrap_or((key, ""));
+    let valid_key = storage_key.len() == STORAGE_KEY_LENGTH
+        && storage_key
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        && (suffix.is_empty()
+            || suffix.strip_prefix(".corrupt-").is_some_and(|seconds| {
+                !seconds.is_empty() && seconds.bytes().all(|b| b.is_ascii_digit())
+            }));
+    if !valid_key {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("invalid storage key {key:?}"),
+        ));
+    }
+    // bridges which store blobs as files must not be given relative paths
+    if domain.is_empty() || domain == "." || domain == ".." {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("invalid domain {domain:?}"),
+        ));
+    }
+    Ok(format!("{domain}/{key}"))
+}
+
+fn content_etag(body: &[u8]) -> String {
+    format!("\"{}\"", blake3::hash(body).to_hex())
+}
+
+// the error is only logged, since it can describe the backend of the bridge
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+fn internal_error(e: std::io::Error) -> Response {
+    event!(ERROR, error = %e, "bridge operation failed");
+    (StatusCode::INTERNAL_SERVER_ERROR, "internal error").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use async_generic::async_generic;
+
+    use super::*;
+    use crate::identity::tests::MockBridge;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    async fn send(app: &Router, method: &str, uri: &str, body: &'static str) -> Response {
+        let request = Request::builder().method(method).uri(uri);
+        app.clone()
+            .oneshot(request.body(Body::from(body)).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_router() {
+        let app = router(MockBridge::default());
+        assert_eq!(
+            send(&app, "GET", "/br/abc", "").await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            send(&app, "HEAD", "/br/abc", "").await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            send(&app, "PUT", "/br/abc", "blob\n").await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            send(&app, "HEAD", "/br/abc", "").await.status(),
+            StatusCode::OK
+        );
+
+        let response = send(&app, "GET", "/br/abc", "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[header::ETAG].clone();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"blob\n");
+
+        // ranged reads
+        let read = |range| {
+            let request = Request::get("/br/abc").header(header::RANGE, range);
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+        let response = read("bytes=1-2").await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 1-2/5");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"lo");
+        let response = read("bytes=3-").await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 3-4/5");
+        let response = read("bytes=5-9").await.unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */5");
+        assert_eq!(
+            read("bytes=0-1, 3-4").await.unwrap().status(),
+            StatusCode::OK
+        );
+
+        let revalidate = Request::get("/br/abc").header(header::IF_NONE_MATCH, etag.clone());
+        let response = app
+            .clone()
+            .oneshot(revalidate.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        // conditional writes
+        let put_if = |name, value: &HeaderValue, body| {
+            let request = Request::put("/br/abc").header(name, value);
+            app.clone().oneshot(request.body(Body::from(body)).unwrap())
+        };
+        let any = HeaderValue::from_static("*");
+        let response = put_if(header::IF_NONE_MATCH, &any, "other\n")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        let response = put_if(header::IF_MATCH, &etag, "next\n").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = put_if(header::IF_MATCH, &etag, "stale\n").await.unwrap();
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+        let corrupt = "/br/abc.corrupt-1700000000";
+        assert_eq!(
+            send(&app, "PUT", corrupt, "blob\n").await.status(),
+            StatusCode::OK
+        );
+        for invalid in ["/br/abcd", "/br/ABC", "/br/abc.corrupt-", "/../abc"] {
+            let status = send(&app, "PUT", invalid, "").await.status();
+            assert!(status.is_client_error(), "{invalid}: {status}");
+        }
+    }
+
+    // fails every operation, describing its backend
+    struct DownBridge;
+
+    impl ConnectionBridge for DownBridge {
+        #[async_generic]
+        fn get(&self, _key: &str) -> std::io::Result<Option<Bytes>> {
+            Err(std::io::Error::other("connection refused by 10.0.0.7:9000"))
+        }
+
+        #[async_generic]
+        fn put(&self, _key: &str, _body: Bytes) -> std::io::Result<()> {
+            Err(std::io::Error::other("connection refused by 10.0.0.7:9000"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_internal_error() {
+        let app = router(DownBridge);
+        for (method, body) in [("GET", ""), ("PUT", "blob\n")] {
+            let response = send(&app, method, "/br/abc", body).await;
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(&body[..], b"internal error");
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Database column types for [`HexString`], [`Storage`] and [`FriendlyName`], stored as text.
+//! Requires the `sqlx` or `diesel` feature. With `diesel`, a `Storage` can only be written to
+//! backends which collect binds as bytes, such as PostgreSQL and MySQL.
+
+use crate::hex_string::HexString;
+use crate::identity::{FriendlyName, Storage};
+
+// implements the traits of each enabled database library for a type which is stored as the
+// text of its Display and FromStr implementations. diesel::serialize::ToSql is implemented
+// separately, because some backends hold on to the serialized text
+macro_rules! text_column {
+    ([$($generics:tt)*] $type:ty) => {
+        #[cfg(feature = "sqlx")]
+        impl<DB, $($generics)*> sqlx::Type<DB> for $type
+        where
+            DB: sqlx::Database,
+            String: sqlx::Type<DB>,
+        {
+            fn type_info() -> DB::TypeInfo {
+                <String as sqlx::Type<DB>>::type_info()
+            }
+
+            fn compatible(ty: &DB::TypeInfo) -> bool {
+                <String as sqlx::Type<DB>>::compatible(ty)
+            }
+        }
+
+        #[cfg(feature = "sqlx")]
+        impl<'q, DB, $($generics)*> sqlx::Encode<'q, DB> for $type
+        where
+            DB: sqlx::Database,
+            String: sqlx::Encode<'q, DB>,
+        {
+            fn encode_by_ref(
+                &self,
+                buf: &mut DB::ArgumentBuffer<'q>,
+            ) -> Result<sqlx::encode::IsNull, sqlx::error::BoxDynError> {
+                <String as sqlx::Encode<'q, DB>>::encode(self.to_string(), buf)
+            }
+        }
+
+        #[cfg(feature = "sqlx")]
+        impl<'r, DB, $($generics)*> sqlx::Decode<'r, DB> for $type
+        where
+            DB: sqlx::Database,
+            &'r str: sqlx::Decode<'r, DB>,
+        {
+            fn decode(value: DB::ValueRef<'r>) -> Result<Self, sqlx::error::BoxDynError> {
+                let text = <&'r str as sqlx::Decode<'r, DB>>::decode(value)?;
+                Ok(text.parse()?)
+            }
+        }
+
+        #[cfg(feature = "diesel")]
+        impl<DB, $($generics)*> diesel::deserialize::FromSql<diesel::sql_types::Text, DB> for $type
+        where
+            DB: diesel::backend::Backend,
+            String: diesel::deserialize::FromSql<diesel::sql_types::Text, DB>,
+        {
+            fn from_sql(bytes: DB::RawValue<'_>) -> diesel::deserialize::Result<Self> {
+                let text = <String as diesel::deserialize::FromSql<_, DB>>::from_sql(bytes)?;
+                Ok(text.parse()?)
+            }
+        }
+    };
+}
+
+text_column!([const N: usize] HexString<N>);
+text_column!([] Storage);
+text_column!([] FriendlyName);
+
+#[cfg(feature = "diesel")]
+mod diesel_to_sql {
+    use diesel::backend::Backend;
+    use diesel::query_builder::bind_collector::RawBytesBindCollector;
+    use diesel::serialize::{Output, Result, ToSql};
+    use diesel::sql_types::Text;
+
+    use super::*;
+
+    impl<DB, const N: usize> ToSql<Text, DB> for HexString<N>
+    where
+        DB: Backend,
+        str: ToSql<Text, DB>,
+    {
+        fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, DB>) -> Result {
+            self.as_str().to_sql(out)
+        }
+    }
+
+    impl<DB> ToSql<Text, DB> for FriendlyName
+    where
+        DB: Backend,
+        str: ToSql<Text, DB>,
+    {
+        fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, DB>) -> Result {
+            self.0.as_str().to_sql(out)
+        }
+    }
+
+    impl<DB> ToSql<Text, DB> for Storage
+    where
+        for<'c> DB: Backend<BindCollector<'c> = RawBytesBindCollector<DB>>,
+    {
+        fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, DB>) -> Result {
+            use std::io::Write;
+
+            write!(out, "{self}")?;
+            Ok(diesel::serialize::IsNull::No)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage() -> Storage {
+        format!("abc{}", "0123456789abcdef".repeat(4))[..64]
+            .parse()
+            .unwrap()
+    }
+
+    #[cfg(feature = "sqlx")]
+    #[test]
+    fn test_sqlx_round_trip() {
+        use sqlx::{Decode, Encode};
+        use sqlx_core::any::{Any, AnyArgumentBuffer, AnyValue, AnyValueKind};
+        use sqlx_core::value::Value;
+
+        fn round_trip<T>(value: &T) -> T
+        where
+            T: for<'q> Encode<'q, Any> + for<'r> Decode<'r, Any>,
+        {
+            let mut buffer = AnyArgumentBuffer(vec![]);
+            let is_null = value.encode_by_ref(&mut buffer).unwrap();
+            assert!(matches!(is_null, sqlx::encode::IsNull::No));
+            let kind = match buffer.0.pop() {
+                Some(AnyValueKind::Text(text)) => AnyValueKind::Text(text.into_owned().into()),
+                other => panic!("expected text, got {other:?}"),
+            };
+            T::decode(AnyValue { kind }.as_ref()).unwrap()
+        }
+
+        let storage = storage();
+        assert_eq!(round_trip(&storage), storage);
+        assert_eq!(round_trip(&storage.digest), storage.digest);
+        let name = FriendlyName("Rosy Ocelot".to_string());
+        assert_eq!(round_trip(&name), name);
+
+        // text which does not parse is an error
+        let kind = AnyValueKind::Text("not hex".into());
+        assert!(<Storage as Decode<Any>>::decode(AnyValue { kind }.as_ref()).is_err());
+    }
+
+    #[cfg(feature = "diesel")]
+    #[test]
+    fn test_diesel_round_trip() {
+        use diesel::deserialize::FromSql;
+        use diesel::mysql::{Mysql, MysqlType, MysqlValue};
+        use diesel::query_builder::BindCollector;
+        use diesel::query_builder::bind_collector::RawBytesBindCollector;
+        use diesel::serialize::ToSql;
+        use diesel::sql_types::Text;
+
+        fn round_trip<T>(value: &T) -> T
+        where
+            T: ToSql<Text, Mysql> + FromSql<Text, Mysql>,
+        {
+            let mut collector = RawBytesBindCollector::<Mysql>::new();
+            collector
+                .push_bound_value::<Text, T>(value, &mut ())
+                .unwrap();
+            let bytes = collector.binds.pop().flatten().unwrap();
+            T::from_sql(MysqlValue::new(&bytes, MysqlType::String)).unwrap()
+        }
+
+        let storage = storage();
+        assert_eq!(round_trip(&storage), storage);
+        assert_eq!(round_trip(&storage.key), storage.key);
+        let name = FriendlyName("Rosy Ocelot".to_string());
+        assert_eq!(round_trip(&name), name);
+
+        // text which does not parse is an error
+        let value = MysqlValue::new(b"not hex", MysqlType::String);
+        assert!(<Storage as FromSql<Text, Mysql>>::from_sql(value).is_err());
+    }
+}
@@ -0,0 +1,1224 @@
+//! Helpers for unit testing code which uses perfume, without generating ingredients.
+//! Requires the `testing` feature, which is intended for `[dev-dependencies]`.
+//!
+//! ```
+//! use perfume::testing::{MemoryStore, MockBridge, tiny_population};
+//!
+//! let population = tiny_population("test");
+//! let mut store = MemoryStore::new(MockBridge::default());
+//! let identity = population.identity("alice@example.com", &mut store).unwrap();
+//! assert_eq!(identity, population.identity("alice@example.com", &mut store).unwrap());
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::future::Future;
+use std::io;
+use std::ops::{ControlFlow, Range};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::task::{Context, Poll, Waker};
+
+use bytes::Bytes;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::identity::{
+    ConnectionBridge, Identity, Ingredients, Population, RemoteStore, Storage, StorageState,
+    Validated,
+};
+use crate::{Error, STORAGE_DIGEST_LENGTH, STORAGE_KEY_LENGTH};
+
+/// A secret of the minimum length, for populations which are only used in tests.
+pub const TEST_SECRET: &[u8; 32] = b"0123456789abcdef0123456789abcdef";
+
+/// The size of the population of [`tiny_ingredients`], which has 4 names per storage blob,
+/// so that full blobs are easy to reach.
+pub const TINY_POPULATION_SIZE: usize = 4 * 16usize.pow(STORAGE_KEY_LENGTH as u32);
+
+const TINY_COLORS: &[&str] = &["red", "blue"];
+const TINY_ANIMALS: &[&str] = &["cat", "dog", "owl"];
+// one for each hex digit of a storage key
+const SYLLABLES: [&str; 16] = [
+    "ba", "de", "fi", "go", "ku", "la", "me", "ni", "po", "ru", "sa", "te", "vi", "wo", "xu", "zy",
+];
+
+/// Ingredients for a population of [`TINY_POPULATION_SIZE`], built when first used.
+/// The prefix of each name is spelled from its storage key, such as "bagosa" for "03a".
+pub fn tiny_ingredients() -> &'static Ingredients {
+    static INGREDIENTS: OnceLock<Ingredients> = OnceLock::new();
+    INGREDIENTS.get_or_init(|| {
+        let entries = (0..16usize.pow(STORAGE_KEY_LENGTH as u32))
+            .map(|i| {
+                let key = format!("{i:0STORAGE_KEY_LENGTH$x}");
+                let prefix = key
+                    .chars()
+                    .map(|c| SYLLABLES[c.to_digit(16).unwrap() as usize])
+                    .collect::<String>();
+                let key: &'static str = key.leak();
+                let prefix: &'static str = prefix.leak();
+                (key, prefix)
+            })
+            .collect::<Vec<_>>();
+        let keys = entries.iter().map(|(key, _)| *key).collect::<Vec<_>>();
+        let state = phf_generator::generate_hash(&keys);
+        let entries = state.map.iter().map(|&i| entries[i]).collect::<Vec<_>>();
+        let prefixes = phf::Map {
+            key: state.key,
+            disps: state.disps.leak(),
+            entries: entries.leak(),
+        };
+        (TINY_POPULATION_SIZE, prefixes, TINY_COLORS, TINY_ANIMALS)
+    })
+}
+
+/// A population of `domain` using [`tiny_ingredients`] and [`TEST_SECRET`].
+pub fn tiny_population(domain: &str) -> Population<'_> {
+    Population {
+        domain,
+        secret: TEST_SECRET,
+        ingredients: tiny_ingredients(),
+    }
+}
+
+/// A [`ConnectionBridge`] which holds blobs in memory, and appends to them in place.
+#[derive(Debug, Default)]
+pub struct MockBridge {
+    resources: RwLock<HashMap<String, Bytes>>,
+}
+
+impl MockBridge {
+    /// True if no blobs have been stored.
+    pub fn is_empty(&self) -> bool {
+        self.resources.read().unwrap().is_empty()
+    }
+
+    /// The number of stored blobs.
+    pub fn len(&self) -> usize {
+        self.resources.read().unwrap().len()
+    }
+}
+
+impl ConnectionBridge for MockBridge {
+    fn get(&self, key: &str) -> io::Result<Option<Bytes>> {
+        Ok(self.resources.read().unwrap().get(key).cloned())
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> io::Result<()> {
+        self.resources
+            .write()
+            .unwrap()
+            .insert(key.to_string(), body);
+        Ok(())
+    }
+
+    async fn get_async(&self, key: &str) -> io::Result<Option<Bytes>> {
+        self.get(key)
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> io::Result<()> {
+        self.put(key, body)
+    }
+
+    fn append(&self, key: &str, bytes: Bytes) -> io::Result<()> {
+        let mut resources = self.resources.write().unwrap();
+        let Some(body) = resources.get_mut(key) else {
+            let message = format!("there is no blob at {key} to append to");
+            return Err(io::Error::new(io::ErrorKind::NotFound, message));
+        };
+        *body = [&body[..], &bytes[..]].concat().into();
+        Ok(())
+    }
+
+    async fn append_async(&self, key: &str, bytes: Bytes) -> io::Result<()> {
+        self.append(key, bytes)
+    }
+
+    fn exists(&self, key: &str) -> io::Result<bool> {
+        Ok(self.resources.read().unwrap().contains_key(key))
+    }
+
+    async fn exists_async(&self, key: &str) -> io::Result<bool> {
+        self.exists(key)
+    }
+}
+
+/// A [`crate::identity::StorageState`] which stores blobs in memory exactly as [`RemoteStore`]
+/// stores them remotely, so that tests also cover the stored format. Created with
+/// `MemoryStore::new(MockBridge::default())`, and configured like any `RemoteStore`.
+/// See [`crate::identity::InMemoryStore`] for a store which does not use blobs.
+pub type MemoryStore = RemoteStore<MockBridge>;
+
+/// Builds an [`Identity`] with chosen fields, for testing code which receives identities
+/// without resolving them.
+#[derive(Debug, Clone)]
+pub struct IdentityBuilder<'dom> {
+    identity: Identity<'dom>,
+}
+
+impl<'dom> IdentityBuilder<'dom> {
+    /// An identity of `domain` named `friendly_name`, with offset 0 in blob "000".
+    pub fn new(domain: &'dom str, friendly_name: &str) -> Self {
+        Self {
+            identity: Identity {
+                domain,
+                friendly_name: friendly_name.to_string(),
+                storage: "0".repeat(64).parse().unwrap(),
+                offset: 0,
+            },
+        }
+    }
+
+    /// Use `storage`, such as one parsed from 64 hex characters.
+    pub fn with_storage(mut self, storage: Storage) -> Self {
+        self.identity.storage = storage;
+        self
+    }
+
+    /// Use `offset`, see [`crate::identity::StorageState::digest_offset`].
+    pub fn with_offset(mut self, offset: usize) -> Self {
+        self.identity.offset = offset;
+        self
+    }
+
+    /// The identity.
+    pub fn build(self) -> Identity<'dom> {
+        self.identity
+    }
+}
+
+/// The size of the large blob written by [`bridge_conformance`], which exceeds the largest
+/// blobs of a population of size `PopulationSize::Brazil`.
+pub const CONFORMANCE_BLOB_SIZE: usize = 4 << 20;
+
+/// Check that bridges made by `factory` behave as [`RemoteStore`] expects, using the blocking
+/// methods of [`ConnectionBridge`]. Panics with a description of the first failure.
+///
+/// `factory` is called for each check, and should make a bridge which holds no blobs, such as
+/// one using a new bucket prefix. The checks cover absent keys, existence, storing and
+/// replacing blobs, validators, chunked and ranged reads, a blob of [`CONFORMANCE_BLOB_SIZE`],
+/// and concurrent writes from several threads.
+pub fn bridge_conformance<B, F>(factory: F)
+where
+    B: ConnectionBridge + Sync,
+    F: Fn() -> B,
+{
+    let checks = std::pin::pin!(conformance(|| Blocking(factory())));
+    let mut cx = Context::from_waker(Waker::noop());
+    // every blocking call completes before its future is polled
+    assert!(checks.poll(&mut cx).is_ready());
+}
+
+/// The async version of [`bridge_conformance`], which uses the async methods of
+/// [`ConnectionBridge`]. Concurrent writes are made from a single task.
+pub async fn bridge_conformance_async<B, F>(factory: F)
+where
+    B: ConnectionBridge + Sync,
+    F: Fn() -> B,
+{
+    conformance(|| Async(factory())).await
+}
+
+async fn conformance<M: Methods>(factory: impl Fn() -> M) {
+    fn expect<T>(result: io::Result<T>, action: &str) -> T {
+        result.unwrap_or_else(|e| panic!("bridge conformance: {action} failed: {e}"))
+    }
+
+    let bridge = factory();
+    expect(bridge.ping().await, "ping of an empty bridge");
+    let absent = expect(bridge.get("abc").await, "get of an absent key");
+    assert!(
+        absent.is_none(),
+        "bridge conformance: absent key has a blob"
+    );
+    let exists = expect(bridge.exists("abc").await, "existence of an absent key");
+    assert!(!exists, "bridge conformance: absent key exists");
+    let chunks = expect(
+        bridge.get_chunks("abc").await,
+        "chunked get of an absent key",
+    );
+    assert!(
+        chunks.is_none(),
+        "bridge conformance: absent key has chunks"
+    );
+    let validated = expect(
+        bridge.get_validated("abc", None).await,
+        "validated get of an absent key",
+    );
+    assert!(
+        matches!(validated, Validated::Modified { body: None, .. }),
+        "bridge conformance: absent key is validated as {validated:?}"
+    );
+
+    let bridge = factory();
+    let first = Bytes::from_static(b"first\n");
+    let second = Bytes::from_static(b"second\n");
+    expect(bridge.put("abc", first.clone()).await, "put of a new key");
+    let body = expect(bridge.get("abc").await, "get of a stored key");
+    assert_eq!(body, Some(first.clone()), "bridge conformance: stored blob");
+    let exists = expect(bridge.exists("abc").await, "existence of a stored key");
+    assert!(exists, "bridge conformance: stored key does not exist");
+    let validated = expect(
+        bridge.get_validated("abc", None).await,
+        "validated get of a stored key",
+    );
+    let Validated::Modified { body, validator } = validated else {
+        panic!("bridge conformance: blob is unmodified without a validator")
+    };
+    assert_eq!(
+        body,
+        Some(first.clone()),
+        "bridge conformance: validated blob"
+    );
+    if let Some(validator) = &validator {
+        let validated = expect(
+            bridge.get_validated("abc", Some(validator)).await,
+            "validated get of an unchanged key",
+        );
+        if let Validated::Modified { body, .. } = validated {
+            assert_eq!(
+                body,
+                Some(first.clone()),
+                "bridge conformance: unchanged blob"
+            );
+        }
+    }
+    expect(
+        bridge.put("abc", second.clone()).await,
+        "put of a stored key",
+    );
+    let body = expect(bridge.get("abc").await, "get of a replaced key");
+    assert_eq!(
+        body,
+        Some(second.clone()),
+        "bridge conformance: replaced blob"
+    );
+    if let Some(validator) = &validator {
+        let validated = expect(
+            bridge.get_validated("abc", Some(validator)).await,
+            "validated get of a replaced key",
+        );
+        assert!(
+            matches!(&validated, Validated::Modified { body: Some(body), .. } if body == &second),
+            "bridge conformance: replaced blob is validated as {validated:?}"
+        );
+    }
+    // such as when a corrupt blob is quarantined
+    expect(
+        bridge.put("abc.corrupt-1", first).await,
+        "put of a suffixed key",
+    );
+    let body = expect(
+        bridge.get("abc").await,
+        "get of a key beside a suffixed key",
+    );
+    assert_eq!(
+        body,
+        Some(second),
+        "bridge conformance: keys are independent"
+    );
+    let absent = expect(bridge.get("abd").await, "get of a neighbouring key");
+    assert!(absent.is_none(), "bridge conformance: keys are independent");
+
+    let bridge = factory();
+    let large = (0..CONFORMANCE_BLOB_SIZE)
+        .map(|i| b"0123456789abcdef\n"[i % 17])
+        .collect::<Bytes>();
+    expect(
+        bridge.put("fff", large.clone()).await,
+        "put of a large blob",
+    );
+    let body = expect(bridge.get("fff").await, "get of a large blob");
+    assert!(
+        body.as_ref() == Some(&large),
+        "bridge conformance: large blob was not stored intact"
+    );
+    let chunks = expect(
+        bridge.get_chunks("fff").await,
+        "chunked get of a large blob",
+    );
+    assert!(
+        chunks.as_deref() == Some(&large[..]),
+        "bridge conformance: large blob was not read intact in chunks"
+    );
+
+    // ranged reads are optional
+    let bridge = factory();
+    expect(
+        bridge.put("abc", Bytes::from_static(b"first\n")).await,
+        "put of a new key",
+    );
+    match bridge.get_range("abc", 1..3).await {
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => {}
+        read => {
+            let read = expect(read, "ranged get of a stored key");
+            assert_eq!(
+                read,
+                Some((Bytes::from_static(b"ir"), 6)),
+                "bridge conformance: ranged get"
+            );
+            let end = expect(
+                bridge.get_range("abc", 4..10).await,
+                "ranged get past the end",
+            );
+            assert_eq!(
+                end,
+                Some((Bytes::from_static(b"t\n"), 6)),
+                "bridge conformance: ranged get past the end"
+            );
+            let absent = expect(
+                bridge.get_range("def", 0..3).await,
+                "ranged get of an absent key",
+            );
+            assert_eq!(absent, None, "bridge conformance: absent key has a range");
+        }
+    }
+
+    // appending is optional
+    let bridge = factory();
+    expect(
+        bridge.put("abc", Bytes::from_static(b"first\n")).await,
+        "put of a new key",
+    );
+    match bridge.append("abc", Bytes::from_static(b"second\n")).await {
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => {}
+        appended => {
+            expect(appended, "append to a stored key");
+            let body = expect(bridge.get("abc").await, "get of an appended key");
+            assert_eq!(
+                body.as_deref(),
+                Some(&b"first\nsecond\n"[..]),
+                "bridge conformance: appended blob"
+            );
+        }
+    }
+
+    let bridge = factory();
+    let puts = (0x100..0x110)
+        .map(|i| (format!("{i:03x}"), Bytes::from(format!("{i}\n"))))
+        .collect::<Vec<_>>();
+    expect(bridge.put_all(&puts).await, "concurrent puts of new keys");
+    for (key, body) in &puts {
+        let stored = expect(bridge.get(key).await, "get of a concurrently stored key");
+        assert_eq!(
+            stored.as_ref(),
+            Some(body),
+            "bridge conformance: concurrent put of {key}"
+        );
+    }
+    let puts = (0..8)
+        .map(|i| ("eee".to_string(), Bytes::from(vec![b'a' + i; 1000])))
+        .collect::<Vec<_>>();
+    expect(bridge.put_all(&puts).await, "concurrent puts of one key");
+    let stored = expect(
+        bridge.get("eee").await,
+        "get of a concurrently replaced key",
+    );
+    assert!(
+        puts.iter()
+            .any(|(_key, body)| stored.as_ref() == Some(body)),
+        "bridge conformance: concurrent puts of one key produced a mixed blob"
+    );
+}
+
+// the methods of a bridge used by `conformance`, either blocking or async
+trait Methods {
+    async fn ping(&self) -> io::Result<()>;
+    async fn get(&self, key: &str) -> io::Result<Option<Bytes>>;
+    async fn exists(&self, key: &str) -> io::Result<bool>;
+    async fn put(&self, key: &str, body: Bytes) -> io::Result<()>;
+    async fn append(&self, key: &str, bytes: Bytes) -> io::Result<()>;
+    async fn get_validated(&self, key: &str, validator: Option<&str>) -> io::Result<Validated>;
+    // the chunks of a blob, joined
+    async fn get_chunks(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+    async fn get_range(&self, key: &str, range: Range<u64>) -> io::Result<Option<(Bytes, u64)>>;
+    // all at once
+    async fn put_all(&self, puts: &[(String, Bytes)]) -> io::Result<()>;
+}
+
+struct Blocking<B>(B);
+
+impl<B: ConnectionBridge + Sync> Methods for Blocking<B> {
+    async fn ping(&self) -> io::Result<()> {
+        self.0.ping()
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Option<Bytes>> {
+        self.0.get(key)
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        self.0.exists(key)
+    }
+
+    async fn put(&self, key: &str, body: Bytes) -> io::Result<()> {
+        self.0.put(key, body)
+    }
+
+    async fn append(&self, key: &str, bytes: Bytes) -> io::Result<()> {
+        self.0.append(key, bytes)
+    }
+
+    async fn get_validated(&self, key: &str, validator: Option<&str>) -> io::Result<Validated> {
+        self.0.get_validated(key, validator)
+    }
+
+    async fn get_chunks(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut joined = vec![];
+        let found = self.0.get_chunks(key, &mut |chunk| {
+            joined.extend_from_slice(chunk);
+            ControlFlow::Continue(())
+        })?;
+        Ok(found.then_some(joined))
+    }
+
+    async fn get_range(&self, key: &str, range: Range<u64>) -> io::Result<Option<(Bytes, u64)>> {
+        self.0.get_range(key, range)
+    }
+
+    async fn put_all(&self, puts: &[(String, Bytes)]) -> io::Result<()> {
+        std::thread::scope(|scope| {
+            let threads = puts
+                .iter()
+                .map(|(key, body)| scope.spawn(|| self.0.put(key, body.clone())))
+                .collect::<Vec<_>>();
+            threads.into_iter().try_for_each(|t| t.join().unwrap())
+        })
+    }
+}
+
+struct Async<B>(B);
+
+impl<B: ConnectionBridge + Sync> Methods for Async<B> {
+    async fn ping(&self) -> io::Result<()> {
+        self.0.ping_async().await
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Option<Bytes>> {
+        self.0.get_async(key).await
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        self.0.exists_async(key).await
+    }
+
+    async fn put(&self, key: &str, body: Bytes) -> io::Result<()> {
+        self.0.put_async(key, body).await
+    }
+
+    async fn append(&self, key: &str, bytes: Bytes) -> io::Result<()> {
+        self.0.append_async(key, bytes).await
+    }
+
+    async fn get_validated(&self, key: &str, validator: Option<&str>) -> io::Result<Validated> {
+        self.0.get_validated_async(key, validator).await
+    }
+
+    async fn get_chunks(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut joined = vec![];
+        let found = self
+            .0
+            .get_chunks_async(key, &mut |chunk| {
+                joined.extend_from_slice(chunk);
+                ControlFlow::Continue(())
+            })
+            .await?;
+        Ok(found.then_some(joined))
+    }
+
+    async fn get_range(&self, key: &str, range: Range<u64>) -> io::Result<Option<(Bytes, u64)>> {
+        self.0.get_range_async(key, range).await
+    }
+
+    async fn put_all(&self, puts: &[(String, Bytes)]) -> io::Result<()> {
+        let mut pending = puts
+            .iter()
+            .map(|(key, body)| Some(Box::pin(self.0.put_async(key, body.clone()))))
+            .collect::<Vec<_>>();
+        std::future::poll_fn(|cx| {
+            for slot in &mut pending {
+                let Some(put) = slot else {
+                    continue;
+                };
+                if let Poll::Ready(result) = put.as_mut().poll(cx) {
+                    result?;
+                    *slot = None;
+                }
+            }
+            if pending.iter().all(Option::is_none) {
+                Poll::Ready(Ok(()))
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+/// Drives virtual writers against stores which share a bridge, in an interleaving chosen by a
+/// seed, and checks that the offsets they resolve are unique and continuous in each domain.
+/// A seed which finds a violation always reproduces it, so that a concurrency fix to a store
+/// can be validated.
+///
+/// Each writer has its own store of each domain, made from a [`SimulatedBridge`] by the
+/// factory passed to [`Simulation::run`]. The bridge suspends every async call once, so that
+/// writers are interleaved at each request, as they would be by a network. Writers resolve
+/// digests of only a few storage keys, so that they contend for the same blobs.
+///
+/// ```
+/// use perfume::identity::RemoteStore;
+/// use perfume::testing::Simulation;
+///
+/// let report = Simulation::new(7)
+///     .with_writers(1)
+///     .run(|_domain, bridge| RemoteStore::new(bridge));
+/// report.assert_ok();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    seed: u64,
+    writers: usize,
+    operations: usize,
+    keys: usize,
+    digests_per_key: usize,
+    domains: Vec<String>,
+}
+
+impl Simulation {
+    /// 4 writers, each resolving 50 digests of 2 storage keys in the domain "sim",
+    /// interleaved according to `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            writers: 4,
+            operations: 50,
+            keys: 2,
+            digests_per_key: 20,
+            domains: vec!["sim".to_string()],
+        }
+    }
+
+    /// The number of writers.
+    pub fn with_writers(mut self, writers: usize) -> Self {
+        self.writers = writers;
+        self
+    }
+
+    /// The number of digests resolved by each writer.
+    pub fn with_operations(mut self, operations: usize) -> Self {
+        self.operations = operations;
+        self
+    }
+
+    /// Resolve `digests_per_key` digests of each of `keys` storage keys, at most 4096.
+    pub fn with_digests(mut self, keys: usize, digests_per_key: usize) -> Self {
+        self.keys = keys.clamp(1, 16usize.pow(STORAGE_KEY_LENGTH as u32));
+        self.digests_per_key = digests_per_key.max(1);
+        self
+    }
+
+    /// Resolve digests of each of `domains`, which have separate bridges.
+    pub fn with_domains(mut self, domains: &[&str]) -> Self {
+        self.domains = domains.iter().map(|domain| domain.to_string()).collect();
+        self
+    }
+
+    /// Run the writers to completion, with stores made by `store` for a domain and its bridge.
+    /// Afterwards, a new store of each domain resolves every digest again, to check that the
+    /// offsets given to the writers were persisted.
+    pub fn run<S, F>(&self, mut store: F) -> SimulationReport
+    where
+        S: StorageState,
+        F: FnMut(&str, SimulatedBridge) -> S,
+    {
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed);
+        let bridges = self
+            .domains
+            .iter()
+            .map(|_| SimulatedBridge::default())
+            .collect::<Vec<_>>();
+        let mut pool = vec![];
+        for key in
+            rand::seq::index::sample(&mut rng, 16usize.pow(STORAGE_KEY_LENGTH as u32), self.keys)
+        {
+            for _ in 0..self.digests_per_key {
+                let digest = (0..STORAGE_DIGEST_LENGTH)
+                    .map(|_| char::from_digit(rng.random_range(0..16), 16).unwrap())
+                    .collect::<String>();
+                let storage = format!("{key:0STORAGE_KEY_LENGTH$x}{digest}");
+                pool.push(storage.parse::<Storage>().unwrap());
+            }
+        }
+
+        let mut writers = (0..self.writers)
+            .map(|_| {
+                let stores = self
+                    .domains
+                    .iter()
+                    .zip(&bridges)
+                    .map(|(domain, bridge)| store(domain, bridge.clone()))
+                    .collect::<Vec<_>>();
+                let plan = (0..self.operations)
+                    .map(|_| {
+                        let domain = rng.random_range(0..self.domains.len());
+                        (domain, rng.random_range(0..pool.len()))
+                    })
+                    .collect::<Vec<_>>();
+                Box::pin(simulated_writer(stores, &self.domains, &pool, plan))
+            })
+            .collect::<Vec<_>>();
+
+        let mut report = SimulationReport {
+            seed: self.seed,
+            steps: 0,
+            resolved: 0,
+            violations: vec![],
+        };
+        // (domain, digest) -> offsets
+        let mut offsets = BTreeMap::<_, BTreeSet<usize>>::new();
+        let mut running = (0..writers.len()).collect::<Vec<_>>();
+        let mut cx = Context::from_waker(Waker::noop());
+        while !running.is_empty() {
+            let next = rng.random_range(0..running.len());
+            let writer = running[next];
+            report.steps += 1;
+            let Poll::Ready((resolved, result)) = writers[writer].as_mut().poll(&mut cx) else {
+                continue;
+            };
+            running.swap_remove(next);
+            if let Err(e) = result {
+                let error = e.to_string();
+                report.violations.push(Violation::Failed { writer, error });
+            }
+            report.resolved += resolved.len();
+            for (domain, digest, offset) in resolved {
+                offsets.entry((domain, digest)).or_default().insert(offset);
+            }
+        }
+        drop(writers);
+
+        // (domain, storage key) -> offset -> digests
+        let mut claims = BTreeMap::<_, BTreeMap<usize, usize>>::new();
+        for (&(domain, digest), found) in &offsets {
+            let storage = &pool[digest];
+            if found.len() > 1 {
+                report.violations.push(Violation::Inconsistent {
+                    domain: self.domains[domain].clone(),
+                    storage: storage.clone(),
+                    offsets: found.iter().copied().collect(),
+                });
+            }
+            let claimed = claims.entry((domain, storage.key.as_str())).or_default();
+            for &offset in found {
+                *claimed.entry(offset).or_default() += 1;
+            }
+        }
+        for ((domain, key), claimed) in &claims {
+            let domain = &self.domains[*domain];
+            let last = claimed.keys().next_back().copied().unwrap_or_default();
+            for offset in 0..=last {
+                match claimed.get(&offset) {
+                    None => report.violations.push(Violation::Gap {
+                        domain: domain.clone(),
+                        key: key.to_string(),
+                        offset,
+                    }),
+                    Some(&count) if count > 1 => report.violations.push(Violation::Duplicate {
+                        domain: domain.clone(),
+                        key: key.to_string(),
+                        offset,
+                        count,
+                    }),
+                    Some(_) => {}
+                }
+            }
+        }
+
+        let mut stores = self
+            .domains
+            .iter()
+            .zip(&bridges)
+            .map(|(domain, bridge)| store(domain, bridge.clone()))
+            .collect::<Vec<_>>();
+        for (&(domain, digest), found) in &offsets {
+            let name = &self.domains[domain];
+            let persisted =
+                poll_to_completion(stores[domain].digest_offset_async(name, &pool[digest]));
+            let persisted = persisted.ok();
+            if persisted.is_none_or(|offset| !found.contains(&offset)) {
+                report.violations.push(Violation::Lost {
+                    domain: name.clone(),
+                    storage: pool[digest].clone(),
+                    offset: persisted,
+                });
+            }
+        }
+        report
+    }
+}
+
+// resolves the planned (domain, digest) pairs, returning (domain, digest, offset) for each
+async fn simulated_writer<S: StorageState>(
+    mut stores: Vec<S>,
+    domains: &[String],
+    pool: &[Storage],
+    plan: Vec<(usize, usize)>,
+) -> (Vec<(usize, usize, usize)>, Result<(), Error>) {
+    let mut resolved = vec![];
+    for (domain, digest) in plan {
+        let result = stores[domain]
+            .digest_offset_async(&domains[domain], &pool[digest])
+            .await;
+        match result {
+            Ok(offset) => resolved.push((domain, digest, offset)),
+            Err(e) => return (resolved, Err(e)),
+        }
+    }
+    (resolved, Ok(()))
+}
+
+fn poll_to_completion<F: Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+/// The outcome of [`Simulation::run`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimulationReport {
+    /// The seed of the interleaving, which reproduces it.
+    pub seed: u64,
+    /// How many times a writer was polled.
+    pub steps: usize,
+    /// How many digests were resolved by the writers.
+    pub resolved: usize,
+    /// Offsets which break the contract of [`StorageState::digest_offset`].
+    pub violations: Vec<Violation>,
+}
+
+impl SimulationReport {
+    /// True if no violations were found.
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    /// Panics with the seed and the violations, if any were found.
+    pub fn assert_ok(&self) {
+        if !self.is_ok() {
+            let violations = self
+                .violations
+                .iter()
+                .map(|violation| format!("\n  {violation}"))
+                .collect::<String>();
+            panic!(
+                "simulation with seed {} found {} violations:{violations}",
+                self.seed,
+                self.violations.len()
+            );
+        }
+    }
+}
+
+/// A problem found by a [`Simulation`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Violation {
+    /// A writer failed to resolve a digest, and stopped.
+    Failed {
+        /// The position of the writer.
+        writer: usize,
+        /// The error returned by its store.
+        error: String,
+    },
+    /// One storage object was given several offsets.
+    Inconsistent {
+        /// The domain of the storage object.
+        domain: String,
+        /// The storage object.
+        storage: Storage,
+        /// The offsets given to it.
+        offsets: Vec<usize>,
+    },
+    /// Several storage objects of one key were given the same offset.
+    Duplicate {
+        /// The domain of the storage objects.
+        domain: String,
+        /// Their storage key.
+        key: String,
+        /// The offset given to each.
+        offset: usize,
+        /// The number of storage objects given the offset.
+        count: usize,
+    },
+    /// An offset below the largest offset of a key was not given to any storage object.
+    Gap {
+        /// The domain of the key.
+        domain: String,
+        /// The storage key.
+        key: String,
+        /// The missing offset.
+        offset: usize,
+    },
+    /// A new store does not resolve a storage object to an offset which it was given.
+    Lost {
+        /// The domain of the storage object.
+        domain: String,
+        /// The storage object.
+        storage: Storage,
+        /// The offset resolved by the new store, if it could resolve one.
+        offset: Option<usize>,
+    },
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Failed { writer, error } => write!(f, "writer {writer} failed: {error}"),
+            Self::Inconsistent {
+                domain,
+                storage,
+                offsets,
+            } => write!(f, "{domain}: {storage} was given offsets {offsets:?}"),
+            Self::Duplicate {
+                domain,
+                key,
+                offset,
+                count,
+            } => write!(
+                f,
+                "{domain}: offset {offset} of {key} was given {count} times"
+            ),
+            Self::Gap {
+                domain,
+                key,
+                offset,
+            } => write!(f, "{domain}: offset {offset} of {key} was skipped"),
+            Self::Lost {
+                domain,
+                storage,
+                offset: Some(offset),
+            } => write!(f, "{domain}: {storage} is now at offset {offset}"),
+            Self::Lost {
+                domain, storage, ..
+            } => write!(f, "{domain}: {storage} can no longer be resolved"),
+        }
+    }
+}
+
+/// The bridge of a domain in a [`Simulation`], which holds blobs in a [`MockBridge`] shared by
+/// all of its clones, and suspends each async call once before making it.
+#[derive(Debug, Clone, Default)]
+pub struct SimulatedBridge {
+    blobs: Arc<MockBridge>,
+}
+
+impl SimulatedBridge {
+    /// The stored blobs.
+    pub fn blobs(&self) -> &MockBridge {
+        &self.blobs
+    }
+}
+
+impl ConnectionBridge for SimulatedBridge {
+    fn get(&self, key: &str) -> io::Result<Option<Bytes>> {
+        self.blobs.get(key)
+    }
+
+    fn put(&self, key: &str, body: Bytes) -> io::Result<()> {
+        self.blobs.put(key, body)
+    }
+
+    async fn get_async(&self, key: &str) -> io::Result<Option<Bytes>> {
+        suspend().await;
+        self.blobs.get(key)
+    }
+
+    async fn put_async(&self, key: &str, body: Bytes) -> io::Result<()> {
+        suspend().await;
+        self.blobs.put(key, body)
+    }
+
+    fn exists(&self, key: &str) -> io::Result<bool> {
+        self.blobs.exists(key)
+    }
+
+    async fn exists_async(&self, key: &str) -> io::Result<bool> {
+        suspend().await;
+        self.blobs.exists(key)
+    }
+}
+
+// returns pending once, so that the scheduler of a simulation can switch writers
+fn suspend() -> impl Future<Output = ()> + Send {
+    let mut suspended = false;
+    std::future::poll_fn(move |cx| {
+        if suspended {
+            return Poll::Ready(());
+        }
+        suspended = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+}
+
+/// Resolve `identifiers` in order with a new [`MemoryStore`], as lines of
+/// `identifier => friendly name`, which are compared by [`assert_names`].
+pub fn names_snapshot<I, S>(population: &Population<'_>, identifiers: I) -> String
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut store = MemoryStore::new(MockBridge::default());
+    let mut snapshot = String::new();
+    for identifier in identifiers {
+        let identifier = identifier.as_ref();
+        let identity = population
+            .identity(identifier, &mut store)
+            .unwrap_or_else(|e| panic!("failed to name {identifier:?}: {e}"));
+        snapshot.push_str(&format!("{identifier} => {}\n", identity.friendly_name));
+    }
+    snapshot
+}
+
+/// Assert that a population gives the same names to a list of identifiers as it did when a
+/// snapshot was taken, so that a test fails if a change to the ingredients or secret
+/// would rename existing identities. The identifiers are resolved in order with a new
+/// [`MemoryStore`], see [`names_snapshot`].
+///
+/// The snapshot is either written inline after `@`, or kept in a file named after the module
+/// and `name` in the `tests/snapshots` directory of the crate. A missing or changed file
+/// snapshot is written beside it with the extension `.snap.new`, and accepted by running the
+/// test again with `PERFUME_UPDATE_SNAPSHOTS=1`. Blank lines, `#` comments and indentation
+/// are ignored.
+///
+/// ```
+/// use perfume::testing::{assert_names, tiny_population};
+///
+/// let population = tiny_population("test");
+/// assert_names!(population, ["alice@example.com", "bob@example.com"], @"
+///     alice@example.com => gotesa-blue-owl
+///     bob@example.com => laposa-blue-owl
+/// ");
+/// ```
+///
+/// ```no_run
+/// # use perfume::testing::{assert_names, tiny_population};
+/// # let population = tiny_population("test");
+/// // compared with tests/snapshots/<module>__users.snap
+/// assert_names!("users", population, ["alice@example.com", "bob@example.com"]);
+/// ```
+#[doc(inline)]
+pub use crate::__assert_names as assert_names;
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __assert_names {
+    ($population:expr, $identifiers:expr, @$snapshot:literal $(,)?) => {
+        $crate::testing::assert_names_inline(&$population, $identifiers, $snapshot)
+    };
+    ($name:literal, $population:expr, $identifiers:expr $(,)?) => {
+        $crate::testing::assert_names_file(
+            &::std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots"),
+            &format!("{}__{}", module_path!().replace("::", "__"), $name),
+            &$population,
+            $identifiers,
+        )
+    };
+}
+
+/// The environment variable which accepts changed snapshots of [`assert_names`].
+pub const UPDATE_SNAPSHOTS_ENV: &str = "PERFUME_UPDATE_SNAPSHOTS";
+
+#[doc(hidden)]
+pub fn assert_names_inline<I, S>(population: &Population<'_>, identifiers: I, snapshot: &str)
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let actual = names_snapshot(population, identifiers);
+    if let Some(difference) = snapshot_difference(snapshot, &actual) {
+        panic!(
+            "names of domain {:?} changed:\n{difference}",
+            population.domain
+        );
+    }
+}
+
+#[doc(hidden)]
+pub fn assert_names_file<I, S>(
+    directory: &std::path::Path,
+    name: &str,
+    population: &Population<'_>,
+    identifiers: I,
+) where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let path = directory.join(format!("{name}.snap"));
+    let actual = format!(
+        "# names of domain {:?}, see perfume::testing::assert_names\n{}",
+        population.domain,
+        names_snapshot(population, identifiers)
+    );
+    let difference = match std::fs::read_to_string(&path) {
+        Ok(expected) => snapshot_difference(&expected, &actual),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Some("missing snapshot".to_string()),
+        Err(e) => panic!("failed to read {}: {e}", path.display()),
+    };
+    let Some(difference) = difference else {
+        return;
+    };
+    let update = std::env::var_os(UPDATE_SNAPSHOTS_ENV).is_some_and(|value| value == "1");
+    let written = if update {
+        path.clone()
+    } else {
+        path.with_extension("snap.new")
+    };
+    std::fs::create_dir_all(directory)
+        .and_then(|()| std::fs::write(&written, actual))
+        .unwrap_or_else(|e| panic!("failed to write {}: {e}", written.display()));
+    if !update {
+        panic!(
+            "names of domain {:?} changed from {}:\n{difference}\nwrote {}, \
+             accept it with {UPDATE_SNAPSHOTS_ENV}=1",
+            population.domain,
+            path.display(),
+            written.display(),
+        );
+    }
+}
+
+// the lines of `expected` and `actual` which differ, ignoring blank lines, comments and indentation
+fn snapshot_difference(expected: &str, actual: &str) -> Option<String> {
+    fn lines(snapshot: &str) -> Vec<&str> {
+        snapshot
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect()
+    }
+    let (expected, actual) = (lines(expected), lines(actual));
+    if expected == actual {
+        return None;
+    }
+    let mut difference = String::new();
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(old), Some(new)) if old == new => {}
+            (old, new) => {
+                old.inspect(|old| difference.push_str(&format!("-{old}\n")));
+                new.inspect(|new| difference.push_str(&format!("+{new}\n")));
+            }
+        }
+    }
+    Some(difference)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::ConcurrentStore;
+
+    #[test]
+    fn test_tiny_population() -> Result<(), Error> {
+        let (size, prefixes, ..) = tiny_ingredients();
+        assert_eq!(*size, TINY_POPULATION_SIZE);
+        assert_eq!(prefixes.len(), 4096);
+        assert_eq!(prefixes.get("03a"), Some(&"bagosa"));
+
+        let population = tiny_population("test");
+        let mut store = MemoryStore::new(MockBridge::default());
+        let identity = population.identity("alice@example.com", &mut store)?;
+        assert_eq!(
+            identity,
+            population.identity("alice@example.com", &mut store)?
+        );
+        let prefix = prefixes.get(identity.storage.key.as_str()).unwrap();
+        assert!(identity.friendly_name.starts_with(prefix));
+        assert_eq!(store.bridge.len(), 1);
+
+        let built = IdentityBuilder::new("test", &identity.friendly_name)
+            .with_storage(identity.storage.clone())
+            .with_offset(identity.offset)
+            .build();
+        assert_eq!(built, identity);
+        assert_eq!(built.storage, identity.storage);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bridge_conformance() {
+        bridge_conformance(MockBridge::default);
+        bridge_conformance_async(MockBridge::default).await;
+    }
+    #[test]
+    fn test_simulation() {
+        // a shared store is consistent in any interleaving
+        let store = ConcurrentStore::new();
+        let report = Simulation::new(1)
+            .with_writers(8)
+            .with_digests(3, 10)
+            .run(|_domain, _bridge| &store);
+        report.assert_ok();
+        assert_eq!(report.resolved, 8 * 50);
+
+        let remote = |seed| {
+            Simulation::new(seed)
+                .with_domains(&["br", "pt"])
+                .run(|_domain, bridge| RemoteStore::new(bridge))
+        };
+        Simulation::new(2)
+            .with_writers(1)
+            .run(|_domain, bridge| RemoteStore::new(bridge))
+            .assert_ok();
+        // stores which share a bridge overwrite each other's records
+        let report = remote(3);
+        assert!(!report.is_ok());
+        assert!(
+            report
+                .violations
+                .iter()
+                .any(|v| matches!(v, Violation::Duplicate { .. }))
+        );
+        // and do so again with the same seed
+        assert_eq!(remote(3), report);
+    }
+    #[test]
+    fn test_assert_names() {
+        let population = tiny_population("test");
+        let identifiers = ["alice@example.com", "bob@example.com"];
+        let snapshot = names_snapshot(&population, identifiers);
+        assert_names_inline(
+            &population,
+            identifiers,
+            &format!("\n  # a comment\n{snapshot}\n"),
+        );
+        let changed = snapshot.replacen("=> ", "=> x", 1);
+        let difference = snapshot_difference(&changed, &snapshot).unwrap();
+        assert_eq!(difference.lines().count(), 2);
+        assert!(difference.starts_with("-alice@example.com => x"));
+
+        let tmp_dir = std::env::var("TMPDIR").unwrap_or("/tmp".to_string());
+        let directory = std::path::Path::new(&tmp_dir).join("perfume_test_snapshots");
+        let _ = std::fs::remove_dir_all(&directory);
+        // a missing snapshot is written beside its path
+        let missing = std::panic::catch_unwind(|| {
+            assert_names_file(&directory, "users", &population, identifiers)
+        });
+        assert!(missing.is_err());
+        let new = directory.join("users.snap.new");
+        std::fs::rename(&new, directory.join("users.snap")).unwrap();
+        assert_names_file(&directory, "users", &population, identifiers);
+        let renamed = Population {
+            secret: b"fedcba9876543210fedcba9876543210",
+            ..tiny_population("test")
+        };
+        let changed = std::panic::catch_unwind(|| {
+            assert_names_file(&directory, "users", &renamed, identifiers)
+        });
+        assert!(changed.is_err());
+        assert!(new.exists());
+    }
+}
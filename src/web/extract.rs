@@ -0,0 +1,120 @@
+use axum::extract::{FromRef, FromRequestParts};
+use axum::http::StatusCode;
+use axum::http::request::Parts;
+
+use crate::identity::Identity;
+
+use super::{SharedPopulation, UserId};
+
+/// Extracts the [`Identity`] of the [`UserId`] of a request, using the [`SharedPopulation`]
+/// of the router state. Rejects requests without a `UserId` as unauthorized, and requests whose
+/// identity could not be resolved as an internal server error, whose body does not describe
+/// the error, since it can name storage keys and backends. The error is logged instead.
+/// An identity which was already resolved by a `PseudonymLayer` is used as it is.
+///
+/// ```no_run
+/// # async fn example(population: perfume::identity::Population<'static>) {
+/// use axum::{Router, routing::get};
+/// use perfume::identity::ConcurrentStore;
+/// use perfume::web::{Pseudonym, SharedPopulation};
+///
+/// async fn greet(Pseudonym(identity): Pseudonym) -> String {
+///     format!("hello {}", identity.friendly_name)
+/// }
+///
+/// // an authentication layer is expected to insert a `UserId` into each request
+/// let app: Router = Router::new()
+///     .route("/", get(greet))
+///     .with_state(SharedPopulation::new(population, ConcurrentStore::new()));
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Pseudonym(pub Identity<'static>);
+
+impl<T> FromRequestParts<T> for Pseudonym
+where
+    SharedPopulation: FromRef<T>,
+    T: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    // the error is only logged
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    async fn from_request_parts(parts: &mut Parts, state: &T) -> Result<Self, Self::Rejection> {
+        if let Some(identity) = parts.extensions.get::<Identity<'static>>() {
+            return Ok(Self(identity.clone()));
+        }
+        let Some(UserId(user_id)) = parts.extensions.get::<UserId>() else {
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                "no authenticated user".to_string(),
+            ));
+        };
+        let population = SharedPopulation::from_ref(state);
+        population.identity(user_id).await.map(Self).map_err(|e| {
+            event!(ERROR, error = %e, "could not resolve the identity of a request");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not resolve identity".to_string(),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::{
+        ConcurrentStore, ConnectionBridge, Population, RemoteStore, tests::PERFUME_INGREDIENTS,
+    };
+    use crate::testing::MockBridge;
+
+    #[tokio::test]
+    async fn test_pseudonym() {
+        let population = SharedPopulation::new(
+            Population {
+                domain: "br",
+                secret: b"0123456789abcdef0123456789abcdef",
+                ingredients: &PERFUME_INGREDIENTS,
+            },
+            ConcurrentStore::new(),
+        );
+
+        let request = axum::http::Request::builder()
+            .extension(UserId("a@b.br".to_string()))
+            .body(())
+            .unwrap();
+        let (mut parts, _body) = request.into_parts();
+        let Pseudonym(identity) = Pseudonym::from_request_parts(&mut parts, &population)
+            .await
+            .unwrap();
+        assert_eq!(identity, population.identity("a@b.br").await.unwrap());
+
+        let (mut parts, _body) = axum::http::Request::new(()).into_parts();
+        let rejection = Pseudonym::from_request_parts(&mut parts, &population)
+            .await
+            .unwrap_err();
+        assert_eq!(rejection.0, StatusCode::UNAUTHORIZED);
+
+        // storage errors are not described to the client
+        let brazilian = Population {
+            domain: "br",
+            secret: b"0123456789abcdef0123456789abcdef",
+            ingredients: &PERFUME_INGREDIENTS,
+        };
+        let key = brazilian.storage_object("a@b.br").key;
+        let bridge = MockBridge::default();
+        bridge.put(key.as_str(), "corrupt".into()).unwrap();
+        let population = SharedPopulation::new(brazilian, RemoteStore::new(bridge));
+        let request = axum::http::Request::builder()
+            .extension(UserId("a@b.br".to_string()))
+            .body(())
+            .unwrap();
+        let (mut parts, _body) = request.into_parts();
+        let rejection = Pseudonym::from_request_parts(&mut parts, &population)
+            .await
+            .unwrap_err();
+        assert_eq!(rejection.0, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!rejection.1.contains(key.as_str()), "{}", rejection.1);
+    }
+}
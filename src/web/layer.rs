@@ -0,0 +1,188 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use http::{HeaderName, Request};
+use tower::{BoxError, Layer, Service};
+
+use crate::identity::{Identity, Storage};
+use crate::lru::Lru;
+
+use super::{SharedPopulation, UserId};
+
+/// Where [`PseudonymService`] finds the identifier of a request.
+#[derive(Debug, Clone)]
+pub enum IdentifierSource {
+    /// A request header, which is removed once its identity is resolved,
+    /// so that the identifier does not reach the inner service.
+    Header(HeaderName),
+    /// The [`UserId`] extension, inserted by authentication middleware.
+    Extension,
+}
+
+/// Wraps services with a [`PseudonymService`].
+#[derive(Debug, Clone)]
+pub struct PseudonymLayer {
+    population: SharedPopulation,
+    source: IdentifierSource,
+    capacity: usize,
+}
+
+impl PseudonymLayer {
+    /// Resolve the identifier found at `source` using `population`. Each service remembers up to
+    /// `capacity` identities, so that repeated requests over a connection are resolved once.
+    pub fn new(population: SharedPopulation, source: IdentifierSource, capacity: usize) -> Self {
+        Self {
+            population,
+            source,
+            capacity,
+        }
+    }
+}
+
+impl<S> Layer<S> for PseudonymLayer {
+    type Service = PseudonymService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PseudonymService {
+            inner,
+            population: self.population.clone(),
+            source: self.source.clone(),
+            capacity: self.capacity,
+            cache: Arc::new(Mutex::new(Lru::new(self.capacity))),
+        }
+    }
+}
+
+/// Inserts the [`Identity`] of each request into its extensions, as `Identity<'static>`,
+/// before calling the inner service. Requests without an identifier are passed on unchanged.
+///
+/// Servers clone a service for each connection, and each clone remembers identities separately.
+/// Identities are remembered by their hash, so identifiers are never retained.
+pub struct PseudonymService<S> {
+    inner: S,
+    population: SharedPopulation,
+    source: IdentifierSource,
+    capacity: usize,
+    // storage object -> (friendly name, offset)
+    cache: Arc<Mutex<Lru<Storage, (String, usize)>>>,
+}
+
+impl<S: Clone> Clone for PseudonymService<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            population: self.population.clone(),
+            source: self.source.clone(),
+            capacity: self.capacity,
+            cache: Arc::new(Mutex::new(Lru::new(self.capacity))),
+        }
+    }
+}
+
+impl<S, B> Service<Request<B>> for PseudonymService<S>
+where
+    S: Service<Request<B>> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, mut request: Request<B>) -> Self::Future {
+        // the service which was polled ready is the one to call
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let identifier = match &self.source {
+            IdentifierSource::Header(name) => request
+                .headers_mut()
+                .remove(name)
+                .and_then(|value| value.to_str().ok().map(str::to_string)),
+            IdentifierSource::Extension => {
+                request.extensions().get::<UserId>().map(|id| id.0.clone())
+            }
+        };
+        let population = self.population.clone();
+        let cache = self.cache.clone();
+
+        Box::pin(async move {
+            if let Some(identifier) = identifier {
+                let storage = population.population.storage_object(&identifier);
+                let remembered = cache.lock().unwrap().get(&storage);
+                let identity = match remembered {
+                    Some((friendly_name, offset)) => Identity {
+                        domain: population.population.domain,
+                        friendly_name,
+                        storage,
+                        offset,
+                    },
+                    None => {
+                        let identity = population.identity(&identifier).await?;
+                        cache.lock().unwrap().insert(
+                            identity.storage.clone(),
+                            (identity.friendly_name.clone(), identity.offset),
+                        );
+                        identity
+                    }
+                };
+                request.extensions_mut().insert(identity);
+            }
+            inner.call(request).await.map_err(Into::into)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::identity::{ConcurrentStore, Population, tests::PERFUME_INGREDIENTS};
+
+    #[tokio::test]
+    async fn test_pseudonym_layer() -> Result<(), BoxError> {
+        let population = SharedPopulation::new(
+            Population {
+                domain: "br",
+                secret: b"0123456789abcdef0123456789abcdef",
+                ingredients: &PERFUME_INGREDIENTS,
+            },
+            ConcurrentStore::new(),
+        );
+        let expected = population.identity("a@b.br").await?;
+
+        let header = HeaderName::from_static("x-user-email");
+        let layer = PseudonymLayer::new(population, IdentifierSource::Header(header.clone()), 8);
+        let mut service = layer.layer(tower::service_fn(|request: Request<()>| async move {
+            assert!(request.headers().is_empty());
+            let identity = request.extensions().get::<Identity<'static>>().cloned();
+            Ok::<_, Infallible>(identity.map(|i| i.friendly_name))
+        }));
+
+        for _ in 0..2 {
+            let request = Request::builder()
+                .header(&header, "a@b.br")
+                .body(())
+                .unwrap();
+            let name = service.ready().await?.call(request).await?;
+            assert_eq!(name, Some(expected.friendly_name.clone()));
+        }
+        assert_eq!(service.cache.lock().unwrap().len(), 1);
+
+        let connection = service.clone();
+        assert_eq!(connection.cache.lock().unwrap().len(), 0);
+        let name = connection.oneshot(Request::new(())).await?;
+        assert_eq!(name, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+//! Integration with web frameworks: an extractor for [axum](https://crates.io/crates/axum)
+//! (feature `axum`), and middleware for [tower](https://crates.io/crates/tower) services
+//! (feature `tower`).
+//!
+//! Both resolve identities using a [`SharedPopulation`].
+
+#[cfg(feature = "axum")]
+mod extract;
+#[cfg(feature = "tower")]
+mod layer;
+
+#[cfg(feature = "axum")]
+#[cfg_attr(docsrs, doc(cfg(feature = "axum")))]
+pub use extract::Pseudonym;
+#[cfg(feature = "tower")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tower")))]
+pub use layer::{IdentifierSource, PseudonymLayer, PseudonymService};
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+
+use crate::Error;
+use crate::identity::{Identity, Population, StorageState};
+
+/// A [`Population`] and its [`StorageState`], which can be cloned into request handlers.
+/// Identities are resolved one at a time, because the state is held exclusively while resolving.
+#[derive(Clone)]
+pub struct SharedPopulation {
+    population: Arc<Population<'static>>,
+    state: Arc<dyn SharedState>,
+}
+
+impl SharedPopulation {
+    /// Share `population`, which persists identities using `state`.
+    pub fn new<S>(population: Population<'static>, state: S) -> Self
+    where
+        S: StorageState + Send + 'static,
+    {
+        Self {
+            population: Arc::new(population),
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// The same as [`Population::identity_async`].
+    pub async fn identity(&self, identifier: &str) -> Result<Identity<'static>, Error> {
+        let identity = self.state.identity(&self.population, identifier).await?;
+        Ok(Identity {
+            domain: self.population.domain,
+            friendly_name: identity.friendly_name,
+            storage: identity.storage,
+            offset: identity.offset,
+        })
+    }
+
+    /// The same as [`StorageState::health_check_async`], for readiness probes.
+    pub async fn health_check(&self) -> Result<(), Error> {
+        self.state.health_check().await
+    }
+}
+
+impl std::fmt::Debug for SharedPopulation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedPopulation")
+            .field("population", &self.population)
+            .finish_non_exhaustive()
+    }
+}
+
+type IdentityFuture<'a> = Pin<Box<dyn Future<Output = Result<Identity<'a>, Error>> + Send + 'a>>;
+
+// hides the type of the state, so that it is not needed to extract a Pseudonym
+trait SharedState: Send + Sync {
+    fn identity<'a>(
+        &'a self,
+        population: &'a Population<'static>,
+        identifier: &'a str,
+    ) -> IdentityFuture<'a>;
+
+    fn health_check(&self) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + '_>>;
+}
+
+impl<S> SharedState for Mutex<S>
+where
+    S: StorageState + Send,
+{
+    fn identity<'a>(
+        &'a self,
+        population: &'a Population<'static>,
+        identifier: &'a str,
+    ) -> IdentityFuture<'a> {
+        Box::pin(async move {
+            let mut state = self.lock().await;
+            population.identity_async(identifier, &mut *state).await
+        })
+    }
+
+    fn health_check(&self) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + '_>> {
+        Box::pin(async move { self.lock().await.health_check_async().await })
+    }
+}
+
+/// The authenticated user of a request, inserted into its extensions by authentication
+/// middleware. Read by the `Pseudonym` extractor, and by [`IdentifierSource::Extension`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserId(pub String);